@@ -15,6 +15,13 @@ function AgentAmp.new(opts)
     self.backend_name = DEFAULT_BACKEND_NAME
     self.lsp_client = LspClient.new({
         cmd = self.opts.cmd,
+        command_namespace = self.opts.command_namespace,
+        include_globs = self.opts.include_globs,
+        exclude_globs = self.opts.exclude_globs,
+        max_output_bytes = self.opts.max_output_bytes,
+        model_params = self.opts.model_params,
+        system_prompt = self.opts.system_prompt,
+        code_action_kind = self.opts.code_action_kind,
         on_apply_edit = function(err, result, ctx)
             self:_on_apply_edit(err, result, ctx)
         end,
@@ -27,6 +34,12 @@ function AgentAmp.new(opts)
         on_backend_info = function(params)
             self:_on_backend_info(params)
         end,
+        on_version_mismatch = function(params)
+            self:_on_version_mismatch(params)
+        end,
+        on_active_regions = function(params)
+            self:_on_active_regions(params)
+        end,
         get_backend_name = function()
             return self.backend_name
         end,
@@ -41,13 +54,48 @@ function AgentAmp:_on_apply_edit(_err, _result, _ctx)
 end
 
 function AgentAmp:_on_backend_info(params)
-    if params and params.name and params.name ~= "" then
-        self.backend_name = params.name
+    if params and params.backend and params.backend ~= "" then
+        self.backend_name = params.backend
         -- Update spinner manager with the new backend name
         self.spinner_manager:set_backend_name(self.backend_name)
     end
 end
 
+-- Restore spinners for jobs the server is still running after the client session restarted
+function AgentAmp:_on_active_regions(params)
+    if not params or not params.uri or not params.regions then
+        return
+    end
+
+    local bufnr = vim.uri_to_bufnr(params.uri)
+    if not bufnr or not vim.api.nvim_buf_is_valid(bufnr) then
+        return
+    end
+
+    for _, region in ipairs(params.regions) do
+        if self.spinner_manager:is_running(region.job_id) then
+            self.spinner_manager:update_job_line(region.job_id, region.line)
+        else
+            self.spinner_manager:start(region.job_id, bufnr, region.line)
+        end
+    end
+end
+
+function AgentAmp:_on_version_mismatch(params)
+    if not params then
+        return
+    end
+    vim.notify(
+        string.format(
+            "[%s] File changed since the action was triggered (version %d, now %d); implementing against the latest text",
+            self.backend_name,
+            params.requested_version,
+            params.actual_version
+        ),
+        vim.log.levels.WARN
+    )
+end
+
 function AgentAmp:_on_job_completed(params)
     if not params or not params.job_id then
         return
@@ -155,13 +203,16 @@ function AgentAmp:_on_progress(params)
     -- Update line position if it changed (due to other job completing)
     self.spinner_manager:update_job_line(server_job_id, line)
 
-    -- Update preview text
-    if params.preview then
+    -- Update preview text (or the queue position while waiting for a run slot)
+    if params.queue_position then
+        self.spinner_manager:set_preview(server_job_id, string.format("Queued (%d ahead)", params.queue_position))
+    elseif params.preview then
         self.spinner_manager:set_preview(server_job_id, params.preview)
     end
 end
 
-function AgentAmp:implement_function()
+function AgentAmp:implement_function(opts)
+    opts = opts or {}
     local bufnr = vim.api.nvim_get_current_buf()
     local pos = vim.api.nvim_win_get_cursor(0)
     local line = pos[1] - 1
@@ -183,7 +234,7 @@ function AgentAmp:implement_function()
             if not cmd and action.edit == nil then
                 cmd = action
             end
-            if cmd and cmd.command == "agent.implFunction" then
+            if cmd and cmd.command == self.lsp_client.command_namespace .. ".implFunction" then
                 amp_action = cmd
                 break
             end
@@ -200,12 +251,20 @@ function AgentAmp:implement_function()
             line = line,
         }
 
-        -- Add pending job ID as 6th argument so server can correlate responses
+        -- Set pending job ID as 6th argument so server can correlate responses
         amp_action.arguments = amp_action.arguments or {}
-        table.insert(amp_action.arguments, job_id)
+        amp_action.arguments[6] = job_id
+        -- 8th argument allows overwriting a function that already has an implementation
+        if opts.force then
+            amp_action.arguments[8] = true
+        end
 
         self.spinner_manager:start(job_id, bufnr, line)
-        self.lsp_client:execute_command(bufnr, amp_action)
+        -- A refused command (e.g. an existing implementation without force) never starts a job
+        self.lsp_client:execute_command(bufnr, amp_action, function()
+            self.spinner_manager:stop(job_id)
+            self.pending_jobs[job_id] = nil
+        end)
     end)
 end
 
@@ -219,9 +278,9 @@ function M.setup(opts)
     opts = opts or {}
     instance = AgentAmp.new(opts)
 
-    vim.api.nvim_create_user_command("AgentImplementFunction", function()
-        M.implement_function()
-    end, { desc = "Implement function with AI agent" })
+    vim.api.nvim_create_user_command("AgentImplementFunction", function(cmd)
+        M.implement_function({ force = cmd.bang })
+    end, { bang = true, desc = "Implement function with AI agent (! overwrites an existing implementation)" })
 
     local augroup = vim.api.nvim_create_augroup("AgentAmp", { clear = true })
 
@@ -245,12 +304,12 @@ function M.setup(opts)
     })
 end
 
-function M.implement_function()
+function M.implement_function(opts)
     if not instance then
         vim.notify("[" .. DEFAULT_BACKEND_NAME .. "] Plugin not initialized. Call require('agent_amp').setup() first", vim.log.levels.ERROR)
         return
     end
-    instance:implement_function()
+    instance:implement_function(opts)
 end
 
 function M.get_instance()
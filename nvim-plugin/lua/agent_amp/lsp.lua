@@ -2,6 +2,8 @@ local LspClient = {}
 LspClient.__index = LspClient
 
 local DEFAULT_BACKEND_NAME = "Agent"
+local DEFAULT_COMMAND_NAMESPACE = "agent"
+local DEFAULT_CODE_ACTION_KIND = "quickfix"
 
 local function get_plugin_root()
     local source = debug.getinfo(1, "S").source:sub(2)
@@ -32,11 +34,20 @@ end
 function LspClient.new(opts)
     local self = setmetatable({}, LspClient)
     self.user_cmd = opts.cmd
+    self.command_namespace = opts.command_namespace or DEFAULT_COMMAND_NAMESPACE
+    self.include_globs = opts.include_globs
+    self.exclude_globs = opts.exclude_globs
+    self.max_output_bytes = opts.max_output_bytes
+    self.model_params = opts.model_params
+    self.system_prompt = opts.system_prompt
+    self.code_action_kind = opts.code_action_kind or DEFAULT_CODE_ACTION_KIND
     self.client_id = nil
     self.on_apply_edit = opts.on_apply_edit
     self.on_progress = opts.on_progress
     self.on_job_completed = opts.on_job_completed
     self.on_backend_info = opts.on_backend_info
+    self.on_version_mismatch = opts.on_version_mismatch
+    self.on_active_regions = opts.on_active_regions
     self.get_backend_name = opts.get_backend_name
     return self
 end
@@ -68,11 +79,28 @@ function LspClient:_create_client_config()
     end
 
     local original_handler = vim.lsp.handlers["workspace/applyEdit"]
+    local ns = self.command_namespace
+
+    -- Servers with different namespaces must not be reused for each other
+    local name = "agent-lsp"
+    if ns ~= DEFAULT_COMMAND_NAMESPACE then
+        name = name .. "-" .. ns
+    end
 
     return {
-        name = "agent-lsp",
+        name = name,
         cmd = cmd,
         root_dir = vim.fn.getcwd(),
+        init_options = {
+            command_namespace = ns,
+            -- nil leaves the server's compiled-in defaults in place
+            include_globs = self.include_globs,
+            exclude_globs = self.exclude_globs,
+            max_output_bytes = self.max_output_bytes,
+            model_params = self.model_params,
+            system_prompt = self.system_prompt,
+            code_action_kind = self.code_action_kind,
+        },
         handlers = {
             ["workspace/applyEdit"] = function(err, result, ctx, config)
                 if self.on_apply_edit then
@@ -83,21 +111,31 @@ function LspClient:_create_client_config()
                 end
                 return { applied = true }
             end,
-            ["agent/implFunctionProgress"] = function(_err, params, _ctx)
+            [ns .. "/implFunctionProgress"] = function(_err, params, _ctx)
                 if self.on_progress then
                     self.on_progress(params)
                 end
             end,
-            ["agent/jobCompleted"] = function(_err, params, _ctx)
+            [ns .. "/jobCompleted"] = function(_err, params, _ctx)
                 if self.on_job_completed then
                     self.on_job_completed(params)
                 end
             end,
-            ["agent/backendInfo"] = function(_err, params, _ctx)
+            [ns .. "/backendInfo"] = function(_err, params, _ctx)
                 if self.on_backend_info then
                     self.on_backend_info(params)
                 end
             end,
+            [ns .. "/activeRegions"] = function(_err, params, _ctx)
+                if self.on_active_regions then
+                    self.on_active_regions(params)
+                end
+            end,
+            [ns .. "/versionMismatch"] = function(_err, params, _ctx)
+                if self.on_version_mismatch then
+                    self.on_version_mismatch(params)
+                end
+            end,
         },
     }
 end
@@ -182,7 +220,7 @@ function LspClient:request_code_actions(bufnr, callback)
         },
         context = {
             diagnostics = {},
-            only = { "quickfix" },
+            only = { self.code_action_kind },
         },
     }
 
@@ -196,7 +234,7 @@ function LspClient:request_code_actions(bufnr, callback)
     end)
 end
 
-function LspClient:execute_command(bufnr, command)
+function LspClient:execute_command(bufnr, command, on_error)
     local client_id = self:ensure_client(bufnr)
     if not client_id then
         return
@@ -211,6 +249,9 @@ function LspClient:execute_command(bufnr, command)
     client.request("workspace/executeCommand", command, function(err, _result)
         if err then
             vim.notify("[" .. self:_get_backend_name() .. "] Execute command failed: " .. vim.inspect(err), vim.log.levels.ERROR)
+            if on_error then
+                on_error(err)
+            end
         end
     end, bufnr)
 end
@@ -1,45 +1,18 @@
 use std::error::Error;
 use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
+use std::process::Stdio;
 
-use serde::Deserialize;
+use lsp_types::Range;
 use tracing::info;
 
-use crate::backend::Backend;
-use crate::utils::strip_markdown_code_block;
-
-#[derive(Debug, Deserialize)]
-struct AmpMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    result: Option<String>,
-    is_error: Option<bool>,
-    message: Option<AssistantMessage>,
-}
-
-#[derive(Debug, Deserialize)]
-struct AssistantMessage {
-    content: Vec<ContentBlock>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-enum ContentBlock {
-    #[serde(rename = "text")]
-    Text { text: String },
-    #[serde(rename = "tool_use")]
-    ToolUse {
-        id: String,
-        name: String,
-        input: serde_json::Value,
-    },
-    #[serde(rename = "tool_result")]
-    ToolResult {
-        tool_use_id: String,
-        content: String,
-        is_error: bool,
-    },
-}
+use crate::backend::{
+    build_selection_prompt, check_cli_installed, cli_command, ignore_model_params,
+    prepend_system_prompt, prompt_context, stop_cancelled_cli, stop_unreadable_cli, Backend,
+    LimitedOutput, ModelParams,
+};
+use crate::cancellation::CancellationToken;
+use crate::config::{BackendType, MAX_OUTPUT_BYTES};
+use crate::utils::extract_function_signature;
 
 /// Build the prompt for function implementation with Amp.
 fn build_prompt(
@@ -63,96 +36,72 @@ fn build_prompt(
     )
 }
 
-pub struct AmpClient;
+pub struct AmpClient {
+    /// Most output the CLI may write for one job before it is killed.
+    max_output_bytes: usize,
+    /// Sampling parameters, which the CLI has no way to take.
+    model_params: ModelParams,
+    /// Instructions put before every prompt.
+    system_prompt: Option<String>,
+}
 
 impl AmpClient {
     pub fn new() -> Self {
-        Self
+        Self {
+            max_output_bytes: MAX_OUTPUT_BYTES,
+            model_params: ModelParams::default(),
+            system_prompt: None,
+        }
     }
-}
 
-impl Default for AmpClient {
-    fn default() -> Self {
-        Self::new()
+    /// Cap the CLI's output per job at `max_output_bytes`.
+    pub fn with_max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.max_output_bytes = max_output_bytes;
+        self
     }
-}
 
-impl Backend for AmpClient {
-    fn implement_function(
-        &self,
-        file_path: &str,
-        line: u32,
-        character: u32,
-        language_id: &str,
-        file_contents: &str,
-    ) -> Result<String, Box<dyn Error + Sync + Send>> {
-        info!(
-            "Calling amp CLI - file: {}, line: {}, character: {}, language: {}",
-            file_path, line, character, language_id
-        );
-
-        // NOTE: implement_function is deprecated in favor of streaming, using dummy path
-        let prompt = build_prompt(line, character, language_id, file_contents, "/tmp/dummy");
-
-        let output = Command::new("amp")
-            .arg("--execute")
-            .arg(&prompt)
-            .arg("--stream-json")
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("amp CLI failed: {}", stderr).into());
-        }
-
-        let stdout = String::from_utf8(output.stdout)?;
-
-        for line in stdout.lines().rev() {
-            if let Ok(msg) = serde_json::from_str::<AmpMessage>(line) {
-                if msg.msg_type == "result" {
-                    if msg.is_error == Some(true) {
-                        return Err(format!(
-                            "amp returned error: {}",
-                            msg.result.unwrap_or_default()
-                        )
-                        .into());
-                    }
-                    let result = msg.result.unwrap_or_default();
-                    let result = strip_markdown_code_block(&result);
-                    info!("Amp CLI returned {} bytes", result.len());
-                    return Ok(result.trim().to_string());
-                }
-            }
-        }
+    pub fn with_model_params(mut self, model_params: ModelParams) -> Self {
+        self.model_params = model_params;
+        self
+    }
 
-        Err("No result found in amp output".into())
+    pub fn with_system_prompt(mut self, system_prompt: Option<String>) -> Self {
+        self.system_prompt = system_prompt;
+        self
     }
 
-    fn implement_function_streaming(
+    /// The prompt for one job: `build_prompt`'s with its `prompt_context`, after the system
+    /// prompt.
+    fn prompt(
         &self,
-        file_path: &str,
         line: u32,
         character: u32,
         language_id: &str,
         file_contents: &str,
         output_path: &str,
-        function_signature: &str,
-        mut on_progress: Box<dyn FnMut(&str) + Send>,
-    ) -> Result<(), Box<dyn Error + Sync + Send>> {
-        info!(
-            "Calling amp CLI (streaming) - file: {}, line: {}, character: {}, language: {}, function: {}",
-            file_path, line, character, language_id, function_signature
-        );
-
-        // TODO: Include function_signature in the prompt for Amp as well
+    ) -> String {
         let prompt = build_prompt(line, character, language_id, file_contents, output_path);
+        let function_signature =
+            extract_function_signature(file_contents, line as usize).unwrap_or_default();
+        prepend_system_prompt(
+            self.system_prompt.as_deref(),
+            prompt + &prompt_context(file_contents, line, &function_signature),
+        )
+    }
+
+    /// Run the CLI on `prompt`, forwarding its output to `on_progress` until it exits.
+    fn run_streaming(
+        &self,
+        prompt: &str,
+        output_path: &str,
+        cancellation: &CancellationToken,
+        on_progress: &mut Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        ignore_model_params("amp", &self.model_params);
 
-        let mut child = Command::new("amp")
+        let mut child = cli_command("amp", output_path)?
             .arg("--execute")
-            .arg(&prompt)
+            .arg(prompt)
             .arg("--stream-json")
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
@@ -160,12 +109,16 @@ impl Backend for AmpClient {
             .spawn()?;
 
         let stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
-        let reader = BufReader::new(stdout);
+        let reader = BufReader::new(LimitedOutput::new(stdout, self.max_output_bytes));
 
         let mut accumulated_text = String::new();
 
         for line_result in reader.lines() {
-            let line = line_result?;
+            let line = line_result.map_err(|e| stop_unreadable_cli(&mut child, e))?;
+            if cancellation.is_cancelled() {
+                info!("Stopping amp CLI for a cancelled job");
+                return Err(stop_cancelled_cli(&mut child));
+            }
 
             // Assume amp streams JSON objects with "content" field
             // But if it's chatting, it might just be text blocks.
@@ -192,3 +145,90 @@ impl Backend for AmpClient {
         Ok(())
     }
 }
+
+impl Default for AmpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for AmpClient {
+    fn check_available(&self) -> Result<(), String> {
+        check_cli_installed("amp")
+    }
+
+    fn backend_type(&self) -> BackendType {
+        BackendType::Amp
+    }
+
+    fn implement_function_streaming(
+        &self,
+        file_path: &str,
+        line: u32,
+        character: u32,
+        language_id: &str,
+        file_contents: &str,
+        output_path: &str,
+        function_signature: &str,
+        cancellation: &CancellationToken,
+        mut on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        info!(
+            "Calling amp CLI (streaming) - file: {}, line: {}, character: {}, language: {}, function: {}",
+            file_path, line, character, language_id, function_signature
+        );
+
+        // TODO: Include function_signature in the prompt for Amp as well
+        let prompt = self.prompt(line, character, language_id, file_contents, output_path);
+
+        self.run_streaming(&prompt, output_path, cancellation, &mut on_progress)
+    }
+
+    fn implement_selection_streaming(
+        &self,
+        file_path: &str,
+        range: Range,
+        selection: &str,
+        language_id: &str,
+        file_contents: &str,
+        output_path: &str,
+        cancellation: &CancellationToken,
+        mut on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        info!(
+            "Calling amp CLI (streaming) - file: {}, selection: {:?}, language: {}",
+            file_path, range, language_id
+        );
+
+        let prompt = prepend_system_prompt(
+            self.system_prompt.as_deref(),
+            build_selection_prompt(range, selection, language_id, file_contents, output_path),
+        );
+        self.run_streaming(&prompt, output_path, cancellation, &mut on_progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_starts_with_system_prompt() {
+        let client = AmpClient::new().with_system_prompt(Some("Prefer iterators.".to_string()));
+        let prompt = client.prompt(9, 4, "rust", "fn main() {}", "/tmp/output.rs");
+        assert!(prompt.starts_with("Prefer iterators.\n\n"));
+        assert!(prompt.ends_with(&build_prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs"
+        )));
+
+        let prompt = AmpClient::new().prompt(9, 4, "rust", "fn main() {}", "/tmp/output.rs");
+        assert_eq!(
+            prompt,
+            build_prompt(9, 4, "rust", "fn main() {}", "/tmp/output.rs")
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use lsp_types::Url;
+
+use crate::sync_utils::lock_recovering;
+
+/// Groups results that become ready for the same document within a short window, so they
+/// can be applied together.
+///
+/// The first submission for a document opens a group and makes its caller the group's
+/// leader: it waits out the window and then takes everything submitted meanwhile.
+/// Submissions after the leader took its group open the next one.
+#[derive(Debug)]
+pub struct ApplyCoordinator<T> {
+    window: Duration,
+    groups: Mutex<HashMap<Url, Vec<T>>>,
+}
+
+impl<T> ApplyCoordinator<T> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Add `item` to the open group of `uri`.
+    ///
+    /// The caller that opened the group gets the whole group back, in submission order, once
+    /// the window has passed; everyone else gets `None` and leaves `item` to the leader.
+    pub fn submit(&self, uri: &Url, item: T) -> Option<Vec<T>> {
+        let opened = {
+            let mut groups = lock_recovering(&self.groups);
+            let group = groups.entry(uri.clone()).or_default();
+            group.push(item);
+            group.len() == 1
+        };
+        if !opened {
+            return None;
+        }
+
+        thread::sleep(self.window);
+        Some(
+            lock_recovering(&self.groups)
+                .remove(uri)
+                .unwrap_or_default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn uri(name: &str) -> Url {
+        Url::parse(&format!("file:///{}.rs", name)).unwrap()
+    }
+
+    #[test]
+    fn test_leader_takes_items_submitted_within_window() {
+        let coordinator = Arc::new(ApplyCoordinator::new(Duration::from_millis(100)));
+
+        let leader = {
+            let coordinator = coordinator.clone();
+            thread::spawn(move || coordinator.submit(&uri("a"), 1))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(coordinator.submit(&uri("a"), 2), None);
+        assert_eq!(coordinator.submit(&uri("a"), 3), None);
+        // Other documents have groups of their own
+        assert_eq!(coordinator.submit(&uri("b"), 4), Some(vec![4]));
+
+        assert_eq!(leader.join().unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_submission_after_window_opens_next_group() {
+        let coordinator = ApplyCoordinator::new(Duration::ZERO);
+
+        assert_eq!(coordinator.submit(&uri("a"), 1), Some(vec![1]));
+        assert_eq!(coordinator.submit(&uri("a"), 2), Some(vec![2]));
+    }
+}
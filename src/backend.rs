@@ -1,27 +1,30 @@
+use std::env;
 use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+
+use lsp_types::Range;
+use serde::Deserialize;
+use tracing::{error, info};
 
 use crate::amp::AmpClient;
+use crate::cancellation::{CancellationToken, Cancelled};
 use crate::claude_code::ClaudeCodeClient;
-use crate::config::{BackendType, CURRENT_BACKEND};
+use crate::config::{
+    BackendType, BACKEND_SANDBOX, CURRENT_BACKEND, MAX_OUTPUT_BYTES, MODEL_TEMPERATURE,
+    MODEL_TOP_P, SYSTEM_PROMPT, USAGE_HINTS,
+};
 use crate::opencode::OpenCodeClient;
+use crate::utils::{find_call_sites, find_decorated_start, find_function_start};
 
 /// Trait for AI backends that can implement functions.
 ///
 /// This abstraction allows switching between different AI providers
 /// (e.g., Amp, OpenCode) for function implementation.
 pub trait Backend: Send + Sync {
-    /// Implement a function at the given location.
-    ///
-    /// Returns the function body implementation as a string.
-    fn implement_function(
-        &self,
-        file_path: &str,
-        line: u32,
-        character: u32,
-        language_id: &str,
-        file_contents: &str,
-    ) -> Result<String, Box<dyn Error + Sync + Send>>;
-
     /// Implement a function with streaming progress updates.
     ///
     /// The `on_progress` callback is called with intermediate results/status
@@ -31,6 +34,10 @@ pub trait Backend: Send + Sync {
     /// helping disambiguate when multiple functions exist in the file.
     ///
     /// The final implementation code should be written to `output_path`.
+    ///
+    /// Implementations should check `cancellation` between chunks of output and, once it is
+    /// tripped, stop the agent and return a `Cancelled` error.
+    #[allow(clippy::too_many_arguments)]
     fn implement_function_streaming(
         &self,
         file_path: &str,
@@ -40,34 +47,679 @@ pub trait Backend: Send + Sync {
         file_contents: &str,
         output_path: &str,
         function_signature: &str,
+        cancellation: &CancellationToken,
         on_progress: Box<dyn FnMut(&str) + Send>,
     ) -> Result<(), Box<dyn Error + Sync + Send>>;
+
+    /// Complete or implement an arbitrary selected region of a file, writing only the text
+    /// that replaces `selection` (the text of `range`) to `output_path`.
+    ///
+    /// Works like `implement_function_streaming`, without a function to target. Backends
+    /// that cannot do it return an error (the default).
+    #[allow(clippy::too_many_arguments)]
+    fn implement_selection_streaming(
+        &self,
+        _file_path: &str,
+        _range: Range,
+        _selection: &str,
+        _language_id: &str,
+        _file_contents: &str,
+        _output_path: &str,
+        _cancellation: &CancellationToken,
+        _on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Err("This backend cannot implement selections".into())
+    }
+
+    /// Suggest text to insert at the cursor for `textDocument/completion`.
+    ///
+    /// Called on the request path with a hard deadline, so implementations should answer
+    /// quickly. Returns None when the backend has no suggestion (the default).
+    fn complete(
+        &self,
+        _file_path: &str,
+        _line: u32,
+        _character: u32,
+        _language_id: &str,
+        _file_contents: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Sync + Send>> {
+        Ok(None)
+    }
+
+    /// Check that the backend can run at all, e.g. that its CLI is installed.
+    ///
+    /// Called before a job is accepted, so it must be cheap. Returns a message for the user
+    /// when the backend is unavailable.
+    fn check_available(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Which backend this is, for the names and limits reported with its jobs. Stand-ins
+    /// such as test doubles keep the default, `CURRENT_BACKEND`.
+    fn backend_type(&self) -> BackendType {
+        CURRENT_BACKEND
+    }
+}
+
+/// Kill a CLI agent whose job was cancelled, returning the error to report.
+pub fn stop_cancelled_cli(child: &mut Child) -> Box<dyn Error + Sync + Send> {
+    let _ = child.kill();
+    let _ = child.wait();
+    Box::new(Cancelled)
+}
+
+/// Error a backend returns when its agent wrote more output than it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for OutputTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "OutputTooLarge: backend output exceeded the limit of {} bytes",
+            self.limit
+        )
+    }
+}
+
+impl Error for OutputTooLarge {}
+
+/// Reader over a CLI agent's output that fails with `OutputTooLarge` once more than
+/// `limit` bytes were read, so a runaway agent cannot exhaust memory even on a single line.
+pub struct LimitedOutput<R> {
+    inner: R,
+    limit: usize,
+    read: usize,
+}
+
+impl<R: Read> LimitedOutput<R> {
+    pub fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            limit,
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedOutput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read;
+        if self.read > self.limit {
+            return Err(io::Error::other(OutputTooLarge { limit: self.limit }));
+        }
+        Ok(read)
+    }
+}
+
+/// Kill a CLI agent whose output could not be read, returning the error to report.
+///
+/// An `OutputTooLarge` from `LimitedOutput` is reported as itself rather than as an I/O error.
+pub fn stop_unreadable_cli(child: &mut Child, error: io::Error) -> Box<dyn Error + Sync + Send> {
+    let _ = child.kill();
+    let _ = child.wait();
+    match error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<OutputTooLarge>())
+    {
+        Some(too_large) => Box::new(*too_large),
+        None => Box::new(error),
+    }
+}
+
+/// `Command` for a CLI agent that writes its result to `output_path`.
+///
+/// With `BACKEND_SANDBOX`, the worker put `output_path` in the job's sandbox directory, and
+/// the CLI runs there.
+pub fn cli_command(program: &str, output_path: &str) -> Result<Command, String> {
+    let mut command = Command::new(program);
+    if BACKEND_SANDBOX {
+        let sandbox = Path::new(output_path)
+            .parent()
+            .ok_or_else(|| format!("Output path {} has no directory", output_path))?;
+        run_in_sandbox(&mut command, sandbox, output_path)?;
+    }
+    Ok(command)
+}
+
+/// Make `command` run in `sandbox`, checking that `output_path` is a file directly inside it.
+pub fn run_in_sandbox(
+    command: &mut Command,
+    sandbox: &Path,
+    output_path: &str,
+) -> Result<(), String> {
+    let output_path = Path::new(output_path);
+    // Relative paths resolve against the working directory, i.e. the sandbox
+    let resolved = sandbox.join(output_path);
+    let inside = resolved.parent() == Some(sandbox)
+        && resolved
+            .file_name()
+            .is_some_and(|name| name != "." && name != "..");
+    if !inside {
+        return Err(format!(
+            "Output path {} is outside the sandbox {}",
+            output_path.display(),
+            sandbox.display()
+        ));
+    }
+    command.current_dir(sandbox);
+    Ok(())
+}
+
+/// Check that the CLI `binary` can be found on `PATH` (or at its path, if it has one).
+pub fn check_cli_installed(binary: &str) -> Result<(), String> {
+    let found = if binary.contains(std::path::MAIN_SEPARATOR) {
+        Path::new(binary).is_file()
+    } else {
+        env::var_os("PATH")
+            .map(|path| env::split_paths(&path).any(|dir| dir.join(binary).is_file()))
+            .unwrap_or(false)
+    };
+
+    if found {
+        Ok(())
+    } else {
+        Err(format!(
+            "Backend CLI '{}' was not found on PATH; install it or select another backend",
+            binary
+        ))
+    }
+}
+
+/// What a backend wrote to its output file.
+///
+/// Backends usually write plain code. They may instead write a JSON object
+/// `{"implementation": "...", "imports": ["..."], "files": [{"path": "...", "content": "..."}]}`
+/// when the implementation needs new imports or new files, such as its tests.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BackendOutput {
+    pub implementation: String,
+    #[serde(default)]
+    pub imports: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<NewFile>,
+}
+
+/// A file a backend asked to create alongside its implementation.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct NewFile {
+    /// Relative to the directory of the file being edited.
+    pub path: String,
+    pub content: String,
+}
+
+impl BackendOutput {
+    /// Parse the output file contents, treating anything that is not a structured result as code.
+    pub fn parse(raw: &str) -> Self {
+        serde_json::from_str(raw.trim()).unwrap_or_else(|_| Self {
+            implementation: raw.to_string(),
+            imports: Vec::new(),
+            files: Vec::new(),
+        })
+    }
+}
+
+/// Optional sampling parameters passed to the model, for backends that accept them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub struct ModelParams {
+    /// Sampling temperature, from 0.0 to 2.0.
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability mass, from 0.0 to 1.0.
+    pub top_p: Option<f64>,
+}
+
+impl ModelParams {
+    /// The parameters set in `src/config.rs`.
+    pub fn configured() -> Self {
+        Self {
+            temperature: MODEL_TEMPERATURE,
+            top_p: MODEL_TOP_P,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.temperature.is_none() && self.top_p.is_none()
+    }
+
+    /// Check that every set parameter is within the range models accept.
+    pub fn validate(&self) -> Result<(), String> {
+        let in_range = |name: &str, value: Option<f64>, max: f64| match value {
+            Some(value) if !(0.0..=max).contains(&value) => Err(format!(
+                "Model parameter {} must be between 0 and {}, got {}",
+                name, max, value
+            )),
+            _ => Ok(()),
+        };
+        in_range("temperature", self.temperature, 2.0)?;
+        in_range("top_p", self.top_p, 1.0)
+    }
+}
+
+/// Log that a backend's CLI has no way to take the configured model parameters.
+pub fn ignore_model_params(cli: &str, model_params: &ModelParams) {
+    if !model_params.is_empty() {
+        info!(
+            "{} CLI does not accept model parameters, ignoring {:?}",
+            cli, model_params
+        );
+    }
+}
+
+/// Put `system_prompt` before `prompt` as its first paragraph. Blank system prompts are
+/// left out.
+pub fn prepend_system_prompt(system_prompt: Option<&str>, prompt: String) -> String {
+    match system_prompt.map(str::trim) {
+        Some(system_prompt) if !system_prompt.is_empty() => {
+            format!("{}\n\n{}", system_prompt, prompt)
+        }
+        _ => prompt,
+    }
+}
+
+/// The prompt asking a backend to complete or implement `selection`, the text of `range`
+/// in `file_contents`, writing its replacement to `output_path`. Shared by all backends.
+pub fn build_selection_prompt(
+    range: Range,
+    selection: &str,
+    language_id: &str,
+    file_contents: &str,
+    output_path: &str,
+) -> String {
+    format!(
+        "Complete or implement the code selected from line {}, character {} to line {}, \
+         character {} in the following {} file.\n\n<SELECTION>\n{}\n</SELECTION>\n\n\
+         IMPORTANT: Your output replaces exactly the selection - do NOT repeat the code around \
+         it, and do NOT write a whole function unless the selection is one.\n\n\
+         Write ONLY the replacement for the selection to the file: {} \
+         Do NOT output the code to stdout. \
+         Output only status messages or confirmation.\n\n<FILE-CONTENT>\n{}</FILE-CONTENT>\n\n\
+         <MUST-OBEY>\n\
+         You can overwrite the output file's content, but NEVER read it, just write to it.\n\
+         Describe your steps before performing them.\n\
+         </MUST-OBEY>",
+        range.start.line + 1,
+        range.start.character + 1,
+        range.end.line + 1,
+        range.end.character + 1,
+        language_id,
+        selection,
+        output_path,
+        file_contents
+    )
+}
+
+/// What a client appends to its prompt about the function at `line` of `file_contents`: a
+/// note that its attributes, decorators and doc comments are already in place, and where it
+/// is called (`usage_hints`).
+pub fn prompt_context(file_contents: &str, line: u32, function_signature: &str) -> String {
+    decorations_note(file_contents, line) + &usage_hints(file_contents, function_signature)
+}
+
+/// A paragraph telling the backend the function at `line` already has attributes, decorators
+/// or doc comments above it, which are kept. Empty when it has none.
+fn decorations_note(file_contents: &str, line: u32) -> String {
+    let lines: Vec<&str> = file_contents.lines().collect();
+    let Some(start) = find_function_start(&lines, line as usize) else {
+        return String::new();
+    };
+    if find_decorated_start(&lines, start) == start {
+        return String::new();
+    }
+    "\n\nThe attributes, decorators and doc comments above the function are already in the \
+     file and are kept. Leave them out of the output unless you change them."
+        .to_string()
+}
+
+/// A `<USAGE>` section listing up to `USAGE_HINTS` call sites of the function with
+/// `function_signature` in `file_contents`, to append to a prompt. Empty when there are none.
+pub fn usage_hints(file_contents: &str, function_signature: &str) -> String {
+    let call_sites = find_call_sites(file_contents, function_signature, USAGE_HINTS);
+    if call_sites.is_empty() {
+        return String::new();
+    }
+    let lines: String = call_sites
+        .iter()
+        .map(|(line, text)| format!("line {}: {}\n", line + 1, text))
+        .collect();
+    format!(
+        "\n\n<USAGE>\nThe function is called like this elsewhere in the file; match these \
+         arguments and how the result is used.\n{}</USAGE>",
+        lines
+    )
+}
+
+/// Backend settings fixed for the session at initialize.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendSettings {
+    /// The backend `create_backend` builds.
+    pub backend: BackendType,
+    /// Most output the agent may write for one job.
+    pub max_output_bytes: usize,
+    pub model_params: ModelParams,
+    /// Instructions put before every prompt.
+    pub system_prompt: Option<String>,
+}
+
+impl Default for BackendSettings {
+    fn default() -> Self {
+        Self {
+            backend: CURRENT_BACKEND,
+            max_output_bytes: MAX_OUTPUT_BYTES,
+            model_params: ModelParams::configured(),
+            system_prompt: SYSTEM_PROMPT.map(str::to_string),
+        }
+    }
+}
+
+impl BackendSettings {
+    /// Settings from the `backend`, `max_output_bytes`, `model_params` and `system_prompt`
+    /// initialization options, with `src/config.rs` values for any that are absent. Unknown
+    /// backends and out-of-range model parameters are logged and replaced by the configured
+    /// ones.
+    pub fn from_initialize_params(params: &serde_json::Value) -> Self {
+        let mut settings = Self::default();
+        let Some(options) = params.get("initializationOptions") else {
+            return settings;
+        };
+
+        if let Some(name) = options.get("backend").and_then(|v| v.as_str()) {
+            match BackendType::from_name(name) {
+                Some(backend) => settings.backend = backend,
+                None => error!("Ignoring unknown backend {:?}", name),
+            }
+        }
+        if let Some(limit) = options.get("max_output_bytes").and_then(|v| v.as_u64()) {
+            settings.max_output_bytes = limit as usize;
+        }
+        if let Some(model_params) = options.get("model_params") {
+            match serde_json::from_value::<ModelParams>(model_params.clone()) {
+                Ok(model_params) => match model_params.validate() {
+                    Ok(()) => settings.model_params = model_params,
+                    Err(e) => error!("Ignoring model_params: {}", e),
+                },
+                Err(e) => error!("Ignoring malformed model_params: {}", e),
+            }
+        }
+        if let Some(system_prompt) = options.get("system_prompt").and_then(|v| v.as_str()) {
+            settings.system_prompt = Some(system_prompt.to_string());
+        }
+        settings
+    }
 }
 
 /// Create a backend instance based on the current configuration.
 ///
-/// Returns a boxed trait object implementing the `Backend` trait.
-/// The specific implementation is determined by `CURRENT_BACKEND` in config.
-pub fn create_backend() -> Box<dyn Backend> {
-    match CURRENT_BACKEND {
-        BackendType::Amp => Box::new(AmpClient::new()),
-        BackendType::OpenCode => Box::new(OpenCodeClient::new()),
-        BackendType::ClaudeCode => Box::new(ClaudeCodeClient::new()),
+/// Returns a shared trait object implementing the `Backend` trait.
+/// The specific implementation is the one `settings.backend` selects, set up with the rest
+/// of the session's `settings`.
+pub fn create_backend(settings: BackendSettings) -> Arc<dyn Backend> {
+    match settings.backend {
+        BackendType::Amp => Arc::new(
+            AmpClient::new()
+                .with_max_output_bytes(settings.max_output_bytes)
+                .with_model_params(settings.model_params)
+                .with_system_prompt(settings.system_prompt),
+        ),
+        BackendType::OpenCode => Arc::new(
+            OpenCodeClient::new()
+                .with_max_output_bytes(settings.max_output_bytes)
+                .with_model_params(settings.model_params)
+                .with_system_prompt(settings.system_prompt),
+        ),
+        BackendType::ClaudeCode => Arc::new(
+            ClaudeCodeClient::new()
+                .with_max_output_bytes(settings.max_output_bytes)
+                .with_model_params(settings.model_params)
+                .with_system_prompt(settings.system_prompt),
+        ),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{BufRead, BufReader};
 
     #[test]
     fn test_create_backend_returns_configured_backend() {
-        // This test verifies that create_backend() returns a valid backend
-        // The actual type depends on CURRENT_BACKEND configuration
-        let backend = create_backend();
+        let backend = create_backend(BackendSettings::default());
+        assert_eq!(backend.backend_type(), CURRENT_BACKEND);
+
+        for backend_type in [
+            BackendType::Amp,
+            BackendType::OpenCode,
+            BackendType::ClaudeCode,
+        ] {
+            let backend = create_backend(BackendSettings {
+                backend: backend_type,
+                ..BackendSettings::default()
+            });
+            assert_eq!(backend.backend_type(), backend_type);
+        }
+    }
+
+    #[test]
+    fn test_backend_output_parse_plain_code() {
+        let output = BackendOutput::parse("fn foo() {\n    42\n}");
+        assert_eq!(output.implementation, "fn foo() {\n    42\n}");
+        assert!(output.imports.is_empty());
+    }
+
+    #[test]
+    fn test_backend_output_parse_structured() {
+        let output = BackendOutput::parse(
+            r#"{"implementation": "fn foo() {}", "imports": ["use std::fmt;"]}"#,
+        );
+        assert_eq!(output.implementation, "fn foo() {}");
+        assert_eq!(output.imports, vec!["use std::fmt;".to_string()]);
+        assert!(output.files.is_empty());
+
+        let output = BackendOutput::parse(
+            r##"{
+                "implementation": "fn foo() {}",
+                "files": [{ "path": "tests/foo.rs", "content": "#[test]\nfn t() {}\n" }]
+            }"##,
+        );
+        assert_eq!(
+            output.files,
+            vec![NewFile {
+                path: "tests/foo.rs".to_string(),
+                content: "#[test]\nfn t() {}\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stop_cancelled_cli_kills_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        let error = stop_cancelled_cli(&mut child);
+        assert!(error.is::<Cancelled>());
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_limited_output_stops_endless_output() {
+        let mut child = std::process::Command::new("yes")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = LimitedOutput::new(child.stdout.take().unwrap(), 100);
+
+        let mut lines = 0;
+        let mut error = None;
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(_) => lines += 1,
+                Err(e) => {
+                    error = Some(stop_unreadable_cli(&mut child, e));
+                    break;
+                }
+            }
+        }
+
+        let error = error.expect("Expected the output limit to be hit");
+        assert_eq!(
+            error.downcast_ref::<OutputTooLarge>(),
+            Some(&OutputTooLarge { limit: 100 })
+        );
+        assert!(lines <= 50);
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_limited_output_bounds_a_single_line() {
+        let long_line = "x".repeat(1000);
+        let mut output = BufReader::new(LimitedOutput::new(long_line.as_bytes(), 10));
+        let mut line = String::new();
+        let error = output.read_line(&mut line).unwrap_err();
+        assert!(error.to_string().contains("OutputTooLarge"), "{}", error);
+
+        let mut output = LimitedOutput::new("short\n".as_bytes(), 10);
+        let mut text = String::new();
+        output.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "short\n");
+    }
+
+    #[test]
+    fn test_backend_settings_from_initialization_options() {
+        let settings = BackendSettings::from_initialize_params(&serde_json::json!({
+            "initializationOptions": {
+                "backend": "claude_code",
+                "max_output_bytes": 1024,
+                "model_params": { "temperature": 0.2, "top_p": 0.9 },
+                "system_prompt": "Follow the project style."
+            }
+        }));
+        assert_eq!(settings.backend, BackendType::ClaudeCode);
+        assert_eq!(settings.max_output_bytes, 1024);
+        assert_eq!(settings.model_params.temperature, Some(0.2));
+        assert_eq!(settings.model_params.top_p, Some(0.9));
+        assert_eq!(
+            settings.system_prompt.as_deref(),
+            Some("Follow the project style.")
+        );
+
+        assert_eq!(
+            BackendSettings::from_initialize_params(&serde_json::json!({})),
+            BackendSettings::default()
+        );
+        assert_eq!(
+            BackendSettings::from_initialize_params(&serde_json::json!({
+                "initializationOptions": { "backend": "unknown" }
+            }))
+            .backend,
+            CURRENT_BACKEND
+        );
+    }
+
+    #[test]
+    fn test_prompt_context_notes_existing_decorations() {
+        let file = "#[test]\n/// Adds.\nfn add() {\n    todo!()\n}\n\nfn sub() {\n    todo!()\n}\n";
+        let context = prompt_context(file, 3, "fn add() {");
+        assert!(context.contains("doc comments above the function are already in the file"));
+        assert!(!context.contains("<USAGE>"));
+        assert_eq!(prompt_context(file, 7, "fn sub() {"), "");
+    }
+
+    #[test]
+    fn test_prepend_system_prompt() {
+        assert_eq!(
+            prepend_system_prompt(Some("Be brief.\n"), "Implement f.".to_string()),
+            "Be brief.\n\nImplement f."
+        );
+        assert_eq!(
+            prepend_system_prompt(Some("  "), "Implement f.".to_string()),
+            "Implement f."
+        );
+        assert_eq!(
+            prepend_system_prompt(None, "Implement f.".to_string()),
+            "Implement f."
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_model_params_are_ignored() {
+        let settings = BackendSettings::from_initialize_params(&serde_json::json!({
+            "initializationOptions": { "model_params": { "temperature": 3.5 } }
+        }));
+        assert_eq!(settings.model_params, ModelParams::configured());
+
+        let error = ModelParams {
+            temperature: None,
+            top_p: Some(-0.1),
+        }
+        .validate()
+        .unwrap_err();
+        assert!(error.contains("top_p"), "{}", error);
+        assert!(ModelParams {
+            temperature: Some(2.0),
+            top_p: Some(0.0),
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_sandboxed_cli_runs_in_sandbox_and_writes_output_there() {
+        let sandbox = tempfile::TempDir::new().unwrap();
+        let output_path = sandbox.path().join("agent_impl_1");
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("pwd; echo 'fn foo() {}' > agent_impl_1");
+
+        run_in_sandbox(&mut command, sandbox.path(), output_path.to_str().unwrap()).unwrap();
+        assert_eq!(command.get_current_dir(), Some(sandbox.path()));
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let working_dir = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(
+            Path::new(working_dir.trim()).canonicalize().unwrap(),
+            sandbox.path().canonicalize().unwrap()
+        );
+        // The relative write landed on the output path
+        assert_eq!(
+            std::fs::read_to_string(&output_path).unwrap(),
+            "fn foo() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_sandbox_rejects_output_path_outside_it() {
+        let sandbox = tempfile::TempDir::new().unwrap();
+        let outside = sandbox.path().join("nested").join("agent_impl_1");
+        let escaping = sandbox.path().join("..").join("agent_impl_1");
+
+        for output_path in [
+            outside.to_str().unwrap(),
+            escaping.to_str().unwrap(),
+            "/tmp/agent_impl_1",
+        ] {
+            let mut command = Command::new("sh");
+            let error = run_in_sandbox(&mut command, sandbox.path(), output_path).unwrap_err();
+            assert!(error.contains("outside the sandbox"), "{}", error);
+            assert_eq!(command.get_current_dir(), None);
+        }
+        // A relative path resolves inside the sandbox
+        let mut command = Command::new("sh");
+        assert!(run_in_sandbox(&mut command, sandbox.path(), "agent_impl_1").is_ok());
+    }
+
+    #[test]
+    fn test_check_cli_installed() {
+        assert!(check_cli_installed("sh").is_ok());
+        assert!(check_cli_installed("/bin/sh").is_ok());
 
-        // We can't easily test the exact type, but we can verify it's valid
-        // by checking that the trait object was created successfully
-        let _ = backend;
+        let error = check_cli_installed("agent-lsp-missing-cli").unwrap_err();
+        assert!(error.contains("'agent-lsp-missing-cli'"), "{}", error);
+        assert!(check_cli_installed("/nonexistent/agent-lsp-missing-cli").is_err());
     }
 }
@@ -1,10 +1,17 @@
 use std::error::Error;
 use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
+use std::process::Stdio;
 
+use lsp_types::Range;
 use tracing::info;
 
-use crate::backend::Backend;
+use crate::backend::{
+    build_selection_prompt, check_cli_installed, cli_command, ignore_model_params,
+    prepend_system_prompt, prompt_context, stop_cancelled_cli, stop_unreadable_cli, Backend,
+    LimitedOutput, ModelParams,
+};
+use crate::cancellation::CancellationToken;
+use crate::config::{BackendType, CLAUDE_CODE_MODEL, MAX_OUTPUT_BYTES};
 
 /// Build the prompt for function implementation with Claude Code.
 fn build_prompt(
@@ -41,84 +48,52 @@ fn build_prompt(
 ///
 /// This client integrates with the Claude Code CLI to provide AI-powered
 /// function implementations.
-pub struct ClaudeCodeClient;
+pub struct ClaudeCodeClient {
+    /// Most output the CLI may write for one job before it is killed.
+    max_output_bytes: usize,
+    /// Sampling parameters, which the CLI has no way to take.
+    model_params: ModelParams,
+    /// Instructions put before every prompt.
+    system_prompt: Option<String>,
+}
 
 impl ClaudeCodeClient {
     /// Create a new ClaudeCodeClient instance.
     pub fn new() -> Self {
-        Self
+        Self {
+            max_output_bytes: MAX_OUTPUT_BYTES,
+            model_params: ModelParams::default(),
+            system_prompt: None,
+        }
     }
-}
 
-impl Default for ClaudeCodeClient {
-    fn default() -> Self {
-        Self::new()
+    /// Cap the CLI's output per job at `max_output_bytes`.
+    pub fn with_max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.max_output_bytes = max_output_bytes;
+        self
     }
-}
-
-impl Backend for ClaudeCodeClient {
-    fn implement_function(
-        &self,
-        file_path: &str,
-        line: u32,
-        character: u32,
-        language_id: &str,
-        file_contents: &str,
-    ) -> Result<String, Box<dyn Error + Sync + Send>> {
-        info!(
-            "Calling claude CLI - file: {}, line: {}, character: {}, language: {}",
-            file_path, line, character, language_id
-        );
-
-        // NOTE: implement_function is deprecated in favor of streaming, passing dummy path and signature
-        let prompt = build_prompt(
-            line,
-            character,
-            language_id,
-            file_contents,
-            "/tmp/dummy",
-            "unknown",
-        );
-
-        let output = Command::new("claude")
-            .arg("-p")
-            .arg(&prompt)
-            .arg("--output-format")
-            .arg("text")
-            .arg("--model")
-            .arg("sonnet")
-            .arg("--dangerously-skip-permissions")
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("claude CLI failed: {}", stderr).into());
-        }
+    pub fn with_model_params(mut self, model_params: ModelParams) -> Self {
+        self.model_params = model_params;
+        self
+    }
 
-        let stdout = String::from_utf8(output.stdout)?;
-        info!("Claude CLI returned {} bytes", stdout.len());
-        Ok(stdout.trim().to_string())
+    pub fn with_system_prompt(mut self, system_prompt: Option<String>) -> Self {
+        self.system_prompt = system_prompt;
+        self
     }
 
-    fn implement_function_streaming(
+    /// The prompt for one job: `build_prompt`'s with its `prompt_context`, after the system
+    /// prompt.
+    fn prompt(
         &self,
-        file_path: &str,
         line: u32,
         character: u32,
         language_id: &str,
         file_contents: &str,
         output_path: &str,
         function_signature: &str,
-        mut on_progress: Box<dyn FnMut(&str) + Send>,
-    ) -> Result<(), Box<dyn Error + Sync + Send>> {
-        info!(
-            "Calling claude CLI (streaming) - file: {}, line: {}, character: {}, language: {}, function: {}",
-            file_path, line, character, language_id, function_signature
-        );
-
+    ) -> String {
         let prompt = build_prompt(
             line,
             character,
@@ -127,14 +102,29 @@ impl Backend for ClaudeCodeClient {
             output_path,
             function_signature,
         );
+        prepend_system_prompt(
+            self.system_prompt.as_deref(),
+            prompt + &prompt_context(file_contents, line, function_signature),
+        )
+    }
+
+    /// Run the CLI on `prompt`, forwarding its output to `on_progress` until it exits.
+    fn run_streaming(
+        &self,
+        prompt: &str,
+        output_path: &str,
+        cancellation: &CancellationToken,
+        on_progress: &mut Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        ignore_model_params("claude", &self.model_params);
 
-        let mut child = Command::new("claude")
+        let mut child = cli_command("claude", output_path)?
             .arg("-p")
-            .arg(&prompt)
+            .arg(prompt)
             .arg("--output-format")
             .arg("text")
             .arg("--model")
-            .arg("sonnet")
+            .arg(CLAUDE_CODE_MODEL)
             .arg("--dangerously-skip-permissions")
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
@@ -143,13 +133,17 @@ impl Backend for ClaudeCodeClient {
 
         let stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
         let stderr = child.stderr.take().ok_or("Failed to capture stderr")?;
-        let reader = BufReader::new(stdout);
+        let reader = BufReader::new(LimitedOutput::new(stdout, self.max_output_bytes));
 
         let mut accumulated_text = String::new();
 
         // Stream plain text output line by line
         for line_result in reader.lines() {
-            let line = line_result?;
+            let line = line_result.map_err(|e| stop_unreadable_cli(&mut child, e))?;
+            if cancellation.is_cancelled() {
+                info!("Stopping claude CLI for a cancelled job");
+                return Err(stop_cancelled_cli(&mut child));
+            }
             info!("claude output line: {}", line);
             accumulated_text.push_str(&line);
             accumulated_text.push('\n');
@@ -179,6 +173,74 @@ impl Backend for ClaudeCodeClient {
     }
 }
 
+impl Default for ClaudeCodeClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for ClaudeCodeClient {
+    fn check_available(&self) -> Result<(), String> {
+        check_cli_installed("claude")
+    }
+
+    fn backend_type(&self) -> BackendType {
+        BackendType::ClaudeCode
+    }
+
+    fn implement_function_streaming(
+        &self,
+        file_path: &str,
+        line: u32,
+        character: u32,
+        language_id: &str,
+        file_contents: &str,
+        output_path: &str,
+        function_signature: &str,
+        cancellation: &CancellationToken,
+        mut on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        info!(
+            "Calling claude CLI (streaming) - file: {}, line: {}, character: {}, language: {}, function: {}",
+            file_path, line, character, language_id, function_signature
+        );
+
+        let prompt = self.prompt(
+            line,
+            character,
+            language_id,
+            file_contents,
+            output_path,
+            function_signature,
+        );
+
+        self.run_streaming(&prompt, output_path, cancellation, &mut on_progress)
+    }
+
+    fn implement_selection_streaming(
+        &self,
+        file_path: &str,
+        range: Range,
+        selection: &str,
+        language_id: &str,
+        file_contents: &str,
+        output_path: &str,
+        cancellation: &CancellationToken,
+        mut on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        info!(
+            "Calling claude CLI (streaming) - file: {}, selection: {:?}, language: {}",
+            file_path, range, language_id
+        );
+
+        let prompt = prepend_system_prompt(
+            self.system_prompt.as_deref(),
+            build_selection_prompt(range, selection, language_id, file_contents, output_path),
+        );
+        self.run_streaming(&prompt, output_path, cancellation, &mut on_progress)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -368,6 +430,7 @@ fn main() {
             file_contents,
             output_path_str,
             function_signature,
+            &CancellationToken::new(),
             Box::new(move |text| {
                 let mut updates = progress_clone.lock().unwrap();
                 updates.push(text.to_string());
@@ -417,4 +480,67 @@ fn main() {
 
         println!("\n=== Test Complete ===");
     }
+
+    #[test]
+    fn test_prompt_starts_with_system_prompt() {
+        let client =
+            ClaudeCodeClient::new().with_system_prompt(Some("Prefer iterators.".to_string()));
+        let prompt = client.prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs",
+            "fn calculate_sum()",
+        );
+        assert!(prompt.starts_with("Prefer iterators.\n\n"));
+        assert!(prompt.ends_with(&build_prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs",
+            "fn calculate_sum()"
+        )));
+
+        let prompt = ClaudeCodeClient::new().prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs",
+            "fn calculate_sum()",
+        );
+        assert_eq!(
+            prompt,
+            build_prompt(
+                9,
+                4,
+                "rust",
+                "fn main() {}",
+                "/tmp/output.rs",
+                "fn calculate_sum()"
+            )
+        );
+    }
+
+    #[test]
+    fn test_prompt_includes_usage_hints() {
+        let file = "fn area(w: u32, h: u32) -> u32 {\n    todo!()\n}\n\nfn main() {\n    let a = area(2, 3);\n    // area(0, 0) is zero\n    println!(\"{}\", a + area(w, h));\n}\n";
+        let prompt = ClaudeCodeClient::new().prompt(
+            0,
+            3,
+            "rust",
+            file,
+            "/tmp/output.rs",
+            "fn area(w: u32, h: u32) -> u32 {",
+        );
+        assert!(prompt.ends_with(
+            "<USAGE>\nThe function is called like this elsewhere in the file; match these \
+             arguments and how the result is used.\n\
+             line 6: let a = area(2, 3);\n\
+             line 8: println!(\"{}\", a + area(w, h));\n\
+             </USAGE>"
+        ));
+    }
 }
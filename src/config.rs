@@ -1,3 +1,9 @@
+use std::path::Path;
+
+use lsp_types::Url;
+use serde_json::{Map, Value};
+use tracing::warn;
+
 /// Available backend types for function implementation.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BackendType {
@@ -10,6 +16,16 @@ pub enum BackendType {
 }
 
 impl BackendType {
+    /// Parse a backend name (`amp`, `opencode` or `claude_code`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "amp" => Some(BackendType::Amp),
+            "opencode" => Some(BackendType::OpenCode),
+            "claude_code" => Some(BackendType::ClaudeCode),
+            _ => None,
+        }
+    }
+
     /// Returns a user-friendly display name for this backend.
     pub fn display_name(&self) -> &'static str {
         match self {
@@ -18,11 +34,36 @@ impl BackendType {
             BackendType::ClaudeCode => "Claude Code",
         }
     }
+
+    /// The model the backend's CLI is asked to use, or None when it picks its own.
+    pub fn model(&self) -> Option<&'static str> {
+        match self {
+            BackendType::Amp => None,
+            BackendType::OpenCode => Some(OPENCODE_MODEL),
+            BackendType::ClaudeCode => Some(CLAUDE_CODE_MODEL),
+        }
+    }
+
+    /// Whether the backend reports partial output while it runs (`implFunctionProgress`
+    /// previews).
+    pub fn supports_streaming(&self) -> bool {
+        match self {
+            BackendType::Amp | BackendType::OpenCode | BackendType::ClaudeCode => true,
+        }
+    }
+
+    /// Whether the backend's CLI can be stopped once a job is cancelled.
+    pub fn supports_cancel(&self) -> bool {
+        match self {
+            BackendType::Amp | BackendType::OpenCode | BackendType::ClaudeCode => true,
+        }
+    }
 }
 
-/// The currently selected backend for function implementation.
+/// The backend implementing functions. Clients can pick another one with the `backend`
+/// initialization option.
 ///
-/// Change this constant to switch between backends.
+/// Default: OpenCode
 pub const CURRENT_BACKEND: BackendType = BackendType::OpenCode;
 
 /// Whether to delete temporary agent implementation files after use.
@@ -32,3 +73,599 @@ pub const CURRENT_BACKEND: BackendType = BackendType::OpenCode;
 ///
 /// Default: true (delete temp files)
 pub const DELETE_TEMP_FILES: bool = false;
+
+/// Directory, under the system temp directory, where the agent's side of each 3-way merge
+/// ("theirs") is kept when `DELETE_TEMP_FILES` is off. Files are named after the job id.
+///
+/// Default: "agent-lsp-merges"
+pub const MERGE_THEIRS_DIR: &str = "agent-lsp-merges";
+
+/// Whether to run each backend CLI in a sandbox: a fresh temporary directory that holds
+/// nothing but the job's output file, used as the CLI's working directory.
+///
+/// Agents run with broad permissions (e.g. Claude's `--dangerously-skip-permissions`), so
+/// this keeps the files they reach with relative paths away from the project. It is not an
+/// OS-level jail. With `DELETE_TEMP_FILES` off, sandboxes are kept for debugging.
+///
+/// Default: false (the output file goes to a `tmp` directory next to the source file)
+pub const BACKEND_SANDBOX: bool = false;
+
+/// Maximum number of documents kept in the `DocumentStore`.
+///
+/// When exceeded, the least-recently-accessed document without active jobs is evicted.
+/// Documents with active jobs are never evicted, so the store may temporarily exceed this cap.
+///
+/// Default: 1000
+pub const MAX_STORED_DOCUMENTS: usize = 1000;
+
+/// Number of earlier versions of each document the `DocumentStore` keeps, so a job can get
+/// back the exact text it was computed against. Older versions are dropped.
+///
+/// Default: 8
+pub const DOCUMENT_HISTORY_SIZE: usize = 8;
+
+/// Wall-clock limit for a single implementation job, in seconds.
+///
+/// When exceeded, the job is marked as timed out, its slot is released and any late
+/// result from the backend is discarded.
+///
+/// Default: 300 (5 minutes)
+pub const JOB_TIMEOUT_SECS: u64 = 300;
+
+/// Number of finished jobs kept for `agent/jobStats`.
+///
+/// Default: 1000
+pub const JOB_HISTORY_SIZE: usize = 1000;
+
+/// Hard deadline for the backend's answer to a `textDocument/completion` request, in
+/// milliseconds.
+///
+/// When exceeded, the request is answered with `null` so typing is never blocked.
+///
+/// Default: 1000
+pub const COMPLETION_TIMEOUT_MS: u64 = 1000;
+
+/// How long a job waits for the client to answer its `workspace/applyEdit` request, in
+/// milliseconds. Without an answer in time the job completes with the `unknown` status, as
+/// the edit may or may not have been applied.
+///
+/// Default: 5000 (5 seconds)
+pub const APPLY_EDIT_TIMEOUT_MS: u64 = 5000;
+
+/// How long shutdown waits for worker threads to finish, in milliseconds, so the
+/// notifications of the jobs it cancelled reach the client before the server exits. Workers
+/// still running after that are left behind.
+///
+/// Default: 2000 (2 seconds)
+pub const SHUTDOWN_FLUSH_TIMEOUT_MS: u64 = 2000;
+
+/// Maximum number of implementation jobs running at once, across all files.
+///
+/// Further jobs wait in a global queue: interactive jobs first, then in submission order.
+///
+/// Default: 10
+pub const MAX_RUNNING_JOBS: usize = 10;
+
+/// Maximum number of jobs waiting for a run slot.
+///
+/// Submissions beyond this are rejected with an error naming the current queue depth.
+///
+/// Default: 50
+pub const MAX_PENDING_JOBS: usize = 50;
+
+/// Maximum number of implementation worker threads, reused across jobs.
+///
+/// Workers wait for their run slot on their own thread, so this must cover every admitted
+/// job (running plus pending) or a queued job could wait for a thread forever.
+///
+/// Default: 60 (MAX_RUNNING_JOBS + MAX_PENDING_JOBS)
+pub const WORKER_POOL_SIZE: usize = MAX_RUNNING_JOBS + MAX_PENDING_JOBS;
+
+/// Maximum number of threads asking the backend for completions, kept apart from the
+/// implementation workers so completions never take a thread an admitted job relies on.
+///
+/// Requests beyond this wait for a thread; they are still answered with `null` at
+/// `COMPLETION_TIMEOUT_MS`.
+///
+/// Default: 2
+pub const COMPLETION_POOL_SIZE: usize = 2;
+
+/// A backend's request rate: a token bucket holding up to `burst` backend runs, refilled at
+/// `requests_per_minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests_per_minute: u32,
+    pub burst: u32,
+}
+
+/// Rate limits on backend runs, per backend, so bursts of jobs (e.g. `implAllFunctions`)
+/// stay within provider quotas. A job over the limit waits for a token after getting its run
+/// slot instead of failing. Backends not listed are not limited.
+///
+/// Default: none
+pub const RATE_LIMITS: &[(BackendType, RateLimit)] = &[];
+
+/// Longest a queued job waits for a run slot before failing, in seconds.
+///
+/// Guards against a slot that is never released blocking every later job.
+///
+/// Default: 120 (2 minutes)
+pub const MAX_QUEUE_WAIT_SECS: u64 = 120;
+
+/// How many times a finished job's edit is recomputed when the document keeps changing
+/// while it is prepared.
+///
+/// Every edit is stamped with the document version it was computed against; after this many
+/// retries the last one is sent and the client decides whether its version still applies.
+///
+/// Default: 3
+pub const MAX_EDIT_RECOMPUTES: usize = 3;
+
+/// How long finished jobs on the same document wait for each other before their edits are
+/// applied, in milliseconds.
+///
+/// Jobs finishing within the window go out as one `workspace/applyEdit`, computed against a
+/// single document version, instead of racing each other's version changes.
+///
+/// Default: 250
+pub const APPLY_BATCH_WINDOW_MS: u64 = 250;
+
+/// Most output a backend's agent may write for one job, in bytes.
+///
+/// When exceeded, the agent is killed and the job fails with an `OutputTooLarge` error
+/// instead of buffering unbounded output. Clients can override it with the
+/// `max_output_bytes` initialization option.
+///
+/// Default: 262144 (256 KiB)
+pub const MAX_OUTPUT_BYTES: usize = 256 * 1024;
+
+/// Sampling temperature passed to the model, for backends that accept one (0.0 to 2.0).
+///
+/// The Amp, OpenCode and Claude Code CLIs take no sampling parameters and ignore it. Clients
+/// can override it with the `model_params` initialization option.
+///
+/// Default: None (the model's own default)
+pub const MODEL_TEMPERATURE: Option<f64> = None;
+
+/// Nucleus sampling probability mass passed to the model, for backends that accept one
+/// (0.0 to 1.0). Clients can override it with the `model_params` initialization option.
+///
+/// Default: None (the model's own default)
+pub const MODEL_TOP_P: Option<f64> = None;
+
+/// Instructions put before every prompt sent to a backend, for a consistent persona or house
+/// style (e.g. "You are a senior Rust engineer; prefer `?` over unwrap"). Every backend
+/// renders it the same way, as the prompt's first paragraph. Clients can override it with
+/// the `system_prompt` initialization option.
+///
+/// Default: None
+pub const SYSTEM_PROMPT: Option<&str> = None;
+
+/// Most call sites of the target function, found by a textual search of its name in the
+/// file, shown to the backend in a `<USAGE>` section of the prompt so the implementation
+/// fits how the function is called. 0 leaves the section out.
+///
+/// Default: 3
+pub const USAGE_HINTS: usize = 3;
+
+/// Whether each implementation is run through the formatter `FORMATTERS` lists for the
+/// document's language before it is spliced in. Output a formatter rejects (a body-only
+/// snippet, a syntax error) or a formatter that is not installed leaves the implementation
+/// as the backend returned it.
+///
+/// Default: false
+pub const FORMAT_IMPLEMENTATIONS: bool = false;
+
+/// Formatter per `languageId`, as (language id, temp file extension, command). The command
+/// gets the path of a temp file holding the implementation as its last argument and must
+/// rewrite that file in place.
+///
+/// Default: rustfmt, black and gofmt for Rust, Python and Go
+pub const FORMATTERS: &[(&str, &str, &[&str])] = &[
+    ("rust", "rs", &["rustfmt", "--edition", "2021"]),
+    ("python", "py", &["black", "--quiet"]),
+    ("go", "go", &["gofmt", "-w"]),
+];
+
+/// Whether the OpenCode CLI is run with `--format json`, streaming newline-delimited JSON
+/// events of which only the text parts are shown in previews. Otherwise its plain output is
+/// shown as is.
+///
+/// Default: false
+pub const OPENCODE_JSON_EVENTS: bool = false;
+
+/// Model the OpenCode CLI is run with (`--model`).
+///
+/// Default: "anthropic/claude-sonnet-4-5"
+pub const OPENCODE_MODEL: &str = "anthropic/claude-sonnet-4-5";
+
+/// Model the Claude Code CLI is run with (`--model`).
+///
+/// Default: "sonnet"
+pub const CLAUDE_CODE_MODEL: &str = "sonnet";
+
+/// Kind of change an edit makes to a document, used to decide whether the client should ask
+/// the user to confirm it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKind {
+    /// Fills in a function that had no implementation yet.
+    Implementation,
+    /// Replaces an implementation that already existed (a forced job).
+    Overwrite,
+    /// Replaces the entire document.
+    WholeFile,
+    /// Leaves merge conflict markers in the document.
+    Conflict,
+}
+
+impl EditKind {
+    /// What an edit of this kind does, as it reads in edit labels.
+    pub fn action(&self) -> &'static str {
+        match self {
+            EditKind::Implementation => "implement",
+            EditKind::Overwrite => "reimplement",
+            EditKind::WholeFile => "rewrite file for",
+            EditKind::Conflict => "resolve merge for",
+        }
+    }
+}
+
+/// Longest label, in characters, of an edit or progress the agent reports (shown by clients
+/// in undo history and progress UIs). Longer labels are cut with an ellipsis.
+///
+/// Default: 80
+pub const MAX_LABEL_CHARS: usize = 80;
+
+/// Longest preview, in characters, sent with each implementation progress notification.
+///
+/// Previews carry everything the agent has written so far; longer ones keep only their tail,
+/// where new content appears, and are flagged `truncated`.
+///
+/// Default: 2000
+pub const PREVIEW_MAX_CHARS: usize = 2000;
+
+/// Kinds of edits sent with `needsConfirmation`, for clients that support change annotations.
+///
+/// Default: [Overwrite, WholeFile, Conflict]
+pub const CONFIRM_EDIT_KINDS: &[EditKind] =
+    &[EditKind::Overwrite, EditKind::WholeFile, EditKind::Conflict];
+
+/// End documents the agent edits with exactly one newline, dropping trailing blank lines,
+/// even when the document had none. Off keeps the document's ending as it was.
+///
+/// Default: true
+pub const ENSURE_FINAL_NEWLINE: bool = true;
+
+/// Strip trailing whitespace from the lines of each implementation before it is spliced in.
+/// The rest of the document is left as it is.
+///
+/// Default: true
+pub const TRIM_TRAILING_WHITESPACE: bool = true;
+
+/// Code action kind the "Implement function" action is offered under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImplementActionKind {
+    /// `quickfix`
+    QuickFix,
+    /// `refactor.rewrite`
+    RefactorRewrite,
+    /// `source`
+    Source,
+}
+
+impl ImplementActionKind {
+    /// Parse the LSP name of a kind (`quickfix`, `refactor.rewrite` or `source`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quickfix" => Some(ImplementActionKind::QuickFix),
+            "refactor.rewrite" => Some(ImplementActionKind::RefactorRewrite),
+            "source" => Some(ImplementActionKind::Source),
+            _ => None,
+        }
+    }
+
+    pub fn code_action_kind(&self) -> lsp_types::CodeActionKind {
+        match self {
+            ImplementActionKind::QuickFix => lsp_types::CodeActionKind::QUICKFIX,
+            ImplementActionKind::RefactorRewrite => lsp_types::CodeActionKind::REFACTOR_REWRITE,
+            ImplementActionKind::Source => lsp_types::CodeActionKind::SOURCE,
+        }
+    }
+}
+
+/// The kind of the implement code action, advertised in `codeActionKinds`. Clients can
+/// override it with the `code_action_kind` initialization option.
+///
+/// Default: QuickFix
+pub const CODE_ACTION_KIND: ImplementActionKind = ImplementActionKind::QuickFix;
+
+/// Namespace for command and notification names (`<ns>.implFunction`,
+/// `<ns>/implFunctionProgress`, ...). Clients running several servers can override it per
+/// server with the `command_namespace` initialization option.
+///
+/// Default: "agent"
+pub const COMMAND_NAMESPACE: &str = "agent";
+
+/// Globs (relative to the workspace root) of files the agent may work on. An empty list
+/// allows every file. Clients can override it with the `include_globs` initialization option.
+///
+/// Default: [] (all files)
+pub const INCLUDE_GLOBS: &[&str] = &[];
+
+/// Globs (relative to the workspace root) of files the agent must never work on, such as
+/// generated or vendored code. Takes precedence over `INCLUDE_GLOBS`. Clients can override it
+/// with the `exclude_globs` initialization option.
+///
+/// Default: [] (no files)
+pub const EXCLUDE_GLOBS: &[&str] = &[];
+
+/// What a 3-way merge does when the user's edits and the agent's change touch the same lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Leave conflict markers in the document.
+    Markers,
+    /// Fail the job, reporting both versions so the client can show a diff.
+    Abort,
+    /// Take the agent's version of the function, keeping the user's changes elsewhere.
+    Theirs,
+}
+
+impl MergeStrategy {
+    /// Parse a strategy name (`markers`, `abort` or `theirs`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "markers" => Some(MergeStrategy::Markers),
+            "abort" => Some(MergeStrategy::Abort),
+            "theirs" => Some(MergeStrategy::Theirs),
+            _ => None,
+        }
+    }
+}
+
+/// How 3-way merges resolve conflicts. Clients can override it with the `merge_strategy`
+/// initialization option.
+///
+/// Default: Markers
+pub const MERGE_STRATEGY: MergeStrategy = MergeStrategy::Markers;
+
+/// How the server answers `textDocument/completion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionMode {
+    /// Advertise completion but always answer `null`.
+    Off,
+    /// Ask the backend for a suggestion at the cursor (`Backend::complete`).
+    AiInline,
+    /// Do not advertise completion at all, so clients never ask.
+    Disabled,
+}
+
+impl CompletionMode {
+    /// Parse a mode name (`off`, `ai_inline` or `disabled`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(CompletionMode::Off),
+            "ai_inline" => Some(CompletionMode::AiInline),
+            "disabled" => Some(CompletionMode::Disabled),
+            _ => None,
+        }
+    }
+}
+
+/// How completion requests are handled. Clients can override it with the `completion_mode`
+/// initialization option.
+///
+/// Default: Disabled
+pub const COMPLETION_MODE: CompletionMode = CompletionMode::Disabled;
+
+/// Whether `agent/backendInfo` (backend name, model and server version) is sent once the
+/// client is initialized. Clients can turn it off with the `backend_info` initialization
+/// option.
+///
+/// Default: true
+pub const SEND_BACKEND_INFO: bool = true;
+
+/// Name of the optional TOML configuration file in the workspace root. It takes the same
+/// settings as the initialization options: `command_namespace`, `include_globs`,
+/// `exclude_globs`, `max_output_bytes`, `model_params`, `system_prompt`, `code_action_kind`,
+/// `merge_strategy`, `backend_info`, `completion_mode` and `backend`.
+///
+/// Default: ".agent.toml"
+pub const CONFIG_FILE_NAME: &str = ".agent.toml";
+
+/// Environment variables overriding single settings, with the setting each one sets
+/// (`model_params` fields as `model_params.<field>`). Glob lists are comma-separated.
+pub const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("AGENT_LSP_COMMAND_NAMESPACE", "command_namespace"),
+    ("AGENT_LSP_INCLUDE_GLOBS", "include_globs"),
+    ("AGENT_LSP_EXCLUDE_GLOBS", "exclude_globs"),
+    ("AGENT_LSP_MAX_OUTPUT_BYTES", "max_output_bytes"),
+    ("AGENT_LSP_TEMPERATURE", "model_params.temperature"),
+    ("AGENT_LSP_TOP_P", "model_params.top_p"),
+    ("AGENT_LSP_CODE_ACTION_KIND", "code_action_kind"),
+    ("AGENT_LSP_SYSTEM_PROMPT", "system_prompt"),
+    ("AGENT_LSP_MERGE_STRATEGY", "merge_strategy"),
+    ("AGENT_LSP_BACKEND_INFO", "backend_info"),
+    ("AGENT_LSP_COMPLETION_MODE", "completion_mode"),
+    ("AGENT_LSP_BACKEND", "backend"),
+];
+
+/// Merge the configuration sources into the effective initialization options.
+///
+/// Precedence, highest first: environment variables (`ENV_OVERRIDES`), the client's
+/// `initializationOptions`, the workspace's `CONFIG_FILE_NAME`, and the constants in this
+/// file for anything no source sets. Sources merge field by field, down into `model_params`,
+/// and `null` fields count as unset. Commands such as `agent.setOutputShape` override the
+/// result at runtime.
+pub fn resolve(file: Value, initialization_options: Value, env: Value) -> Value {
+    let mut resolved = Map::new();
+    for source in [file, initialization_options, env] {
+        merge(&mut resolved, source);
+    }
+    Value::Object(resolved)
+}
+
+fn merge(base: &mut Map<String, Value>, source: Value) {
+    let Value::Object(source) = source else {
+        return;
+    };
+    for (key, value) in source {
+        match value {
+            Value::Null => {}
+            Value::Object(fields) => match base.get_mut(&key) {
+                Some(Value::Object(existing)) => merge(existing, Value::Object(fields)),
+                _ => {
+                    base.insert(key, Value::Object(fields));
+                }
+            },
+            value => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The settings in `root`'s `CONFIG_FILE_NAME`, or `Value::Null` without a readable file.
+pub fn load_config_file(root: &Path) -> Value {
+    let path = root.join(CONFIG_FILE_NAME);
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return Value::Null;
+    };
+    match toml::from_str::<Value>(&text) {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!("Ignoring malformed {}: {}", path.display(), e);
+            Value::Null
+        }
+    }
+}
+
+/// The settings set by the `ENV_OVERRIDES` variables among `vars`.
+///
+/// Values are read as JSON where they parse (numbers), and as strings otherwise.
+pub fn env_config(vars: impl IntoIterator<Item = (String, String)>) -> Value {
+    let mut settings = Map::new();
+    for (name, raw) in vars {
+        let Some((_, setting)) = ENV_OVERRIDES.iter().find(|(var, _)| *var == name) else {
+            continue;
+        };
+        let value = if setting.ends_with("_globs") {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|glob| !glob.is_empty())
+                .map(|glob| Value::String(glob.to_string()))
+                .collect()
+        } else {
+            serde_json::from_str(&raw).unwrap_or(Value::String(raw))
+        };
+
+        let (parent, field) = match setting.split_once('.') {
+            Some((parent, field)) => (Some(parent), field),
+            None => (None, *setting),
+        };
+        let target = match parent {
+            Some(parent) => settings
+                .entry(parent)
+                .or_insert_with(|| Value::Object(Map::new()))
+                .as_object_mut()
+                .expect("nested settings are objects"),
+            None => &mut settings,
+        };
+        target.insert(field.to_string(), value);
+    }
+    Value::Object(settings)
+}
+
+/// `params` with `initializationOptions` replaced by their `resolve`d value, reading the
+/// config file from the workspace root (`rootUri`) and overrides from the environment.
+pub fn resolve_initialize_params(params: &Value) -> Value {
+    let file = params
+        .get("rootUri")
+        .and_then(|uri| uri.as_str())
+        .and_then(|uri| Url::parse(uri).ok())
+        .and_then(|uri| uri.to_file_path().ok())
+        .map_or(Value::Null, |root| load_config_file(&root));
+    let initialization_options = params
+        .get("initializationOptions")
+        .cloned()
+        .unwrap_or(Value::Null);
+
+    let mut params = params.clone();
+    params["initializationOptions"] =
+        resolve(file, initialization_options, env_config(std::env::vars()));
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_file_fields_survive_other_initialization_options() {
+        let file = json!({
+            "max_output_bytes": 1024,
+            "model_params": { "temperature": 0.2, "top_p": 0.9 }
+        });
+        let initialization_options = json!({
+            "max_output_bytes": null,
+            "code_action_kind": "source",
+            "model_params": { "temperature": 0.5 }
+        });
+
+        assert_eq!(
+            resolve(file, initialization_options, Value::Null),
+            json!({
+                "max_output_bytes": 1024,
+                "code_action_kind": "source",
+                "model_params": { "temperature": 0.5, "top_p": 0.9 }
+            })
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_file_and_initialization_options() {
+        let file = json!({ "command_namespace": "file", "include_globs": ["src/**"] });
+        let initialization_options = json!({
+            "command_namespace": "client",
+            "model_params": { "temperature": 0.5, "top_p": 0.9 }
+        });
+        let env = env_config([
+            ("AGENT_LSP_COMMAND_NAMESPACE".to_string(), "env".to_string()),
+            ("AGENT_LSP_TEMPERATURE".to_string(), "1.5".to_string()),
+            (
+                "AGENT_LSP_EXCLUDE_GLOBS".to_string(),
+                "target/**, vendor/**".to_string(),
+            ),
+            ("UNRELATED".to_string(), "ignored".to_string()),
+        ]);
+
+        assert_eq!(
+            resolve(file, initialization_options, env),
+            json!({
+                "command_namespace": "env",
+                "include_globs": ["src/**"],
+                "exclude_globs": ["target/**", "vendor/**"],
+                "model_params": { "temperature": 1.5, "top_p": 0.9 }
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_config_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(load_config_file(dir.path()), Value::Null);
+
+        std::fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "max_output_bytes = 4096\n\n[model_params]\ntop_p = 0.5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            load_config_file(dir.path()),
+            json!({ "max_output_bytes": 4096, "model_params": { "top_p": 0.5 } })
+        );
+
+        std::fs::write(dir.path().join(CONFIG_FILE_NAME), "max_output_bytes = ").unwrap();
+        assert_eq!(load_config_file(dir.path()), Value::Null);
+    }
+}
@@ -1,7 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 
 use lsp_types::{Position, Url};
+use tracing::{info, warn};
+
+use crate::config::{DOCUMENT_HISTORY_SIZE, MAX_STORED_DOCUMENTS};
+use crate::sync_utils::lock_recovering;
 
 #[derive(Debug, Clone)]
 pub struct Document {
@@ -10,38 +14,132 @@ pub struct Document {
     pub language_id: String,
 }
 
+#[derive(Debug)]
+struct StoredDocument {
+    document: Document,
+    /// Earlier `(version, text)` pairs, oldest first, at most `DOCUMENT_HISTORY_SIZE`.
+    history: VecDeque<(i32, String)>,
+    /// Logical timestamp of the last `open`/`get`/`change`, used for LRU eviction.
+    last_access: u64,
+}
+
+#[derive(Debug, Default)]
+struct Documents {
+    entries: HashMap<Url, StoredDocument>,
+    access_clock: u64,
+}
+
+impl Documents {
+    fn tick(&mut self) -> u64 {
+        self.access_clock += 1;
+        self.access_clock
+    }
+}
+
+/// What `DocumentStore::open` did with the document it was given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenOutcome {
+    /// The URI was not open before.
+    Opened,
+    /// The URI was already open and its document was replaced by the given one.
+    Reopened {
+        previous_version: i32,
+        text_changed: bool,
+    },
+    /// The URI was already open at a newer version, which was kept.
+    KeptNewer { version: i32 },
+}
+
 #[derive(Debug, Clone)]
 pub struct DocumentStore {
-    documents: Arc<Mutex<HashMap<Url, Document>>>,
+    documents: Arc<Mutex<Documents>>,
+    max_documents: usize,
 }
 
 impl DocumentStore {
     pub fn new() -> Self {
+        Self::with_capacity(MAX_STORED_DOCUMENTS)
+    }
+
+    /// Create a store that keeps at most `max_documents` inactive documents.
+    pub fn with_capacity(max_documents: usize) -> Self {
         Self {
-            documents: Arc::new(Mutex::new(HashMap::new())),
+            documents: Arc::new(Mutex::new(Documents::default())),
+            max_documents,
         }
     }
 
-    pub fn open(&self, uri: Url, text: String, version: i32, language_id: String) {
-        let mut docs = self.documents.lock().unwrap();
-        docs.insert(
+    /// Start tracking a document the client opened.
+    ///
+    /// A URI that is already open (some clients re-open on reload) keeps whichever of the two
+    /// versions is newer, so a late `didOpen` can't roll the document back.
+    pub fn open(&self, uri: Url, text: String, version: i32, language_id: String) -> OpenOutcome {
+        let mut docs = lock_recovering(&self.documents);
+        let last_access = docs.tick();
+        let outcome = match docs.entries.get_mut(&uri) {
+            None => OpenOutcome::Opened,
+            Some(stored) if stored.document.version > version => {
+                warn!(
+                    "{} re-opened at version {}, keeping newer version {}",
+                    uri, version, stored.document.version
+                );
+                stored.last_access = last_access;
+                return OpenOutcome::KeptNewer {
+                    version: stored.document.version,
+                };
+            }
+            Some(stored) => {
+                warn!(
+                    "{} re-opened at version {} without being closed (was version {})",
+                    uri, version, stored.document.version
+                );
+                OpenOutcome::Reopened {
+                    previous_version: stored.document.version,
+                    text_changed: stored.document.text != text,
+                }
+            }
+        };
+        docs.entries.insert(
             uri,
-            Document {
-                text,
-                version,
-                language_id,
+            StoredDocument {
+                document: Document {
+                    text,
+                    version,
+                    language_id,
+                },
+                history: VecDeque::new(),
+                last_access,
             },
         );
+        outcome
     }
 
+    /// Apply `changes` to `uri`, making it `version`.
+    ///
+    /// The previous text is kept in the document's history only when `keep_history` is set
+    /// (the document has jobs that may need their base version back); otherwise the history
+    /// is dropped, so documents nobody is waiting on are not copied on every keystroke.
     pub fn change(
         &self,
         uri: &Url,
         version: i32,
         changes: &[lsp_types::TextDocumentContentChangeEvent],
+        keep_history: bool,
     ) {
-        let mut docs = self.documents.lock().unwrap();
-        if let Some(doc) = docs.get_mut(uri) {
+        let mut docs = lock_recovering(&self.documents);
+        let last_access = docs.tick();
+        if let Some(stored) = docs.entries.get_mut(uri) {
+            stored.last_access = last_access;
+            if keep_history {
+                let previous = (stored.document.version, stored.document.text.clone());
+                stored.history.push_back(previous);
+                while stored.history.len() > DOCUMENT_HISTORY_SIZE {
+                    stored.history.pop_front();
+                }
+            } else {
+                stored.history.clear();
+            }
+            let doc = &mut stored.document;
             doc.version = version;
             for change in changes {
                 if let Some(range) = change.range {
@@ -57,8 +155,67 @@ impl DocumentStore {
     }
 
     pub fn get(&self, uri: &Url) -> Option<Document> {
-        let docs = self.documents.lock().unwrap();
-        docs.get(uri).cloned()
+        let mut docs = lock_recovering(&self.documents);
+        let last_access = docs.tick();
+        docs.entries.get_mut(uri).map(|stored| {
+            stored.last_access = last_access;
+            stored.document.clone()
+        })
+    }
+
+    /// Text of `uri` at `version`, if it is the current version or one of the last
+    /// `DOCUMENT_HISTORY_SIZE` earlier ones.
+    pub fn get_version(&self, uri: &Url, version: i32) -> Option<String> {
+        let docs = lock_recovering(&self.documents);
+        let stored = docs.entries.get(uri)?;
+        if stored.document.version == version {
+            return Some(stored.document.text.clone());
+        }
+        stored
+            .history
+            .iter()
+            .rev()
+            .find(|(old_version, _)| *old_version == version)
+            .map(|(_, text)| text.clone())
+    }
+
+    /// Stop tracking a document after the client closed it.
+    pub fn close(&self, uri: &Url) {
+        let mut docs = lock_recovering(&self.documents);
+        docs.entries.remove(uri);
+    }
+
+    /// Evict least-recently-accessed documents until the store is within its cap.
+    ///
+    /// Documents for which `has_active_jobs` returns true are never evicted.
+    /// Returns the URIs that were evicted.
+    pub fn evict_excess(&self, has_active_jobs: impl Fn(&Url) -> bool) -> Vec<Url> {
+        let mut docs = lock_recovering(&self.documents);
+        if docs.entries.len() <= self.max_documents {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<(u64, Url)> = docs
+            .entries
+            .iter()
+            .filter(|(uri, _)| !has_active_jobs(uri))
+            .map(|(uri, stored)| (stored.last_access, uri.clone()))
+            .collect();
+        candidates.sort_by_key(|(last_access, _)| *last_access);
+
+        let excess = docs.entries.len() - self.max_documents;
+        let evicted: Vec<Url> = candidates
+            .into_iter()
+            .take(excess)
+            .map(|(_, uri)| uri)
+            .collect();
+
+        for uri in &evicted {
+            docs.entries.remove(uri);
+            info!("Evicted least-recently-used document: {}", uri);
+        }
+
+        evicted
     }
 }
 
@@ -70,30 +227,231 @@ impl Default for DocumentStore {
 
 fn position_to_offset(text: &str, position: Position) -> usize {
     let mut offset = 0;
-    for (line_num, line) in text.lines().enumerate() {
+    // Unlike `lines()`, keeps each line's real terminator, so `\r\n` lines add up correctly
+    for (line_num, line) in text.split_inclusive('\n').enumerate() {
         if line_num == position.line as usize {
-            return offset + position.character as usize;
+            let content = line.trim_end_matches(['\r', '\n']);
+            return offset + (position.character as usize).min(content.len());
         }
-        offset += line.len() + 1;
+        offset += line.len();
     }
-    offset
+    // The empty line after a trailing newline, or any line past the end, starts at EOF
+    text.len()
 }
 
-#[allow(dead_code)]
-fn offset_to_position(text: &str, offset: usize) -> Position {
-    let mut current_offset = 0;
-    for (line_num, line) in text.lines().enumerate() {
-        let line_end = current_offset + line.len() + 1;
-        if offset < line_end {
-            return Position {
-                line: line_num as u32,
-                character: (offset - current_offset) as u32,
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn uri(name: &str) -> Url {
+        Url::parse(&format!("file:///{}.rs", name)).unwrap()
+    }
+
+    #[test]
+    fn test_evict_excess_removes_oldest_inactive_document() {
+        let store = DocumentStore::with_capacity(2);
+        store.open(uri("a"), "a".to_string(), 1, "rust".to_string());
+        store.open(uri("b"), "b".to_string(), 1, "rust".to_string());
+        store.open(uri("c"), "c".to_string(), 1, "rust".to_string());
+
+        let evicted = store.evict_excess(|_| false);
+
+        assert_eq!(evicted, vec![uri("a")]);
+        assert!(store.get(&uri("a")).is_none());
+        assert!(store.get(&uri("b")).is_some());
+        assert!(store.get(&uri("c")).is_some());
+    }
+
+    #[test]
+    fn test_evict_excess_retains_documents_with_active_jobs() {
+        let store = DocumentStore::with_capacity(2);
+        store.open(uri("active"), "a".to_string(), 1, "rust".to_string());
+        store.open(uri("old"), "b".to_string(), 1, "rust".to_string());
+        store.open(uri("new"), "c".to_string(), 1, "rust".to_string());
+
+        let evicted = store.evict_excess(|u| *u == uri("active"));
+
+        assert_eq!(evicted, vec![uri("old")]);
+        assert!(store.get(&uri("active")).is_some());
+        assert!(store.get(&uri("new")).is_some());
+    }
+
+    #[test]
+    fn test_get_and_change_refresh_last_access() {
+        let store = DocumentStore::with_capacity(2);
+        store.open(uri("a"), "a".to_string(), 1, "rust".to_string());
+        store.open(uri("b"), "b".to_string(), 1, "rust".to_string());
+
+        // Touch "a" so that "b" becomes the least recently used
+        store.get(&uri("a"));
+        store.open(uri("c"), "c".to_string(), 1, "rust".to_string());
+        assert_eq!(store.evict_excess(|_| false), vec![uri("b")]);
+
+        // A change also counts as an access
+        store.change(&uri("a"), 2, &[], false);
+        store.open(uri("d"), "d".to_string(), 1, "rust".to_string());
+        assert_eq!(store.evict_excess(|_| false), vec![uri("c")]);
+    }
+
+    #[test]
+    fn test_evict_excess_within_cap_is_noop() {
+        let store = DocumentStore::with_capacity(2);
+        store.open(uri("a"), "a".to_string(), 1, "rust".to_string());
+
+        assert!(store.evict_excess(|_| false).is_empty());
+        assert!(store.get(&uri("a")).is_some());
+    }
+
+    fn insert_at(text: &str, line: u32, character: u32, inserted: &str) -> String {
+        let store = DocumentStore::new();
+        store.open(uri("a"), text.to_string(), 1, "rust".to_string());
+        let position = Position::new(line, character);
+        store.change(
+            &uri("a"),
+            2,
+            &[lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range::new(position, position)),
+                range_length: None,
+                text: inserted.to_string(),
+            }],
+            false,
+        );
+        store.get(&uri("a")).unwrap().text
+    }
+
+    #[test]
+    fn test_change_at_end_of_newline_terminated_document() {
+        // Line 2 is the empty line after the final newline
+        assert_eq!(position_to_offset("a\nb\n", Position::new(2, 0)), 4);
+        assert_eq!(insert_at("a\nb\n", 2, 0, "c\n"), "a\nb\nc\n");
+        assert_eq!(insert_at("a\r\nb\r\n", 2, 0, "c"), "a\r\nb\r\nc");
+        assert_eq!(insert_at("", 0, 0, "a"), "a");
+    }
+
+    #[test]
+    fn test_change_at_end_of_unterminated_document() {
+        assert_eq!(position_to_offset("a\nb", Position::new(1, 1)), 3);
+        assert_eq!(insert_at("a\nb", 1, 1, "\n"), "a\nb\n");
+        // Positions past the end of a line or the document clamp instead of panicking
+        assert_eq!(insert_at("a\nb", 0, 9, "!"), "a!\nb");
+        assert_eq!(insert_at("a\nb", 5, 0, "!"), "a\nb!");
+    }
+
+    fn replace_all(store: &DocumentStore, version: i32, text: &str) {
+        store.change(
+            &uri("a"),
+            version,
+            &[lsp_types::TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_string(),
+            }],
+            true,
+        );
+    }
+
+    #[test]
+    fn test_get_version_returns_recent_versions() {
+        let store = DocumentStore::new();
+        store.open(uri("a"), "v1".to_string(), 1, "rust".to_string());
+        replace_all(&store, 2, "v2");
+        replace_all(&store, 3, "v3");
+
+        assert_eq!(store.get_version(&uri("a"), 1).as_deref(), Some("v1"));
+        assert_eq!(store.get_version(&uri("a"), 2).as_deref(), Some("v2"));
+        assert_eq!(store.get_version(&uri("a"), 3).as_deref(), Some("v3"));
+        assert_eq!(store.get_version(&uri("a"), 4), None);
+        assert_eq!(store.get_version(&uri("b"), 1), None);
+    }
+
+    #[test]
+    fn test_change_without_history_drops_earlier_versions() {
+        let store = DocumentStore::new();
+        store.open(uri("a"), "v1".to_string(), 1, "rust".to_string());
+        replace_all(&store, 2, "v2");
+        store.change(&uri("a"), 3, &[], false);
+
+        assert_eq!(store.get_version(&uri("a"), 1), None);
+        assert_eq!(store.get_version(&uri("a"), 2), None);
+        assert_eq!(store.get_version(&uri("a"), 3).as_deref(), Some("v2"));
+    }
+
+    #[test]
+    fn test_get_version_forgets_evicted_versions() {
+        let store = DocumentStore::new();
+        store.open(uri("a"), "v1".to_string(), 1, "rust".to_string());
+        let last = DOCUMENT_HISTORY_SIZE as i32 + 2;
+        for version in 2..=last {
+            replace_all(&store, version, &format!("v{}", version));
         }
-        current_offset = line_end;
+
+        assert_eq!(store.get_version(&uri("a"), 1), None);
+        assert_eq!(store.get_version(&uri("a"), 2).as_deref(), Some("v2"));
+        assert_eq!(
+            store.get_version(&uri("a"), last).as_deref(),
+            Some(format!("v{}", last).as_str())
+        );
+
+        // Re-opening starts a new history
+        store.open(uri("a"), "new".to_string(), last + 1, "rust".to_string());
+        assert_eq!(store.get_version(&uri("a"), last), None);
+        assert_eq!(
+            store.get_version(&uri("a"), last + 1).as_deref(),
+            Some("new")
+        );
+    }
+
+    #[test]
+    fn test_reopen_keeps_newer_version() {
+        let store = DocumentStore::new();
+        assert_eq!(
+            store.open(uri("a"), "v3".to_string(), 3, "rust".to_string()),
+            OpenOutcome::Opened
+        );
+        assert_eq!(
+            store.open(uri("a"), "v2".to_string(), 2, "rust".to_string()),
+            OpenOutcome::KeptNewer { version: 3 }
+        );
+        let doc = store.get(&uri("a")).unwrap();
+        assert_eq!((doc.text.as_str(), doc.version), ("v3", 3));
+
+        // A newer or equal version replaces it
+        assert_eq!(
+            store.open(uri("a"), "v3".to_string(), 3, "rust".to_string()),
+            OpenOutcome::Reopened {
+                previous_version: 3,
+                text_changed: false
+            }
+        );
+        assert_eq!(
+            store.open(uri("a"), "v4".to_string(), 4, "rust".to_string()),
+            OpenOutcome::Reopened {
+                previous_version: 3,
+                text_changed: true
+            }
+        );
+        let doc = store.get(&uri("a")).unwrap();
+        assert_eq!((doc.text.as_str(), doc.version), ("v4", 4));
     }
-    Position {
-        line: text.lines().count() as u32,
-        character: 0,
+
+    #[test]
+    fn test_store_usable_after_lock_poisoned() {
+        let store = DocumentStore::new();
+        store.open(uri("a"), "a".to_string(), 1, "rust".to_string());
+
+        thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _docs = store.documents.lock().unwrap();
+                panic!("handler panicked while holding the documents lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+
+        assert_eq!(store.get(&uri("a")).unwrap().text, "a");
+        store.open(uri("b"), "b".to_string(), 1, "rust".to_string());
+        store.close(&uri("a"));
+        assert!(store.get(&uri("a")).is_none());
+        assert_eq!(store.get(&uri("b")).unwrap().version, 1);
     }
 }
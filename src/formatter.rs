@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::io::Write;
+use std::process::Command;
+
+use tracing::{info, warn};
+
+/// `implementation` run through the formatter `formatters` lists for `language_id`, or as
+/// it is when there is none or the formatter fails.
+///
+/// Formatters put top-level code at column zero, so a formatted method is indented back to
+/// where the implementation's first line was.
+pub fn format_implementation(
+    implementation: &str,
+    language_id: &str,
+    formatters: &[(&str, &str, &[&str])],
+) -> String {
+    let Some((_, extension, command)) = formatters.iter().find(|(id, _, _)| *id == language_id)
+    else {
+        return implementation.to_string();
+    };
+    let formatted = match run_formatter(implementation, extension, command) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            warn!(
+                "Formatter {:?} failed, using the implementation as returned: {}",
+                command, e
+            );
+            return implementation.to_string();
+        }
+    };
+    info!("Formatted implementation with {:?}", command);
+
+    let indent = implementation
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .map_or("", |line| &line[..line.len() - line.trim_start().len()]);
+    formatted
+        .lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                String::new()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Write `text` to a temp file with `extension`, run `command` with its path and read back
+/// what the command left in it.
+fn run_formatter(
+    text: &str,
+    extension: &str,
+    command: &[&str],
+) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let (program, args) = command.split_first().ok_or("empty formatter command")?;
+    let mut file = tempfile::Builder::new()
+        .prefix("agent-format-")
+        .suffix(&format!(".{}", extension))
+        .tempfile()?;
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+
+    let output = Command::new(program).args(args).arg(file.path()).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(std::fs::read_to_string(file.path())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a formatter: rewrites the file it is given, or fails on `fail`.
+    const MOCK_FORMATTER: &[&str] = &[
+        "sh",
+        "-c",
+        "grep -q fail \"$0\" && exit 1; printf 'fn add(a: i32) -> i32 {\\n    a + 1\\n}\\n' > \"$0\"",
+    ];
+
+    #[test]
+    fn test_formatted_output_is_used() {
+        let formatters: &[(&str, &str, &[&str])] = &[("rust", "rs", MOCK_FORMATTER)];
+        let raw = "fn add(a:i32)->i32{a+1}";
+        assert_eq!(
+            format_implementation(raw, "rust", formatters),
+            "fn add(a: i32) -> i32 {\n    a + 1\n}"
+        );
+        // A method keeps the indentation it was returned with
+        assert_eq!(
+            format_implementation("    fn add(a:i32)->i32{a+1}", "rust", formatters),
+            "    fn add(a: i32) -> i32 {\n        a + 1\n    }"
+        );
+    }
+
+    #[test]
+    fn test_raw_implementation_is_kept_without_formatter() {
+        let formatters: &[(&str, &str, &[&str])] = &[
+            ("rust", "rs", MOCK_FORMATTER),
+            ("go", "go", &["agent-lsp-missing-formatter"]),
+        ];
+        // The formatter rejects it
+        let raw = "fn fail(){}";
+        assert_eq!(format_implementation(raw, "rust", formatters), raw);
+        // The formatter is not installed
+        assert_eq!(
+            format_implementation("func add(){}", "go", formatters),
+            "func add(){}"
+        );
+        // No formatter for the language
+        assert_eq!(
+            format_implementation("def add(): pass", "python", formatters),
+            "def add(): pass"
+        );
+    }
+}
@@ -1,41 +1,321 @@
+use std::any::Any;
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
-use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::Sender;
-use lsp_server::{Connection, Message, Notification, Request};
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use lsp_server::{Connection, Message, Notification, Request, RequestId};
 use lsp_types::request::CodeActionRequest;
 use lsp_types::{
-    notification::DidChangeTextDocument, notification::DidOpenTextDocument,
-    notification::Notification as _, request::Completion, request::ExecuteCommand,
-    request::Request as _, CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
-    CompletionParams, DidChangeTextDocumentParams, DidOpenTextDocumentParams, ExecuteCommandParams,
-    Url,
+    notification::DidChangeTextDocument, notification::DidCloseTextDocument,
+    notification::DidOpenTextDocument, notification::Initialized,
+    notification::Notification as _, notification::WorkDoneProgressCancel, request::Completion,
+    request::ExecuteCommand, request::Request as _, CodeAction,
+    CodeActionOrCommand, CodeActionParams, CompletionParams, DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, ExecuteCommandParams, NumberOrString,
+    Position, Range, Url, WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCancelParams,
+    WorkDoneProgressEnd,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::backend::create_backend;
-use crate::config::{CURRENT_BACKEND, DELETE_TEMP_FILES};
-use crate::document_store::DocumentStore;
-use crate::job_tracker::JobTracker;
-use crate::lsp_utils::{LspClient, WorkspaceEditBuilder};
+use crate::apply_coordinator::ApplyCoordinator;
+use crate::backend::{Backend, BackendOutput, NewFile};
+use crate::cancellation::CancellationToken;
+use crate::config::{
+    BackendType, CompletionMode, EditKind, MergeStrategy, APPLY_EDIT_TIMEOUT_MS, BACKEND_SANDBOX,
+    COMMAND_NAMESPACE, COMPLETION_TIMEOUT_MS, CONFIRM_EDIT_KINDS, DELETE_TEMP_FILES, FORMATTERS,
+    FORMAT_IMPLEMENTATIONS, MAX_EDIT_RECOMPUTES, PREVIEW_MAX_CHARS,
+};
+use crate::document_store::{DocumentStore, OpenOutcome};
+use crate::formatter::format_implementation;
+use crate::imports::{apply_import_edits, insert_imports};
+use crate::job_history::{compute_stats, HistoryEntry, JobStats};
+use crate::job_queue::{JobPriority, JobQueue, QueueWaitError};
+use crate::job_tracker::{JobSnapshot, JobState, JobTracker};
+use crate::lsp_utils::{
+    agent_label, is_disconnected, ApplyEditOutcome, EditCapabilities, LspClient, PendingRequests,
+    WorkspaceEditBuilder,
+};
+use crate::path_filter::PathFilter;
+use crate::rate_limiter::RateLimiterState;
+use crate::session::SessionSettings;
+use crate::signature::FunctionSignature;
+use crate::sync_utils::lock_recovering;
+use crate::timer::Timer;
+use crate::utils::{
+    detect_line_ending, function_range, merge_3way, preview_tail, region_unchanged, MergeConflict,
+    MergeError, MergeOptions, OutputShape, MERGE_CONFLICT_ERROR, SUPPORTED_LANGUAGES,
+};
+use crate::validation::{validate_implementation, ValidationError};
+use crate::worker_pool::WorkerPool;
+
+/// Command and notification names, built from the server's command namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodNames {
+    pub impl_function: String,
+    pub impl_all_functions: String,
+    pub impl_selection: String,
+    pub set_output_shape: String,
+    pub supported_languages: String,
+    pub command_schema: String,
+    pub status: String,
+    pub impl_function_progress: String,
+    pub job_completed: String,
+    pub batch_completed: String,
+    pub job_failed: String,
+    pub backend_info: String,
+    pub job_status: String,
+    pub job_stats: String,
+    pub version_mismatch: String,
+    pub active_regions: String,
+    pub job_list: String,
+    /// Test hook replacing a document's state, see `handle_set_document`.
+    #[cfg(feature = "test-hooks")]
+    pub set_document: String,
+}
+
+impl MethodNames {
+    pub fn new(namespace: &str) -> Self {
+        Self {
+            impl_function: format!("{}.implFunction", namespace),
+            impl_all_functions: format!("{}.implAllFunctions", namespace),
+            impl_selection: format!("{}.implSelection", namespace),
+            set_output_shape: format!("{}.setOutputShape", namespace),
+            supported_languages: format!("{}.supportedLanguages", namespace),
+            command_schema: format!("{}.commandSchema", namespace),
+            status: format!("{}.status", namespace),
+            impl_function_progress: format!("{}/implFunctionProgress", namespace),
+            job_completed: format!("{}/jobCompleted", namespace),
+            batch_completed: format!("{}/batchCompleted", namespace),
+            job_failed: format!("{}/jobFailed", namespace),
+            backend_info: format!("{}/backendInfo", namespace),
+            job_status: format!("{}/jobStatus", namespace),
+            job_stats: format!("{}/jobStats", namespace),
+            version_mismatch: format!("{}/versionMismatch", namespace),
+            active_regions: format!("{}/activeRegions", namespace),
+            job_list: format!("{}/jobList", namespace),
+            #[cfg(feature = "test-hooks")]
+            set_document: format!("{}.__setDocument", namespace),
+        }
+    }
+
+    /// Names for the namespace in the client's `initializationOptions.command_namespace`,
+    /// falling back to `COMMAND_NAMESPACE`.
+    pub fn from_initialization_options(options: Option<&serde_json::Value>) -> Self {
+        let namespace = options
+            .and_then(|options| options.get("command_namespace"))
+            .and_then(|namespace| namespace.as_str())
+            .filter(|namespace| !namespace.is_empty())
+            .unwrap_or(COMMAND_NAMESPACE);
+        Self::new(namespace)
+    }
+
+    /// Commands advertised in `ServerCapabilities`. Test hooks are dispatched but never
+    /// advertised.
+    pub fn commands(&self) -> Vec<String> {
+        vec![
+            self.impl_function.clone(),
+            self.impl_all_functions.clone(),
+            self.impl_selection.clone(),
+            self.set_output_shape.clone(),
+            self.supported_languages.clone(),
+            self.command_schema.clone(),
+            self.status.clone(),
+        ]
+    }
+}
+
+impl Default for MethodNames {
+    fn default() -> Self {
+        Self::new(COMMAND_NAMESPACE)
+    }
+}
+
+/// One positional argument of a command, as `commandSchema` describes it.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandArgument {
+    pub name: &'static str,
+    /// JSON type of the value: `string`, `integer`, `boolean` or `object`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub required: bool,
+    /// The values a string argument may take, if it is an enumeration.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "enum")]
+    pub values: Option<&'static [&'static str]>,
+    pub description: &'static str,
+}
+
+/// The arguments of a command, in the order `workspace/executeCommand` takes them.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandSchema {
+    pub command: String,
+    pub arguments: Vec<CommandArgument>,
+}
+
+const fn argument(
+    name: &'static str,
+    kind: &'static str,
+    required: bool,
+    description: &'static str,
+) -> CommandArgument {
+    CommandArgument {
+        name,
+        kind,
+        required,
+        values: None,
+        description,
+    }
+}
 
-pub const COMMAND_IMPL_FUNCTION: &str = "agent.implFunction";
-pub const NOTIFICATION_IMPL_FUNCTION_PROGRESS: &str = "agent/implFunctionProgress";
-pub const NOTIFICATION_JOB_COMPLETED: &str = "agent/jobCompleted";
-pub const NOTIFICATION_BACKEND_INFO: &str = "agent/backendInfo";
+impl MethodNames {
+    /// The argument lists of the advertised commands (test hooks aside), for clients to
+    /// build and validate `workspace/executeCommand` calls against.
+    pub fn command_schemas(&self) -> Vec<CommandSchema> {
+        let uri = argument("uri", "string", true, "URI of an open document");
+        vec![
+            CommandSchema {
+                command: self.impl_function.clone(),
+                arguments: vec![
+                    uri.clone(),
+                    argument(
+                        "line",
+                        "integer",
+                        true,
+                        "Zero-based line inside the function",
+                    ),
+                    argument(
+                        "character",
+                        "integer",
+                        true,
+                        "Zero-based character on that line",
+                    ),
+                    argument(
+                        "version",
+                        "integer",
+                        true,
+                        "Document version the request was made against",
+                    ),
+                    argument(
+                        "language_id",
+                        "string",
+                        true,
+                        "LSP language id of the document",
+                    ),
+                    argument(
+                        "pending_id",
+                        "string",
+                        false,
+                        "Client correlation id, echoed in the response",
+                    ),
+                    CommandArgument {
+                        values: Some(&["interactive", "batch"]),
+                        ..argument("priority", "string", false, "Scheduling priority")
+                    },
+                    argument(
+                        "force",
+                        "boolean",
+                        false,
+                        "Overwrite a function that already has an implementation",
+                    ),
+                    argument(
+                        "signature",
+                        "string",
+                        false,
+                        "The function's signature as the client saw it",
+                    ),
+                    argument(
+                        "target_uri",
+                        "string",
+                        false,
+                        "Open document to append the implementation to instead",
+                    ),
+                ],
+            },
+            CommandSchema {
+                command: self.impl_all_functions.clone(),
+                arguments: vec![uri.clone()],
+            },
+            CommandSchema {
+                command: self.impl_selection.clone(),
+                arguments: vec![
+                    uri,
+                    argument(
+                        "range",
+                        "object",
+                        true,
+                        "LSP range of the selected code, which the result replaces",
+                    ),
+                ],
+            },
+            CommandSchema {
+                command: self.set_output_shape.clone(),
+                arguments: vec![CommandArgument {
+                    values: Some(&["BodyOnly", "FullFunction", "Auto"]),
+                    ..argument(
+                        "shape",
+                        "string",
+                        true,
+                        "How results are spliced; Auto detects it from each result",
+                    )
+                }],
+            },
+            CommandSchema {
+                command: self.supported_languages.clone(),
+                arguments: Vec::new(),
+            },
+            CommandSchema {
+                command: self.command_schema.clone(),
+                arguments: Vec::new(),
+            },
+            CommandSchema {
+                command: self.status.clone(),
+                arguments: Vec::new(),
+            },
+        ]
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ImplFunctionProgressParams {
     pub job_id: String,
     pub uri: String,
     pub line: u32,
+    /// The tail of the agent's output so far, at most `PREVIEW_MAX_CHARS` (config) long.
     pub preview: String,
+    /// Whether the start of the output was cut off from `preview`.
+    pub truncated: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pending_id: Option<String>,
+    /// Number of jobs that will start before this one, while it waits for a run slot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    /// The job's state in the tracker when the notification was sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<JobState>,
+    /// First line of the region the implementation will replace, in the current document.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_line: Option<u32>,
+    /// Last line of the region the implementation will replace, in the current document.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_line: Option<u32>,
+}
+
+/// Terminal status of a job, reported in `agent/jobCompleted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Success,
+    Failed,
+    Timeout,
+    Cancelled,
+    /// The edit was sent but the client never confirmed it, so it may not have been applied.
+    Unknown,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,48 +324,381 @@ pub struct JobCompletedParams {
     pub uri: String,
     pub success: bool,
     pub error: Option<String>,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_id: Option<String>,
+    /// Id of the `workspace/applyEdit` request that carried the job's changes; jobs applied
+    /// together in one edit share it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit_id: Option<RequestId>,
+    /// Both versions of the document, when the job failed because the user's edits conflict
+    /// with the agent's change (`MergeStrategy::Abort`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<MergeConflict>,
+    /// The agent's side of the job's 3-way merge, kept on disk when `DELETE_TEMP_FILES` is
+    /// off, for clients to diff against the buffer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theirs_path: Option<String>,
+    /// The agent's implementation matched the function as it already was, so no edit was
+    /// sent.
+    #[serde(default)]
+    pub no_change: bool,
+    /// The agent's output as it was written, when it failed validation and nothing was
+    /// applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_output: Option<String>,
+}
+
+/// Result of the status command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub backend: String,
+    /// Jobs holding a run slot.
+    pub running_jobs: usize,
+    /// Jobs waiting for a run slot.
+    pub pending_jobs: usize,
+    /// The backend's rate limiter (`RATE_LIMITS`), absent when it is not limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimiterState>,
+    /// Every active job with its state, by document and then line.
+    #[serde(default)]
+    pub jobs: Vec<JobListEntry>,
+}
+
+/// One function's result in `agent/batchCompleted`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchEntry {
+    pub job_id: String,
+    pub signature: String,
+    /// The function's line when the batch was submitted.
+    pub line: u32,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Sent once every job of an `agent.implAllFunctions` batch has finished.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchCompletedParams {
+    pub batch_id: String,
+    pub uri: String,
+    /// In document order.
+    pub entries: Vec<BatchEntry>,
+}
+
+/// Result of `agent.implAllFunctions`: the batch and the jobs it started.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImplAllFunctionsResult {
+    pub batch_id: String,
+    pub job_ids: Vec<String>,
+}
+
+/// Why a job failed before it was started, reported in `agent/jobFailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobFailureCode {
+    /// The backend's CLI is not installed.
+    BackendNotFound,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobFailedParams {
+    pub job_id: String,
+    pub uri: String,
+    pub code: JobFailureCode,
+    pub error: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pending_id: Option<String>,
 }
 
+/// Characters of the latest preview included in a job status record.
+const JOB_STATUS_PREVIEW_CHARS: usize = 200;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobStatusParams {
+    pub job_id: String,
+}
+
+/// Result of `agent/jobStatus`. `job` is omitted when the id is unknown.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobStatusResult {
+    pub found: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub job: Option<JobRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    pub state: JobState,
+    pub uri: String,
+    pub function_signature: String,
+    pub current_line: u32,
+    /// Every state the job entered, oldest first.
+    pub states: Vec<JobState>,
+    pub elapsed_ms: u64,
+    pub backend: String,
+    /// The tail of the latest streaming preview.
+    pub preview: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl JobRecord {
+    fn from_snapshot(snapshot: JobSnapshot, backend: BackendType) -> Self {
+        let error = match snapshot.state {
+            JobState::Failed => snapshot.error,
+            JobState::Cancelled => Some("Job was cancelled".to_string()),
+            JobState::TimedOut => Some("Job timed out".to_string()),
+            _ => None,
+        };
+        let preview_chars = snapshot.last_preview.chars().count();
+        let preview = snapshot
+            .last_preview
+            .chars()
+            .skip(preview_chars.saturating_sub(JOB_STATUS_PREVIEW_CHARS))
+            .collect();
+        Self {
+            job_id: snapshot.job_id,
+            state: snapshot.state,
+            uri: snapshot.uri.to_string(),
+            function_signature: snapshot.function_signature.to_string(),
+            current_line: snapshot.current_line,
+            states: snapshot.states,
+            elapsed_ms: snapshot.elapsed.as_millis() as u64,
+            backend: backend.display_name().to_string(),
+            preview,
+            error,
+        }
+    }
+}
+
+/// Number of most recent history entries returned by `agent/jobStats`.
+const JOB_STATS_RECENT_ENTRIES: usize = 50;
+
+/// Result of `agent/jobStats`: aggregates over the whole history plus its newest entries.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobStatsResult {
+    pub stats: JobStats,
+    /// Newest first.
+    pub recent: Vec<HistoryEntry>,
+}
+
+/// Sent when a command targets an older document version than the server has, i.e. the
+/// file changed after the code action was offered.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionMismatchParams {
+    pub uri: String,
+    pub requested_version: i32,
+    pub actual_version: i32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BackendInfoParams {
-    pub name: String,
+    /// Display name of the backend.
+    pub backend: String,
+    /// The model the backend runs, when the server picks one.
+    pub model: Option<String>,
+    /// Version of the server.
+    pub version: String,
+    /// Whether jobs stream previews of the output while the agent runs.
+    pub supports_streaming: bool,
+    /// Whether cancelling a job stops the agent.
+    pub supports_cancel: bool,
+}
+
+/// A job's place in a file, as announced in `agent/activeRegions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActiveRegion {
+    pub job_id: String,
+    pub line: u32,
+    pub function_signature: String,
+    pub state: JobState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActiveRegionsParams {
+    pub uri: String,
+    pub regions: Vec<ActiveRegion>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobListEntry {
+    pub job_id: String,
+    pub uri: String,
+    pub line: u32,
+    pub state: JobState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobListParams {
+    pub jobs: Vec<JobListEntry>,
 }
 
 /// Sends the backend info notification to inform the client which backend is being used.
-/// This should be called immediately after LSP initialization completes.
+/// This should be called immediately after LSP initialization completes, unless the client
+/// turned it off with the `backend_info` setting.
 pub fn send_backend_info_notification(
     connection: &Connection,
+    methods: &MethodNames,
+    backend: BackendType,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
     let lsp_client = LspClient::new(connection);
-    let backend_name = CURRENT_BACKEND.display_name();
+    let backend_name = backend.display_name();
     lsp_client.send_notification(
-        NOTIFICATION_BACKEND_INFO,
+        &methods.backend_info,
         BackendInfoParams {
-            name: backend_name.to_string(),
+            backend: backend_name.to_string(),
+            model: backend.model().map(str::to_string),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            supports_streaming: backend.supports_streaming(),
+            supports_cancel: backend.supports_cancel(),
         },
     )?;
     info!("Sent backend info notification: {}", backend_name);
     Ok(())
 }
 
+/// Bring a freshly (re)initialized client up to date: the backend info (when the session
+/// has it on), then the active regions of every file with running jobs and a summary of
+/// all jobs.
+///
+/// Jobs live in server memory, so a restarted client session learns about them only
+/// from here. The region and job notifications are skipped when no job is active.
+pub fn send_catch_up_notifications(
+    connection: &Connection,
+    methods: &MethodNames,
+    job_tracker: &JobTracker,
+    session: &SessionSettings,
+    backend: BackendType,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    if session.backend_info() {
+        send_backend_info_notification(connection, methods, backend)?;
+    }
+
+    let active = job_tracker.active_snapshots();
+    if active.is_empty() {
+        return Ok(());
+    }
+
+    let lsp_client = LspClient::new(connection);
+    for (uri, snapshots) in active {
+        let regions = snapshots
+            .iter()
+            .map(|snapshot| ActiveRegion {
+                job_id: snapshot.job_id.clone(),
+                line: snapshot.current_line,
+                function_signature: snapshot.function_signature.to_string(),
+                state: snapshot.state,
+            })
+            .collect();
+        lsp_client.send_notification(
+            &methods.active_regions,
+            ActiveRegionsParams {
+                uri: uri.to_string(),
+                regions,
+            },
+        )?;
+    }
+
+    let jobs = job_list(job_tracker);
+    info!("Re-announced {} active jobs", jobs.len());
+    lsp_client.send_notification(&methods.job_list, JobListParams { jobs })?;
+    Ok(())
+}
+
+/// Every active job with its current line and state, by document and then line.
+fn job_list(job_tracker: &JobTracker) -> Vec<JobListEntry> {
+    job_tracker
+        .active_snapshots()
+        .into_iter()
+        .flat_map(|(uri, snapshots)| {
+            snapshots.into_iter().map(move |snapshot| JobListEntry {
+                job_id: snapshot.job_id,
+                uri: uri.to_string(),
+                line: snapshot.current_line,
+                state: snapshot.state,
+            })
+        })
+        .collect()
+}
+
+/// Cancel every active job, telling the client with `reason`. Returns how many were
+/// cancelled.
+pub fn cancel_all_jobs(
+    connection: &Connection,
+    methods: &MethodNames,
+    job_tracker: &JobTracker,
+    reason: &str,
+) -> Result<usize, Box<dyn Error + Sync + Send>> {
+    let lsp_client = LspClient::new(connection);
+    let cancelled = job_tracker.cancel_all();
+    for (uri, job_id) in &cancelled {
+        lsp_client.send_notification(
+            &methods.job_completed,
+            JobCompletedParams {
+                job_id: job_id.clone(),
+                uri: uri.to_string(),
+                success: false,
+                error: Some(reason.to_string()),
+                status: JobStatus::Cancelled,
+                pending_id: None,
+                edit_id: None,
+                conflict: None,
+                theirs_path: None,
+                no_change: false,
+                raw_output: None,
+            },
+        )?;
+    }
+    Ok(cancelled.len())
+}
+
 pub struct RequestHandler<'a> {
     connection: &'a Connection,
     document_store: Arc<DocumentStore>,
     job_tracker: Arc<JobTracker>,
+    job_queue: Arc<JobQueue>,
+    backend: Arc<dyn Backend>,
+    session: Arc<SessionSettings>,
+    methods: Arc<MethodNames>,
+    path_filter: Arc<PathFilter>,
+    worker_pool: Arc<WorkerPool>,
+    pending_requests: Arc<PendingRequests>,
+    apply_coordinator: Arc<ApplyCoordinator<PendingApply>>,
+    completion_pool: Arc<WorkerPool>,
+    timer: Arc<Timer>,
 }
 
 impl<'a> RequestHandler<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         connection: &'a Connection,
         document_store: Arc<DocumentStore>,
         job_tracker: Arc<JobTracker>,
+        job_queue: Arc<JobQueue>,
+        backend: Arc<dyn Backend>,
+        session: Arc<SessionSettings>,
+        methods: Arc<MethodNames>,
+        path_filter: Arc<PathFilter>,
+        worker_pool: Arc<WorkerPool>,
+        pending_requests: Arc<PendingRequests>,
+        apply_coordinator: Arc<ApplyCoordinator<PendingApply>>,
+        completion_pool: Arc<WorkerPool>,
+        timer: Arc<Timer>,
     ) -> Self {
         Self {
             connection,
             document_store,
             job_tracker,
+            job_queue,
+            backend,
+            session,
+            methods,
+            path_filter,
+            worker_pool,
+            pending_requests,
+            apply_coordinator,
+            completion_pool,
+            timer,
         }
     }
 
@@ -96,6 +709,8 @@ impl<'a> RequestHandler<'a> {
             Completion::METHOD => self.handle_completion(req, &lsp_client),
             CodeActionRequest::METHOD => self.handle_code_action(req, &lsp_client),
             ExecuteCommand::METHOD => self.handle_execute_command(req, &lsp_client),
+            method if method == self.methods.job_status => self.handle_job_status(req, &lsp_client),
+            method if method == self.methods.job_stats => self.handle_job_stats(req, &lsp_client),
             _ => {
                 info!("Unhandled request: {}", req.method);
                 lsp_client.send_method_not_found(req, &req.method)
@@ -117,7 +732,107 @@ impl<'a> RequestHandler<'a> {
             params.context
         );
 
-        lsp_client.send_success(req, serde_json::Value::Null)
+        // Only `AiInline` asks the backend; `Disabled` servers are not asked by clients that
+        // follow the capabilities
+        if self.session.completion_mode() != CompletionMode::AiInline {
+            return lsp_client.send_success(req, serde_json::Value::Null);
+        }
+        let uri = &params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        if !self.path_filter.allows(uri) {
+            return lsp_client.send_success(req, serde_json::Value::Null);
+        }
+        let (Some(doc), Ok(file_path)) = (self.document_store.get(uri), uri.to_file_path()) else {
+            return lsp_client.send_success(req, serde_json::Value::Null);
+        };
+
+        // The backend runs on the completion pool and answers from there, so the message loop
+        // returns at once; the timer answers with null at the deadline if the backend has not,
+        // even while every completion thread is busy
+        let responder = Arc::new(CompletionResponder::new(
+            req.clone(),
+            lsp_client.clone_sender(),
+        ));
+        let deadline = Instant::now() + Duration::from_millis(COMPLETION_TIMEOUT_MS);
+        let late_responder = responder.clone();
+        self.timer.schedule(deadline, move || {
+            if late_responder.is_pending() {
+                info!(
+                    "Completion exceeded {}ms deadline, returning null",
+                    COMPLETION_TIMEOUT_MS
+                );
+                late_responder.answer(None);
+            }
+        });
+
+        let backend = self.backend.clone();
+        self.completion_pool.execute(move || {
+            // Skip backend calls for requests that waited past their deadline for a thread
+            if !responder.is_pending() {
+                return;
+            }
+            let completion = backend
+                .complete(
+                    &file_path.to_string_lossy(),
+                    position.line,
+                    position.character,
+                    &doc.language_id,
+                    &doc.text,
+                )
+                .unwrap_or_else(|e| {
+                    error!("Completion failed: {}", e);
+                    None
+                });
+            responder.answer(completion);
+        });
+        Ok(())
+    }
+
+    fn handle_job_status(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let params: JobStatusParams = match serde_json::from_value(req.params.clone()) {
+            Ok(params) => params,
+            Err(e) => return lsp_client.send_invalid_params(req, &e.to_string()),
+        };
+
+        let job = self
+            .job_tracker
+            .job_snapshot(&params.job_id)
+            .map(|snapshot| JobRecord::from_snapshot(snapshot, self.backend.backend_type()));
+        info!(
+            "Job status for {}: {:?}",
+            params.job_id,
+            job.as_ref().map(|job| job.state)
+        );
+
+        let result = JobStatusResult {
+            found: job.is_some(),
+            job,
+        };
+        lsp_client.send_success(req, serde_json::to_value(result)?)
+    }
+
+    fn handle_job_stats(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let entries = self.job_tracker.history().entries();
+        let stats = compute_stats(&entries);
+        info!("Job stats: {} jobs, {:?}", stats.total, stats.by_outcome);
+
+        let result = JobStatsResult {
+            stats,
+            recent: entries
+                .into_iter()
+                .rev()
+                .take(JOB_STATS_RECENT_ENTRIES)
+                .collect(),
+        };
+        lsp_client.send_success(req, serde_json::to_value(result)?)
     }
 
     fn handle_code_action(
@@ -134,24 +849,36 @@ impl<'a> RequestHandler<'a> {
             uri, position
         );
 
+        if !self.path_filter.allows(uri) {
+            info!("No code actions for excluded file {}", uri);
+            return lsp_client.send_success(req, json!([]));
+        }
         let doc = match self.document_store.get(uri) {
             Some(d) => d,
             None => return lsp_client.send_success(req, json!([])),
         };
 
-        let backend_name = CURRENT_BACKEND.display_name();
+        // Capture the signature the user sees now, before later edits can move the function
+        let function_signature =
+            crate::utils::extract_function_signature(&doc.text, position.line as usize);
+
+        let backend_name = self.backend.backend_type().display_name();
         let action = CodeAction {
             title: format!("Implement function with {}", backend_name),
-            kind: Some(CodeActionKind::QUICKFIX),
+            kind: Some(self.session.code_action_kind().code_action_kind()),
             command: Some(lsp_types::Command {
                 title: format!("Implement function with {}", backend_name),
-                command: COMMAND_IMPL_FUNCTION.to_string(),
+                command: self.methods.impl_function.clone(),
                 arguments: Some(vec![
                     json!(uri.to_string()),
                     json!(position.line),
                     json!(position.character),
                     json!(doc.version),
                     json!(doc.language_id),
+                    json!(null),
+                    json!(JobPriority::Interactive),
+                    json!(false),
+                    json!(function_signature),
                 ]),
             }),
             ..Default::default()
@@ -169,36 +896,246 @@ impl<'a> RequestHandler<'a> {
         let params: ExecuteCommandParams = serde_json::from_value(req.params.clone())?;
         info!("Execute command: {}", params.command);
 
-        if params.command != COMMAND_IMPL_FUNCTION {
-            return lsp_client
-                .send_invalid_params(req, &format!("Unknown command: {}", params.command));
+        match params.command.as_str() {
+            command if command == self.methods.impl_function => {
+                self.handle_impl_function(req, lsp_client, &params)
+            }
+            command if command == self.methods.impl_all_functions => {
+                self.handle_impl_all_functions(req, lsp_client, &params)
+            }
+            command if command == self.methods.impl_selection => {
+                self.handle_impl_selection(req, lsp_client, &params)
+            }
+            command if command == self.methods.set_output_shape => {
+                self.handle_set_output_shape(req, lsp_client, &params)
+            }
+            command if command == self.methods.supported_languages => {
+                lsp_client.send_success(req, serde_json::to_value(SUPPORTED_LANGUAGES)?)
+            }
+            command if command == self.methods.command_schema => {
+                lsp_client.send_success(req, serde_json::to_value(self.methods.command_schemas())?)
+            }
+            command if command == self.methods.status => {
+                let status = ServerStatus {
+                    backend: self.backend.backend_type().display_name().to_string(),
+                    running_jobs: self.job_queue.running_len(),
+                    pending_jobs: self.job_queue.pending_len(),
+                    rate_limit: self.job_queue.rate_limiter().state(),
+                    jobs: job_list(&self.job_tracker),
+                };
+                lsp_client.send_success(req, serde_json::to_value(status)?)
+            }
+            #[cfg(feature = "test-hooks")]
+            command if command == self.methods.set_document => {
+                self.handle_set_document(req, lsp_client, &params)
+            }
+            _ => {
+                lsp_client.send_invalid_params(req, &format!("Unknown command: {}", params.command))
+            }
+        }
+    }
+
+    /// Test hook: set a document's text, version and language directly, bypassing `didOpen`.
+    ///
+    /// Arguments: `[uri, text, version, languageId]`. Lets tests reproduce exact states
+    /// (CRLF line endings, non-ASCII text, no trailing newline) without a real editor.
+    #[cfg(feature = "test-hooks")]
+    fn handle_set_document(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+        params: &ExecuteCommandParams,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let args = &params.arguments;
+        if args.len() < 4 {
+            return lsp_client.send_invalid_params(
+                req,
+                &format!("Missing arguments for {}", self.methods.set_document),
+            );
         }
 
+        let uri: Url = serde_json::from_value(args[0].clone())?;
+        let text: String = serde_json::from_value(args[1].clone())?;
+        let version: i32 = serde_json::from_value(args[2].clone())?;
+        let language_id: String = serde_json::from_value(args[3].clone())?;
+        info!("Test hook set {} to version {}", uri, version);
+        // Any version goes, not just a newer one
+        self.document_store.close(&uri);
+        self.document_store.open(uri, text, version, language_id);
+
+        lsp_client.send_success(req, serde_json::Value::Null)
+    }
+
+    fn handle_set_output_shape(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+        params: &ExecuteCommandParams,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let shape: Option<String> = params
+            .arguments
+            .first()
+            .and_then(|v| serde_json::from_value(v.clone()).ok());
+
+        // "Auto" clears the override so the shape is detected from each result again
+        let output_shape = match shape.as_deref() {
+            Some("Auto") => None,
+            Some(name) => match serde_json::from_value::<OutputShape>(json!(name)) {
+                Ok(output_shape) => Some(output_shape),
+                Err(_) => {
+                    return lsp_client.send_invalid_params(
+                        req,
+                        &format!(
+                            "Invalid output shape '{}': expected BodyOnly, FullFunction or Auto",
+                            name
+                        ),
+                    )
+                }
+            },
+            None => {
+                return lsp_client.send_invalid_params(
+                    req,
+                    &format!("Missing arguments for {}", self.methods.set_output_shape),
+                )
+            }
+        };
+
+        info!("Output shape set to {:?}", output_shape);
+        self.session.set_output_shape(output_shape);
+
+        lsp_client.send_success(req, serde_json::Value::Null)
+    }
+
+    fn handle_impl_function(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+        params: &ExecuteCommandParams,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
         let args = &params.arguments;
         if args.len() < 5 {
-            return lsp_client.send_invalid_params(req, "Missing arguments for agent.implFunction");
+            return lsp_client.send_invalid_params(
+                req,
+                &format!("Missing arguments for {}", self.methods.impl_function),
+            );
         }
 
         let uri_str: String = serde_json::from_value(args[0].clone())?;
         let line: u32 = serde_json::from_value(args[1].clone())?;
         let character: u32 = serde_json::from_value(args[2].clone())?;
-        let _version: i32 = serde_json::from_value(args[3].clone())?;
+        let version: i32 = serde_json::from_value(args[3].clone())?;
         let language_id: String = serde_json::from_value(args[4].clone())?;
         // Optional 6th argument: pending_id from client for correlation
         let pending_id: Option<String> = args
             .get(5)
             .and_then(|v| serde_json::from_value(v.clone()).ok());
+        // Optional 7th argument: scheduling priority ("interactive" or "batch")
+        let priority: JobPriority = args
+            .get(6)
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        // Optional 8th argument: force, allowing an existing implementation to be overwritten
+        let force = args.get(7).and_then(|v| v.as_bool()).unwrap_or(false);
+        // Optional 9th argument: the function's signature as computed by the client
+        let client_signature: Option<String> = args
+            .get(8)
+            .and_then(|v| serde_json::from_value::<String>(v.clone()).ok())
+            .filter(|signature| !signature.trim().is_empty());
+        // Optional 10th argument: the document to append the implementation to, for a
+        // declaration implemented in another file
+        let target_uri: Option<String> = args
+            .get(9)
+            .and_then(|v| serde_json::from_value(v.clone()).ok());
 
         let uri = Url::parse(&uri_str)?;
+        if !self.path_filter.allows(&uri) {
+            return lsp_client.send_invalid_params(
+                req,
+                &format!(
+                    "{} is excluded from agent edits by the include/exclude globs",
+                    uri
+                ),
+            );
+        }
         let doc = match self.document_store.get(&uri) {
             Some(d) => d,
             None => return lsp_client.send_invalid_params(req, "Document not found"),
         };
+        let target = match target_uri {
+            Some(target_uri) => {
+                let target = match Url::parse(&target_uri) {
+                    Ok(target) => target,
+                    Err(e) => {
+                        return lsp_client
+                            .send_invalid_params(req, &format!("Invalid target uri: {}", e))
+                    }
+                };
+                if !self.path_filter.allows(&target) {
+                    return lsp_client.send_invalid_params(
+                        req,
+                        &format!(
+                            "{} is excluded from agent edits by the include/exclude globs",
+                            target
+                        ),
+                    );
+                }
+                if self.document_store.get(&target).is_none() {
+                    return lsp_client.send_invalid_params(req, "Target document not found");
+                }
+                Some(target)
+            }
+            None => None,
+        };
+
+        // The agent works on the latest text; let the client warn that it changed
+        if doc.version != version {
+            info!(
+                "Version mismatch for {}: requested {}, actual {}",
+                uri, version, doc.version
+            );
+            lsp_client.send_notification(
+                &self.methods.version_mismatch,
+                VersionMismatchParams {
+                    uri: uri.to_string(),
+                    requested_version: version,
+                    actual_version: doc.version,
+                },
+            )?;
+        }
+
+        // Never silently overwrite code the user already wrote
+        let has_implementation = crate::utils::has_non_placeholder_body(&doc.text, line as usize);
+        if has_implementation && !force {
+            return lsp_client.send_invalid_params(
+                req,
+                "Function already has an implementation; pass force: true to overwrite it",
+            );
+        }
+
+        let job_id = Uuid::new_v4().to_string();
+
+        // Without a usable backend the job could only fail later, after the spinner started
+        if let Err(e) = self.backend.check_available() {
+            error!("Backend unavailable, rejecting job {}: {}", job_id, e);
+            self.job_tracker.record_rejection(&job_id);
+            lsp_client.send_notification(
+                &self.methods.job_failed,
+                JobFailedParams {
+                    job_id,
+                    uri: uri.to_string(),
+                    code: JobFailureCode::BackendNotFound,
+                    error: e.clone(),
+                    pending_id,
+                },
+            )?;
+            return lsp_client.send_error(req, lsp_server::ErrorCode::RequestFailed as i32, &e);
+        }
 
         // Check if we've reached the max concurrent jobs limit for this file
         if self.job_tracker.active_job_count(&uri)
             >= crate::job_tracker::MAX_CONCURRENT_JOBS_PER_FILE
         {
+            self.job_tracker.record_rejection(&job_id);
             return lsp_client.send_invalid_params(
                 req,
                 &format!(
@@ -208,428 +1145,5040 @@ impl<'a> RequestHandler<'a> {
             );
         }
 
-        // Extract function signature for tracking
-        let function_signature = crate::utils::extract_function_signature(&doc.text, line as usize)
-            .unwrap_or_else(|| format!("line_{}", line));
-
-        info!(
-            "Extracted function signature for line {}: '{}'",
-            line, function_signature
-        );
-
-        let file_path = uri
-            .to_file_path()
-            .map_err(|_| "Invalid file URI")?
-            .to_string_lossy()
-            .to_string();
+        // Function signature for tracking; prefer the one the client saw
+        let function_signature = match client_signature {
+            Some(signature) => {
+                info!(
+                    "Using client function signature for line {}: '{}'",
+                    line, signature
+                );
+                signature.trim().to_string()
+            }
+            None => {
+                let signature = crate::utils::extract_function_signature(&doc.text, line as usize)
+                    .unwrap_or_else(|| format!("line_{}", line));
+                info!(
+                    "Extracted function signature for line {}: '{}'",
+                    line, signature
+                );
+                signature
+            }
+        };
 
-        let job_id = Uuid::new_v4().to_string();
-        let sender = self.connection.sender.clone();
-        let uri_clone = uri.clone();
-        let job_tracker = self.job_tracker.clone();
-        let document_store = self.document_store.clone();
-
-        lsp_client.send_success(req, serde_json::Value::Null)?;
-
-        spawn_implementation_worker(
-            job_id,
-            sender,
-            uri_clone,
-            file_path,
+        let job = match self.new_job(
+            job_id.clone(),
+            uri,
             line,
             character,
             language_id,
             function_signature,
-            job_tracker,
-            document_store,
-            pending_id,
+        ) {
+            Ok(job) => ImplementationJob {
+                pending_id,
+                overwrite: has_implementation,
+                target,
+                ..job
+            },
+            Err(e) => {
+                self.job_tracker.record_rejection(&job_id);
+                return lsp_client.send_invalid_params(req, &e.to_string());
+            }
+        };
+
+        // Enqueue before spawning so jobs are scheduled in submission order
+        if let Err(e) = self.job_queue.enqueue(&job.job_id, priority) {
+            self.job_tracker.record_rejection(&job.job_id);
+            return lsp_client.send_invalid_params(req, &e);
+        }
+        info!(
+            "Queued job {} ({} jobs pending)",
+            job.job_id,
+            self.job_queue.pending_len()
         );
 
+        lsp_client.send_success(req, serde_json::Value::Null)?;
+
+        self.spawn_worker(job);
         Ok(())
     }
-}
-
-fn spawn_implementation_worker(
-    job_id: String,
-    sender: Sender<Message>,
-    uri: Url,
-    file_path: String,
-    original_line: u32,
-    character: u32,
-    language_id: String,
-    function_signature: String,
-    job_tracker: Arc<JobTracker>,
-    document_store: Arc<DocumentStore>,
-    pending_id: Option<String>,
-) {
-    thread::spawn(move || {
-        let lsp_client = LspClient::new_from_sender(sender.clone());
-        let backend = create_backend();
 
-        // Register the job (non-blocking)
-        if let Err(e) =
-            job_tracker.register_job(&uri, &job_id, original_line, function_signature.clone())
-        {
-            error!("Failed to register job: {}", e);
-            // Send job completed with error
-            let _ = lsp_client.send_notification(
-                NOTIFICATION_JOB_COMPLETED,
-                JobCompletedParams {
-                    job_id: job_id.clone(),
-                    uri: uri.to_string(),
-                    success: false,
-                    error: Some(e),
-                    pending_id: pending_id.clone(),
-                },
+    /// Implement every function of a document whose body is still a placeholder.
+    ///
+    /// Arguments: `[uri]`. Each function gets its own batch-priority job; once all of them
+    /// finished, `agent/batchCompleted` summarizes the results. Functions that could not be
+    /// submitted (e.g. over the per-file limit) are reported as failed in that summary.
+    fn handle_impl_all_functions(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+        params: &ExecuteCommandParams,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let Some(uri_str) = params.arguments.first().and_then(|v| v.as_str()) else {
+            return lsp_client.send_invalid_params(
+                req,
+                &format!("Missing arguments for {}", self.methods.impl_all_functions),
             );
-            return;
+        };
+        let uri = Url::parse(uri_str)?;
+        if !self.path_filter.allows(&uri) {
+            return lsp_client.send_invalid_params(
+                req,
+                &format!(
+                    "{} is excluded from agent edits by the include/exclude globs",
+                    uri
+                ),
+            );
+        }
+        let doc = match self.document_store.get(&uri) {
+            Some(d) => d,
+            None => return lsp_client.send_invalid_params(req, "Document not found"),
+        };
+        if let Err(e) = self.backend.check_available() {
+            error!("Backend unavailable, rejecting batch for {}: {}", uri, e);
+            return lsp_client.send_error(req, lsp_server::ErrorCode::RequestFailed as i32, &e);
         }
 
+        let batch_id = Uuid::new_v4().to_string();
+        let lines = crate::utils::find_placeholder_functions(&doc.text);
         info!(
-            "Registered job {} at line {} for {}",
-            job_id, original_line, uri
+            "Batch {} implements {} functions in {}",
+            batch_id,
+            lines.len(),
+            uri
         );
 
-        // Get current document state
-        let doc = match document_store.get(&uri) {
-            Some(d) => d,
-            None => {
-                error!("Document not found");
-                job_tracker.complete_job(&uri, &job_id);
-                let _ = lsp_client.send_notification(
-                    NOTIFICATION_JOB_COMPLETED,
-                    JobCompletedParams {
-                        job_id: job_id.clone(),
-                        uri: uri.to_string(),
+        let mut jobs = Vec::new();
+        let mut rejected = Vec::new();
+        let already_active = self.job_tracker.active_job_count(&uri);
+        for line in lines {
+            let line = line as u32;
+            let job_id = Uuid::new_v4().to_string();
+            let function_signature =
+                crate::utils::extract_function_signature(&doc.text, line as usize)
+                    .unwrap_or_else(|| format!("line_{}", line));
+
+            let submitted = if already_active + jobs.len()
+                >= crate::job_tracker::MAX_CONCURRENT_JOBS_PER_FILE
+            {
+                Err(format!(
+                    "Maximum concurrent implementations ({}) reached for this file",
+                    crate::job_tracker::MAX_CONCURRENT_JOBS_PER_FILE
+                ))
+            } else {
+                // Only a job that exists takes a slot in the queue
+                self.new_job(
+                    job_id.clone(),
+                    uri.clone(),
+                    line,
+                    0,
+                    doc.language_id.clone(),
+                    function_signature.clone(),
+                )
+                .map_err(|e| e.to_string())
+                .and_then(|job| {
+                    self.job_queue
+                        .enqueue(&job.job_id, JobPriority::Batch)
+                        .map(|()| job)
+                })
+            };
+            match submitted {
+                Ok(job) => jobs.push(job),
+                Err(e) => {
+                    self.job_tracker.record_rejection(&job_id);
+                    rejected.push(BatchEntry {
+                        job_id,
+                        signature: function_signature,
+                        line,
                         success: false,
-                        error: Some("Document not found".to_string()),
-                        pending_id: pending_id.clone(),
-                    },
-                );
-                return;
+                        error: Some(e),
+                    });
+                }
             }
+        }
+
+        let batch = Arc::new(Batch::new(
+            batch_id.clone(),
+            uri,
+            self.methods.clone(),
+            jobs.len() + rejected.len(),
+            rejected,
+        ));
+        let result = ImplAllFunctionsResult {
+            batch_id,
+            job_ids: jobs.iter().map(|job| job.job_id.clone()).collect(),
         };
+        lsp_client.send_success(req, serde_json::to_value(result)?)?;
 
-        // Clone values for the progress callback closure
-        let progress_job_id = job_id.clone();
-        let progress_uri = uri.to_string();
-        let progress_job_tracker = job_tracker.clone();
-        let progress_sender = lsp_client.clone_sender();
-        let progress_pending_id = pending_id.clone();
+        // With no job to wait for, the batch is already complete
+        if jobs.is_empty() {
+            batch.report_if_complete(lsp_client);
+        }
+        for job in jobs {
+            self.spawn_worker(ImplementationJob {
+                batch: Some(batch.clone()),
+                ..job
+            });
+        }
+        Ok(())
+    }
 
-        // Generate a temporary file path for the agent to create and write the implementation
-        // We DON'T create the file - let the agent create it to avoid unnecessary reads of empty files
-        // Place it in the same directory as the file being edited to avoid permission errors.
-        let parent_dir = std::path::Path::new(&file_path)
-            .parent()
-            .unwrap_or_else(|| std::path::Path::new("."))
-            .join("tmp");
+    /// Complete or implement an arbitrary selected region instead of a function.
+    ///
+    /// Arguments: `[uri, range]`. The selected text goes to the backend with the file, and
+    /// the result replaces exactly `range`; no function is looked for.
+    fn handle_impl_selection(
+        &self,
+        req: &Request,
+        lsp_client: &LspClient,
+        params: &ExecuteCommandParams,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let args = &params.arguments;
+        if args.len() < 2 {
+            return lsp_client.send_invalid_params(
+                req,
+                &format!("Missing arguments for {}", self.methods.impl_selection),
+            );
+        }
 
-        let temp_filename = format!("agent_impl_{}", Uuid::new_v4());
-        let output_path = parent_dir.join(&temp_filename);
-        let output_path_str = output_path.to_string_lossy().to_string();
-        info!(
-            "Generated temp file path for agent output: {}",
-            output_path_str
-        );
+        let uri: Url = match serde_json::from_value(args[0].clone()) {
+            Ok(uri) => uri,
+            Err(e) => return lsp_client.send_invalid_params(req, &format!("Invalid uri: {}", e)),
+        };
+        let range: Range = match serde_json::from_value(args[1].clone()) {
+            Ok(range) => range,
+            Err(e) => return lsp_client.send_invalid_params(req, &format!("Invalid range: {}", e)),
+        };
+        if !self.path_filter.allows(&uri) {
+            return lsp_client.send_invalid_params(
+                req,
+                &format!(
+                    "{} is excluded from agent edits by the include/exclude globs",
+                    uri
+                ),
+            );
+        }
+        let doc = match self.document_store.get(&uri) {
+            Some(d) => d,
+            None => return lsp_client.send_invalid_params(req, "Document not found"),
+        };
+        let text = match crate::lsp_utils::text_in_range(&doc.text, range) {
+            Some(text) if !text.trim().is_empty() => text.to_string(),
+            _ => return lsp_client.send_invalid_params(req, "Selection is empty"),
+        };
 
-        match backend.implement_function_streaming(
-            &file_path,
-            original_line,
-            character,
-            &language_id,
-            &doc.text,
-            &output_path_str,
-            &function_signature,
-            Box::new(move |preview| {
-                // Get current line (may have been adjusted by other jobs)
-                let current_line = progress_job_tracker
-                    .get_current_line(&progress_job_id)
-                    .unwrap_or(original_line);
-
-                let params = ImplFunctionProgressParams {
-                    job_id: progress_job_id.clone(),
-                    uri: progress_uri.clone(),
-                    line: current_line,
-                    preview: preview.to_string(),
-                    pending_id: progress_pending_id.clone(),
-                };
-                let progress_client = LspClient::new_from_sender(progress_sender.clone());
-                if let Err(e) =
-                    progress_client.send_notification(NOTIFICATION_IMPL_FUNCTION_PROGRESS, params)
-                {
-                    error!("Failed to send progress notification: {}", e);
-                }
-            }),
-        ) {
-            Ok(_) => {
-                // Read the implementation from the temp file that the agent created
-                let implementation = match std::fs::read_to_string(&output_path) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("Failed to read agent output from temp file: {}", e);
-                        job_tracker.complete_job(&uri, &job_id);
-                        let _ = lsp_client.send_notification(
-                            NOTIFICATION_JOB_COMPLETED,
-                            JobCompletedParams {
-                                job_id: job_id.clone(),
-                                uri: uri.to_string(),
-                                success: false,
-                                error: Some(format!("Failed to read output: {}", e)),
-                                pending_id: pending_id.clone(),
-                            },
-                        );
-                        return;
-                    }
-                };
+        let job_id = Uuid::new_v4().to_string();
+        if let Err(e) = self.backend.check_available() {
+            error!("Backend unavailable, rejecting job {}: {}", job_id, e);
+            self.job_tracker.record_rejection(&job_id);
+            return lsp_client.send_error(req, lsp_server::ErrorCode::RequestFailed as i32, &e);
+        }
+        if self.job_tracker.active_job_count(&uri)
+            >= crate::job_tracker::MAX_CONCURRENT_JOBS_PER_FILE
+        {
+            self.job_tracker.record_rejection(&job_id);
+            return lsp_client.send_invalid_params(
+                req,
+                &format!(
+                    "Maximum concurrent implementations ({}) reached for this file. Please wait.",
+                    crate::job_tracker::MAX_CONCURRENT_JOBS_PER_FILE
+                ),
+            );
+        }
 
-                // Log the implementation we received for debugging
-                info!(
-                    "Job {} (original_line={}, signature='{}') received implementation:\n{}",
-                    job_id,
-                    original_line,
-                    function_signature,
-                    implementation
-                        .lines()
-                        .take(5)
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                );
+        // Stands in for the function signature in labels and job listings; it names no
+        // function, so user edits never re-anchor the job by it
+        let description = format!(
+            "selection at lines {}-{}",
+            range.start.line + 1,
+            range.end.line + 1
+        );
+        let job = match self.new_job(
+            job_id.clone(),
+            uri,
+            range.start.line,
+            range.start.character,
+            doc.language_id,
+            description,
+        ) {
+            Ok(job) => ImplementationJob {
+                selection: Some(Selection { range, text }),
+                ..job
+            },
+            Err(e) => {
+                self.job_tracker.record_rejection(&job_id);
+                return lsp_client.send_invalid_params(req, &e.to_string());
+            }
+        };
 
-                // Clean up the temp file if configured to do so
-                if DELETE_TEMP_FILES {
-                    if let Err(e) = std::fs::remove_file(&output_path) {
-                        error!("Failed to remove temp file: {}", e);
-                    }
-                } else {
-                    info!("Preserving temp file for debugging: {}", output_path_str);
-                }
+        if let Err(e) = self.job_queue.enqueue(&job.job_id, JobPriority::Interactive) {
+            self.job_tracker.record_rejection(&job.job_id);
+            return lsp_client.send_invalid_params(req, &e);
+        }
+        info!("Queued selection job {} on {}", job.job_id, job.uri);
 
-                if implementation.trim().is_empty() {
-                    error!("Agent output file is empty");
-                    job_tracker.complete_job(&uri, &job_id);
-                    let _ = lsp_client.send_notification(
-                        NOTIFICATION_JOB_COMPLETED,
-                        JobCompletedParams {
-                            job_id: job_id.clone(),
-                            uri: uri.to_string(),
-                            success: false,
-                            error: Some("Agent output is empty".to_string()),
-                            pending_id: pending_id.clone(),
-                        },
-                    );
-                    return;
-                }
+        lsp_client.send_success(req, serde_json::Value::Null)?;
 
-                // Get current document state
-                let current_doc = match document_store.get(&uri) {
-                    Some(d) => d,
-                    None => {
-                        error!("Document not found when applying edit");
-                        job_tracker.complete_job(&uri, &job_id);
-                        let _ = lsp_client.send_notification(
-                            NOTIFICATION_JOB_COMPLETED,
-                            JobCompletedParams {
-                                job_id: job_id.clone(),
-                                uri: uri.to_string(),
-                                success: false,
-                                error: Some("Document not found".to_string()),
-                                pending_id: pending_id.clone(),
-                            },
-                        );
-                        return;
-                    }
-                };
-                let current_text = current_doc.text.clone();
-
-                // Get current line (may have been adjusted by other jobs)
-                let current_line = job_tracker
-                    .get_current_line(&job_id)
-                    .unwrap_or(original_line) as usize;
-
-                // Get the expected function signature for verification
-                // This ensures we replace the correct function even if line numbers have shifted
-                let expected_signature = job_tracker.get_function_signature(&job_id);
-
-                // Replace function in current document
-                // This always uses latest agent output, overriding any user edits to this specific function
-                let (new_text, start_line, end_line, lines_delta) =
-                    match crate::utils::replace_function_in_document(
-                        &current_text,
-                        current_line,
-                        &implementation,
-                        expected_signature.as_deref(),
-                    ) {
-                        Ok(result) => result,
-                        Err(e) => {
-                            error!("Failed to replace function: {}", e);
-                            job_tracker.complete_job(&uri, &job_id);
-                            let _ = lsp_client.send_notification(
-                                NOTIFICATION_JOB_COMPLETED,
-                                JobCompletedParams {
-                                    job_id: job_id.clone(),
-                                    uri: uri.to_string(),
-                                    success: false,
-                                    error: Some(format!("Failed to replace function: {}", e)),
-                                    pending_id: pending_id.clone(),
-                                },
-                            );
-                            return;
-                        }
-                    };
+        self.spawn_worker(job);
+        Ok(())
+    }
 
-                info!(
-                    "Replaced function at lines {}-{}, delta: {}",
-                    start_line, end_line, lines_delta
-                );
+    /// A job for the function at `line`, with the session's current settings.
+    fn new_job(
+        &self,
+        job_id: String,
+        uri: Url,
+        line: u32,
+        character: u32,
+        language_id: String,
+        function_signature: String,
+    ) -> Result<ImplementationJob, Box<dyn Error + Sync + Send>> {
+        let file_path = uri
+            .to_file_path()
+            .map_err(|_| "Invalid file URI")?
+            .to_string_lossy()
+            .to_string();
+        let base_version = self.document_store.get(&uri).map(|doc| doc.version);
 
-                // Create workspace edit
-                let edit =
-                    WorkspaceEditBuilder::create_full_replace(&uri, &current_text, &new_text);
-
-                // Send the edit
-                if let Err(e) = lsp_client.send_apply_edit(edit) {
-                    error!("Failed to send apply edit: {}", e);
-                    job_tracker.complete_job(&uri, &job_id);
-                    let _ = lsp_client.send_notification(
-                        NOTIFICATION_JOB_COMPLETED,
-                        JobCompletedParams {
-                            job_id: job_id.clone(),
-                            uri: uri.to_string(),
-                            success: false,
-                            error: Some(format!("Failed to apply edit: {}", e)),
-                            pending_id: pending_id.clone(),
-                        },
-                    );
-                    return;
-                }
+        Ok(ImplementationJob {
+            job_id,
+            uri,
+            file_path,
+            original_line: line,
+            character,
+            function_signature: FunctionSignature::parse(&function_signature, &language_id),
+            language_id,
+            pending_id: None,
+            output_shape: self.session.output_shape(),
+            work_done_progress: self.session.work_done_progress(),
+            edit_capabilities: self.session.edit_capabilities(),
+            overwrite: false,
+            base_version,
+            merge_strategy: self.session.merge_strategy(),
+            selection: None,
+            target: None,
+            batch: None,
+            pending_requests: self.pending_requests.clone(),
+            methods: self.methods.clone(),
+            backend: self.backend.backend_type(),
+        })
+    }
 
-                // Adjust other jobs' lines
-                job_tracker.adjust_lines_for_edit(&uri, start_line, end_line, lines_delta, &job_id);
-
-                // Send line update notifications to other jobs
-                let other_jobs = job_tracker.get_active_jobs(&uri);
-                for (other_job_id, updated_line) in other_jobs {
-                    if other_job_id != job_id {
-                        let _ = lsp_client.send_notification(
-                            NOTIFICATION_IMPL_FUNCTION_PROGRESS,
-                            ImplFunctionProgressParams {
-                                job_id: other_job_id,
-                                uri: uri.to_string(),
-                                line: updated_line,
-                                preview: String::new(), // Empty preview indicates line update only
-                                pending_id: None, // Other jobs already have their pending_id resolved
-                            },
-                        );
-                    }
-                }
+    fn spawn_worker(&self, job: ImplementationJob) {
+        spawn_implementation_worker(
+            job,
+            &self.worker_pool,
+            self.connection.sender.clone(),
+            self.backend.clone(),
+            self.job_tracker.clone(),
+            self.job_queue.clone(),
+            self.document_store.clone(),
+            self.apply_coordinator.clone(),
+            self.timer.clone(),
+        );
+    }
+}
 
-                // Send job completed notification
-                let _ = lsp_client.send_notification(
-                    NOTIFICATION_JOB_COMPLETED,
-                    JobCompletedParams {
-                        job_id: job_id.clone(),
-                        uri: uri.to_string(),
-                        success: true,
-                        error: None,
-                        pending_id: pending_id.clone(),
-                    },
-                );
+/// The response to one completion request, sent by whichever answers first: the backend or
+/// the deadline.
+struct CompletionResponder {
+    req: Request,
+    lsp_client: LspClient,
+    answered: AtomicBool,
+}
 
-                // Complete the job
-                job_tracker.complete_job(&uri, &job_id);
-            }
-            Err(e) => {
-                error!("Backend error: {}", e);
-                // Clean up the temp file on error (if it exists and cleanup is enabled)
-                if DELETE_TEMP_FILES && output_path.exists() {
-                    if let Err(cleanup_err) = std::fs::remove_file(&output_path) {
-                        error!("Failed to remove temp file after error: {}", cleanup_err);
-                    }
-                } else if !DELETE_TEMP_FILES && output_path.exists() {
-                    info!(
-                        "Preserving temp file for debugging (error case): {}",
-                        output_path_str
-                    );
-                }
+impl CompletionResponder {
+    fn new(req: Request, sender: Sender<Message>) -> Self {
+        Self {
+            req,
+            lsp_client: LspClient::new_from_sender(sender),
+            answered: AtomicBool::new(false),
+        }
+    }
 
-                // Send job completed notification with error
-                let _ = lsp_client.send_notification(
-                    NOTIFICATION_JOB_COMPLETED,
-                    JobCompletedParams {
-                        job_id: job_id.clone(),
-                        uri: uri.to_string(),
-                        success: false,
-                        error: Some(format!("Backend error: {}", e)),
-                        pending_id: pending_id.clone(),
-                    },
-                );
+    fn is_pending(&self) -> bool {
+        !self.answered.load(Ordering::SeqCst)
+    }
 
-                // Complete the job
-                job_tracker.complete_job(&uri, &job_id);
+    /// Answer with `completion`, or with null for none, unless the request was answered
+    /// already.
+    fn answer(&self, completion: Option<String>) {
+        if self.answered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let result = match completion {
+            Some(text) => {
+                let item = lsp_types::CompletionItem {
+                    label: text.lines().next().unwrap_or_default().trim().to_string(),
+                    insert_text: Some(text),
+                    ..Default::default()
+                };
+                json!([item])
             }
+            None => serde_json::Value::Null,
+        };
+        if let Err(e) = self.lsp_client.send_success(&self.req, result) {
+            error!("Failed to answer completion request: {}", e);
         }
-    });
+    }
 }
 
-pub struct NotificationHandler<'a> {
-    document_store: &'a DocumentStore,
+/// The jobs of one `agent.implAllFunctions` command, collecting their results until all of
+/// them finished.
+#[derive(Debug)]
+struct Batch {
+    batch_id: String,
+    uri: Url,
+    methods: Arc<MethodNames>,
+    /// Number of functions in the batch, including those rejected at submission.
+    expected: usize,
+    entries: Mutex<Vec<BatchEntry>>,
 }
 
-impl<'a> NotificationHandler<'a> {
-    pub fn new(document_store: &'a DocumentStore) -> Self {
-        Self { document_store }
+impl Batch {
+    fn new(
+        batch_id: String,
+        uri: Url,
+        methods: Arc<MethodNames>,
+        expected: usize,
+        entries: Vec<BatchEntry>,
+    ) -> Self {
+        Self {
+            batch_id,
+            uri,
+            methods,
+            expected,
+            entries: Mutex::new(entries),
+        }
     }
 
-    pub fn handle(&self, notification: &Notification) -> Result<(), Box<dyn Error + Sync + Send>> {
-        match notification.method.as_str() {
-            DidOpenTextDocument::METHOD => self.handle_did_open(notification),
-            DidChangeTextDocument::METHOD => self.handle_did_change(notification),
-            _ => {
-                info!("Unhandled notification: {}", notification.method);
-                Ok(())
-            }
+    /// Record a job's result, sending `agent/batchCompleted` once it was the last one.
+    ///
+    /// Only the first result of a job counts, so whoever ends a job may record it.
+    fn record(&self, entry: BatchEntry, lsp_client: &LspClient) {
+        let mut entries = lock_recovering(&self.entries);
+        if entries.iter().any(|e| e.job_id == entry.job_id) {
+            return;
         }
+        entries.push(entry);
+        self.send_if_complete(&entries, lsp_client);
     }
 
-    fn handle_did_open(
-        &self,
-        notification: &Notification,
-    ) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let params: DidOpenTextDocumentParams =
-            serde_json::from_value(notification.params.clone())?;
+    fn report_if_complete(&self, lsp_client: &LspClient) {
+        self.send_if_complete(&lock_recovering(&self.entries), lsp_client);
+    }
+
+    fn send_if_complete(&self, entries: &[BatchEntry], lsp_client: &LspClient) {
+        if entries.len() < self.expected {
+            return;
+        }
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|entry| entry.line);
         info!(
-            "Document opened - uri: {}, language: {}, version: {}",
-            params.text_document.uri,
-            params.text_document.language_id,
-            params.text_document.version
-        );
-        self.document_store.open(
-            params.text_document.uri,
-            params.text_document.text,
-            params.text_document.version,
-            params.text_document.language_id,
+            "Batch {} completed: {} of {} functions implemented",
+            self.batch_id,
+            entries.iter().filter(|entry| entry.success).count(),
+            entries.len()
         );
-        Ok(())
+        if let Err(e) = lsp_client.send_notification(
+            &self.methods.batch_completed,
+            BatchCompletedParams {
+                batch_id: self.batch_id.clone(),
+                uri: self.uri.to_string(),
+                entries,
+            },
+        ) {
+            error!(
+                "Failed to send batch completion for {}: {}",
+                self.batch_id, e
+            );
+        }
     }
+}
 
-    fn handle_did_change(
+/// Everything a worker needs to know about a single implementation request.
+#[derive(Debug, Clone)]
+struct ImplementationJob {
+    job_id: String,
+    uri: Url,
+    file_path: String,
+    original_line: u32,
+    character: u32,
+    language_id: String,
+    function_signature: FunctionSignature,
+    pending_id: Option<String>,
+    /// Output shape forced at submission time; `None` detects it from the result.
+    output_shape: Option<OutputShape>,
+    /// Names the job's notifications are sent under.
+    methods: Arc<MethodNames>,
+    /// The backend implementing it, named in its edit labels.
+    backend: BackendType,
+    /// Whether to also report the job as cancellable `$/progress`, with the job id as token.
+    work_done_progress: bool,
+    /// Shape of the edit sent to the client, captured at submission.
+    edit_capabilities: EditCapabilities,
+    /// Whether the job replaces an implementation that already existed.
+    overwrite: bool,
+    /// Version of the document the job was submitted against, the base its implementation
+    /// is 3-way merged from when the user edits the function meanwhile.
+    base_version: Option<i32>,
+    /// How that merge resolves conflicts, captured at submission.
+    merge_strategy: MergeStrategy,
+    /// The region an `agent.implSelection` job replaces, instead of a function.
+    selection: Option<Selection>,
+    /// The document the implementation is appended to instead of replacing the function,
+    /// for a declaration implemented in another file.
+    target: Option<Url>,
+    /// The `agent.implAllFunctions` batch the job belongs to, if any.
+    batch: Option<Arc<Batch>>,
+    /// Where the main loop routes the client's answers to the job's requests.
+    pending_requests: Arc<PendingRequests>,
+}
+
+/// Code selected for an `agent.implSelection` job, as it was when the job was submitted.
+#[derive(Debug, Clone)]
+struct Selection {
+    range: Range,
+    text: String,
+}
+
+impl ImplementationJob {
+    fn completed_params(&self, status: JobStatus, error: Option<String>) -> JobCompletedParams {
+        JobCompletedParams {
+            job_id: self.job_id.clone(),
+            uri: self.uri.to_string(),
+            success: status == JobStatus::Success,
+            error,
+            status,
+            pending_id: self.pending_id.clone(),
+            edit_id: None,
+            conflict: None,
+            theirs_path: None,
+            no_change: false,
+            raw_output: None,
+        }
+    }
+
+    /// Send `agent/jobCompleted` and add the result to the job's batch.
+    fn report_completed(&self, lsp_client: &LspClient, status: JobStatus, error: Option<String>) {
+        self.send_completed(lsp_client, self.completed_params(status, error));
+    }
+
+    /// Like `report_completed`, for a job whose changes went out in the edit `edit_id`,
+    /// merged via the file at `theirs_path` if any.
+    fn report_applied(
         &self,
-        notification: &Notification,
-    ) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let params: DidChangeTextDocumentParams =
-            serde_json::from_value(notification.params.clone())?;
-        info!(
-            "Document changed - uri: {}, version: {}, changes: {}",
-            params.text_document.uri,
-            params.text_document.version,
-            params.content_changes.len()
-        );
+        lsp_client: &LspClient,
+        status: JobStatus,
+        error: Option<String>,
+        edit_id: RequestId,
+        theirs_path: Option<String>,
+    ) {
+        let params = JobCompletedParams {
+            edit_id: Some(edit_id),
+            theirs_path,
+            ..self.completed_params(status, error)
+        };
+        self.send_completed(lsp_client, params);
+    }
+
+    fn send_completed(&self, lsp_client: &LspClient, params: JobCompletedParams) {
+        let success = params.success;
+        let error = params.error.clone();
+        let _ = lsp_client.send_notification(&self.methods.job_completed, params);
+        self.record_in_batch(lsp_client, success, error);
+    }
+
+    /// Label for `action` on the job's function, e.g. `Agent (Amp): implement fn foo`.
+    fn label(&self, action: &str) -> String {
+        agent_label(
+            self.backend.display_name(),
+            &format!("{} {}", action, self.function_signature.short()),
+        )
+    }
+
+    fn record_in_batch(&self, lsp_client: &LspClient, success: bool, error: Option<String>) {
+        if let Some(batch) = &self.batch {
+            batch.record(
+                BatchEntry {
+                    job_id: self.job_id.clone(),
+                    signature: self.function_signature.to_string(),
+                    line: self.original_line,
+                    success,
+                    error,
+                },
+                lsp_client,
+            );
+        }
+    }
+}
+
+/// How a job's worker finished when it was not an error.
+#[derive(Debug, Clone)]
+enum ImplementationOutcome {
+    /// The client applied the edit with this request id. `theirs_path` is the agent's side
+    /// of the merge, when the implementation was merged with the user's edits.
+    Applied {
+        edit_id: RequestId,
+        theirs_path: Option<String>,
+    },
+    /// The edit with this request id was sent, but the client did not say in time whether
+    /// it applied it.
+    Unconfirmed {
+        edit_id: RequestId,
+        theirs_path: Option<String>,
+    },
+    /// The implementation left the document as it was, so there was nothing to send.
+    Unchanged,
+    /// The job was abandoned (e.g. timed out) before its result could be applied.
+    Discarded,
+    /// The agent's output failed validation, so nothing was applied; the job fails with
+    /// the error and reports the raw output.
+    Invalid {
+        error: ValidationError,
+        raw_output: String,
+    },
+    /// The user's edits to the function conflict with the agent's change and the merge
+    /// strategy is `abort`, so nothing was applied; the job fails with both versions.
+    Conflict(MergeConflict),
+}
+
+impl ImplementationOutcome {
+    /// Record that the sent edit carried an implementation merged via `theirs_path`.
+    fn with_theirs_path(self, path: Option<String>) -> Self {
+        match self {
+            ImplementationOutcome::Applied { edit_id, .. } => ImplementationOutcome::Applied {
+                edit_id,
+                theirs_path: path,
+            },
+            ImplementationOutcome::Unconfirmed { edit_id, .. } => {
+                ImplementationOutcome::Unconfirmed {
+                    edit_id,
+                    theirs_path: path,
+                }
+            }
+            outcome => outcome,
+        }
+    }
+}
+
+/// Frees a job's run slot when the worker finishes, even on early returns.
+struct QueueSlot<'a> {
+    job_queue: &'a JobQueue,
+    job_id: &'a str,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.job_queue.release(self.job_id);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_implementation_worker(
+    job: ImplementationJob,
+    worker_pool: &WorkerPool,
+    sender: Sender<Message>,
+    backend: Arc<dyn Backend>,
+    job_tracker: Arc<JobTracker>,
+    job_queue: Arc<JobQueue>,
+    document_store: Arc<DocumentStore>,
+    apply_coordinator: Arc<ApplyCoordinator<PendingApply>>,
+    timer: Arc<Timer>,
+) {
+    worker_pool.execute(move || {
+        let _slot = QueueSlot {
+            job_queue: &job_queue,
+            job_id: &job.job_id,
+        };
+        let _progress = job
+            .work_done_progress
+            .then(|| WorkDoneProgressGuard::begin(&job, &sender));
+
+        // A panic anywhere in the worker must still end the job: the slot guard above
+        // releases the queue slot while unwinding, and the tracker entry is failed here.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_worker(
+                &job,
+                sender.clone(),
+                backend.as_ref(),
+                &job_tracker,
+                &job_queue,
+                &document_store,
+                &apply_coordinator,
+                &timer,
+            )
+        }));
+        if let Err(payload) = result {
+            error!(
+                "Worker for job {} panicked: {}",
+                job.job_id,
+                panic_message(payload.as_ref())
+            );
+            if job_tracker.fail_job(&job.uri, &job.job_id, WORKER_PANIC_ERROR) {
+                job.report_completed(
+                    &LspClient::new_from_sender(sender),
+                    JobStatus::Failed,
+                    Some(WORKER_PANIC_ERROR.to_string()),
+                );
+            }
+        }
+    });
+}
+
+/// Reports a job as cancellable work done progress, ending it when the worker finishes.
+///
+/// Cancelling the progress (`window/workDoneProgress/cancel`) cancels the job.
+struct WorkDoneProgressGuard {
+    lsp_client: LspClient,
+    token: String,
+}
+
+impl WorkDoneProgressGuard {
+    fn begin(job: &ImplementationJob, sender: &Sender<Message>) -> Self {
+        let lsp_client = LspClient::new_from_sender(sender.clone());
+        let token = job.job_id.clone();
+        let begin = WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: job.label("implement"),
+            cancellable: Some(true),
+            message: Some(job.function_signature.to_string()),
+            percentage: None,
+        });
+        if let Err(e) = lsp_client
+            .send_work_done_progress_create(&token)
+            .and_then(|()| lsp_client.send_work_done_progress(&token, begin))
+        {
+            error!("Failed to begin progress for job {}: {}", token, e);
+        }
+        Self { lsp_client, token }
+    }
+}
+
+impl Drop for WorkDoneProgressGuard {
+    fn drop(&mut self) {
+        let end = WorkDoneProgress::End(WorkDoneProgressEnd { message: None });
+        if let Err(e) = self.lsp_client.send_work_done_progress(&self.token, end) {
+            error!("Failed to end progress for job {}: {}", self.token, e);
+        }
+    }
+}
+
+/// Error reported to the client when a worker panics; the payload itself only goes to the log.
+const WORKER_PANIC_ERROR: &str = "Internal error while implementing the function";
+
+/// Best-effort text of a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
+
+/// Register the job, run it and report its outcome.
+#[allow(clippy::too_many_arguments)]
+fn run_worker(
+    job: &ImplementationJob,
+    sender: Sender<Message>,
+    backend: &dyn Backend,
+    job_tracker: &Arc<JobTracker>,
+    job_queue: &JobQueue,
+    document_store: &DocumentStore,
+    apply_coordinator: &ApplyCoordinator<PendingApply>,
+    timer: &Timer,
+) {
+    let lsp_client = LspClient::new_from_sender(sender.clone())
+        .with_pending_requests(job.pending_requests.clone());
+
+    // Register the job (non-blocking)
+    let cancellation = match job_tracker.register_job(
+        &job.uri,
+        &job.job_id,
+        job.original_line,
+        job.function_signature.clone(),
+    ) {
+        Ok(cancellation) => cancellation,
+        Err(e) => {
+            error!("Failed to register job: {}", e);
+            job_tracker.record_rejection(&job.job_id);
+            job.report_completed(&lsp_client, JobStatus::Failed, Some(e));
+            return;
+        }
+    };
+
+    info!(
+        "Registered job {} at line {} for {}",
+        job.job_id, job.original_line, job.uri
+    );
+
+    // The watchdog does nothing once `worker_done` is dropped at the end of this closure
+    let (worker_done, worker_done_rx) = crossbeam_channel::bounded::<()>(0);
+    schedule_job_watchdog(
+        job.clone(),
+        sender,
+        job_tracker.clone(),
+        worker_done_rx,
+        timer,
+    );
+
+    let result = run_implementation(
+        job,
+        &lsp_client,
+        backend,
+        job_tracker,
+        job_queue,
+        document_store,
+        apply_coordinator,
+        &cancellation,
+    );
+    drop(worker_done);
+
+    // Only the first terminal transition reports an outcome; a job that was cancelled
+    // or timed out while running has already been reported by whoever abandoned it.
+    match result {
+        Ok(ImplementationOutcome::Applied {
+            edit_id,
+            theirs_path,
+        }) if job_tracker.complete_job(&job.uri, &job.job_id) => {
+            job.report_applied(&lsp_client, JobStatus::Success, None, edit_id, theirs_path);
+        }
+        Ok(ImplementationOutcome::Unchanged) if job_tracker.complete_job(&job.uri, &job.job_id) => {
+            let params = JobCompletedParams {
+                no_change: true,
+                ..job.completed_params(JobStatus::Success, None)
+            };
+            job.send_completed(&lsp_client, params);
+        }
+        Ok(ImplementationOutcome::Unconfirmed {
+            edit_id,
+            theirs_path,
+        }) if job_tracker.complete_job(&job.uri, &job.job_id) => {
+            job.report_applied(
+                &lsp_client,
+                JobStatus::Unknown,
+                Some(format!(
+                    "The client did not confirm the edit within {} ms; it may not have been applied",
+                    APPLY_EDIT_TIMEOUT_MS
+                )),
+                edit_id,
+                theirs_path,
+            );
+        }
+        Ok(ImplementationOutcome::Invalid { error, raw_output })
+            if job_tracker.fail_job(&job.uri, &job.job_id, &error.to_string()) =>
+        {
+            error!(
+                "Job {} failed: {}; the agent wrote:\n{}",
+                job.job_id, error, raw_output
+            );
+            let params = JobCompletedParams {
+                raw_output: Some(raw_output),
+                ..job.completed_params(JobStatus::Failed, Some(error.to_string()))
+            };
+            job.send_completed(&lsp_client, params);
+        }
+        Ok(ImplementationOutcome::Conflict(conflict))
+            if job_tracker.fail_job(&job.uri, &job.job_id, MERGE_CONFLICT_ERROR) =>
+        {
+            error!("Job {} failed: {}", job.job_id, MERGE_CONFLICT_ERROR);
+            let params = JobCompletedParams {
+                conflict: Some(conflict),
+                ..job.completed_params(JobStatus::Failed, Some(MERGE_CONFLICT_ERROR.to_string()))
+            };
+            job.send_completed(&lsp_client, params);
+        }
+        Err(e) if job_tracker.fail_job(&job.uri, &job.job_id, &e) => {
+            error!("Job {} failed: {}", job.job_id, e);
+            job.report_completed(&lsp_client, JobStatus::Failed, Some(e));
+        }
+        _ => {
+            info!(
+                "Discarded result of abandoned job {} ({:?})",
+                job.job_id,
+                job_tracker.job_state(&job.job_id)
+            );
+            // Whoever abandoned the job told the client, but not the job's batch
+            let error = job_tracker
+                .job_snapshot(&job.job_id)
+                .and_then(|snapshot| JobRecord::from_snapshot(snapshot, job.backend).error);
+            job.record_in_batch(&lsp_client, false, error);
+        }
+    }
+}
+
+/// Race the worker against the job's wall-clock limit on the shared timer thread.
+///
+/// If the worker has not finished (dropped its end of `worker_done`) by the tracker's job
+/// timeout, the job is marked as timed out, its slot is released and the client is told.
+fn schedule_job_watchdog(
+    job: ImplementationJob,
+    sender: Sender<Message>,
+    job_tracker: Arc<JobTracker>,
+    worker_done: Receiver<()>,
+    timer: &Timer,
+) {
+    let timeout = job_tracker.job_timeout();
+    timer.schedule(Instant::now() + timeout, move || {
+        if worker_done.try_recv() != Err(TryRecvError::Empty) {
+            return;
+        }
+
+        if !job_tracker.time_out_job(&job.uri, &job.job_id) {
+            return;
+        }
+
+        error!(
+            "Job {} timed out after {} seconds",
+            job.job_id,
+            timeout.as_secs()
+        );
+        let lsp_client = LspClient::new_from_sender(sender);
+        job.report_completed(
+            &lsp_client,
+            JobStatus::Timeout,
+            Some(format!(
+                "Implementation timed out after {} seconds",
+                timeout.as_secs()
+            )),
+        );
+    });
+}
+
+/// Error for a job whose target function was removed or renamed while it ran.
+const ORPHANED_JOB_ERROR: &str = "Target function no longer exists";
+
+/// Outcome for a job that could not move to its next state: orphaned jobs fail, while
+/// cancelled or timed-out jobs were already reported by whoever abandoned them.
+fn abandoned_outcome(
+    job_tracker: &JobTracker,
+    job_id: &str,
+) -> Result<ImplementationOutcome, String> {
+    if job_tracker.job_state(job_id) == Some(JobState::Orphaned) {
+        Err(ORPHANED_JOB_ERROR.to_string())
+    } else {
+        Ok(ImplementationOutcome::Discarded)
+    }
+}
+
+/// Cancel the job if `error` shows the client disconnected, so its backend stops instead of
+/// running to completion for nobody.
+fn cancel_if_disconnected(
+    job_tracker: &JobTracker,
+    uri: &Url,
+    job_id: &str,
+    error: &(dyn Error + Sync + Send + 'static),
+) {
+    if is_disconnected(error) && job_tracker.cancel_job(uri, job_id).is_some() {
+        info!("Client disconnected; cancelled job {}", job_id);
+    }
+}
+
+/// Run the backend for a registered job and apply its result to the document.
+#[allow(clippy::too_many_arguments)]
+fn run_implementation(
+    job: &ImplementationJob,
+    lsp_client: &LspClient,
+    backend: &dyn Backend,
+    job_tracker: &JobTracker,
+    job_queue: &JobQueue,
+    document_store: &DocumentStore,
+    apply_coordinator: &ApplyCoordinator<PendingApply>,
+    cancellation: &CancellationToken,
+) -> Result<ImplementationOutcome, String> {
+    let uri = &job.uri;
+    let job_id = &job.job_id;
+    let original_line = job.original_line;
+    let selection = job.selection.as_ref().map(|selection| selection.range);
+
+    // Wait for a run slot, telling the client where the job is in the queue
+    let queued = job_queue.wait_for_slot(job_id, cancellation, |position| {
+        let line = job_tracker
+            .get_current_line(job_id)
+            .unwrap_or(original_line);
+        let (start_line, end_line) = replaced_region(document_store, uri, line, selection).unzip();
+        let params = ImplFunctionProgressParams {
+            job_id: job_id.clone(),
+            uri: uri.to_string(),
+            line,
+            preview: String::new(),
+            truncated: false,
+            pending_id: job.pending_id.clone(),
+            queue_position: Some(position),
+            state: job_tracker.job_state(job_id),
+            start_line,
+            end_line,
+        };
+        if let Err(e) = lsp_client.send_notification(&job.methods.impl_function_progress, params) {
+            error!("Failed to send queue progress notification: {}", e);
+            cancel_if_disconnected(job_tracker, uri, job_id, e.as_ref());
+        }
+    });
+    match queued {
+        Ok(()) => {}
+        // Cancelled or timed out while waiting; whoever abandoned the job reported it
+        Err(QueueWaitError::Cancelled) => return Ok(ImplementationOutcome::Discarded),
+        Err(QueueWaitError::TimedOut(e)) => return Err(e),
+    }
+    // Then for the backend's rate limit, staying queued
+    let rate_limited = job_queue.rate_limiter().acquire(cancellation, |wait| {
+        info!(
+            "Job {} waiting {} ms for the backend's rate limit",
+            job_id,
+            wait.as_millis()
+        );
+    });
+    if !rate_limited {
+        return Ok(ImplementationOutcome::Discarded);
+    }
+    if !job_tracker.set_state(uri, job_id, JobState::Running) {
+        return abandoned_outcome(job_tracker, job_id);
+    }
+
+    // Get current document state
+    let doc = document_store
+        .get(uri)
+        .ok_or_else(|| "Document not found".to_string())?;
+
+    // Clone values for the progress callback closure
+    let progress_job_id = job_id.clone();
+    let progress_uri = uri.to_string();
+    let progress_job_tracker = job_tracker.clone();
+    let progress_sender = lsp_client.clone_sender();
+    let progress_pending_id = job.pending_id.clone();
+    let progress_methods = job.methods.clone();
+    let progress_cancellation = cancellation.clone();
+    let progress_document_store = document_store.clone();
+    let progress_url = uri.clone();
+
+    // Generate a temporary file path for the agent to create and write the implementation
+    // We DON'T create the file - let the agent create it to avoid unnecessary reads of empty files
+    // Place it in the same directory as the file being edited to avoid permission errors,
+    // or in a fresh sandbox directory the backend CLI then runs in.
+    let sandbox = if BACKEND_SANDBOX {
+        let sandbox = tempfile::Builder::new()
+            .prefix("agent_sandbox_")
+            .disable_cleanup(!DELETE_TEMP_FILES)
+            .tempdir()
+            .map_err(|e| format!("Failed to create backend sandbox: {}", e))?;
+        info!(
+            "Running job {} in sandbox {}",
+            job_id,
+            sandbox.path().display()
+        );
+        Some(sandbox)
+    } else {
+        None
+    };
+    let parent_dir = match &sandbox {
+        Some(sandbox) => sandbox.path().to_path_buf(),
+        None => std::path::Path::new(&job.file_path)
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."))
+            .join("tmp"),
+    };
+
+    let temp_filename = format!("agent_impl_{}", Uuid::new_v4());
+    let output_path = parent_dir.join(&temp_filename);
+    let output_path_str = output_path.to_string_lossy().to_string();
+    info!(
+        "Generated temp file path for agent output: {}",
+        output_path_str
+    );
+
+    let on_progress: Box<dyn FnMut(&str) + Send> = Box::new(move |preview| {
+        // Backends may keep streaming after a cancel; the client already saw the job end
+        if progress_cancellation.is_cancelled() {
+            return;
+        }
+        progress_job_tracker.set_preview(&progress_job_id, preview);
+
+        // Get current line (may have been adjusted by other jobs)
+        let current_line = progress_job_tracker
+            .get_current_line(&progress_job_id)
+            .unwrap_or(original_line);
+        let (start_line, end_line) = replaced_region(
+            &progress_document_store,
+            &progress_url,
+            current_line,
+            selection,
+        )
+        .unzip();
+        let (preview, truncated) = preview_tail(preview, PREVIEW_MAX_CHARS);
+
+        let params = ImplFunctionProgressParams {
+            job_id: progress_job_id.clone(),
+            uri: progress_uri.clone(),
+            line: current_line,
+            preview: preview.to_string(),
+            truncated,
+            pending_id: progress_pending_id.clone(),
+            queue_position: None,
+            state: progress_job_tracker.job_state(&progress_job_id),
+            start_line,
+            end_line,
+        };
+        let progress_client = LspClient::new_from_sender(progress_sender.clone());
+        if let Err(e) =
+            progress_client.send_notification(&progress_methods.impl_function_progress, params)
+        {
+            error!("Failed to send progress notification: {}", e);
+            cancel_if_disconnected(
+                &progress_job_tracker,
+                &progress_url,
+                &progress_job_id,
+                e.as_ref(),
+            );
+        }
+    });
+    let backend_result = match &job.selection {
+        Some(selection) => backend.implement_selection_streaming(
+            &job.file_path,
+            selection.range,
+            &selection.text,
+            &job.language_id,
+            &doc.text,
+            &output_path_str,
+            cancellation,
+            on_progress,
+        ),
+        None => backend.implement_function_streaming(
+            &job.file_path,
+            original_line,
+            job.character,
+            &job.language_id,
+            &doc.text,
+            &output_path_str,
+            &job.function_signature.to_string(),
+            cancellation,
+            on_progress,
+        ),
+    };
+
+    if let Err(e) = backend_result {
+        // Clean up the temp file on error (if it exists and cleanup is enabled)
+        if DELETE_TEMP_FILES && output_path.exists() {
+            if let Err(cleanup_err) = std::fs::remove_file(&output_path) {
+                error!("Failed to remove temp file after error: {}", cleanup_err);
+            }
+        } else if !DELETE_TEMP_FILES && output_path.exists() {
+            info!(
+                "Preserving temp file for debugging (error case): {}",
+                output_path_str
+            );
+        }
+
+        // Whoever cancelled the job already reported it
+        if cancellation.is_cancelled() {
+            return Ok(ImplementationOutcome::Discarded);
+        }
+        return Err(format!("Backend error: {}", e));
+    }
+
+    // Read the implementation (and any imports it needs) from the temp file that the agent created
+    let raw_output = std::fs::read_to_string(&output_path)
+        .map_err(|e| format!("Failed to read output: {}", e))?;
+    let BackendOutput {
+        implementation,
+        imports,
+        files,
+    } = BackendOutput::parse(&raw_output);
+
+    // Log the implementation we received for debugging
+    info!(
+        "Job {} (original_line={}, signature='{}') received implementation:\n{}",
+        job_id,
+        original_line,
+        job.function_signature,
+        implementation
+            .lines()
+            .take(5)
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+
+    // Clean up the temp file if configured to do so
+    if DELETE_TEMP_FILES {
+        if let Err(e) = std::fs::remove_file(&output_path) {
+            error!("Failed to remove temp file: {}", e);
+        }
+    } else {
+        info!("Preserving temp file for debugging: {}", output_path_str);
+    }
+
+    if implementation.trim().is_empty() {
+        return Err("Agent output is empty".to_string());
+    }
+
+    // Never splice into whatever function a signature search would find instead
+    if job_tracker.job_state(job_id) == Some(JobState::Orphaned) {
+        return Err(ORPHANED_JOB_ERROR.to_string());
+    }
+
+    // A selection is replaced by the output as it is; there is no function to find in it
+    if let Some(selection) = &job.selection {
+        let replacement = crate::utils::strip_markdown_code_block(&implementation);
+        if cancellation.is_cancelled() {
+            return Ok(ImplementationOutcome::Discarded);
+        }
+        if !job_tracker.set_state(uri, job_id, JobState::Applying) {
+            return abandoned_outcome(job_tracker, job_id);
+        }
+        job_queue.release(job_id);
+        return apply_selection(
+            job,
+            selection,
+            lsp_client,
+            document_store,
+            job_tracker,
+            &replacement,
+        );
+    }
+
+    // Models do not always follow the prompt, so the shape is decided from what they returned
+    let implementation = crate::utils::strip_markdown_code_block(&implementation);
+    let implementation = if job.output_shape == Some(OutputShape::BodyOnly) {
+        implementation
+    } else {
+        crate::utils::extract_target_function(&implementation, &job.function_signature)?
+    };
+    let implementation = if FORMAT_IMPLEMENTATIONS {
+        format_implementation(&implementation, &job.language_id, FORMATTERS)
+    } else {
+        implementation
+    };
+    let output_shape = job
+        .output_shape
+        .unwrap_or_else(|| crate::utils::classify_output_shape(&implementation, &job.language_id));
+    // Nothing that is not code for the function may reach the document
+    if let Err(error) = validate_implementation(
+        &implementation,
+        job.function_signature.name.as_deref(),
+        &job.language_id,
+        output_shape,
+    ) {
+        return Ok(ImplementationOutcome::Invalid { error, raw_output });
+    }
+    info!("Applying implementation as {:?}", output_shape);
+
+    // A job that timed out while the backend was running must not touch the document
+    if cancellation.is_cancelled() {
+        return Ok(ImplementationOutcome::Discarded);
+    }
+    if !job_tracker.set_state(uri, job_id, JobState::Applying) {
+        return abandoned_outcome(job_tracker, job_id);
+    }
+
+    for file in &files {
+        // A file that cannot be created does not undo the implementation itself
+        if let Err(e) = create_new_file(job, lsp_client, file) {
+            error!("Job {} could not create {}: {}", job_id, file.path, e);
+        }
+    }
+
+    // The declaration stays as it is; the implementation goes to the end of the target
+    if let Some(target) = &job.target {
+        job_queue.release(job_id);
+        return apply_to_target(job, target, lsp_client, document_store, &implementation);
+    }
+
+    // Jobs on this document finishing close together go out as one edit, so later ones do
+    // not race against the document versions earlier ones produce. The backend is done, so
+    // the next job can have the run slot while the group is open.
+    job_queue.release(job_id);
+    let (outcome, outcome_receiver) = crossbeam_channel::bounded(1);
+    let pending = PendingApply {
+        job: job.clone(),
+        implementation,
+        imports,
+        output_shape,
+        outcome,
+    };
+    if let Some(group) = apply_coordinator.submit(uri, pending) {
+        apply_group(group, lsp_client, document_store, job_tracker);
+    }
+    outcome_receiver
+        .recv()
+        .unwrap_or_else(|_| Err("The edit for the job was not applied".to_string()))
+}
+
+/// A job's implementation waiting in the `ApplyCoordinator` for the rest of its group.
+pub struct PendingApply {
+    job: ImplementationJob,
+    implementation: String,
+    imports: Vec<String>,
+    output_shape: OutputShape,
+    /// Where the group's leader sends the job's outcome.
+    outcome: Sender<Result<ImplementationOutcome, String>>,
+}
+
+/// Apply a group of jobs on one document, sending each its outcome.
+fn apply_group(
+    group: Vec<PendingApply>,
+    lsp_client: &LspClient,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+) {
+    // Jobs cancelled or orphaned while the group was open are left out
+    let (live, abandoned): (Vec<_>, Vec<_>) = group.into_iter().partition(|pending| {
+        job_tracker.job_state(&pending.job.job_id) == Some(JobState::Applying)
+    });
+    for pending in abandoned {
+        let _ = pending
+            .outcome
+            .send(abandoned_outcome(job_tracker, &pending.job.job_id));
+    }
+
+    match live.as_slice() {
+        [] => {}
+        [pending] => {
+            let outcome = apply_implementation(
+                &pending.job,
+                lsp_client,
+                document_store,
+                job_tracker,
+                &pending.implementation,
+                &pending.imports,
+                pending.output_shape,
+            );
+            let _ = pending.outcome.send(outcome);
+        }
+        _ => apply_combined(&live, lsp_client, document_store, job_tracker),
+    }
+}
+
+/// Splice a single job's implementation into the latest version of its document and send
+/// the edit.
+fn apply_implementation(
+    job: &ImplementationJob,
+    lsp_client: &LspClient,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+    implementation: &str,
+    imports: &[String],
+    output_shape: OutputShape,
+) -> Result<ImplementationOutcome, String> {
+    let uri = &job.uri;
+    let job_id = &job.job_id;
+    let prepared = match prepare_latest_edit(
+        job,
+        document_store,
+        job_tracker,
+        implementation,
+        imports,
+        output_shape,
+    ) {
+        Ok(prepared) => prepared,
+        Err(MergeError::Conflict(conflict)) => {
+            return Ok(ImplementationOutcome::Conflict(conflict))
+        }
+        Err(MergeError::Failed(e)) => return Err(e),
+    };
+    let PreparedEdit {
+        version,
+        old_text,
+        new_text,
+        start_line,
+        end_line,
+        lines_delta,
+        import_edits,
+        theirs_path,
+    } = prepared;
+
+    // Re-running on a function that is already implemented this way would only add an
+    // undo entry
+    if import_edits.is_empty()
+        && region_unchanged(&old_text, &new_text, start_line, end_line, lines_delta)
+    {
+        info!("Job {} left the function as it was", job_id);
+        return Ok(ImplementationOutcome::Unchanged);
+    }
+
+    // Edit only the changed lines, so marks and folds elsewhere in the buffer survive
+    let Some(mut edit) = WorkspaceEditBuilder::create_minimal_edits(
+        uri,
+        &old_text,
+        &new_text,
+        Some(version),
+        job.edit_capabilities,
+    ) else {
+        info!("Job {} produced no changes", job_id);
+        return Ok(ImplementationOutcome::Unchanged);
+    };
+    // The diff may put changes on identical lines next to the function or at the end of the
+    // document; without imports, replacing exactly the function's lines touches nothing else
+    if import_edits.is_empty()
+        && !WorkspaceEditBuilder::edits_within_lines(&edit, start_line, end_line)
+    {
+        let new_function_lines = (end_line - start_line + 1) as i32 + lines_delta;
+        let new_function = new_text
+            .lines()
+            .skip(start_line as usize)
+            .take(new_function_lines.max(0) as usize)
+            .collect::<Vec<_>>()
+            .join("\n");
+        info!(
+            "Job {} replaces lines {}-{} instead of diffing",
+            job_id, start_line, end_line
+        );
+        edit = WorkspaceEditBuilder::create_function_replace(
+            uri,
+            &old_text,
+            start_line,
+            end_line,
+            &new_function,
+            Some(version),
+            job.edit_capabilities,
+        );
+    }
+    let kind = classify_edit(job.overwrite, &edit, &old_text, &new_text);
+    let label = job.label(kind.action());
+    let edit = WorkspaceEditBuilder::annotate(
+        edit,
+        lsp_types::ChangeAnnotation {
+            label: label.clone(),
+            needs_confirmation: Some(CONFIRM_EDIT_KINDS.contains(&kind)),
+            description: Some(format!("{:?} of {}", kind, job.function_signature)),
+        },
+        job.edit_capabilities,
+    );
+
+    // Send the edit, and only report the job once the client says what became of it. Other
+    // jobs move when the client reports the edit back with didChange
+    Ok(send_edit(label, edit, lsp_client)?.with_theirs_path(theirs_path))
+}
+
+/// Replace exactly a selection job's region, where user edits above it moved it, with
+/// `replacement` and send the edit.
+///
+/// Fails if the user changed the selected text while the agent was working.
+fn apply_selection(
+    job: &ImplementationJob,
+    selection: &Selection,
+    lsp_client: &LspClient,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+    replacement: &str,
+) -> Result<ImplementationOutcome, String> {
+    let uri = &job.uri;
+    let job_id = &job.job_id;
+    let doc = document_store
+        .get(uri)
+        .ok_or_else(|| "Document not found".to_string())?;
+
+    let Range { start, end } = selection.range;
+    let start_line = job_tracker.get_current_line(job_id).unwrap_or(start.line);
+    let end_line = start_line + (end.line - start.line);
+    let range = Range {
+        start: Position::new(start_line, start.character),
+        end: Position::new(end_line, end.character),
+    };
+    if crate::lsp_utils::text_in_range(&doc.text, range) != Some(selection.text.as_str()) {
+        return Err("The selected code changed while the agent was working".to_string());
+    }
+
+    // The output's line breaks at its end follow the selection's
+    let mut new_text = replacement.trim_end_matches(['\r', '\n']).to_string();
+    if selection.text.ends_with('\n') {
+        new_text.push_str(detect_line_ending(&doc.text));
+    }
+    if new_text == selection.text {
+        info!("Job {} produced no changes", job_id);
+        return Ok(ImplementationOutcome::Unchanged);
+    }
+
+    let edit = WorkspaceEditBuilder::create_range_replace(
+        uri,
+        range,
+        &new_text,
+        Some(doc.version),
+        job.edit_capabilities,
+    );
+    let kind = EditKind::Implementation;
+    let label = job.label(kind.action());
+    let edit = WorkspaceEditBuilder::annotate(
+        edit,
+        lsp_types::ChangeAnnotation {
+            label: label.clone(),
+            needs_confirmation: Some(CONFIRM_EDIT_KINDS.contains(&kind)),
+            description: Some(format!("{:?} of {}", kind, job.function_signature)),
+        },
+        job.edit_capabilities,
+    );
+    send_edit(label, edit, lsp_client)
+}
+
+/// Append a job's implementation to the latest version of its target document and send the
+/// edit.
+fn apply_to_target(
+    job: &ImplementationJob,
+    target: &Url,
+    lsp_client: &LspClient,
+    document_store: &DocumentStore,
+    implementation: &str,
+) -> Result<ImplementationOutcome, String> {
+    let doc = document_store
+        .get(target)
+        .ok_or_else(|| "Target document not found".to_string())?;
+    let (new_text, start_line) =
+        crate::utils::append_function_to_document(&doc.text, implementation);
+    info!(
+        "Job {} appends its implementation to {} at line {}",
+        job.job_id, target, start_line
+    );
+
+    let Some(edit) = WorkspaceEditBuilder::create_minimal_edits(
+        target,
+        &doc.text,
+        &new_text,
+        Some(doc.version),
+        job.edit_capabilities,
+    ) else {
+        info!("Job {} produced no changes", job.job_id);
+        return Ok(ImplementationOutcome::Unchanged);
+    };
+    let kind = EditKind::Implementation;
+    let label = job.label(kind.action());
+    let edit = WorkspaceEditBuilder::annotate(
+        edit,
+        lsp_types::ChangeAnnotation {
+            label: label.clone(),
+            needs_confirmation: Some(CONFIRM_EDIT_KINDS.contains(&kind)),
+            description: Some(format!("{:?} of {}", kind, job.function_signature)),
+        },
+        job.edit_capabilities,
+    );
+    send_edit(label, edit, lsp_client)
+}
+
+/// Send `edit` under `label` and wait for the client to say whether it applied it.
+fn send_edit(
+    label: String,
+    edit: lsp_types::WorkspaceEdit,
+    lsp_client: &LspClient,
+) -> Result<ImplementationOutcome, String> {
+    let pending_edit = lsp_client
+        .send_apply_edit(label, edit)
+        .map_err(|e| format!("Failed to apply edit: {}", e))?;
+    let edit_id = pending_edit.id().clone();
+    match pending_edit.wait_applied(Duration::from_millis(APPLY_EDIT_TIMEOUT_MS)) {
+        ApplyEditOutcome::Applied => Ok(ImplementationOutcome::Applied {
+            edit_id,
+            theirs_path: None,
+        }),
+        ApplyEditOutcome::Rejected(reason) => {
+            Err(format!("Client did not apply the edit: {}", reason))
+        }
+        // The edit most likely went through; if so, its didChange moves the other jobs
+        ApplyEditOutcome::Unknown => Ok(ImplementationOutcome::Unconfirmed {
+            edit_id,
+            theirs_path: None,
+        }),
+    }
+}
+
+/// Tell the client where the `moved` jobs on `uri` are now.
+fn notify_moved_jobs(
+    uri: &Url,
+    moved: Vec<(String, u32)>,
+    methods: &MethodNames,
+    lsp_client: &LspClient,
+    job_tracker: &JobTracker,
+) {
+    for (job_id, updated_line) in moved {
+        let state = job_tracker.job_state(&job_id);
+        let _ = lsp_client.send_notification(
+            &methods.impl_function_progress,
+            ImplFunctionProgressParams {
+                job_id,
+                uri: uri.to_string(),
+                line: updated_line,
+                preview: String::new(), // Empty preview indicates line update only
+                truncated: false,
+                pending_id: None, // Other jobs already have their pending_id resolved
+                queue_position: None,
+                state,
+                start_line: None,
+                end_line: None,
+            },
+        );
+    }
+}
+
+/// Several jobs' implementations spliced into one version of their document.
+struct CombinedEdit {
+    /// Version of the document the edit was computed against.
+    version: i32,
+    old_text: String,
+    new_text: String,
+    /// Indices in the group of the jobs spliced in, bottom-up, with their
+    /// `Splice::theirs_path`.
+    splices: Vec<(usize, Option<String>)>,
+    /// Jobs whose function could not be replaced, with the reason.
+    failures: Vec<(usize, MergeError)>,
+}
+
+/// Splice every job of `group` into the latest version of their document, one after the
+/// other. A job whose function cannot be replaced is left out without affecting the rest.
+fn prepare_combined_edit(
+    group: &[PendingApply],
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+) -> Result<CombinedEdit, String> {
+    let first = &group[0].job;
+    let current_doc = document_store
+        .get(&first.uri)
+        .ok_or_else(|| "Document not found".to_string())?;
+
+    // Replace from the bottom up, so every splice leaves the functions above it in place
+    let current_line = |pending: &PendingApply| {
+        job_tracker
+            .get_current_line(&pending.job.job_id)
+            .unwrap_or(pending.job.original_line)
+    };
+    let mut order: Vec<usize> = (0..group.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(current_line(&group[index])));
+
+    let mut text = current_doc.text.clone();
+    let mut splices = Vec::new();
+    let mut failures = Vec::new();
+    for index in order {
+        let pending = &group[index];
+        match splice_implementation(
+            &pending.job,
+            document_store,
+            job_tracker,
+            &text,
+            current_doc.version,
+            &pending.implementation,
+            pending.output_shape,
+        ) {
+            Ok(splice) => {
+                text = splice.text;
+                splices.push((index, splice.theirs_path));
+            }
+            Err(e) => failures.push((index, e)),
+        }
+    }
+    info!(
+        "Replaced {} of {} functions in version {}",
+        splices.len(),
+        group.len(),
+        current_doc.version
+    );
+
+    // Add the imports every replaced function needs, each once
+    let mut imports: Vec<String> = Vec::new();
+    for (index, _) in &splices {
+        for import in &group[*index].imports {
+            if !imports.contains(import) {
+                imports.push(import.clone());
+            }
+        }
+    }
+    let import_edits = insert_imports(&text, &first.language_id, &imports);
+    let new_text = apply_import_edits(&text, &import_edits);
+
+    Ok(CombinedEdit {
+        version: current_doc.version,
+        old_text: current_doc.text,
+        new_text,
+        splices,
+        failures,
+    })
+}
+
+/// Send the implementations of several jobs on one document as a single edit, sending each
+/// job its outcome.
+fn apply_combined(
+    group: &[PendingApply],
+    lsp_client: &LspClient,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+) {
+    let fail_all = |error: String| {
+        for pending in group {
+            let _ = pending.outcome.send(Err(error.clone()));
+        }
+    };
+    let first = &group[0].job;
+    let mut combined = match prepare_combined_edit(group, document_store, job_tracker) {
+        Ok(combined) => combined,
+        Err(e) => return fail_all(e),
+    };
+    for _ in 0..MAX_EDIT_RECOMPUTES {
+        let latest_version = document_store.get(&first.uri).map(|doc| doc.version);
+        if latest_version == Some(combined.version) {
+            break;
+        }
+        info!(
+            "Document changed from version {} while preparing {} jobs, recomputing the edit",
+            combined.version,
+            group.len()
+        );
+        combined = match prepare_combined_edit(group, document_store, job_tracker) {
+            Ok(combined) => combined,
+            Err(e) => return fail_all(e),
+        };
+    }
+
+    for (index, error) in &combined.failures {
+        let outcome = match error {
+            MergeError::Conflict(conflict) => Ok(ImplementationOutcome::Conflict(conflict.clone())),
+            MergeError::Failed(e) => Err(e.clone()),
+        };
+        let _ = group[*index].outcome.send(outcome);
+    }
+    let applied: Vec<&PendingApply> = combined
+        .splices
+        .iter()
+        .map(|(index, _)| &group[*index])
+        .collect();
+    let result = send_combined_edit(first, &applied, &combined, lsp_client);
+    for (pending, (_, theirs_path)) in applied.into_iter().zip(&combined.splices) {
+        let outcome = result
+            .clone()
+            .map(|outcome| outcome.with_theirs_path(theirs_path.clone()));
+        let _ = pending.outcome.send(outcome);
+    }
+}
+
+/// Send one edit covering every splice of `combined`, the implementations of `applied`.
+fn send_combined_edit(
+    first: &ImplementationJob,
+    applied: &[&PendingApply],
+    combined: &CombinedEdit,
+    lsp_client: &LspClient,
+) -> Result<ImplementationOutcome, String> {
+    let Some(edit) = WorkspaceEditBuilder::create_minimal_edits(
+        &first.uri,
+        &combined.old_text,
+        &combined.new_text,
+        Some(combined.version),
+        first.edit_capabilities,
+    ) else {
+        info!("{} jobs produced no changes", applied.len());
+        return Ok(ImplementationOutcome::Unchanged);
+    };
+
+    let overwrite = applied.iter().any(|pending| pending.job.overwrite);
+    let kind = classify_edit(overwrite, &edit, &combined.old_text, &combined.new_text);
+    let signatures: Vec<String> = applied
+        .iter()
+        .map(|pending| pending.job.function_signature.to_string())
+        .collect();
+    info!(
+        "Applying {} implementations as one edit: {}",
+        applied.len(),
+        signatures.join(", ")
+    );
+    let label = agent_label(
+        first.backend.display_name(),
+        &format!("{} {} functions", kind.action(), applied.len()),
+    );
+    let edit = WorkspaceEditBuilder::annotate(
+        edit,
+        lsp_types::ChangeAnnotation {
+            label: label.clone(),
+            needs_confirmation: Some(CONFIRM_EDIT_KINDS.contains(&kind)),
+            description: Some(format!("{:?} of {}", kind, signatures.join(", "))),
+        },
+        first.edit_capabilities,
+    );
+    send_edit(label, edit, lsp_client)
+}
+
+/// Ask the client to create a file the backend wrote next to the job's document.
+///
+/// Existing files are never overwritten.
+fn create_new_file(
+    job: &ImplementationJob,
+    lsp_client: &LspClient,
+    file: &NewFile,
+) -> Result<(), String> {
+    let relative = std::path::Path::new(&file.path);
+    // Keep new files inside the document's directory
+    let stays_inside = relative
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if !stays_inside || file.path.is_empty() {
+        return Err("Path must be relative to the document's directory".to_string());
+    }
+    let path = std::path::Path::new(&job.file_path)
+        .parent()
+        .ok_or("Document has no parent directory")?
+        .join(relative);
+    let uri = Url::from_file_path(&path).map_err(|_| format!("Invalid path {:?}", path))?;
+
+    let edit = WorkspaceEditBuilder::create_file_with_content(
+        &uri,
+        &file.content,
+        lsp_types::CreateFileOptions {
+            overwrite: Some(false),
+            ignore_if_exists: Some(true),
+        },
+        job.edit_capabilities,
+    )?;
+    info!("Job {} creating {}", job.job_id, uri);
+    lsp_client
+        .send_apply_edit(job.label(&format!("create {} for", file.path)), edit)
+        .map(|_| ())
+        .map_err(|e| format!("Failed to apply edit: {}", e))
+}
+
+/// Decide what kind of change `edit` makes, turning `old_text` into `new_text`.
+fn classify_edit(
+    overwrite: bool,
+    edit: &lsp_types::WorkspaceEdit,
+    old_text: &str,
+    new_text: &str,
+) -> EditKind {
+    const CONFLICT_MARKER: &str = "<<<<<<<";
+    if new_text.matches(CONFLICT_MARKER).count() > old_text.matches(CONFLICT_MARKER).count() {
+        EditKind::Conflict
+    } else if WorkspaceEditBuilder::is_whole_document_rewrite(edit, old_text) {
+        EditKind::WholeFile
+    } else if overwrite {
+        EditKind::Overwrite
+    } else {
+        EditKind::Implementation
+    }
+}
+
+/// A job's implementation spliced into one version of its document.
+struct PreparedEdit {
+    /// Version of the document the edit was computed against.
+    version: i32,
+    old_text: String,
+    new_text: String,
+    start_line: u32,
+    end_line: u32,
+    lines_delta: i32,
+    import_edits: Vec<lsp_types::TextEdit>,
+    /// See `Splice::theirs_path`.
+    theirs_path: Option<String>,
+}
+
+/// The lines of the function at `line` in the latest version of the document, i.e. the
+/// region an implementation would replace, for live highlighting while it streams. A
+/// selection job replaces the lines of its `selection`, now starting at `line`.
+fn replaced_region(
+    document_store: &DocumentStore,
+    uri: &Url,
+    line: u32,
+    selection: Option<Range>,
+) -> Option<(u32, u32)> {
+    if let Some(range) = selection {
+        return Some((line, line + (range.end.line - range.start.line)));
+    }
+    let doc = document_store.get(uri)?;
+    let lines: Vec<&str> = doc.text.lines().collect();
+    let start_line = crate::utils::find_function_start(&lines, line as usize)?;
+    let end_line = crate::utils::find_function_end(&lines, start_line)?;
+    Some((start_line as u32, end_line as u32))
+}
+
+/// Splice `implementation` (and any `imports` it needs) into the latest version of the
+/// job's document, at the function's current line.
+fn prepare_edit(
+    job: &ImplementationJob,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+    implementation: &str,
+    imports: &[String],
+    output_shape: OutputShape,
+) -> Result<PreparedEdit, MergeError> {
+    let current_doc = document_store
+        .get(&job.uri)
+        .ok_or_else(|| "Document not found".to_string())?;
+
+    let Splice {
+        text: new_text,
+        start_line,
+        end_line,
+        lines_delta,
+        theirs_path,
+    } = splice_implementation(
+        job,
+        document_store,
+        job_tracker,
+        &current_doc.text,
+        current_doc.version,
+        implementation,
+        output_shape,
+    )?;
+
+    info!(
+        "Replaced function at lines {}-{} of version {}, delta: {}",
+        start_line, end_line, current_doc.version, lines_delta
+    );
+
+    // Add imports the implementation needs, skipping ones the file already has
+    let import_edits = insert_imports(&new_text, &job.language_id, imports);
+    let new_text = apply_import_edits(&new_text, &import_edits);
+    if !import_edits.is_empty() {
+        info!("Added imports at {} locations", import_edits.len());
+    }
+
+    Ok(PreparedEdit {
+        version: current_doc.version,
+        old_text: current_doc.text,
+        new_text,
+        start_line,
+        end_line,
+        lines_delta,
+        import_edits,
+        theirs_path,
+    })
+}
+
+/// `prepare_edit` against the newest version of the document.
+///
+/// The user may keep typing while the edit is computed; an edit must match the text of the
+/// version it is stamped with, so it is recomputed while the version moves on.
+fn prepare_latest_edit(
+    job: &ImplementationJob,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+    implementation: &str,
+    imports: &[String],
+    output_shape: OutputShape,
+) -> Result<PreparedEdit, MergeError> {
+    let mut prepared = prepare_edit(
+        job,
+        document_store,
+        job_tracker,
+        implementation,
+        imports,
+        output_shape,
+    )?;
+    for _ in 0..MAX_EDIT_RECOMPUTES {
+        let latest_version = document_store.get(&job.uri).map(|doc| doc.version);
+        if latest_version == Some(prepared.version) {
+            break;
+        }
+        info!(
+            "Document changed from version {} while preparing job {}, recomputing the edit",
+            prepared.version, job.job_id
+        );
+        prepared = prepare_edit(
+            job,
+            document_store,
+            job_tracker,
+            implementation,
+            imports,
+            output_shape,
+        )?;
+    }
+    Ok(prepared)
+}
+
+/// A job's implementation spliced into one text of its document.
+struct Splice {
+    text: String,
+    start_line: u32,
+    end_line: u32,
+    lines_delta: i32,
+    /// The agent's side of the merge, when the implementation was merged with the user's
+    /// edits and it was kept on disk.
+    theirs_path: Option<String>,
+}
+
+/// Splice a job's `implementation` into `text`, `version` of its document, replacing the
+/// function at the job's current line.
+///
+/// The agent's function replaces the current one, unless the user changed that function
+/// since the job was submitted: then the implementation is 3-way merged from the job's base
+/// version, so the user's edits are kept and conflicts resolved by the job's merge strategy.
+fn splice_implementation(
+    job: &ImplementationJob,
+    document_store: &DocumentStore,
+    job_tracker: &JobTracker,
+    text: &str,
+    version: i32,
+    implementation: &str,
+    output_shape: OutputShape,
+) -> Result<Splice, MergeError> {
+    // Get current line (may have been moved by edits above it)
+    let current_line = job_tracker
+        .get_current_line(&job.job_id)
+        .unwrap_or(job.original_line) as usize;
+
+    // Get the expected function signature for verification
+    // This ensures we replace the correct function even if line numbers have shifted
+    let expected_signature = job_tracker.get_function_signature(&job.job_id);
+    let expected_signature = expected_signature.as_ref();
+
+    let failed = |e: String| {
+        MergeError::Failed(format!(
+            "Failed to replace function in document version {}: {}",
+            version, e
+        ))
+    };
+    if output_shape == OutputShape::FullFunction {
+        if let Some((base_text, base_line)) =
+            merge_base(job, document_store, text, current_line, expected_signature)
+        {
+            let (start_line, end_line) = function_range(text, current_line, expected_signature)
+                .ok_or_else(|| failed("Could not find function".to_string()))?;
+            let merged = merge_3way(
+                &job.uri,
+                &base_text,
+                text,
+                implementation,
+                base_line,
+                start_line,
+                &MergeOptions {
+                    signature: expected_signature,
+                    ..MergeOptions::new(&job.job_id, job.merge_strategy)
+                },
+            )
+            .map_err(|e| match e {
+                MergeError::Failed(e) => failed(e),
+                conflict => conflict,
+            })?;
+            info!(
+                "Merged job {}'s function ({} lines) into version {} by {:?}, delta: {}, theirs file: {:?}",
+                job.job_id,
+                merged.function_lines,
+                version,
+                job.merge_strategy,
+                merged.lines_delta,
+                merged.theirs_path
+            );
+            return Ok(Splice {
+                text: merged.text,
+                start_line: start_line as u32,
+                end_line: end_line as u32,
+                lines_delta: merged.lines_delta,
+                theirs_path: merged.theirs_path.map(|path| path.display().to_string()),
+            });
+        }
+    }
+
+    let replace = match output_shape {
+        OutputShape::FullFunction => crate::utils::replace_function_in_document,
+        OutputShape::BodyOnly => crate::utils::replace_function_body_in_document,
+    };
+    let (text, start_line, end_line, lines_delta) =
+        replace(text, current_line, implementation, expected_signature).map_err(failed)?;
+    Ok(Splice {
+        text,
+        start_line,
+        end_line,
+        lines_delta,
+        theirs_path: None,
+    })
+}
+
+/// The text of the document `job` was submitted against, and the line its function starts
+/// on there, if the user changed that function in `text` since; None if the function is as
+/// it was or that version is no longer kept.
+fn merge_base(
+    job: &ImplementationJob,
+    document_store: &DocumentStore,
+    text: &str,
+    current_line: usize,
+    expected_signature: Option<&FunctionSignature>,
+) -> Option<(String, usize)> {
+    let base_version = job.base_version?;
+    let Some(base_text) = document_store.get_version(&job.uri, base_version) else {
+        info!(
+            "Version {} of {} is no longer kept, applying job {} without merging",
+            base_version, job.uri, job.job_id
+        );
+        return None;
+    };
+    let (base_start, base_end) =
+        function_range(&base_text, job.original_line as usize, expected_signature)?;
+    let (start, end) = function_range(text, current_line, expected_signature)?;
+    let base_function = base_text
+        .lines()
+        .skip(base_start)
+        .take(base_end + 1 - base_start);
+    if base_function.eq(text.lines().skip(start).take(end + 1 - start)) {
+        return None;
+    }
+    info!(
+        "The function of job {} changed since version {}, merging",
+        job.job_id, base_version
+    );
+    Some((base_text, base_start))
+}
+
+pub struct NotificationHandler<'a> {
+    connection: &'a Connection,
+    document_store: &'a DocumentStore,
+    job_tracker: &'a JobTracker,
+    backend: &'a dyn Backend,
+    session: &'a SessionSettings,
+    methods: &'a MethodNames,
+}
+
+impl<'a> NotificationHandler<'a> {
+    pub fn new(
+        connection: &'a Connection,
+        document_store: &'a DocumentStore,
+        job_tracker: &'a JobTracker,
+        backend: &'a dyn Backend,
+        session: &'a SessionSettings,
+        methods: &'a MethodNames,
+    ) -> Self {
+        Self {
+            connection,
+            document_store,
+            job_tracker,
+            backend,
+            session,
+            methods,
+        }
+    }
+
+    pub fn handle(&self, notification: &Notification) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => self.handle_did_open(notification),
+            DidChangeTextDocument::METHOD => self.handle_did_change(notification),
+            DidCloseTextDocument::METHOD => self.handle_did_close(notification),
+            WorkDoneProgressCancel::METHOD => self.handle_work_done_progress_cancel(notification),
+            Initialized::METHOD => self.handle_initialized(),
+            _ => {
+                info!("Unhandled notification: {}", notification.method);
+                Ok(())
+            }
+        }
+    }
+
+    /// The client finished the handshake, the first time or after restarting its session:
+    /// check the backend and bring the client up to date, once per `initialize`.
+    fn handle_initialized(&self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        if !self.session.mark_initialized() {
+            info!("Ignoring repeated initialized notification");
+            return Ok(());
+        }
+        if let Err(e) = self.backend.check_available() {
+            warn!(
+                "{} is not available: {}",
+                self.backend.backend_type().display_name(),
+                e
+            );
+        }
+        send_catch_up_notifications(
+            self.connection,
+            self.methods,
+            self.job_tracker,
+            self.session,
+            self.backend.backend_type(),
+        )
+    }
+
+    fn handle_did_open(
+        &self,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let params: DidOpenTextDocumentParams =
+            serde_json::from_value(notification.params.clone())?;
+        info!(
+            "Document opened - uri: {}, language: {}, version: {}",
+            params.text_document.uri,
+            params.text_document.language_id,
+            params.text_document.version
+        );
+        let uri = params.text_document.uri;
+        let outcome = self.document_store.open(
+            uri.clone(),
+            params.text_document.text,
+            params.text_document.version,
+            params.text_document.language_id,
+        );
+        // Jobs' lines refer to the text that was replaced
+        if let OpenOutcome::Reopened {
+            text_changed: true, ..
+        } = outcome
+        {
+            self.cancel_document_jobs(&uri, "Document was re-opened with different text")?;
+        }
+        self.document_store
+            .evict_excess(|uri| self.job_tracker.active_job_count(uri) > 0);
+        Ok(())
+    }
+
+    fn handle_did_change(
+        &self,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let params: DidChangeTextDocumentParams =
+            serde_json::from_value(notification.params.clone())?;
+        info!(
+            "Document changed - uri: {}, version: {}, changes: {}",
+            params.text_document.uri,
+            params.text_document.version,
+            params.content_changes.len()
+        );
+
+        // Every edit moves tracked functions, whether the user typed it or the client applied
+        // one of the agent's; changes are relative to the previous one
+        let uri = &params.text_document.uri;
+        let lines_before: HashMap<String, u32> =
+            self.job_tracker.get_active_jobs(uri).into_iter().collect();
+        let mut affected = Vec::new();
+        for change in &params.content_changes {
+            if let Some(range) = change.range {
+                affected.extend(self.job_tracker.adjust_lines_for_user_edit(
+                    uri,
+                    range,
+                    &change.text,
+                ));
+            }
+        }
+
+        // Only jobs still running may need the text they were computed against
+        let keep_history = self.job_tracker.active_job_count(uri) > 0;
         self.document_store.change(
-            &params.text_document.uri,
+            uri,
             params.text_document.version,
             &params.content_changes,
+            keep_history,
+        );
+
+        // Jobs whose function may have been edited are found again by signature
+        affected.sort();
+        affected.dedup();
+        if !affected.is_empty() {
+            if let Some(doc) = self.document_store.get(uri) {
+                for job_id in self.job_tracker.reanchor_jobs(uri, &affected, &doc.text) {
+                    info!("Job {} orphaned by an edit to {}", job_id, uri);
+                }
+            }
+            let moved = self
+                .job_tracker
+                .get_active_jobs(uri)
+                .into_iter()
+                .filter(|(job_id, line)| lines_before.get(job_id) != Some(line))
+                .collect();
+            notify_moved_jobs(
+                uri,
+                moved,
+                self.methods,
+                &LspClient::new(self.connection),
+                self.job_tracker,
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_did_close(
+        &self,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let params: DidCloseTextDocumentParams =
+            serde_json::from_value(notification.params.clone())?;
+        let uri = params.text_document.uri;
+        info!("Document closed - uri: {}", uri);
+
+        self.cancel_document_jobs(&uri, "Document was closed")?;
+        self.document_store.close(&uri);
+        Ok(())
+    }
+
+    /// Cancel every job on `uri`, notifying the client with `reason`.
+    fn cancel_document_jobs(
+        &self,
+        uri: &Url,
+        reason: &str,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let lsp_client = LspClient::new(self.connection);
+        for job_id in self.job_tracker.cancel_all_for_uri(uri) {
+            info!("Cancelled job {} on {}: {}", job_id, uri, reason);
+            lsp_client.send_notification(
+                &self.methods.job_completed,
+                JobCompletedParams {
+                    job_id,
+                    uri: uri.to_string(),
+                    success: false,
+                    error: Some(reason.to_string()),
+                    status: JobStatus::Cancelled,
+                    pending_id: None,
+                    edit_id: None,
+                    conflict: None,
+                    theirs_path: None,
+                    no_change: false,
+                    raw_output: None,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn handle_work_done_progress_cancel(
+        &self,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let params: WorkDoneProgressCancelParams =
+            serde_json::from_value(notification.params.clone())?;
+        // Job progress tokens are the job ids
+        let NumberOrString::String(job_id) = params.token else {
+            info!(
+                "Ignoring cancel for unknown progress token {:?}",
+                params.token
+            );
+            return Ok(());
+        };
+        let Some(uri) = self.job_tracker.cancel_job_by_id(&job_id) else {
+            info!("Ignoring cancel for job {}, which already finished", job_id);
+            return Ok(());
+        };
+
+        info!("Cancelled job {} from its progress token", job_id);
+        LspClient::new(self.connection).send_notification(
+            &self.methods.job_completed,
+            JobCompletedParams {
+                job_id,
+                uri: uri.to_string(),
+                success: false,
+                error: Some("Cancelled by the client".to_string()),
+                status: JobStatus::Cancelled,
+                pending_id: None,
+                edit_id: None,
+                conflict: None,
+                theirs_path: None,
+                no_change: false,
+                raw_output: None,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use lsp_server::Response;
+    use lsp_types::request::ApplyWorkspaceEdit;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::cancellation::Cancelled;
+    use crate::config::{
+        RateLimit, APPLY_BATCH_WINDOW_MS, COMPLETION_POOL_SIZE, CURRENT_BACKEND, MAX_PENDING_JOBS,
+        MAX_RUNNING_JOBS, WORKER_POOL_SIZE,
+    };
+    use crate::job_history::JobOutcome;
+
+    /// Backend that writes a fixed implementation after an optional delay.
+    struct MockBackend {
+        implementation: String,
+        delay: Duration,
+    }
+
+    impl Backend for MockBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            output_path: &str,
+            _function_signature: &str,
+            _cancellation: &CancellationToken,
+            mut on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            on_progress("working");
+            thread::sleep(self.delay);
+            let output_path = std::path::Path::new(output_path);
+            std::fs::create_dir_all(output_path.parent().unwrap())?;
+            std::fs::write(output_path, &self.implementation)?;
+            Ok(())
+        }
+    }
+
+    /// Backend whose completion answers after `delay`; implementations always fail.
+    struct CompletionBackend {
+        completion: &'static str,
+        delay: Duration,
+    }
+
+    impl Backend for CompletionBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            _output_path: &str,
+            _function_signature: &str,
+            _cancellation: &CancellationToken,
+            _on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            Err("not supported".into())
+        }
+
+        fn complete(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+        ) -> Result<Option<String>, Box<dyn Error + Sync + Send>> {
+            thread::sleep(self.delay);
+            Ok(Some(self.completion.to_string()))
+        }
+    }
+
+    /// Backend that writes a fixed implementation, then changes the document the way a
+    /// `didChange` arriving just before the edit is applied would.
+    struct EditingBackend {
+        implementation: String,
+        document_store: Arc<DocumentStore>,
+        uri: Url,
+        change: lsp_types::TextDocumentContentChangeEvent,
+    }
+
+    impl Backend for EditingBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            output_path: &str,
+            _function_signature: &str,
+            _cancellation: &CancellationToken,
+            _on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            let output_path = std::path::Path::new(output_path);
+            std::fs::create_dir_all(output_path.parent().unwrap())?;
+            std::fs::write(output_path, &self.implementation)?;
+            self.document_store
+                .change(&self.uri, 2, std::slice::from_ref(&self.change), true);
+            Ok(())
+        }
+    }
+
+    /// Backend that panics mid-implementation, as a bug in output handling would.
+    struct PanickingBackend;
+
+    impl Backend for PanickingBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            _output_path: &str,
+            _function_signature: &str,
+            _cancellation: &CancellationToken,
+            mut on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            on_progress("working");
+            panic!("backend exploded");
+        }
+    }
+
+    /// Backend that streams `chunks` previews, then fails; it stops early when cancelled.
+    struct ChunkedBackend {
+        chunks: usize,
+        interval: Duration,
+        /// Number of previews streamed so far.
+        streamed: Arc<AtomicUsize>,
+    }
+
+    impl Backend for ChunkedBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            _output_path: &str,
+            _function_signature: &str,
+            cancellation: &CancellationToken,
+            mut on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            for chunk in 0..self.chunks {
+                thread::sleep(self.interval);
+                if cancellation.is_cancelled() {
+                    return Err(Cancelled.into());
+                }
+                on_progress(&format!("chunk {}", chunk));
+                self.streamed.fetch_add(1, Ordering::SeqCst);
+            }
+            Err("no output".into())
+        }
+    }
+
+    /// Backend that streams one line at a time, each preview holding every line so far.
+    struct AccumulatingBackend {
+        lines: usize,
+    }
+
+    impl Backend for AccumulatingBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            _output_path: &str,
+            _function_signature: &str,
+            _cancellation: &CancellationToken,
+            mut on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            let mut preview = String::new();
+            for line in 0..self.lines {
+                preview.push_str(&format!("    let x{} = {};\n", line, line));
+                on_progress(&preview);
+            }
+            Err("no output".into())
+        }
+    }
+
+    /// Backend whose CLI is not installed.
+    struct MissingCliBackend;
+
+    impl Backend for MissingCliBackend {
+        fn implement_function_streaming(
+            &self,
+            _file_path: &str,
+            _line: u32,
+            _character: u32,
+            _language_id: &str,
+            _file_contents: &str,
+            _output_path: &str,
+            _function_signature: &str,
+            _cancellation: &CancellationToken,
+            _on_progress: Box<dyn FnMut(&str) + Send>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            unreachable!("unavailable backend was called");
+        }
+
+        fn check_available(&self) -> Result<(), String> {
+            crate::backend::check_cli_installed("agent-lsp-missing-cli")
+        }
+    }
+
+    struct Harness {
+        _dir: TempDir,
+        uri: Url,
+        server: Connection,
+        client: Connection,
+        document_store: Arc<DocumentStore>,
+        job_tracker: Arc<JobTracker>,
+        job_queue: Arc<JobQueue>,
+        backend: Arc<dyn Backend>,
+        session: Arc<SessionSettings>,
+        methods: Arc<MethodNames>,
+        path_filter: Arc<PathFilter>,
+        worker_pool: Arc<WorkerPool>,
+        pending_requests: Arc<PendingRequests>,
+        apply_coordinator: Arc<ApplyCoordinator<PendingApply>>,
+        completion_pool: Arc<WorkerPool>,
+        timer: Arc<Timer>,
+        /// How the client answers `workspace/applyEdit` requests.
+        apply_edit_answer: Arc<Mutex<ApplyEditAnswer>>,
+    }
+
+    /// How the harness client answers `workspace/applyEdit` requests.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum ApplyEditAnswer {
+        Apply,
+        Reject,
+        Ignore,
+    }
+
+    impl Harness {
+        fn new(text: &str, backend: MockBackend, job_tracker: JobTracker) -> Self {
+            let dir = TempDir::new().unwrap();
+            let uri = Url::from_file_path(dir.path().join("lib.rs")).unwrap();
+            let document_store = Arc::new(DocumentStore::new());
+            document_store.open(uri.clone(), text.to_string(), 1, "rust".to_string());
+            let (server, client) = Connection::memory();
+            let pending_requests = Arc::new(PendingRequests::new());
+            let apply_edit_answer = Arc::new(Mutex::new(ApplyEditAnswer::Apply));
+            let client = answer_apply_edits(client, &pending_requests, &apply_edit_answer);
+            Self {
+                _dir: dir,
+                uri,
+                server,
+                client,
+                document_store,
+                job_tracker: Arc::new(job_tracker),
+                job_queue: Arc::new(JobQueue::new(MAX_RUNNING_JOBS, MAX_PENDING_JOBS)),
+                backend: Arc::new(backend),
+                session: Arc::new(SessionSettings::new()),
+                methods: Arc::new(MethodNames::default()),
+                path_filter: Arc::new(PathFilter::default()),
+                worker_pool: Arc::new(WorkerPool::new(WORKER_POOL_SIZE)),
+                pending_requests,
+                apply_coordinator: Arc::new(ApplyCoordinator::new(Duration::from_millis(
+                    APPLY_BATCH_WINDOW_MS,
+                ))),
+                completion_pool: Arc::new(WorkerPool::new(COMPLETION_POOL_SIZE)),
+                timer: Arc::new(Timer::new()),
+                apply_edit_answer,
+            }
+        }
+
+        fn answer_apply_edits_with(&self, answer: ApplyEditAnswer) {
+            *self.apply_edit_answer.lock().unwrap() = answer;
+        }
+
+        fn request(&self, id: i32, method: &str, params: serde_json::Value) {
+            let handler = RequestHandler::new(
+                &self.server,
+                self.document_store.clone(),
+                self.job_tracker.clone(),
+                self.job_queue.clone(),
+                self.backend.clone(),
+                self.session.clone(),
+                self.methods.clone(),
+                self.path_filter.clone(),
+                self.worker_pool.clone(),
+                self.pending_requests.clone(),
+                self.apply_coordinator.clone(),
+                self.completion_pool.clone(),
+                self.timer.clone(),
+            );
+            let req = Request {
+                id: RequestId::from(id),
+                method: method.to_string(),
+                params,
+            };
+            handler.handle(&req).unwrap();
+        }
+
+        fn execute_command(&self, command: &str, arguments: serde_json::Value) {
+            self.request(
+                1,
+                ExecuteCommand::METHOD,
+                json!({
+                    "command": command,
+                    "arguments": arguments,
+                }),
+            );
+        }
+
+        /// Query `agent/jobStatus`, skipping any notifications sent in the meantime.
+        fn job_status(&self, job_id: &str) -> serde_json::Value {
+            self.request(2, &self.methods.job_status, json!({ "job_id": job_id }));
+            loop {
+                match self.client.receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(Message::Response(resp)) if resp.id == RequestId::from(2) => {
+                        return resp.result.unwrap();
+                    }
+                    Ok(_) => {}
+                    Err(e) => panic!("No job status response: {}", e),
+                }
+            }
+        }
+
+        fn execute_impl_function(&self, line: u32) {
+            self.execute_command(
+                &self.methods.impl_function,
+                json!([self.uri.to_string(), line, 0, 1, "rust"]),
+            );
+        }
+
+        /// Collect every message sent to the client until `timeout` elapses.
+        fn collect_messages(&self, timeout: Duration) -> Vec<Message> {
+            let deadline = Instant::now() + timeout;
+            let mut messages = Vec::new();
+            while let Ok(msg) = self
+                .client
+                .receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                messages.push(msg);
+            }
+            messages
+        }
+    }
+
+    /// Play the client's part in `workspace/applyEdit`: answer each request as `answer` says
+    /// (routing the answer like the main loop would) and pass every message on to the
+    /// returned connection.
+    fn answer_apply_edits(
+        client: Connection,
+        pending_requests: &Arc<PendingRequests>,
+        answer: &Arc<Mutex<ApplyEditAnswer>>,
+    ) -> Connection {
+        let (forward, receiver) = crossbeam_channel::unbounded();
+        let pending_requests = pending_requests.clone();
+        let answer = answer.clone();
+        thread::spawn(move || {
+            for msg in client.receiver {
+                if let Message::Request(req) = &msg {
+                    if req.method == ApplyWorkspaceEdit::METHOD {
+                        let applied = match *answer.lock().unwrap() {
+                            ApplyEditAnswer::Apply => Some(json!({ "applied": true })),
+                            ApplyEditAnswer::Reject => Some(json!({
+                                "applied": false,
+                                "failureReason": "buffer is read-only"
+                            })),
+                            ApplyEditAnswer::Ignore => None,
+                        };
+                        if let Some(result) = applied {
+                            pending_requests.resolve(Response::new_ok(req.id.clone(), result));
+                        }
+                    }
+                }
+                if forward.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        Connection {
+            sender: client.sender,
+            receiver,
+        }
+    }
+
+    fn apply_edit_requests(messages: &[Message]) -> Vec<&Request> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Request(req) if req.method == "workspace/applyEdit" => Some(req),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Document text after applying the edit sent in an applyEdit request.
+    fn applied_text(text: &str, request: &Request) -> String {
+        let params: lsp_types::ApplyWorkspaceEditParams =
+            serde_json::from_value(request.params.clone()).unwrap();
+        crate::lsp_utils::apply_workspace_edit(text, &params.edit)
+    }
+
+    /// The label the agent gives `action` with the configured backend.
+    fn agent(action: &str) -> String {
+        format!("Agent ({}): {}", CURRENT_BACKEND.display_name(), action)
+    }
+
+    /// Label of an applyEdit request.
+    fn edit_label(request: &Request) -> String {
+        request.params["label"].as_str().unwrap().to_string()
+    }
+
+    /// Whether the edit sent in an applyEdit request asks the user for confirmation.
+    fn needs_confirmation(request: &Request) -> Option<bool> {
+        let params: lsp_types::ApplyWorkspaceEditParams =
+            serde_json::from_value(request.params.clone()).unwrap();
+        let annotations = params.edit.change_annotations?;
+        assert_eq!(annotations.len(), 1);
+        annotations.into_values().next().unwrap().needs_confirmation
+    }
+
+    fn job_completed_notifications(messages: &[Message]) -> Vec<JobCompletedParams> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) if n.method == MethodNames::default().job_completed => {
+                    Some(serde_json::from_value(n.params.clone()).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_successful_job_applies_edit() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edit_label(edits[0]), agent("implement fn foo"));
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Success);
+        assert!(completed[0].success);
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+    }
+
+    #[test]
+    fn test_edit_keeps_missing_trailing_newline() {
+        let text = "fn foo() {\n    todo!()\n}\n\nfn bar() {}";
+        let harness = Harness::new(
+            text,
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(0);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            applied_text(text, edits[0]),
+            "fn foo() {\n    42\n}\n\nfn bar() {}"
+        );
+    }
+
+    #[test]
+    fn test_set_output_shape_applies_body_only_result() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "42".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(&harness.methods.set_output_shape, json!(["BodyOnly"]));
+        assert_eq!(harness.session.output_shape(), Some(OutputShape::BodyOnly));
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            applied_text("fn foo() -> i32 {\n    todo!()\n}\n", edits[0]),
+            "fn foo() -> i32 {\n    42\n}\n"
+        );
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+    }
+
+    fn batch_completed_notifications(messages: &[Message]) -> Vec<BatchCompletedParams> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) if n.method == MethodNames::default().batch_completed => {
+                    Some(serde_json::from_value(n.params.clone()).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_impl_all_functions_reports_one_batch_summary() {
+        let harness = Harness::new(
+            "fn a() -> i32 {\n    todo!()\n}\n\nfn b() -> i32 {\n    todo!()\n}\n\n\
+             fn c() -> i32 {\n    todo!()\n}\n\nfn d() -> i32 {\n    1\n}\n",
+            MockBackend {
+                implementation: "42".to_string(),
+                delay: Duration::from_millis(50),
+            },
+            JobTracker::new(),
+        );
+        harness
+            .session
+            .set_output_shape(Some(OutputShape::BodyOnly));
+
+        harness.execute_command(
+            &harness.methods.impl_all_functions,
+            json!([harness.uri.to_string()]),
+        );
+        let messages = harness.collect_messages(Duration::from_millis(1000));
+
+        let result: ImplAllFunctionsResult = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Response(resp) => serde_json::from_value(resp.result.clone()?).ok(),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(result.job_ids.len(), 3);
+        assert_eq!(job_completed_notifications(&messages).len(), 3);
+
+        let batches = batch_completed_notifications(&messages);
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.batch_id, result.batch_id);
+        assert_eq!(batch.uri, harness.uri.to_string());
+        let summary: Vec<(&str, u32, bool)> = batch
+            .entries
+            .iter()
+            .map(|entry| (entry.signature.as_str(), entry.line, entry.success))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("fn a() -> i32 {", 0, true),
+                ("fn b() -> i32 {", 4, true),
+                ("fn c() -> i32 {", 8, true),
+            ]
+        );
+        assert!(batch.entries.iter().all(|entry| entry.error.is_none()));
+    }
+
+    #[test]
+    fn test_impl_all_functions_without_stubs_completes_empty_batch() {
+        let harness = Harness::new(
+            "fn d() -> i32 {\n    1\n}\n",
+            MockBackend {
+                implementation: "42".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(
+            &harness.methods.impl_all_functions,
+            json!([harness.uri.to_string()]),
+        );
+        let messages = harness.collect_messages(Duration::from_millis(200));
+
+        assert!(apply_edit_requests(&messages).is_empty());
+        let batches = batch_completed_notifications(&messages);
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].entries.is_empty());
+    }
+
+    #[test]
+    fn test_impl_all_functions_rejects_jobs_on_non_file_uri() {
+        let harness = Harness::new(
+            "",
+            MockBackend {
+                implementation: "42".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        let uri = Url::parse("untitled:stubs.rs").unwrap();
+        harness.document_store.open(
+            uri.clone(),
+            "fn a() -> i32 {\n    todo!()\n}\n".to_string(),
+            1,
+            "rust".to_string(),
+        );
+
+        harness.execute_command(
+            &harness.methods.impl_all_functions,
+            json!([uri.to_string()]),
+        );
+        let messages = harness.collect_messages(Duration::from_millis(200));
+
+        let result: ImplAllFunctionsResult = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Response(resp) => serde_json::from_value(resp.result.clone()?).ok(),
+                _ => None,
+            })
+            .unwrap();
+        assert!(result.job_ids.is_empty());
+        let batches = batch_completed_notifications(&messages);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].entries.len(), 1);
+        assert!(!batches[0].entries[0].success);
+        assert_eq!(
+            batches[0].entries[0].error.as_deref(),
+            Some("Invalid file URI")
+        );
+        // No queue slot is left behind
+        assert_eq!(harness.job_queue.pending_len(), 0);
+        assert_eq!(harness.job_queue.running_len(), 0);
+    }
+
+    const THREE_STUBS: &str =
+        "fn a() -> i32 {\n    todo!()\n}\n\nfn b() -> i32 {\n    todo!()\n}\n\n\
+         fn c() -> i32 {\n    todo!()\n}\n";
+
+    #[test]
+    fn test_jobs_finishing_together_share_one_edit() {
+        let harness = Harness::new(
+            THREE_STUBS,
+            MockBackend {
+                implementation: "42".to_string(),
+                delay: Duration::from_millis(20),
+            },
+            JobTracker::new(),
+        );
+        harness
+            .session
+            .set_output_shape(Some(OutputShape::BodyOnly));
+
+        for line in [1, 5, 9] {
+            harness.execute_impl_function(line);
+        }
+        let messages = harness.collect_messages(Duration::from_millis(1000));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edit_label(edits[0]), agent("implement 3 functions"));
+        assert_eq!(
+            applied_text(THREE_STUBS, edits[0]),
+            "fn a() -> i32 {\n    42\n}\n\nfn b() -> i32 {\n    42\n}\n\n\
+             fn c() -> i32 {\n    42\n}\n"
+        );
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 3);
+        for params in &completed {
+            assert_eq!(params.status, JobStatus::Success);
+            assert_eq!(params.edit_id.as_ref(), Some(&edits[0].id));
+        }
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+    }
+
+    #[test]
+    fn test_combined_edit_leaves_out_function_it_cannot_replace() {
+        let harness = Harness::new(
+            THREE_STUBS,
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        let group: Vec<PendingApply> = [("a", 0), ("missing", 99), ("c", 8)]
+            .into_iter()
+            .map(|(name, line)| {
+                let (outcome, _) = crossbeam_channel::bounded(1);
+                let request = RequestHandler::new(
+                    &harness.server,
+                    harness.document_store.clone(),
+                    harness.job_tracker.clone(),
+                    harness.job_queue.clone(),
+                    harness.backend.clone(),
+                    harness.session.clone(),
+                    harness.methods.clone(),
+                    harness.path_filter.clone(),
+                    harness.worker_pool.clone(),
+                    harness.pending_requests.clone(),
+                    harness.apply_coordinator.clone(),
+                    harness.completion_pool.clone(),
+                    harness.timer.clone(),
+                );
+                let job = request
+                    .new_job(
+                        name.to_string(),
+                        harness.uri.clone(),
+                        line,
+                        0,
+                        "rust".to_string(),
+                        format!("fn {}() -> i32 {{", name),
+                    )
+                    .unwrap();
+                PendingApply {
+                    job,
+                    implementation: format!("\"{}\".len() as i32", name),
+                    imports: Vec::new(),
+                    output_shape: OutputShape::BodyOnly,
+                    outcome,
+                }
+            })
+            .collect();
+
+        let combined =
+            prepare_combined_edit(&group, &harness.document_store, &harness.job_tracker).unwrap();
+
+        assert_eq!(combined.version, 1);
+        assert_eq!(combined.failures.len(), 1);
+        assert_eq!(combined.failures[0].0, 1);
+        // Bottom-up
+        assert_eq!(combined.splices, vec![(2, None), (0, None)]);
+        assert_eq!(
+            combined.new_text,
+            "fn a() -> i32 {\n    \"a\".len() as i32\n}\n\nfn b() -> i32 {\n    todo!()\n}\n\n\
+             fn c() -> i32 {\n    \"c\".len() as i32\n}\n"
+        );
+    }
+
+    #[cfg(feature = "test-hooks")]
+    #[test]
+    fn test_set_document_hook_reproduces_tricky_document() {
+        let harness = Harness::new(
+            "",
+            MockBackend {
+                implementation: "fn grüß() -> &'static str {\n    \"héllo\"\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        // CRLF line endings, non-ASCII text and no trailing newline
+        let text = "// Größe: ½\r\nfn grüß() -> &'static str {\r\n    todo!()\r\n}";
+        harness.execute_command(
+            &harness.methods.set_document,
+            json!([harness.uri.to_string(), text, 1, "rust"]),
+        );
+        let doc = harness.document_store.get(&harness.uri).unwrap();
+        assert_eq!(doc.text, text);
+        assert_eq!(doc.version, 1);
+
+        harness.execute_impl_function(2);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        let new_text = applied_text(text, edits[0]);
+        assert!(new_text
+            .starts_with("// Größe: ½\r\nfn grüß() -> &'static str {\r\n    \"héllo\"\r\n}"));
+        // The backend's LF output follows the document's CRLF line endings
+        assert!(!new_text.replace("\r\n", "").contains('\n'));
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+    }
+
+    /// Harness whose backend applies `change` (as version 2) right after producing its result.
+    fn editing_harness(text: &str, change: lsp_types::TextDocumentContentChangeEvent) -> Harness {
+        let mut harness = Harness::new(
+            text,
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(EditingBackend {
+            implementation: "fn foo() {\n    42\n}".to_string(),
+            document_store: harness.document_store.clone(),
+            uri: harness.uri.clone(),
+            change,
+        });
+        harness
+    }
+
+    #[test]
+    fn test_edit_targets_document_version_changed_before_apply() {
+        let harness = editing_harness(
+            "fn foo() {\n    todo!()\n}\n\nfn bar() {}\n",
+            lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range::new(
+                    lsp_types::Position::new(4, 10),
+                    lsp_types::Position::new(4, 10),
+                )),
+                range_length: None,
+                text: " 1 ".to_string(),
+            },
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        let params: lsp_types::ApplyWorkspaceEditParams =
+            serde_json::from_value(edits[0].params.clone()).unwrap();
+        let Some(lsp_types::DocumentChanges::Edits(document_edits)) = &params.edit.document_changes
+        else {
+            panic!("expected document edits");
+        };
+        assert_eq!(document_edits[0].text_document.version, Some(2));
+        let fresh_text = harness.document_store.get(&harness.uri).unwrap().text;
+        assert_eq!(fresh_text, "fn foo() {\n    todo!()\n}\n\nfn bar() { 1 }\n");
+        assert_eq!(
+            applied_text(&fresh_text, edits[0]),
+            "fn foo() {\n    42\n}\n\nfn bar() { 1 }\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_implementation_fails_job_with_raw_output() {
+        let raw = "Here is the implementation:\nfn foo() {\n    42\n";
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: raw.to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed[0].status, JobStatus::Failed);
+        assert_eq!(
+            completed[0].error.as_deref(),
+            Some("Implementation failed the prompt echo check: prose instead of code on line 1")
+        );
+        assert_eq!(completed[0].raw_output.as_deref(), Some(raw));
+        assert_eq!(
+            harness.document_store.get(&harness.uri).unwrap().text,
+            "fn foo() {\n    todo!()\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_function_removed_before_apply_fails_job() {
+        let harness = editing_harness(
+            "fn foo() {\n    todo!()\n}\n",
+            lsp_types::TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "// nothing left\n".to_string(),
+            },
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed[0].status, JobStatus::Failed);
+        let error = completed[0].error.as_deref().unwrap();
+        assert!(error.contains("document version 2"), "{}", error);
+    }
+
+    #[test]
+    fn test_supported_languages_command() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(&harness.methods.supported_languages, json!([]));
+        let result = match harness.client.receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(Message::Response(resp)) => resp.result.unwrap(),
+            other => panic!("Expected supported languages response, got {:?}", other),
+        };
+
+        let languages = result.as_array().unwrap();
+        assert_eq!(languages.len(), SUPPORTED_LANGUAGES.len());
+        let rust = languages
+            .iter()
+            .find(|language| language["language_id"] == "rust")
+            .unwrap();
+        assert_eq!(rust["detection"], "heuristic");
+        for language_id in ["python", "go", "cpp", "java", "typescript"] {
+            assert!(languages
+                .iter()
+                .any(|language| language["language_id"] == language_id));
+        }
+    }
+
+    #[test]
+    fn test_command_schema_command() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(&harness.methods.command_schema, json!([]));
+        let result = match harness.client.receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(Message::Response(resp)) => resp.result.unwrap(),
+            other => panic!("Expected command schema response, got {:?}", other),
+        };
+
+        let schemas = result.as_array().unwrap();
+        let impl_function = schemas
+            .iter()
+            .find(|schema| schema["command"] == "agent.implFunction")
+            .unwrap();
+        let required: Vec<(&str, &str)> = impl_function["arguments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|argument| argument["required"] == true)
+            .map(|argument| {
+                (
+                    argument["name"].as_str().unwrap(),
+                    argument["type"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            required,
+            vec![
+                ("uri", "string"),
+                ("line", "integer"),
+                ("character", "integer"),
+                ("version", "integer"),
+                ("language_id", "string"),
+            ]
+        );
+        assert_eq!(
+            impl_function["arguments"][6]["enum"],
+            json!(["interactive", "batch"])
+        );
+
+        // Every advertised command is described
+        let described: Vec<&str> = schemas
+            .iter()
+            .map(|schema| schema["command"].as_str().unwrap())
+            .collect();
+        for command in harness.methods.commands() {
+            assert!(described.contains(&command.as_str()), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_set_output_shape_auto_clears_override() {
+        let harness = Harness::new(
+            "fn foo() {}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(&harness.methods.set_output_shape, json!(["FullFunction"]));
+        assert_eq!(
+            harness.session.output_shape(),
+            Some(OutputShape::FullFunction)
+        );
+        harness.execute_command(&harness.methods.set_output_shape, json!(["Auto"]));
+        assert_eq!(harness.session.output_shape(), None);
+    }
+
+    #[test]
+    fn test_structured_output_inserts_imports_once() {
+        let harness = Harness::new(
+            "use std::fmt;\n\nfn foo() -> usize {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: json!({
+                    "implementation": "fn foo() -> usize {\n    HashMap::<u8, u8>::new().len()\n}",
+                    "imports": [
+                        "use std::collections::HashMap;",
+                        "use std::collections::HashMap;",
+                        "use std::fmt;"
+                    ],
+                })
+                .to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(2);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        let edit = applied_text(
+            "use std::fmt;\n\nfn foo() -> usize {\n    todo!()\n}\n",
+            edits[0],
+        );
+        assert_eq!(edit.matches("use std::collections::HashMap;").count(), 1);
+        assert_eq!(edit.matches("use std::fmt;").count(), 1);
+        assert!(edit.contains("HashMap::<u8, u8>::new().len()"));
+    }
+
+    #[test]
+    fn test_submissions_beyond_pending_cap_are_rejected() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(200),
+            },
+            JobTracker::new(),
+        );
+        harness.job_queue = Arc::new(JobQueue::new(1, 2));
+
+        // The first job takes the only run slot, the next two fill the queue
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(50));
+        harness.execute_impl_function(1);
+        harness.execute_impl_function(1);
+        harness.execute_impl_function(1);
+
+        let messages = harness.collect_messages(Duration::from_millis(1500));
+
+        let errors: Vec<String> = messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Response(resp) => resp.error.as_ref().map(|e| e.message.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("2 of 2 pending jobs"), "{}", errors[0]);
+
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 3);
+        assert!(completed.iter().all(|c| c.status == JobStatus::Success));
+    }
+
+    #[test]
+    fn test_existing_implementation_requires_force() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    41\n}\n",
+            MockBackend {
+                implementation: "fn foo() -> i32 {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(300));
+        let error = messages
+            .iter()
+            .find_map(|msg| match msg {
+                Message::Response(resp) => resp.error.as_ref(),
+                _ => None,
+            })
+            .expect("Expected the command to be refused");
+        assert!(error.message.contains("force"), "{}", error.message);
+        assert!(apply_edit_requests(&messages).is_empty());
+        assert!(job_completed_notifications(&messages).is_empty());
+
+        harness.execute_command(
+            &harness.methods.impl_function,
+            json!([harness.uri.to_string(), 1, 0, 1, "rust", null, null, true]),
+        );
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        // Overwriting the user's code is confirmed before it is applied
+        assert_eq!(needs_confirmation(edits[0]), Some(true));
+        assert_eq!(edit_label(edits[0]), agent("reimplement fn foo"));
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Success);
+    }
+
+    #[test]
+    fn test_impl_selection_rejects_malformed_arguments() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "42".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        let range = json!({
+            "start": { "line": 1, "character": 4 },
+            "end": { "line": 1, "character": 11 },
+        });
+
+        harness.execute_command(&harness.methods.impl_selection, json!(["not a uri", range]));
+        harness.execute_command(
+            &harness.methods.impl_selection,
+            json!([harness.uri.to_string(), { "start": 1 }]),
+        );
+        // Stored, but not a file the job could write to
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        harness.document_store.open(
+            untitled.clone(),
+            "fn foo() {\n    todo!()\n}\n".to_string(),
+            1,
+            "rust".to_string(),
+        );
+        harness.execute_command(
+            &harness.methods.impl_selection,
+            json!([untitled.to_string(), range]),
+        );
+
+        let messages = harness.collect_messages(Duration::from_millis(300));
+        let errors: Vec<_> = messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Response(resp) => resp.error.as_ref(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors.len(), 3);
+        assert!(errors
+            .iter()
+            .all(|e| e.code == lsp_server::ErrorCode::InvalidParams as i32));
+        assert!(
+            errors[0].message.contains("Invalid uri"),
+            "{}",
+            errors[0].message
+        );
+        assert!(
+            errors[1].message.contains("Invalid range"),
+            "{}",
+            errors[1].message
+        );
+        assert_eq!(errors[2].message, "Invalid file URI");
+        assert!(apply_edit_requests(&messages).is_empty());
+    }
+
+    #[test]
+    fn test_impl_function_rejects_invalid_uris() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() -> i32 {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(
+            &harness.methods.impl_function,
+            json!([
+                harness.uri.to_string(),
+                0,
+                0,
+                1,
+                "rust",
+                null,
+                null,
+                null,
+                null,
+                "not a uri"
+            ]),
+        );
+        // Stored, but not a file the job could write to
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        harness.document_store.open(
+            untitled.clone(),
+            "fn foo() -> i32 {\n    todo!()\n}\n".to_string(),
+            1,
+            "rust".to_string(),
+        );
+        harness.execute_command(
+            &harness.methods.impl_function,
+            json!([untitled.to_string(), 0, 0, 1, "rust"]),
+        );
+
+        let messages = harness.collect_messages(Duration::from_millis(300));
+        let errors: Vec<_> = messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Response(resp) => resp.error.as_ref(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|e| e.code == lsp_server::ErrorCode::InvalidParams as i32));
+        assert!(
+            errors[0].message.contains("Invalid target uri"),
+            "{}",
+            errors[0].message
+        );
+        assert_eq!(errors[1].message, "Invalid file URI");
+        assert!(apply_edit_requests(&messages).is_empty());
+        assert!(job_completed_notifications(&messages).is_empty());
+    }
+
+    #[test]
+    fn test_identical_implementation_sends_no_edit() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    42\n}\n",
+            MockBackend {
+                // Only trailing whitespace differs
+                implementation: "fn foo() -> i32 {  \n    42\n}\n".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_command(
+            &harness.methods.impl_function,
+            json!([harness.uri.to_string(), 1, 0, 1, "rust", null, null, true]),
+        );
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Success);
+        assert!(completed[0].no_change);
+    }
+
+    #[test]
+    fn test_job_succeeds_once_client_applies_edit() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() -> i32 {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        assert_eq!(apply_edit_requests(&messages).len(), 1);
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Success);
+        assert!(completed[0].error.is_none());
+        assert!(!completed[0].no_change);
+    }
+
+    #[test]
+    fn test_job_fails_when_client_rejects_edit() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() -> i32 {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.answer_apply_edits_with(ApplyEditAnswer::Reject);
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        assert_eq!(apply_edit_requests(&messages).len(), 1);
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Failed);
+        let error = completed[0].error.as_deref().unwrap();
+        assert!(error.contains("buffer is read-only"), "{}", error);
+    }
+
+    #[test]
+    fn test_unanswered_edit_completes_with_unknown_status() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() -> i32 {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.answer_apply_edits_with(ApplyEditAnswer::Ignore);
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(APPLY_EDIT_TIMEOUT_MS / 2));
+        assert_eq!(apply_edit_requests(&messages).len(), 1);
+        assert!(job_completed_notifications(&messages).is_empty());
+
+        let messages = harness.collect_messages(Duration::from_millis(APPLY_EDIT_TIMEOUT_MS));
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Unknown);
+        assert!(!completed[0].success);
+        assert!(completed[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("may not have been applied"));
+    }
+
+    #[test]
+    fn test_placeholder_implementation_needs_no_confirmation() {
+        let harness = Harness::new(
+            "fn foo() -> i32 {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() -> i32 {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(needs_confirmation(edits[0]), Some(false));
+        assert_eq!(
+            applied_text("fn foo() -> i32 {\n    todo!()\n}\n", edits[0]),
+            "fn foo() -> i32 {\n    42\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_excluded_file_gets_no_actions_and_rejects_commands() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.path_filter = Arc::new(PathFilter::new(None, &[], &["**/lib.rs"]));
+
+        harness.request(
+            3,
+            CodeActionRequest::METHOD,
+            json!({
+                "textDocument": { "uri": harness.uri.to_string() },
+                "range": {
+                    "start": { "line": 1, "character": 0 },
+                    "end": { "line": 1, "character": 0 }
+                },
+                "context": { "diagnostics": [] }
+            }),
+        );
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(300));
+
+        let responses: Vec<&Response> = messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Response(resp) => Some(resp),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].result, Some(json!([])));
+        let error = responses[1].error.as_ref().unwrap();
+        assert!(error.message.contains("excluded"), "{}", error.message);
+        assert!(apply_edit_requests(&messages).is_empty());
+        assert!(job_completed_notifications(&messages).is_empty());
+    }
+
+    #[test]
+    fn test_many_jobs_reuse_bounded_worker_threads() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(50),
+            },
+            JobTracker::new(),
+        );
+        harness.job_queue = Arc::new(JobQueue::new(2, MAX_PENDING_JOBS));
+        harness.worker_pool = Arc::new(WorkerPool::new(3));
+
+        for _ in 0..8 {
+            harness.execute_impl_function(1);
+        }
+        let messages = harness.collect_messages(Duration::from_millis(1500));
+
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 8);
+        assert!(completed.iter().all(|c| c.status == JobStatus::Success));
+        assert!(harness.worker_pool.spawned_threads() <= 3);
+    }
+
+    #[test]
+    fn test_rate_limit_spaces_out_backend_runs() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
         );
-        Ok(())
+        // One backend run every 150 ms
+        harness.job_queue = Arc::new(
+            JobQueue::new(MAX_RUNNING_JOBS, MAX_PENDING_JOBS).with_rate_limit(Some(RateLimit {
+                requests_per_minute: 400,
+                burst: 1,
+            })),
+        );
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            harness.execute_impl_function(1);
+        }
+        // When each job's backend run started, from its first progress with a preview
+        let mut started_at = Vec::new();
+        let mut status = None;
+        while let Ok(msg) = harness.client.receiver.recv_timeout(Duration::from_secs(1)) {
+            match msg {
+                Message::Notification(n) if n.method == harness.methods.impl_function_progress => {
+                    let progress: ImplFunctionProgressParams =
+                        serde_json::from_value(n.params).unwrap();
+                    if progress.preview.is_empty() {
+                        continue;
+                    }
+                    started_at.push(start.elapsed());
+                    if started_at.len() == 1 {
+                        harness.execute_command(&harness.methods.status, json!([]));
+                    }
+                }
+                Message::Response(resp) => {
+                    status =
+                        status.or(resp.result.filter(|result| result.get("backend").is_some()));
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(started_at.len(), 3);
+        for pair in started_at.windows(2) {
+            assert!(
+                pair[1] - pair[0] >= Duration::from_millis(130),
+                "{:?}",
+                started_at
+            );
+        }
+        // Asked once the first run started: the others wait for a token
+        let status: ServerStatus = serde_json::from_value(status.unwrap()).unwrap();
+        let rate_limit = status.rate_limit.unwrap();
+        assert_eq!(rate_limit.requests_per_minute, 400);
+        assert_eq!(rate_limit.available, 0);
+        assert!(rate_limit.next_token_ms <= 150, "{:?}", rate_limit);
+    }
+
+    #[test]
+    fn test_status_lists_jobs_with_their_states() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n\nfn bar() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(300),
+            },
+            JobTracker::new(),
+        );
+        // One run slot: the second job waits behind the first
+        harness.job_queue = Arc::new(JobQueue::new(1, MAX_PENDING_JOBS));
+
+        let status = |harness: &Harness| -> ServerStatus {
+            harness.execute_command(&harness.methods.status, json!([]));
+            loop {
+                match harness.client.receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(Message::Response(resp)) => match resp.result {
+                        Some(result) if result.get("backend").is_some() => {
+                            return serde_json::from_value(result).unwrap();
+                        }
+                        _ => {}
+                    },
+                    Ok(_) => {}
+                    Err(e) => panic!("No status response: {}", e),
+                }
+            }
+        };
+
+        harness.execute_impl_function(1);
+        harness.execute_impl_function(5);
+        thread::sleep(Duration::from_millis(100));
+        let jobs = status(&harness).jobs;
+        let states: Vec<(u32, JobState)> = jobs.iter().map(|job| (job.line, job.state)).collect();
+        assert_eq!(states, vec![(1, JobState::Running), (5, JobState::Queued)]);
+        assert!(jobs.iter().all(|job| job.uri == harness.uri.as_str()));
+
+        harness.collect_messages(Duration::from_millis(1000));
+        assert!(status(&harness).jobs.is_empty());
+    }
+
+    #[test]
+    fn test_recorded_transcript_for_single_implementation() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        let transcript_path = harness._dir.path().join("transcript.jsonl");
+        harness.server.sender =
+            crate::recorder::record_outgoing(harness.server.sender.clone(), &transcript_path)
+                .unwrap();
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        assert_eq!(messages.len(), 4);
+
+        let sequence: Vec<String> = crate::recorder::read_transcript(&transcript_path)
+            .into_iter()
+            .map(|msg| match msg {
+                Message::Request(req) => format!("request {}", req.method),
+                Message::Response(resp) => format!("response {}", resp.id),
+                Message::Notification(notification) => {
+                    format!("notification {}", notification.method)
+                }
+            })
+            .collect();
+        assert_eq!(
+            sequence,
+            vec![
+                "response 1".to_string(),
+                format!("notification {}", harness.methods.impl_function_progress),
+                "request workspace/applyEdit".to_string(),
+                format!("notification {}", harness.methods.job_completed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timed_out_job_discards_late_result() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_secs(2),
+            },
+            JobTracker::with_job_timeout(Duration::from_secs(1)),
+        );
+
+        harness.execute_impl_function(1);
+
+        // The slot is released as soon as the timeout fires, before the backend returns
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+
+        let messages = harness.collect_messages(Duration::from_secs(2));
+        assert!(apply_edit_requests(&messages).is_empty());
+
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Timeout);
+        assert!(!completed[0].success);
+        assert!(completed[0].error.as_deref().unwrap().contains("timed out"));
+    }
+
+    #[test]
+    fn test_panicking_backend_fails_job_and_frees_slot() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        // A single run slot: if the panicking job leaked it, the next job could never start
+        harness.job_queue = Arc::new(JobQueue::new(1, MAX_PENDING_JOBS));
+        let good_backend = std::mem::replace(&mut harness.backend, Arc::new(PanickingBackend));
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Failed);
+        assert_eq!(completed[0].error.as_deref(), Some(WORKER_PANIC_ERROR));
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+        assert_eq!(harness.job_queue.pending_len(), 0);
+
+        harness.backend = good_backend;
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Success);
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+    }
+
+    #[test]
+    fn test_missing_backend_cli_fails_command_fast() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(MissingCliBackend);
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(300));
+
+        let failed: Vec<JobFailedParams> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) if n.method == harness.methods.job_failed => {
+                    Some(serde_json::from_value(n.params.clone()).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].code, JobFailureCode::BackendNotFound);
+        assert!(failed[0].error.contains("agent-lsp-missing-cli"));
+
+        let error = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Response(resp) => resp.error.as_ref(),
+                _ => None,
+            })
+            .expect("command should fail");
+        assert_eq!(error.code, lsp_server::ErrorCode::RequestFailed as i32);
+        assert_eq!(error.message, failed[0].error);
+
+        // No worker was started
+        assert!(job_completed_notifications(&messages).is_empty());
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+        assert_eq!(harness.job_queue.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_did_change_adjusts_job_lines_in_change_order() {
+        let harness = Harness::new(
+            "a\nb\nc\nd\ne\nfn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness
+            .job_tracker
+            .register_job(
+                &harness.uri,
+                "job1",
+                5,
+                FunctionSignature::parse("fn foo() {", "rust"),
+            )
+            .unwrap();
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: DidChangeTextDocument::METHOD.to_string(),
+                params: json!({
+                    "textDocument": { "uri": harness.uri.to_string(), "version": 2 },
+                    "contentChanges": [
+                        {
+                            "range": {
+                                "start": { "line": 0, "character": 0 },
+                                "end": { "line": 0, "character": 0 }
+                            },
+                            "text": "x\ny\nz\n"
+                        },
+                        {
+                            "range": {
+                                "start": { "line": 6, "character": 0 },
+                                "end": { "line": 8, "character": 0 }
+                            },
+                            "text": ""
+                        }
+                    ]
+                }),
+            })
+            .unwrap();
+
+        // +3 lines above, then the two lines right above the function are deleted
+        assert_eq!(harness.job_tracker.get_current_line("job1"), Some(6));
+    }
+
+    /// The `didChange` a client sends after applying the edit of an applyEdit request: its
+    /// text edits bottom-up, each relative to the text the previous one left.
+    fn echo_did_change(request: &Request, version: i32) -> Notification {
+        let params: lsp_types::ApplyWorkspaceEditParams =
+            serde_json::from_value(request.params.clone()).unwrap();
+        let Some(lsp_types::DocumentChanges::Edits(document_edits)) = params.edit.document_changes
+        else {
+            panic!("Expected document edits");
+        };
+        let uri = document_edits[0].text_document.uri.clone();
+        let mut edits: Vec<lsp_types::TextEdit> = document_edits
+            .into_iter()
+            .flat_map(|document_edit| document_edit.edits)
+            .map(|edit| match edit {
+                lsp_types::OneOf::Left(edit) => edit,
+                lsp_types::OneOf::Right(annotated) => annotated.text_edit,
+            })
+            .collect();
+        edits.sort_by_key(|edit| {
+            std::cmp::Reverse((edit.range.start.line, edit.range.start.character))
+        });
+        let content_changes: Vec<_> = edits
+            .into_iter()
+            .map(|edit| json!({ "range": edit.range, "text": edit.new_text }))
+            .collect();
+        Notification {
+            method: DidChangeTextDocument::METHOD.to_string(),
+            params: json!({
+                "textDocument": { "uri": uri, "version": version },
+                "contentChanges": content_changes
+            }),
+        }
+    }
+
+    #[test]
+    fn test_agent_edit_moves_other_jobs_once_when_echoed() {
+        let text = "fn foo() {\n    todo!()\n}\n\n// bar\nfn bar() {\n    todo!()\n}\n";
+        let harness = Harness::new(
+            text,
+            MockBackend {
+                implementation: "fn foo() {\n    let a = 1;\n    let b = 2;\n    a + b\n}"
+                    .to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        // A job known only by its line, which re-anchoring by signature cannot repair
+        harness
+            .job_tracker
+            .register_job(
+                &harness.uri,
+                "job2",
+                4,
+                FunctionSignature::parse("line_4", "rust"),
+            )
+            .unwrap();
+
+        harness.execute_impl_function(0);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+        // Nothing moves until the client reports the edit
+        assert_eq!(harness.job_tracker.get_current_line("job2"), Some(4));
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler.handle(&echo_did_change(edits[0], 2)).unwrap();
+
+        assert_eq!(
+            harness.document_store.get(&harness.uri).unwrap().text,
+            applied_text(text, edits[0])
+        );
+        assert_eq!(harness.job_tracker.get_current_line("job2"), Some(6));
+        let moved: Vec<ImplFunctionProgressParams> = harness
+            .collect_messages(Duration::from_millis(100))
+            .into_iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) if n.method == harness.methods.impl_function_progress => {
+                    serde_json::from_value(n.params).ok()
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(moved.len(), 1);
+        assert_eq!((moved[0].job_id.as_str(), moved[0].line), ("job2", 6));
+    }
+
+    #[test]
+    fn test_renaming_target_function_fails_running_job() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(300),
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(100));
+
+        // The user renames the function while the backend is still working
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: DidChangeTextDocument::METHOD.to_string(),
+                params: json!({
+                    "textDocument": { "uri": harness.uri.to_string(), "version": 2 },
+                    "contentChanges": [{
+                        "range": {
+                            "start": { "line": 0, "character": 3 },
+                            "end": { "line": 0, "character": 6 }
+                        },
+                        "text": "renamed"
+                    }]
+                }),
+            })
+            .unwrap();
+
+        let messages = harness.collect_messages(Duration::from_millis(800));
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Failed);
+        assert_eq!(completed[0].error.as_deref(), Some(ORPHANED_JOB_ERROR));
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+    }
+
+    /// Run a job on `foo` whose body the user rewrites while the backend is working, merging
+    /// by `strategy`; returns the messages the client got.
+    fn run_job_with_conflicting_user_edit(strategy: &str) -> (Harness, Vec<Message>) {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n\nfn bar() {}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    agent_change();\n}".to_string(),
+                delay: Duration::from_millis(300),
+            },
+            JobTracker::new(),
+        );
+        harness.session.set_client_capabilities(&json!({
+            "initializationOptions": { "merge_strategy": strategy }
+        }));
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(100));
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: DidChangeTextDocument::METHOD.to_string(),
+                params: json!({
+                    "textDocument": { "uri": harness.uri.to_string(), "version": 2 },
+                    "contentChanges": [
+                        {
+                            "range": {
+                                "start": { "line": 4, "character": 10 },
+                                "end": { "line": 4, "character": 10 }
+                            },
+                            "text": " bar_change(); "
+                        },
+                        {
+                            "range": {
+                                "start": { "line": 1, "character": 4 },
+                                "end": { "line": 1, "character": 11 }
+                            },
+                            "text": "user_change();"
+                        }
+                    ]
+                }),
+            })
+            .unwrap();
+
+        let messages = harness.collect_messages(Duration::from_millis(800));
+        (harness, messages)
+    }
+
+    const USER_TEXT: &str = "fn foo() {\n    user_change();\n}\n\nfn bar() { bar_change(); }\n";
+
+    #[test]
+    fn test_user_edit_to_function_is_merged_with_markers() {
+        let (_harness, messages) = run_job_with_conflicting_user_edit("markers");
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        let merged = applied_text(USER_TEXT, edits[0]);
+        assert!(merged.starts_with("fn foo() {\n<<<<<<<"), "{}", merged);
+        assert!(merged.contains("    user_change();\n"));
+        assert!(merged.contains("    agent_change();\n"));
+        assert!(merged.ends_with("fn bar() { bar_change(); }\n"));
+        assert_eq!(
+            edit_label(edits[0]),
+            agent(&format!("{} fn foo", EditKind::Conflict.action()))
+        );
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+    }
+
+    #[test]
+    fn test_user_edit_to_function_aborts_with_both_versions() {
+        let (harness, messages) = run_job_with_conflicting_user_edit("abort");
+
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Failed);
+        assert_eq!(completed[0].error.as_deref(), Some(MERGE_CONFLICT_ERROR));
+        assert_eq!(
+            completed[0].conflict,
+            Some(MergeConflict {
+                current: USER_TEXT.to_string(),
+                agent: "fn foo() {\n    agent_change();\n}\n\nfn bar() {}\n".to_string(),
+            })
+        );
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+    }
+
+    #[test]
+    fn test_user_edit_to_function_is_replaced_by_theirs() {
+        let (_harness, messages) = run_job_with_conflicting_user_edit("theirs");
+
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        // The agent's function wins; the user's edit elsewhere stays
+        assert_eq!(
+            applied_text(USER_TEXT, edits[0]),
+            "fn foo() {\n    agent_change();\n}\n\nfn bar() { bar_change(); }\n"
+        );
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+    }
+
+    #[test]
+    fn test_merged_job_reports_theirs_path() {
+        let (_harness, messages) = run_job_with_conflicting_user_edit("markers");
+
+        let completed = job_completed_notifications(&messages);
+        let theirs_path = completed[0]
+            .theirs_path
+            .as_deref()
+            .expect("merged job should report its theirs file");
+        assert_eq!(
+            std::fs::read_to_string(theirs_path).unwrap(),
+            "fn foo() {\n    agent_change();\n}\n\nfn bar() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_unmerged_job_reports_no_theirs_path() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(0),
+            },
+            JobTracker::new(),
+        );
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed[0].status, JobStatus::Success);
+        assert_eq!(completed[0].theirs_path, None);
+    }
+
+    #[test]
+    fn test_client_signature_re_anchors_job_after_lines_shift() {
+        let harness = Harness::new(
+            "// helper\nfn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(300),
+            },
+            JobTracker::new(),
+        );
+
+        // On the comment line the server finds no signature of its own
+        let uri = harness.uri.to_string();
+        harness.execute_command(
+            &harness.methods.impl_function,
+            json!([uri, 0, 0, 1, "rust", null, null, false, "fn foo() {"]),
+        );
+        thread::sleep(Duration::from_millis(100));
+        let (job_id, _) = harness.job_tracker.get_active_jobs(&harness.uri)[0].clone();
+        let signature = harness.job_tracker.get_function_signature(&job_id);
+        assert_eq!(
+            signature.map(|signature| signature.raw),
+            Some("fn foo() {".to_string())
+        );
+
+        // Lines inserted above the job while the backend is working
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: DidChangeTextDocument::METHOD.to_string(),
+                params: json!({
+                    "textDocument": { "uri": harness.uri.to_string(), "version": 2 },
+                    "contentChanges": [{
+                        "range": {
+                            "start": { "line": 0, "character": 0 },
+                            "end": { "line": 0, "character": 0 }
+                        },
+                        "text": "use std::fmt;\n\n"
+                    }]
+                }),
+            })
+            .unwrap();
+        assert_eq!(harness.job_tracker.get_current_line(&job_id), Some(3));
+
+        let messages = harness.collect_messages(Duration::from_millis(800));
+        let edits = apply_edit_requests(&messages);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            applied_text(
+                "use std::fmt;\n\n// helper\nfn foo() {\n    todo!()\n}\n",
+                edits[0]
+            ),
+            "use std::fmt;\n\n// helper\nfn foo() {\n    42\n}\n"
+        );
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+    }
+
+    #[test]
+    fn test_did_close_cancels_running_job() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(500),
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(100));
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: DidCloseTextDocument::METHOD.to_string(),
+                params: json!({ "textDocument": { "uri": harness.uri.to_string() } }),
+            })
+            .unwrap();
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+
+        let messages = harness.collect_messages(Duration::from_secs(1));
+        assert!(apply_edit_requests(&messages).is_empty());
+
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_reopen_with_new_text_cancels_running_job() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(500),
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(100));
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        let did_open = |text: &str, version: i32| {
+            handler
+                .handle(&Notification {
+                    method: DidOpenTextDocument::METHOD.to_string(),
+                    params: json!({ "textDocument": {
+                        "uri": harness.uri.to_string(),
+                        "languageId": "rust",
+                        "version": version,
+                        "text": text,
+                    } }),
+                })
+                .unwrap();
+        };
+        // An older version is ignored and the job keeps running
+        did_open("// stale\n", 0);
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 1);
+        assert_eq!(harness.document_store.get(&harness.uri).unwrap().version, 1);
+
+        did_open("fn bar() {}\n", 2);
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+
+        let messages = harness.collect_messages(Duration::from_secs(1));
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Cancelled);
+        assert_eq!(
+            completed[0].error.as_deref(),
+            Some("Document was re-opened with different text")
+        );
+    }
+
+    #[test]
+    fn test_work_done_progress_cancel_cancels_job() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(500),
+            },
+            JobTracker::new(),
+        );
+        harness.session.set_client_capabilities(&json!({
+            "capabilities": { "window": { "workDoneProgress": true } }
+        }));
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(100));
+        let create = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Request(req) if req.method == "window/workDoneProgress/create" => {
+                    Some(req)
+                }
+                _ => None,
+            })
+            .expect("progress token should be created");
+        let token = create.params["token"].clone();
+        let begin = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Notification(n) if n.method == "$/progress" => Some(&n.params),
+                _ => None,
+            })
+            .expect("progress should begin");
+        assert_eq!(begin["token"], token);
+        assert_eq!(begin["value"]["kind"], "begin");
+        assert_eq!(begin["value"]["cancellable"], true);
+        assert_eq!(begin["value"]["title"], agent("implement fn foo"));
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: "window/workDoneProgress/cancel".to_string(),
+                params: json!({ "token": token }),
+            })
+            .unwrap();
+        assert_eq!(harness.job_tracker.active_job_count(&harness.uri), 0);
+
+        let messages = harness.collect_messages(Duration::from_secs(1));
+        assert!(apply_edit_requests(&messages).is_empty());
+        let completed = job_completed_notifications(&messages);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].status, JobStatus::Cancelled);
+        assert_eq!(json!(completed[0].job_id), token);
+        assert!(messages.iter().any(|m| matches!(
+            m,
+            Message::Notification(n)
+                if n.method == "$/progress"
+                    && n.params["token"] == token
+                    && n.params["value"]["kind"] == "end"
+        )));
+    }
+
+    #[test]
+    fn test_no_progress_after_cancellation() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(ChunkedBackend {
+            chunks: 20,
+            interval: Duration::from_millis(20),
+            streamed: Arc::new(AtomicUsize::new(0)),
+        });
+
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(150));
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: DidCloseTextDocument::METHOD.to_string(),
+                params: json!({ "textDocument": { "uri": harness.uri.to_string() } }),
+            })
+            .unwrap();
+
+        // Wait past the backend's last chunk
+        let messages = harness.collect_messages(Duration::from_millis(600));
+        let cancelled_at = messages
+            .iter()
+            .position(|m| {
+                matches!(m, Message::Notification(n) if n.method == harness.methods.job_completed)
+            })
+            .expect("job should be reported cancelled");
+        let is_preview = |m: &Message| {
+            matches!(m, Message::Notification(n)
+                if n.method == harness.methods.impl_function_progress
+                    && n.params["preview"].as_str().is_some_and(|p| p.starts_with("chunk")))
+        };
+        assert!(messages[..cancelled_at].iter().any(is_preview));
+        assert!(!messages[cancelled_at..].iter().any(is_preview));
+        assert_eq!(job_completed_notifications(&messages).len(), 1);
+    }
+
+    #[test]
+    fn test_progress_reports_region_to_replace() {
+        let harness = Harness::new(
+            THREE_STUBS,
+            MockBackend {
+                implementation: "fn b() -> i32 {\n    2\n}".to_string(),
+                delay: Duration::from_millis(20),
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(5);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        let preview = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Notification(n)
+                    if n.method == harness.methods.impl_function_progress
+                        && n.params["preview"] == "working" =>
+                {
+                    Some(&n.params)
+                }
+                _ => None,
+            })
+            .expect("backend progress should be reported");
+        assert_eq!(preview["line"], 5);
+        assert_eq!(preview["start_line"], 4);
+        assert_eq!(preview["end_line"], 6);
+    }
+
+    #[test]
+    fn test_worker_stops_when_client_disconnects() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        let streamed = Arc::new(AtomicUsize::new(0));
+        harness.backend = Arc::new(ChunkedBackend {
+            chunks: 50,
+            interval: Duration::from_millis(20),
+            streamed: streamed.clone(),
+        });
+
+        harness.execute_impl_function(1);
+        let job_id = harness
+            .collect_messages(Duration::from_millis(150))
+            .iter()
+            .find_map(|m| match m {
+                Message::Notification(n) if n.method == harness.methods.impl_function_progress => {
+                    n.params["job_id"].as_str().map(str::to_string)
+                }
+                _ => None,
+            })
+            .expect("job should report progress");
+
+        // The client goes away mid-stream
+        harness.client.receiver = crossbeam_channel::never();
+
+        let deadline = Instant::now() + Duration::from_millis(300);
+        while harness.job_tracker.job_state(&job_id) != Some(JobState::Cancelled) {
+            assert!(Instant::now() < deadline, "job should be cancelled");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let streamed_at_cancel = streamed.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(200));
+        assert!(streamed.load(Ordering::SeqCst) <= streamed_at_cancel + 1);
+        assert!(streamed.load(Ordering::SeqCst) < 50);
+    }
+
+    #[test]
+    fn test_progress_preview_keeps_tail_of_long_output() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(AccumulatingBackend { lines: 300 });
+
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        let previews: Vec<_> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) if n.method == harness.methods.impl_function_progress => {
+                    Some(&n.params)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(previews.len(), 300);
+
+        let first = previews[0];
+        assert_eq!(first["preview"], "    let x0 = 0;\n");
+        assert_eq!(first["truncated"], false);
+        let last = previews[299];
+        let preview = last["preview"].as_str().unwrap();
+        assert_eq!(preview.chars().count(), PREVIEW_MAX_CHARS);
+        assert!(preview.ends_with("    let x299 = 299;\n"));
+        assert_eq!(last["truncated"], true);
+    }
+
+    #[test]
+    fn test_job_status_reports_running_then_finished_job() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(300),
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(100));
+
+        let (job_id, _) = harness.job_tracker.get_active_jobs(&harness.uri)[0].clone();
+        let status = harness.job_status(&job_id);
+        assert_eq!(status["found"], true);
+        assert_eq!(status["job_id"], job_id.as_str());
+        assert_eq!(status["state"], "running");
+        assert_eq!(status["states"], json!(["queued", "running"]));
+        assert_eq!(status["uri"], harness.uri.as_str());
+        assert_eq!(status["function_signature"], "fn foo() {");
+        assert_eq!(status["current_line"], 1);
+        assert_eq!(status["backend"], CURRENT_BACKEND.display_name());
+        assert_eq!(status["preview"], "working");
+        assert!(status.get("error").is_none());
+
+        harness.collect_messages(Duration::from_millis(500));
+        let status = harness.job_status(&job_id);
+        assert_eq!(status["found"], true);
+        assert_eq!(status["state"], "done");
+        assert_eq!(
+            status["states"],
+            json!(["queued", "running", "applying", "done"])
+        );
+        assert!(status["elapsed_ms"].as_u64().unwrap() >= 300);
+
+        let status = harness.job_status("unknown");
+        assert_eq!(status, json!({ "found": false }));
+    }
+
+    /// Send a completion request at the start of line 1 and return its result.
+    fn request_completion(harness: &Harness) -> serde_json::Value {
+        send_completion_request(harness);
+        receive_completion(harness)
+    }
+
+    fn send_completion_request(harness: &Harness) {
+        harness.request(
+            3,
+            Completion::METHOD,
+            json!({
+                "textDocument": { "uri": harness.uri.to_string() },
+                "position": { "line": 1, "character": 0 },
+            }),
+        );
+    }
+
+    fn receive_completion(harness: &Harness) -> serde_json::Value {
+        match harness.client.receiver.recv_timeout(Duration::from_secs(2)) {
+            Ok(Message::Response(resp)) => resp.result.unwrap(),
+            other => panic!("Expected completion response, got {:?}", other),
+        }
+    }
+
+    fn enable_ai_completion(harness: &Harness) {
+        harness.session.set_client_capabilities(&json!({
+            "initializationOptions": { "completion_mode": "ai_inline" }
+        }));
+    }
+
+    #[test]
+    fn test_slow_completion_returns_null_within_deadline() {
+        let mut harness = Harness::new(
+            "fn foo() {\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(CompletionBackend {
+            completion: "    42",
+            delay: Duration::from_secs(5),
+        });
+        enable_ai_completion(&harness);
+
+        let started = Instant::now();
+        send_completion_request(&harness);
+        // The message loop is free while the backend works
+        assert!(started.elapsed() < Duration::from_millis(100));
+        let result = receive_completion(&harness);
+
+        assert_eq!(result, serde_json::Value::Null);
+        let deadline = Duration::from_millis(COMPLETION_TIMEOUT_MS);
+        assert!(started.elapsed() >= deadline);
+        assert!(started.elapsed() < deadline + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_completion_deadline_holds_while_completion_threads_are_busy() {
+        let mut harness = Harness::new(
+            "fn foo() {\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(CompletionBackend {
+            completion: "    42",
+            delay: Duration::ZERO,
+        });
+        harness.completion_pool = Arc::new(WorkerPool::new(1));
+        let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+        harness.completion_pool.execute(move || {
+            let _ = release_rx.recv_timeout(Duration::from_secs(5));
+        });
+        enable_ai_completion(&harness);
+
+        let started = Instant::now();
+        let result = request_completion(&harness);
+
+        assert_eq!(result, serde_json::Value::Null);
+        let deadline = Duration::from_millis(COMPLETION_TIMEOUT_MS);
+        assert!(started.elapsed() < deadline + Duration::from_millis(500));
+        // Completions never take an implementation worker
+        assert_eq!(harness.worker_pool.spawned_threads(), 0);
+        drop(release_tx);
+    }
+
+    #[test]
+    fn test_fast_completion_returns_item() {
+        let mut harness = Harness::new(
+            "fn foo() {\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(CompletionBackend {
+            completion: "    42",
+            delay: Duration::ZERO,
+        });
+        enable_ai_completion(&harness);
+
+        let result = request_completion(&harness);
+
+        assert_eq!(result[0]["label"], "42");
+        assert_eq!(result[0]["insertText"], "    42");
+    }
+
+    #[test]
+    fn test_completion_asks_backend_only_in_ai_inline_mode() {
+        let mut harness = Harness::new(
+            "fn foo() {\n}\n",
+            MockBackend {
+                implementation: String::new(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+        harness.backend = Arc::new(CompletionBackend {
+            completion: "    42",
+            delay: Duration::ZERO,
+        });
+
+        // Clients that ignore the capabilities still get an answer
+        for mode in ["off", "disabled"] {
+            harness.session.set_client_capabilities(&json!({
+                "initializationOptions": { "completion_mode": mode }
+            }));
+            assert_eq!(
+                request_completion(&harness),
+                serde_json::Value::Null,
+                "{}",
+                mode
+            );
+        }
+
+        enable_ai_completion(&harness);
+        assert_eq!(request_completion(&harness)[0]["label"], "42");
+    }
+
+    #[test]
+    fn test_job_stats_counts_finished_and_rejected_jobs() {
+        let mut harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(100),
+            },
+            JobTracker::new(),
+        );
+        harness.job_queue = Arc::new(JobQueue::new(1, 1));
+
+        // One job runs, one waits and the third is turned away
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(20));
+        harness.execute_impl_function(1);
+        harness.execute_impl_function(1);
+        harness.collect_messages(Duration::from_millis(600));
+
+        harness.request(4, &harness.methods.job_stats, serde_json::Value::Null);
+        let result = match harness.client.receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(Message::Response(resp)) => resp.result.unwrap(),
+            other => panic!("Expected job stats response, got {:?}", other),
+        };
+        let result: JobStatsResult = serde_json::from_value(result).unwrap();
+
+        assert_eq!(result.stats.total, 3);
+        assert_eq!(result.stats.by_outcome[&JobOutcome::Done], 2);
+        assert_eq!(result.stats.by_outcome[&JobOutcome::Rejected], 1);
+        assert_eq!(result.stats.success_rate, 1.0);
+        assert_eq!(
+            result.stats.by_backend[CURRENT_BACKEND.display_name()].total,
+            2
+        );
+        assert!(result.stats.p50_duration_ms >= 100);
+        // The rejection happened before either job finished, so it is the oldest entry
+        assert_eq!(result.recent.len(), 3);
+        assert_eq!(result.recent[2].outcome, JobOutcome::Rejected);
+    }
+
+    #[test]
+    fn test_stale_version_sends_version_mismatch() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::ZERO,
+            },
+            JobTracker::new(),
+        );
+
+        // The harness opened the document at version 1
+        harness.execute_command(
+            &harness.methods.impl_function,
+            json!([harness.uri.to_string(), 1, 0, 0, "rust"]),
+        );
+        let messages = harness.collect_messages(Duration::from_millis(500));
+
+        let mismatches: Vec<VersionMismatchParams> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) if n.method == harness.methods.version_mismatch => {
+                    Some(serde_json::from_value(n.params.clone()).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].uri, harness.uri.to_string());
+        assert_eq!(mismatches[0].requested_version, 0);
+        assert_eq!(mismatches[0].actual_version, 1);
+
+        // The job still runs against the latest text
+        assert_eq!(
+            job_completed_notifications(&messages)[0].status,
+            JobStatus::Success
+        );
+
+        // A current version sends nothing
+        harness.execute_impl_function(1);
+        let messages = harness.collect_messages(Duration::from_millis(500));
+        assert!(!messages.iter().any(|m| matches!(
+            m,
+            Message::Notification(n) if n.method == harness.methods.version_mismatch
+        )));
+    }
+
+    #[test]
+    fn test_initialized_re_announces_active_jobs() {
+        let harness = Harness::new(
+            "fn foo() {\n    todo!()\n}\n",
+            MockBackend {
+                implementation: "fn foo() {\n    42\n}".to_string(),
+                delay: Duration::from_millis(500),
+            },
+            JobTracker::new(),
+        );
+
+        harness.execute_impl_function(1);
+        thread::sleep(Duration::from_millis(100));
+        harness.collect_messages(Duration::from_millis(50));
+
+        let handler = NotificationHandler::new(
+            &harness.server,
+            &harness.document_store,
+            &harness.job_tracker,
+            harness.backend.as_ref(),
+            &harness.session,
+            &harness.methods,
+        );
+        handler
+            .handle(&Notification {
+                method: Initialized::METHOD.to_string(),
+                params: json!({}),
+            })
+            .unwrap();
+
+        let notifications: Vec<Notification> = harness
+            .collect_messages(Duration::from_millis(50))
+            .into_iter()
+            .filter_map(|m| match m {
+                Message::Notification(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        let methods: Vec<&str> = notifications.iter().map(|n| n.method.as_str()).collect();
+        assert_eq!(
+            methods,
+            vec![
+                harness.methods.backend_info.as_str(),
+                harness.methods.active_regions.as_str(),
+                harness.methods.job_list.as_str(),
+            ]
+        );
+
+        let regions: ActiveRegionsParams =
+            serde_json::from_value(notifications[1].params.clone()).unwrap();
+        assert_eq!(regions.uri, harness.uri.to_string());
+        assert_eq!(regions.regions.len(), 1);
+        assert_eq!(regions.regions[0].line, 1);
+        assert_eq!(regions.regions[0].state, JobState::Running);
+
+        let job_list: JobListParams =
+            serde_json::from_value(notifications[2].params.clone()).unwrap();
+        assert_eq!(job_list.jobs.len(), 1);
+        assert_eq!(job_list.jobs[0].job_id, regions.regions[0].job_id);
     }
 }
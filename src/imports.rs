@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+
+use lsp_types::{Position, Range, TextEdit};
+
+use crate::utils::detect_line_ending;
+
+/// A top-level import statement, possibly spanning several lines.
+struct ImportEntry {
+    start: usize,
+    end: usize,
+    key: String,
+    sort_key: String,
+}
+
+/// Build the edits that add `imports` to a file, skipping ones it already has.
+///
+/// Imports are placed in the file's existing import block for `language_id` (Rust `use`,
+/// Python `import`/`from`, Go `import (...)`, C/C++ `#include`, JS/TS `import`). When the
+/// existing imports are sorted, new ones are inserted at their sorted position; otherwise
+/// they are appended after the last import. Edits are insertions at the start of a line.
+pub fn insert_imports(file_text: &str, language_id: &str, imports: &[String]) -> Vec<TextEdit> {
+    let lines: Vec<&str> = file_text.lines().collect();
+    let newline = detect_line_ending(file_text);
+
+    let inserts = if language_id == "go" {
+        go_inserts(&lines, imports)
+    } else {
+        block_inserts(&lines, language_id, imports)
+    };
+
+    // Combine imports landing on the same line into a single edit, keeping their order
+    let mut by_line: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for (line, text) in inserts {
+        by_line.entry(line).or_default().push(text);
+    }
+
+    by_line
+        .into_iter()
+        .map(|(line, texts)| {
+            let mut new_text: String = texts.iter().map(|t| format!("{}{}", t, newline)).collect();
+            let position =
+                if line >= lines.len() && !file_text.is_empty() && !file_text.ends_with('\n') {
+                    // Appending after a last line that has no terminator
+                    new_text = format!("{}{}", newline, new_text.trim_end_matches(newline));
+                    Position {
+                        line: (lines.len() - 1) as u32,
+                        character: lines[lines.len() - 1].len() as u32,
+                    }
+                } else {
+                    Position {
+                        line: line as u32,
+                        character: 0,
+                    }
+                };
+            TextEdit {
+                range: Range {
+                    start: position,
+                    end: position,
+                },
+                new_text,
+            }
+        })
+        .collect()
+}
+
+/// Apply edits produced by `insert_imports` to `text`.
+pub fn apply_import_edits(text: &str, edits: &[TextEdit]) -> String {
+    let mut result = text.to_string();
+
+    // Apply from the bottom up so earlier positions stay valid
+    let mut edits: Vec<&TextEdit> = edits.iter().collect();
+    edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+    for edit in edits.into_iter().rev() {
+        let line_start: usize = text
+            .split_inclusive('\n')
+            .take(edit.range.start.line as usize)
+            .map(str::len)
+            .sum();
+        let offset = (line_start + edit.range.start.character as usize).min(result.len());
+        result.insert_str(offset, &edit.new_text);
+    }
+
+    result
+}
+
+fn import_prefixes(language_id: &str) -> &'static [&'static str] {
+    match language_id {
+        "rust" => &["use ", "pub use ", "extern crate "],
+        "python" => &["import ", "from "],
+        "c" | "cpp" | "objc" | "objcpp" => &["#include"],
+        "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => &["import "],
+        _ => &["use ", "import ", "from ", "#include"],
+    }
+}
+
+/// Whether `line` (trimmed) is an import statement, or Go's or Java's `package` clause that
+/// comes before them.
+pub fn is_import_line(line: &str, language_id: &str) -> bool {
+    line.starts_with("package ")
+        || import_prefixes(language_id)
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+}
+
+/// Whitespace-insensitive key used to detect duplicate imports.
+fn import_key(text: &str) -> String {
+    text.split_whitespace().collect()
+}
+
+/// Key used to order imports: the imported path, without the leading keyword.
+fn import_sort_key(text: &str, prefixes: &[&str]) -> String {
+    let path = prefixes
+        .iter()
+        .find_map(|prefix| text.strip_prefix(prefix))
+        .unwrap_or(text);
+    import_key(path)
+}
+
+/// Find the top-level import statements of a file.
+fn find_import_entries(lines: &[&str], prefixes: &[&str]) -> Vec<ImportEntry> {
+    let mut entries = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        if !prefixes.iter().any(|prefix| line.starts_with(prefix)) {
+            i += 1;
+            continue;
+        }
+
+        // Follow bracketed lists (`use a::{`, `from a import (`) to their closing line
+        let mut end = i;
+        let closer = if line.contains('{') && !line.contains('}') {
+            Some('}')
+        } else if line.contains('(') && !line.contains(')') {
+            Some(')')
+        } else {
+            None
+        };
+        if let Some(closer) = closer {
+            while end + 1 < lines.len() && !lines[end].contains(closer) {
+                end += 1;
+            }
+        }
+
+        let text = lines[i..=end].join(" ");
+        entries.push(ImportEntry {
+            start: i,
+            end,
+            key: import_key(&text),
+            sort_key: import_sort_key(&text, prefixes),
+        });
+        i = end + 1;
+    }
+
+    entries
+}
+
+/// Where imports go in a file that has none yet: after shebangs, module docs and docstrings.
+fn preamble_end(lines: &[&str], language_id: &str) -> usize {
+    let mut index = 0;
+    match language_id {
+        "python" => {
+            while index < lines.len() && lines[index].starts_with('#') {
+                index += 1;
+            }
+            let first = lines.get(index).map(|l| l.trim_start()).unwrap_or("");
+            for quote in ["\"\"\"", "'''"] {
+                if let Some(rest) = first.strip_prefix(quote) {
+                    if !rest.contains(quote) {
+                        index += 1;
+                        while index < lines.len() && !lines[index].contains(quote) {
+                            index += 1;
+                        }
+                    }
+                    index += 1;
+                    break;
+                }
+            }
+        }
+        _ => {
+            while index < lines.len()
+                && (lines[index].starts_with("//!") || lines[index].starts_with("#!"))
+            {
+                index += 1;
+            }
+        }
+    }
+    index.min(lines.len())
+}
+
+fn block_inserts(lines: &[&str], language_id: &str, imports: &[String]) -> Vec<(usize, String)> {
+    let prefixes = import_prefixes(language_id);
+    let entries = find_import_entries(lines, prefixes);
+
+    let mut seen: Vec<String> = entries.iter().map(|e| e.key.clone()).collect();
+    let mut new_imports: Vec<&str> = Vec::new();
+    for import in imports.iter().map(|i| i.trim()) {
+        let key = import_key(import);
+        if !import.is_empty() && !seen.contains(&key) {
+            seen.push(key);
+            new_imports.push(import);
+        }
+    }
+
+    let Some(last) = entries.last() else {
+        let line = preamble_end(lines, language_id);
+        return new_imports
+            .into_iter()
+            .map(|i| (line, i.to_string()))
+            .collect();
+    };
+
+    let sorted = entries
+        .windows(2)
+        .all(|pair| pair[0].sort_key <= pair[1].sort_key);
+    if !sorted {
+        return new_imports
+            .into_iter()
+            .map(|i| (last.end + 1, i.to_string()))
+            .collect();
+    }
+
+    new_imports.sort_by_key(|i| import_sort_key(i, prefixes));
+    new_imports
+        .into_iter()
+        .map(|import| {
+            let key = import_sort_key(import, prefixes);
+            let line = entries
+                .iter()
+                .find(|entry| entry.sort_key > key)
+                .map(|entry| entry.start)
+                .unwrap_or(last.end + 1);
+            (line, import.to_string())
+        })
+        .collect()
+}
+
+/// Go imports are package paths, optionally aliased: `"fmt"`, `import "fmt"`, `str "strings"`.
+fn go_inserts(lines: &[&str], imports: &[String]) -> Vec<(usize, String)> {
+    let go_key = |spec: &str| spec.split_whitespace().last().unwrap_or("").to_string();
+
+    // Existing specs: entries of `import (...)` blocks and single-line imports
+    let mut block: Option<(usize, usize)> = None;
+    let mut single_imports: Vec<usize> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        if line.starts_with("import (") {
+            let start = i;
+            i += 1;
+            while i < lines.len() && lines[i].trim() != ")" {
+                if !lines[i].trim().is_empty() {
+                    seen.push(go_key(lines[i]));
+                }
+                i += 1;
+            }
+            if block.is_none() {
+                block = Some((start, i));
+            }
+        } else if let Some(spec) = line.strip_prefix("import ") {
+            seen.push(go_key(spec));
+            single_imports.push(i);
+        }
+        i += 1;
+    }
+
+    let mut new_specs: Vec<String> = Vec::new();
+    for import in imports {
+        let spec = import.trim();
+        let spec = spec.strip_prefix("import ").unwrap_or(spec).trim();
+        let key = go_key(spec);
+        if !spec.is_empty() && !seen.contains(&key) {
+            seen.push(key);
+            new_specs.push(spec.to_string());
+        }
+    }
+
+    if let Some((open, close)) = block {
+        let existing: Vec<String> = lines[open + 1..close]
+            .iter()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| go_key(l))
+            .collect();
+        let sorted = existing.windows(2).all(|pair| pair[0] <= pair[1]);
+        if sorted {
+            new_specs.sort_by_key(|spec| go_key(spec));
+        }
+        return new_specs
+            .into_iter()
+            .map(|spec| {
+                let key = go_key(&spec);
+                let line = (open + 1..close)
+                    .find(|&l| sorted && !lines[l].trim().is_empty() && go_key(lines[l]) > key)
+                    .unwrap_or(close);
+                (line, format!("\t{}", spec))
+            })
+            .collect();
+    }
+
+    let line = match single_imports.last() {
+        Some(&last) => last + 1,
+        None => lines
+            .iter()
+            .position(|l| l.starts_with("package "))
+            .map(|p| p + 1)
+            .unwrap_or(0),
+    };
+    new_specs
+        .into_iter()
+        .map(|spec| (line, format!("import {}", spec)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imports(list: &[&str]) -> Vec<String> {
+        list.iter().map(|i| i.to_string()).collect()
+    }
+
+    fn apply(text: &str, language_id: &str, list: &[&str]) -> String {
+        let edits = insert_imports(text, language_id, &imports(list));
+        apply_import_edits(text, &edits)
+    }
+
+    #[test]
+    fn test_rust_inserts_in_sorted_position_and_skips_duplicates() {
+        let code = "use std::fmt;\nuse std::sync::Arc;\n\nfn foo() {}\n";
+
+        let result = apply(
+            code,
+            "rust",
+            &[
+                "use std::collections::HashMap;",
+                "use std::collections::HashMap;",
+                "use std::fmt;",
+            ],
+        );
+
+        assert_eq!(
+            result,
+            "use std::collections::HashMap;\nuse std::fmt;\nuse std::sync::Arc;\n\nfn foo() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_rust_appends_after_unsorted_block_and_multiline_use() {
+        let code = "use std::sync::{\n    Arc,\n    Mutex,\n};\nuse std::fmt;\n\nfn foo() {}\n";
+
+        let result = apply(code, "rust", &["use std::io;"]);
+
+        assert_eq!(
+            result,
+            "use std::sync::{\n    Arc,\n    Mutex,\n};\nuse std::fmt;\nuse std::io;\n\nfn foo() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_rust_without_imports_goes_after_module_docs() {
+        let code = "//! Docs\nfn foo() {}\n";
+
+        let edits = insert_imports(code, "rust", &imports(&["use std::fmt;"]));
+
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            edits[0].range.start,
+            Position {
+                line: 1,
+                character: 0
+            }
+        );
+        assert_eq!(
+            apply_import_edits(code, &edits),
+            "//! Docs\nuse std::fmt;\nfn foo() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_python_imports() {
+        let code = "\"\"\"Module docs.\"\"\"\nimport os\nfrom typing import List\n\ndef foo():\n    pass\n";
+
+        let result = apply(
+            code,
+            "python",
+            &["import os", "import sys", "from typing import List"],
+        );
+
+        assert_eq!(
+            result,
+            "\"\"\"Module docs.\"\"\"\nimport os\nimport sys\nfrom typing import List\n\ndef foo():\n    pass\n"
+        );
+    }
+
+    #[test]
+    fn test_python_without_imports_skips_docstring() {
+        let code = "#!/usr/bin/env python\n\"\"\"\nModule docs.\n\"\"\"\ndef foo():\n    pass\n";
+
+        let result = apply(code, "python", &["import os"]);
+
+        assert_eq!(
+            result,
+            "#!/usr/bin/env python\n\"\"\"\nModule docs.\n\"\"\"\nimport os\ndef foo():\n    pass\n"
+        );
+    }
+
+    #[test]
+    fn test_go_import_block() {
+        let code = "package main\n\nimport (\n\t\"fmt\"\n\t\"strings\"\n)\n\nfunc main() {}\n";
+
+        let result = apply(code, "go", &["\"os\"", "import \"fmt\"", "\"sync\""]);
+
+        assert_eq!(
+            result,
+            "package main\n\nimport (\n\t\"fmt\"\n\t\"os\"\n\t\"strings\"\n\t\"sync\"\n)\n\nfunc main() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_go_single_and_missing_imports() {
+        let single = "package main\n\nimport \"fmt\"\n\nfunc main() {}\n";
+        assert_eq!(
+            apply(single, "go", &["\"os\"", "\"fmt\""]),
+            "package main\n\nimport \"fmt\"\nimport \"os\"\n\nfunc main() {}\n"
+        );
+
+        let none = "package main\n\nfunc main() {}\n";
+        assert_eq!(
+            apply(none, "go", &["\"os\""]),
+            "package main\nimport \"os\"\n\nfunc main() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_c_includes() {
+        let code = "#include <stdio.h>\n#include <string.h>\n\nint main() {}\n";
+
+        let result = apply(code, "c", &["#include <stdlib.h>", "#include <stdio.h>"]);
+
+        assert_eq!(
+            result,
+            "#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\n\nint main() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_crlf_file_gets_crlf_imports() {
+        let code = "use std::fmt;\r\n\r\nfn foo() {}\r\n";
+        assert_eq!(
+            apply(code, "rust", &["use std::io;"]),
+            "use std::fmt;\r\nuse std::io;\r\n\r\nfn foo() {}\r\n"
+        );
+    }
+
+    #[test]
+    fn test_no_new_imports_produces_no_edits() {
+        let code = "use std::fmt;\n";
+        assert!(insert_imports(code, "rust", &imports(&["use std::fmt;"])).is_empty());
+    }
+
+    #[test]
+    fn test_append_to_file_without_trailing_newline() {
+        let code = "use std::fmt;";
+        assert_eq!(
+            apply(code, "rust", &["use std::io;"]),
+            "use std::fmt;\nuse std::io;"
+        );
+    }
+}
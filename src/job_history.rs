@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::job_tracker::JobState;
+use crate::sync_utils::lock_recovering;
+
+/// How a job ended, as far as history is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobOutcome {
+    Done,
+    Failed,
+    Cancelled,
+    TimedOut,
+    /// Turned away at submission (per-file limit or full queue); never ran.
+    Rejected,
+}
+
+impl JobOutcome {
+    /// The outcome for a job that left the tracker in `state`, or None if it has not finished.
+    pub fn from_state(state: JobState) -> Option<Self> {
+        match state {
+            JobState::Done => Some(JobOutcome::Done),
+            JobState::Failed => Some(JobOutcome::Failed),
+            JobState::Cancelled => Some(JobOutcome::Cancelled),
+            JobState::TimedOut => Some(JobOutcome::TimedOut),
+            JobState::Queued | JobState::Running | JobState::Applying | JobState::Orphaned => None,
+        }
+    }
+}
+
+/// One finished (or rejected) job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub job_id: String,
+    pub backend: String,
+    pub outcome: JobOutcome,
+    /// Time from registration to the terminal state; 0 for rejected jobs.
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackendStats {
+    pub total: usize,
+    pub done: usize,
+    pub avg_duration_ms: u64,
+}
+
+/// Aggregates over a set of history entries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobStats {
+    pub total: usize,
+    pub by_outcome: BTreeMap<JobOutcome, usize>,
+    /// Done jobs over jobs that ran (rejected jobs excluded); 0 when nothing ran.
+    pub success_rate: f64,
+    /// Duration percentiles over jobs that ran.
+    pub p50_duration_ms: u64,
+    pub p95_duration_ms: u64,
+    pub by_backend: BTreeMap<String, BackendStats>,
+}
+
+/// Nearest-rank percentile of sorted values; 0 for an empty slice.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Compute aggregate stats over `entries`.
+pub fn compute_stats(entries: &[HistoryEntry]) -> JobStats {
+    let mut by_outcome = BTreeMap::new();
+    let mut by_backend: BTreeMap<String, BackendStats> = BTreeMap::new();
+    let mut durations = Vec::new();
+    let mut backend_durations: BTreeMap<&str, u64> = BTreeMap::new();
+
+    for entry in entries {
+        *by_outcome.entry(entry.outcome).or_insert(0) += 1;
+        if entry.outcome == JobOutcome::Rejected {
+            continue;
+        }
+
+        durations.push(entry.duration_ms);
+        let backend = by_backend.entry(entry.backend.clone()).or_default();
+        backend.total += 1;
+        if entry.outcome == JobOutcome::Done {
+            backend.done += 1;
+        }
+        *backend_durations.entry(&entry.backend).or_insert(0) += entry.duration_ms;
+    }
+
+    for (name, backend) in by_backend.iter_mut() {
+        backend.avg_duration_ms = backend_durations[name.as_str()] / backend.total as u64;
+    }
+
+    durations.sort_unstable();
+    let done = by_outcome.get(&JobOutcome::Done).copied().unwrap_or(0);
+    let success_rate = if durations.is_empty() {
+        0.0
+    } else {
+        done as f64 / durations.len() as f64
+    };
+
+    JobStats {
+        total: entries.len(),
+        by_outcome,
+        success_rate,
+        p50_duration_ms: percentile(&durations, 50),
+        p95_duration_ms: percentile(&durations, 95),
+        by_backend,
+    }
+}
+
+/// Bounded ring of the most recent finished jobs.
+///
+/// It has its own lock, held only to push or copy entries, so recording never contends
+/// with job tracking.
+#[derive(Debug)]
+pub struct JobHistory {
+    entries: Mutex<VecDeque<HistoryEntry>>,
+    capacity: usize,
+}
+
+impl JobHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, entry: HistoryEntry) {
+        let mut entries = lock_recovering(&self.entries);
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// All recorded entries, oldest first.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        lock_recovering(&self.entries).iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(backend: &str, outcome: JobOutcome, duration_ms: u64) -> HistoryEntry {
+        HistoryEntry {
+            job_id: format!("{}-{:?}-{}", backend, outcome, duration_ms),
+            backend: backend.to_string(),
+            outcome,
+            duration_ms,
+        }
+    }
+
+    #[test]
+    fn test_empty_stats() {
+        let stats = compute_stats(&[]);
+        assert_eq!(stats, JobStats::default());
+    }
+
+    #[test]
+    fn test_counts_and_success_rate() {
+        let entries = vec![
+            entry("Amp", JobOutcome::Done, 100),
+            entry("Amp", JobOutcome::Done, 300),
+            entry("Amp", JobOutcome::Failed, 200),
+            entry("OpenCode", JobOutcome::TimedOut, 400),
+            entry("OpenCode", JobOutcome::Rejected, 0),
+        ];
+
+        let stats = compute_stats(&entries);
+
+        assert_eq!(stats.total, 5);
+        assert_eq!(stats.by_outcome[&JobOutcome::Done], 2);
+        assert_eq!(stats.by_outcome[&JobOutcome::Failed], 1);
+        assert_eq!(stats.by_outcome[&JobOutcome::TimedOut], 1);
+        assert_eq!(stats.by_outcome[&JobOutcome::Rejected], 1);
+        assert!(!stats.by_outcome.contains_key(&JobOutcome::Cancelled));
+        // Rejected jobs never ran, so they do not count against the success rate
+        assert_eq!(stats.success_rate, 0.5);
+    }
+
+    #[test]
+    fn test_per_backend_breakdown() {
+        let entries = vec![
+            entry("Amp", JobOutcome::Done, 100),
+            entry("Amp", JobOutcome::Failed, 300),
+            entry("OpenCode", JobOutcome::Done, 1000),
+            entry("OpenCode", JobOutcome::Rejected, 0),
+        ];
+
+        let stats = compute_stats(&entries);
+
+        assert_eq!(
+            stats.by_backend["Amp"],
+            BackendStats {
+                total: 2,
+                done: 1,
+                avg_duration_ms: 200
+            }
+        );
+        assert_eq!(
+            stats.by_backend["OpenCode"],
+            BackendStats {
+                total: 1,
+                done: 1,
+                avg_duration_ms: 1000
+            }
+        );
+    }
+
+    #[test]
+    fn test_duration_percentiles() {
+        let entries: Vec<HistoryEntry> = (1..=100)
+            .rev()
+            .map(|i| entry("Amp", JobOutcome::Done, i * 10))
+            .collect();
+
+        let stats = compute_stats(&entries);
+        assert_eq!(stats.p50_duration_ms, 500);
+        assert_eq!(stats.p95_duration_ms, 950);
+
+        let stats = compute_stats(&[entry("Amp", JobOutcome::Done, 42)]);
+        assert_eq!(stats.p50_duration_ms, 42);
+        assert_eq!(stats.p95_duration_ms, 42);
+
+        let stats = compute_stats(&[
+            entry("Amp", JobOutcome::Done, 10),
+            entry("Amp", JobOutcome::Done, 20),
+            entry("Amp", JobOutcome::Done, 30),
+        ]);
+        assert_eq!(stats.p50_duration_ms, 20);
+        assert_eq!(stats.p95_duration_ms, 30);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let history = JobHistory::new(3);
+        for i in 0..5 {
+            history.record(entry("Amp", JobOutcome::Done, i));
+        }
+
+        let durations: Vec<u64> = history.entries().iter().map(|e| e.duration_ms).collect();
+        assert_eq!(durations, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_outcome_from_state() {
+        assert_eq!(
+            JobOutcome::from_state(JobState::Done),
+            Some(JobOutcome::Done)
+        );
+        assert_eq!(
+            JobOutcome::from_state(JobState::TimedOut),
+            Some(JobOutcome::TimedOut)
+        );
+        assert_eq!(JobOutcome::from_state(JobState::Running), None);
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashSet;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::cancellation::CancellationToken;
+use crate::config::{BackendType, CURRENT_BACKEND, MAX_QUEUE_WAIT_SECS};
+use crate::rate_limiter::RateLimiter;
+use crate::sync_utils::{lock_recovering, wait_timeout_recovering};
+
+/// How often a waiting job re-checks its cancellation token.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Scheduling priority of a job. Higher priorities are started first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobPriority {
+    /// Background work submitted in bulk.
+    Batch,
+    /// A single job the user is waiting on (e.g. a code action).
+    #[default]
+    Interactive,
+}
+
+/// Why a job stopped waiting for a run slot without starting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueWaitError {
+    /// The job was cancelled (or timed out) while queued.
+    Cancelled,
+    /// No slot freed up within the queue's maximum wait.
+    TimedOut(String),
+}
+
+/// A job waiting for a run slot.
+#[derive(Debug, Clone)]
+pub struct PendingJob {
+    pub job_id: String,
+    pub priority: JobPriority,
+    /// Submission order, used to keep jobs of equal priority FIFO.
+    pub seq: u64,
+}
+
+/// Pick the pending job that should run next: highest priority first, then oldest.
+pub fn next_to_run(pending: &[PendingJob]) -> Option<usize> {
+    pending
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, job)| (std::cmp::Reverse(job.priority), job.seq))
+        .map(|(index, _)| index)
+}
+
+/// Number of pending jobs that will be started before `job_id`, or `None` if it is not pending.
+pub fn queue_position(pending: &[PendingJob], job_id: &str) -> Option<usize> {
+    let job = pending.iter().find(|job| job.job_id == job_id)?;
+    Some(
+        pending
+            .iter()
+            .filter(|other| {
+                (std::cmp::Reverse(other.priority), other.seq)
+                    < (std::cmp::Reverse(job.priority), job.seq)
+            })
+            .count(),
+    )
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    running: HashSet<String>,
+    pending: Vec<PendingJob>,
+    next_seq: u64,
+}
+
+/// Global scheduler limiting how many jobs run at once across all files.
+///
+/// Jobs are enqueued in submission order and started by `wait_for_slot` as slots free up,
+/// according to `next_to_run`. At most `max_pending` jobs may wait at once, each for at most
+/// `max_wait`, so a slot that is never released cannot block later jobs forever. Started
+/// jobs then take a token from the backend's `RateLimiter` before running it.
+#[derive(Debug)]
+pub struct JobQueue {
+    state: Mutex<QueueState>,
+    slot_freed: Condvar,
+    max_running: usize,
+    max_pending: usize,
+    max_wait: Duration,
+    rate_limiter: RateLimiter,
+}
+
+impl JobQueue {
+    pub fn new(max_running: usize, max_pending: usize) -> Self {
+        Self::with_max_wait(
+            max_running,
+            max_pending,
+            Duration::from_secs(MAX_QUEUE_WAIT_SECS),
+        )
+    }
+
+    /// Create a queue whose jobs give up after waiting `max_wait` for a run slot.
+    pub fn with_max_wait(max_running: usize, max_pending: usize, max_wait: Duration) -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            slot_freed: Condvar::new(),
+            max_running,
+            max_pending,
+            max_wait,
+            rate_limiter: RateLimiter::for_backend(CURRENT_BACKEND),
+        }
+    }
+
+    /// Pace backend runs by `backend`'s entry in `RATE_LIMITS` instead of `CURRENT_BACKEND`'s.
+    pub fn with_backend(mut self, backend: BackendType) -> Self {
+        self.rate_limiter = RateLimiter::for_backend(backend);
+        self
+    }
+
+    /// Replace the rate limit from `RATE_LIMITS` with `limit`.
+    #[cfg(test)]
+    pub fn with_rate_limit(mut self, limit: Option<crate::config::RateLimit>) -> Self {
+        self.rate_limiter = RateLimiter::new(limit);
+        self
+    }
+
+    /// The limiter pacing the backend runs of started jobs.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Add a job to the queue. Call in submission order; the job waits in `wait_for_slot`.
+    ///
+    /// Fails without queueing the job when `max_pending` jobs are already waiting.
+    pub fn enqueue(&self, job_id: &str, priority: JobPriority) -> Result<(), String> {
+        let mut state = lock_recovering(&self.state);
+        if state.pending.len() >= self.max_pending {
+            return Err(format!(
+                "Job queue is full ({} of {} pending jobs). Please wait for running jobs to finish.",
+                state.pending.len(),
+                self.max_pending
+            ));
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.pending.push(PendingJob {
+            job_id: job_id.to_string(),
+            priority,
+            seq,
+        });
+        Ok(())
+    }
+
+    /// Number of jobs waiting for a run slot.
+    pub fn pending_len(&self) -> usize {
+        lock_recovering(&self.state).pending.len()
+    }
+
+    /// Number of jobs holding a run slot.
+    pub fn running_len(&self) -> usize {
+        lock_recovering(&self.state).running.len()
+    }
+
+    /// Block until `job_id` is scheduled to run.
+    ///
+    /// `on_queued` is called with the job's queue position whenever it changes while waiting.
+    /// Returns an error (and leaves the queue) if `cancellation` is tripped or `max_wait` elapses
+    /// before the job starts.
+    pub fn wait_for_slot(
+        &self,
+        job_id: &str,
+        cancellation: &CancellationToken,
+        mut on_queued: impl FnMut(usize),
+    ) -> Result<(), QueueWaitError> {
+        let mut state = lock_recovering(&self.state);
+        let mut last_position = None;
+        let deadline = Instant::now() + self.max_wait;
+
+        loop {
+            if cancellation.is_cancelled() {
+                state.pending.retain(|job| job.job_id != job_id);
+                self.slot_freed.notify_all();
+                return Err(QueueWaitError::Cancelled);
+            }
+
+            if state.running.len() < self.max_running {
+                if let Some(index) = next_to_run(&state.pending) {
+                    if state.pending[index].job_id == job_id {
+                        state.pending.remove(index);
+                        state.running.insert(job_id.to_string());
+                        // Another waiter may also fit in a remaining slot
+                        self.slot_freed.notify_all();
+                        return Ok(());
+                    }
+                }
+            }
+
+            // A job that is no longer pending was dropped by `release`; treat it as cancelled
+            let Some(position) = queue_position(&state.pending, job_id) else {
+                return Err(QueueWaitError::Cancelled);
+            };
+            if last_position != Some(position) {
+                info!("Job {} queued at position {}", job_id, position);
+                on_queued(position);
+                last_position = Some(position);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                state.pending.retain(|job| job.job_id != job_id);
+                self.slot_freed.notify_all();
+                let holders: Vec<&str> = state.running.iter().map(String::as_str).collect();
+                error!(
+                    "Job {} gave up after waiting {} seconds for a slot held by {:?}",
+                    job_id,
+                    self.max_wait.as_secs(),
+                    holders
+                );
+                return Err(QueueWaitError::TimedOut(format!(
+                    "Timed out waiting for other implementations to finish (slots held by {})",
+                    holders.join(", ")
+                )));
+            }
+
+            state = wait_timeout_recovering(
+                &self.slot_freed,
+                state,
+                CANCEL_POLL_INTERVAL.min(deadline - now),
+            );
+        }
+    }
+
+    /// Free the slot held by `job_id`, or drop it from the queue if it never started.
+    ///
+    /// Releasing a job that holds no slot (e.g. a second release) is a no-op.
+    pub fn release(&self, job_id: &str) {
+        let mut state = lock_recovering(&self.state);
+        let was_running = state.running.remove(job_id);
+        let pending_before = state.pending.len();
+        state.pending.retain(|job| job.job_id != job_id);
+        if was_running || state.pending.len() != pending_before {
+            self.slot_freed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn pending(jobs: &[(&str, JobPriority)]) -> Vec<PendingJob> {
+        jobs.iter()
+            .enumerate()
+            .map(|(seq, (job_id, priority))| PendingJob {
+                job_id: job_id.to_string(),
+                priority: *priority,
+                seq: seq as u64,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_next_to_run_is_fifo_within_priority() {
+        let queue = pending(&[
+            ("a", JobPriority::Batch),
+            ("b", JobPriority::Batch),
+            ("c", JobPriority::Batch),
+        ]);
+        assert_eq!(next_to_run(&queue), Some(0));
+        assert_eq!(next_to_run(&[]), None);
+    }
+
+    #[test]
+    fn test_next_to_run_prefers_interactive() {
+        let queue = pending(&[
+            ("a", JobPriority::Batch),
+            ("b", JobPriority::Batch),
+            ("c", JobPriority::Interactive),
+            ("d", JobPriority::Interactive),
+        ]);
+        assert_eq!(next_to_run(&queue), Some(2));
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let queue = pending(&[
+            ("a", JobPriority::Batch),
+            ("b", JobPriority::Batch),
+            ("c", JobPriority::Interactive),
+        ]);
+        assert_eq!(queue_position(&queue, "c"), Some(0));
+        assert_eq!(queue_position(&queue, "a"), Some(1));
+        assert_eq!(queue_position(&queue, "b"), Some(2));
+        assert_eq!(queue_position(&queue, "missing"), None);
+    }
+
+    #[test]
+    fn test_enqueue_rejects_beyond_pending_cap() {
+        let queue = JobQueue::new(1, 2);
+        queue.enqueue("a", JobPriority::Batch).unwrap();
+        queue.enqueue("b", JobPriority::Batch).unwrap();
+
+        let err = queue.enqueue("c", JobPriority::Interactive).unwrap_err();
+
+        assert!(err.contains("2 of 2"), "{}", err);
+        assert_eq!(queue.pending_len(), 2);
+    }
+
+    #[test]
+    fn test_cancelled_job_leaves_queue() {
+        let queue = JobQueue::new(1, 16);
+        queue.enqueue("running", JobPriority::Batch).unwrap();
+        queue
+            .wait_for_slot("running", &CancellationToken::new(), |_| {})
+            .unwrap();
+
+        queue.enqueue("waiting", JobPriority::Batch).unwrap();
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let result = queue.wait_for_slot("waiting", &cancellation, |_| {});
+
+        assert_eq!(result, Err(QueueWaitError::Cancelled));
+        assert!(queue.state.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_queue_usable_after_lock_poisoned() {
+        let queue = JobQueue::new(1, 16);
+        queue.enqueue("first", JobPriority::Batch).unwrap();
+
+        thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _state = queue.state.lock().unwrap();
+                panic!("worker panicked while holding the queue lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+
+        queue
+            .wait_for_slot("first", &CancellationToken::new(), |_| {})
+            .unwrap();
+        queue.release("first");
+        queue.enqueue("second", JobPriority::Batch).unwrap();
+        queue
+            .wait_for_slot("second", &CancellationToken::new(), |_| {})
+            .unwrap();
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_waiter_times_out_when_slot_is_never_released() {
+        let queue = JobQueue::with_max_wait(1, 16, Duration::from_millis(200));
+        queue.enqueue("stuck", JobPriority::Batch).unwrap();
+        queue
+            .wait_for_slot("stuck", &CancellationToken::new(), |_| {})
+            .unwrap();
+
+        queue.enqueue("waiting", JobPriority::Batch).unwrap();
+        let started = Instant::now();
+        let result = queue.wait_for_slot("waiting", &CancellationToken::new(), |_| {});
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        match result {
+            Err(QueueWaitError::TimedOut(message)) => assert!(message.contains("stuck")),
+            other => panic!("expected timeout, got {:?}", other),
+        }
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_double_release_does_not_free_extra_slots() {
+        let queue = JobQueue::new(1, 16);
+        queue.enqueue("a", JobPriority::Batch).unwrap();
+        queue
+            .wait_for_slot("a", &CancellationToken::new(), |_| {})
+            .unwrap();
+        queue.release("a");
+        queue.release("a");
+
+        queue.enqueue("b", JobPriority::Batch).unwrap();
+        queue
+            .wait_for_slot("b", &CancellationToken::new(), |_| {})
+            .unwrap();
+        queue.enqueue("c", JobPriority::Batch).unwrap();
+        queue.release("a");
+
+        let state = queue.state.lock().unwrap();
+        assert_eq!(state.running.len(), 1);
+        assert!(state.running.contains("b"));
+        assert_eq!(state.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_interactive_job_is_not_starved_by_batch_jobs() {
+        let queue = Arc::new(JobQueue::new(1, 16));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // Occupy the only slot, then submit a burst of batch jobs followed by one interactive job
+        queue.enqueue("first", JobPriority::Batch).unwrap();
+        queue
+            .wait_for_slot("first", &CancellationToken::new(), |_| {})
+            .unwrap();
+        let mut job_ids: Vec<String> = (0..8).map(|i| format!("batch-{}", i)).collect();
+        for job_id in &job_ids {
+            queue.enqueue(job_id, JobPriority::Batch).unwrap();
+        }
+        queue
+            .enqueue("interactive", JobPriority::Interactive)
+            .unwrap();
+        job_ids.push("interactive".to_string());
+
+        let handles: Vec<_> = job_ids
+            .into_iter()
+            .map(|job_id| {
+                let queue = queue.clone();
+                let order = order.clone();
+                thread::spawn(move || {
+                    queue
+                        .wait_for_slot(&job_id, &CancellationToken::new(), |_| {})
+                        .unwrap();
+                    order.lock().unwrap().push(job_id.clone());
+                    queue.release(&job_id);
+                })
+            })
+            .collect();
+
+        // Let every waiter block before freeing the slot
+        thread::sleep(Duration::from_millis(200));
+        queue.release("first");
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let order = order.lock().unwrap();
+        let expected: Vec<String> = std::iter::once("interactive".to_string())
+            .chain((0..8).map(|i| format!("batch-{}", i)))
+            .collect();
+        assert_eq!(*order, expected);
+    }
+}
@@ -1,57 +1,358 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use lsp_types::Url;
-use tracing::info;
+use lsp_types::{Range, Url};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::cancellation::CancellationToken;
+use crate::config::{BackendType, CURRENT_BACKEND, JOB_HISTORY_SIZE, JOB_TIMEOUT_SECS};
+use crate::job_history::{HistoryEntry, JobHistory, JobOutcome};
+use crate::signature::FunctionSignature;
+use crate::sync_utils::lock_recovering;
+use crate::utils::relocate_function;
 
 pub const MAX_CONCURRENT_JOBS_PER_FILE: usize = 10;
 
+/// Number of finished jobs whose terminal state is kept for status queries.
+const FINISHED_JOB_HISTORY: usize = 100;
+
+/// Lifecycle of a job.
+///
+/// Jobs start `Queued`, move to `Running` once they hold a run slot and to `Applying` once
+/// their edit is being sent. A `Queued` or `Running` job becomes `Orphaned` when user edits
+/// remove or rename its function. Any non-terminal state can end as `Failed`, `Cancelled` or
+/// `TimedOut`; only `Applying` can end as `Done`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Applying,
+    /// The target function no longer exists; the worker fails the job instead of applying it.
+    Orphaned,
+    Done,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+impl JobState {
+    /// Whether the job has left the tracker.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            JobState::Done | JobState::Failed | JobState::Cancelled | JobState::TimedOut
+        )
+    }
+
+    /// Whether moving from `self` to `next` is a legal transition.
+    pub fn can_transition_to(self, next: JobState) -> bool {
+        use JobState::*;
+        matches!(
+            (self, next),
+            (Queued, Running)
+                | (Running, Applying)
+                | (Applying, Done)
+                | (Queued | Running, Orphaned)
+                | (
+                    Queued | Running | Applying | Orphaned,
+                    Failed | Cancelled | TimedOut
+                )
+        )
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ActiveJob {
     pub job_id: String,
-    pub original_line: u32,
     pub current_line: u32,
-    pub function_signature: String,
+    pub function_signature: FunctionSignature,
+    /// Tripped once the job has been abandoned (cancelled or timed out); the worker must not
+    /// apply its result, and the backend should stop early.
+    pub cancellation: CancellationToken,
+    pub state: JobState,
+    /// Every state the job entered, with when it entered it, oldest first.
+    pub transitions: Vec<(JobState, Instant)>,
+    /// Most recent streaming preview from the backend.
+    pub last_preview: String,
+}
+
+impl ActiveJob {
+    /// Move the job to `next`, recording when it happened.
+    ///
+    /// Illegal transitions are logged (and assert in debug builds) and leave the state
+    /// unchanged. Returns whether the transition happened.
+    fn transition(&mut self, next: JobState) -> bool {
+        if !self.state.can_transition_to(next) {
+            error!(
+                "Illegal state transition for job {}: {:?} -> {:?}",
+                self.job_id, self.state, next
+            );
+            debug_assert!(
+                false,
+                "illegal job state transition {:?} -> {:?}",
+                self.state, next
+            );
+            return false;
+        }
+        self.state = next;
+        self.transitions.push((next, Instant::now()));
+        true
+    }
+
+    /// Time from registration until now, or until the job finished.
+    fn elapsed(&self) -> Duration {
+        let started = self.transitions[0].1;
+        if self.state.is_terminal() {
+            self.transitions[self.transitions.len() - 1].1 - started
+        } else {
+            started.elapsed()
+        }
+    }
+
+    /// Time spent in each state, for logging.
+    fn timeline(&self) -> String {
+        self.transitions
+            .windows(2)
+            .map(|pair| format!("{:?} {}ms", pair[0].0, (pair[1].1 - pair[0].1).as_millis()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FinishedJob {
+    uri: Url,
+    job: ActiveJob,
+    /// Why the job failed, if it did.
+    error: Option<String>,
+}
+
+/// Point-in-time view of an active or recently finished job, for status queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobSnapshot {
+    pub job_id: String,
+    pub uri: Url,
+    pub state: JobState,
+    pub function_signature: FunctionSignature,
+    pub current_line: u32,
+    /// Every state the job entered, oldest first.
+    pub states: Vec<JobState>,
+    pub elapsed: Duration,
+    pub last_preview: String,
+    pub error: Option<String>,
+}
+
+impl JobSnapshot {
+    fn new(uri: &Url, job: &ActiveJob, error: Option<String>) -> Self {
+        Self {
+            job_id: job.job_id.clone(),
+            uri: uri.clone(),
+            state: job.state,
+            function_signature: job.function_signature.clone(),
+            current_line: job.current_line,
+            states: job.transitions.iter().map(|(state, _)| *state).collect(),
+            elapsed: job.elapsed(),
+            last_preview: job.last_preview.clone(),
+            error,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    /// Active jobs per file, for per-file limits and line adjustments.
+    active: HashMap<Url, HashMap<String, ActiveJob>>,
+    /// The file of every active job, so lookups by id need not scan every file.
+    uri_by_job: HashMap<String, Url>,
+    /// Most recently finished jobs, oldest first.
+    finished: VecDeque<FinishedJob>,
+}
+
+impl Jobs {
+    fn insert(&mut self, uri: &Url, job: ActiveJob) {
+        self.uri_by_job.insert(job.job_id.clone(), uri.clone());
+        self.active
+            .entry(uri.clone())
+            .or_default()
+            .insert(job.job_id.clone(), job);
+    }
+
+    /// An active job and its file.
+    fn get(&self, job_id: &str) -> Option<(&Url, &ActiveJob)> {
+        let uri = self.uri_by_job.get(job_id)?;
+        Some((uri, self.active.get(uri)?.get(job_id)?))
+    }
+
+    fn get_mut(&mut self, job_id: &str) -> Option<&mut ActiveJob> {
+        let uri = self.uri_by_job.get(job_id)?;
+        self.active.get_mut(uri)?.get_mut(job_id)
+    }
+
+    /// Move an active job into the given terminal state.
+    ///
+    /// Returns None if the job is not active (it already reached a terminal state) or the
+    /// transition is illegal from its current state.
+    fn finish(
+        &mut self,
+        uri: &Url,
+        job_id: &str,
+        state: JobState,
+        error: Option<String>,
+    ) -> Option<ActiveJob> {
+        let file_jobs = self.active.get_mut(uri)?;
+        if !file_jobs.get_mut(job_id)?.transition(state) {
+            return None;
+        }
+        let job = file_jobs.remove(job_id)?;
+        self.uri_by_job.remove(job_id);
+
+        info!(
+            "Finished job {} for {} as {:?} after {} ({} remaining)",
+            job_id,
+            uri,
+            state,
+            job.timeline(),
+            file_jobs.len()
+        );
+
+        // Clean up empty file entries
+        if file_jobs.is_empty() {
+            self.active.remove(uri);
+        }
+
+        if matches!(state, JobState::Cancelled | JobState::TimedOut) {
+            job.cancellation.cancel();
+        }
+
+        if self.finished.len() >= FINISHED_JOB_HISTORY {
+            self.finished.pop_front();
+        }
+        self.finished.push_back(FinishedJob {
+            uri: uri.clone(),
+            job: job.clone(),
+            error,
+        });
+
+        Some(job)
+    }
 }
 
 #[derive(Clone)]
 pub struct JobTracker {
-    jobs: Arc<Mutex<HashMap<Url, HashMap<String, ActiveJob>>>>,
+    jobs: Arc<Mutex<Jobs>>,
+    job_timeout: Duration,
+    history: Arc<JobHistory>,
+    /// The backend the jobs run on, named in their history entries.
+    backend: BackendType,
 }
 
 impl JobTracker {
     pub fn new() -> Self {
+        Self::with_job_timeout(Duration::from_secs(JOB_TIMEOUT_SECS))
+    }
+
+    /// Create a tracker whose jobs time out after `job_timeout`.
+    pub fn with_job_timeout(job_timeout: Duration) -> Self {
         Self {
-            jobs: Arc::new(Mutex::new(HashMap::new())),
+            jobs: Arc::new(Mutex::new(Jobs::default())),
+            job_timeout,
+            history: Arc::new(JobHistory::new(JOB_HISTORY_SIZE)),
+            backend: CURRENT_BACKEND,
+        }
+    }
+
+    /// Record jobs as run by `backend` instead of `CURRENT_BACKEND`.
+    pub fn with_backend(mut self, backend: BackendType) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Finished and rejected jobs, for aggregate stats.
+    pub fn history(&self) -> &JobHistory {
+        &self.history
+    }
+
+    /// Record a job that left the tracker. Called after the jobs lock is released.
+    fn record_finished(&self, job: &ActiveJob) {
+        if let Some(outcome) = JobOutcome::from_state(job.state) {
+            self.history.record(HistoryEntry {
+                job_id: job.job_id.clone(),
+                backend: self.backend.display_name().to_string(),
+                outcome,
+                duration_ms: job.elapsed().as_millis() as u64,
+            });
         }
     }
 
-    /// Register a new job. Returns Err if max concurrent jobs reached.
+    /// Record a submission that was turned away before it became a job.
+    pub fn record_rejection(&self, job_id: &str) {
+        self.history.record(HistoryEntry {
+            job_id: job_id.to_string(),
+            backend: self.backend.display_name().to_string(),
+            outcome: JobOutcome::Rejected,
+            duration_ms: 0,
+        });
+    }
+
+    /// Finish an active job as `state` and record it in the history.
+    fn finish_job(
+        &self,
+        uri: &Url,
+        job_id: &str,
+        state: JobState,
+        error: Option<String>,
+    ) -> Option<ActiveJob> {
+        let job = {
+            let mut jobs = lock_recovering(&self.jobs);
+            jobs.finish(uri, job_id, state, error)?
+        };
+        self.record_finished(&job);
+        Some(job)
+    }
+
+    /// Wall-clock limit after which a job is timed out.
+    pub fn job_timeout(&self) -> Duration {
+        self.job_timeout
+    }
+
+    /// Register a new job in the `Queued` state. Returns Err if max concurrent jobs reached.
+    ///
+    /// On success, returns the job's cancellation token, which the worker should check
+    /// before applying its result and pass on to the backend.
     pub fn register_job(
         &self,
         uri: &Url,
         job_id: &str,
         line: u32,
-        function_signature: String,
-    ) -> Result<(), String> {
-        let mut jobs = self.jobs.lock().unwrap();
-
-        let file_jobs = jobs.entry(uri.clone()).or_insert_with(HashMap::new);
+        function_signature: FunctionSignature,
+    ) -> Result<CancellationToken, String> {
+        let mut jobs = lock_recovering(&self.jobs);
 
-        if file_jobs.len() >= MAX_CONCURRENT_JOBS_PER_FILE {
+        if jobs.uri_by_job.contains_key(job_id) {
+            return Err(format!("Job {} is already registered", job_id));
+        }
+        let active_count = jobs.active.get(uri).map_or(0, HashMap::len);
+        if active_count >= MAX_CONCURRENT_JOBS_PER_FILE {
             return Err(format!(
                 "Maximum concurrent implementations ({}) reached for this file. Please wait.",
                 MAX_CONCURRENT_JOBS_PER_FILE
             ));
         }
 
-        file_jobs.insert(
-            job_id.to_string(),
+        let cancellation = CancellationToken::new();
+        jobs.insert(
+            uri,
             ActiveJob {
                 job_id: job_id.to_string(),
-                original_line: line,
                 current_line: line,
                 function_signature,
+                cancellation: cancellation.clone(),
+                state: JobState::Queued,
+                transitions: vec![(JobState::Queued, Instant::now())],
+                last_preview: String::new(),
             },
         );
 
@@ -60,94 +361,292 @@ impl JobTracker {
             job_id,
             uri,
             line,
-            file_jobs.len()
+            active_count + 1
         );
 
-        Ok(())
+        Ok(cancellation)
     }
 
     /// Get current line for a job (may have been adjusted)
     pub fn get_current_line(&self, job_id: &str) -> Option<u32> {
-        let jobs = self.jobs.lock().unwrap();
-        for file_jobs in jobs.values() {
-            if let Some(job) = file_jobs.get(job_id) {
-                return Some(job.current_line);
-            }
-        }
-        None
+        let jobs = lock_recovering(&self.jobs);
+        jobs.get(job_id).map(|(_, job)| job.current_line)
     }
 
     /// Get function signature for fallback matching
-    pub fn get_function_signature(&self, job_id: &str) -> Option<String> {
-        let jobs = self.jobs.lock().unwrap();
-        for file_jobs in jobs.values() {
-            if let Some(job) = file_jobs.get(job_id) {
-                return Some(job.function_signature.clone());
-            }
+    pub fn get_function_signature(&self, job_id: &str) -> Option<FunctionSignature> {
+        let jobs = lock_recovering(&self.jobs);
+        jobs.get(job_id)
+            .map(|(_, job)| job.function_signature.clone())
+    }
+
+    /// Remember the latest streaming preview for status queries.
+    pub fn set_preview(&self, job_id: &str, preview: &str) {
+        let mut jobs = lock_recovering(&self.jobs);
+        if let Some(job) = jobs.get_mut(job_id) {
+            job.last_preview = preview.to_string();
         }
-        None
     }
 
-    /// Adjust lines for all jobs in a file after an edit
-    pub fn adjust_lines_for_edit(
+    /// Adjust lines for all jobs in a file after the client replaced `range` with `new_text`.
+    ///
+    /// This is the only place jobs move: the agent's own edits are shifted for when the
+    /// client reports them back with `didChange`, like the user's.
+    ///
+    /// Jobs below the edit move by the lines added minus the lines removed; jobs whose line
+    /// falls inside the edited range stay pinned. Returns the ids of the jobs at or below the
+    /// edit start, whose functions the edit may have touched; re-anchor them with
+    /// `reanchor_jobs` once the edit is applied.
+    pub fn adjust_lines_for_user_edit(
+        &self,
+        uri: &Url,
+        range: Range,
+        new_text: &str,
+    ) -> Vec<String> {
+        let lines_delta =
+            new_text.matches('\n').count() as i32 - (range.end.line - range.start.line) as i32;
+        // An edit ending at the first column moves its end line as a whole
+        let first_moved_line = if range.end.character == 0 {
+            range.end.line
+        } else {
+            range.end.line + 1
+        };
+        self.shift_lines(uri, range.start.line, first_moved_line, lines_delta)
+    }
+
+    /// Shift jobs at or after `first_moved_line` by `lines_delta`, keeping jobs in
+    /// `edit_start_line..first_moved_line` where they are. Returns the ids of both.
+    fn shift_lines(
         &self,
         uri: &Url,
         edit_start_line: u32,
-        edit_end_line: u32,
+        first_moved_line: u32,
         lines_delta: i32,
-        excluding_job_id: &str,
-    ) {
-        let mut jobs = self.jobs.lock().unwrap();
-        if let Some(file_jobs) = jobs.get_mut(uri) {
+    ) -> Vec<String> {
+        let mut affected = Vec::new();
+        let mut jobs = lock_recovering(&self.jobs);
+        if let Some(file_jobs) = jobs.active.get_mut(uri) {
             for (job_id, job) in file_jobs.iter_mut() {
-                if job_id == excluding_job_id {
-                    continue;
-                }
-
                 // If job's function is AFTER the edited region, shift it
-                if job.current_line > edit_end_line {
+                if job.current_line >= first_moved_line {
                     let new_line = (job.current_line as i32 + lines_delta).max(0) as u32;
                     info!(
                         "Adjusted job {} line: {} -> {} (delta: {})",
                         job_id, job.current_line, new_line, lines_delta
                     );
                     job.current_line = new_line;
+                    affected.push(job_id.clone());
                 }
                 // If job's function OVERLAPS with edited region, keep current line
-                // The merge logic will handle finding the function via signature matching
+                // until it is re-anchored by signature
+                else if job.current_line >= edit_start_line {
+                    info!(
+                        "Job {} at line {} overlaps edited region {}-{}, keeping line",
+                        job_id,
+                        job.current_line,
+                        edit_start_line,
+                        first_moved_line.saturating_sub(1)
+                    );
+                    affected.push(job_id.clone());
+                }
             }
         }
+        affected
     }
 
-    /// Remove job from tracking
-    pub fn complete_job(&self, uri: &Url, job_id: &str) {
-        let mut jobs = self.jobs.lock().unwrap();
-        if let Some(file_jobs) = jobs.get_mut(uri) {
-            file_jobs.remove(job_id);
-            info!(
-                "Completed job {} for {} ({} remaining)",
-                job_id,
-                uri,
-                file_jobs.len()
-            );
+    /// Find the functions of `job_ids` again in the edited document `text`.
+    ///
+    /// A job whose line left its function moves to where the function is now; a queued or
+    /// running job whose function was removed or renamed becomes `Orphaned`. Jobs already
+    /// applying, or whose signature has no name to search for, are left alone. Returns the
+    /// orphaned job ids.
+    pub fn reanchor_jobs(&self, uri: &Url, job_ids: &[String], text: &str) -> Vec<String> {
+        let mut orphaned = Vec::new();
+        let mut jobs = lock_recovering(&self.jobs);
+        let Some(file_jobs) = jobs.active.get_mut(uri) else {
+            return orphaned;
+        };
 
-            // Clean up empty file entries
-            if file_jobs.is_empty() {
-                jobs.remove(uri);
+        for job_id in job_ids {
+            let Some(job) = file_jobs.get_mut(job_id) else {
+                continue;
+            };
+            if !matches!(job.state, JobState::Queued | JobState::Running)
+                || !job.function_signature.has_name()
+            {
+                continue;
+            }
+
+            match relocate_function(text, job.current_line as usize, &job.function_signature) {
+                Some(line) => {
+                    if line as u32 != job.current_line {
+                        info!(
+                            "Re-anchored job {} by signature: {} -> {}",
+                            job_id, job.current_line, line
+                        );
+                        job.current_line = line as u32;
+                    }
+                }
+                None => {
+                    info!(
+                        "Function '{}' of job {} no longer exists",
+                        job.function_signature, job_id
+                    );
+                    if job.transition(JobState::Orphaned) {
+                        orphaned.push(job_id.clone());
+                    }
+                }
             }
         }
+        orphaned
+    }
+
+    /// Move an active job to a non-terminal state (`Running` or `Applying`).
+    ///
+    /// Returns false if the job is no longer active, was orphaned or the transition is illegal.
+    pub fn set_state(&self, uri: &Url, job_id: &str, state: JobState) -> bool {
+        debug_assert!(
+            !state.is_terminal(),
+            "use the finishing methods for {:?}",
+            state
+        );
+        let mut jobs = lock_recovering(&self.jobs);
+        jobs.active
+            .get_mut(uri)
+            .and_then(|file_jobs| file_jobs.get_mut(job_id))
+            // An orphaned job only ever fails; its worker checks the state to say why
+            .is_some_and(|job| job.state != JobState::Orphaned && job.transition(state))
+    }
+
+    /// Remove job from tracking after its edit was applied.
+    ///
+    /// Returns false if the job had already been cancelled or timed out, in which case the
+    /// caller must not report its outcome.
+    pub fn complete_job(&self, uri: &Url, job_id: &str) -> bool {
+        self.finish_job(uri, job_id, JobState::Done, None).is_some()
+    }
+
+    /// Remove job from tracking after it failed on its own with `error`.
+    ///
+    /// Returns false if the job had already been cancelled or timed out, in which case the
+    /// caller must not report its outcome.
+    pub fn fail_job(&self, uri: &Url, job_id: &str, error: &str) -> bool {
+        self.finish_job(uri, job_id, JobState::Failed, Some(error.to_string()))
+            .is_some()
+    }
+
+    /// Cancel an active job: flag it as cancelled and release its slot.
+    ///
+    /// Returns the job's cancellation token, or None if the job had already finished.
+    pub fn cancel_job(&self, uri: &Url, job_id: &str) -> Option<CancellationToken> {
+        self.finish_job(uri, job_id, JobState::Cancelled, None)
+            .map(|job| job.cancellation)
+    }
+
+    /// Cancel an active job known only by its id.
+    ///
+    /// Returns the job's file, or None if the job is unknown or had already finished.
+    pub fn cancel_job_by_id(&self, job_id: &str) -> Option<Url> {
+        let uri = lock_recovering(&self.jobs)
+            .get(job_id)
+            .map(|(uri, _)| uri.clone())?;
+        self.cancel_job(&uri, job_id).map(|_| uri)
+    }
+
+    /// Cancel every active job for a file. Returns the ids of the cancelled jobs.
+    pub fn cancel_all_for_uri(&self, uri: &Url) -> Vec<String> {
+        let job_ids: Vec<String> = self
+            .get_active_jobs(uri)
+            .into_iter()
+            .map(|(job_id, _)| job_id)
+            .collect();
+
+        job_ids
+            .into_iter()
+            .filter(|job_id| self.cancel_job(uri, job_id).is_some())
+            .collect()
+    }
+
+    /// Cancel every active job. Returns the files and ids of the cancelled jobs.
+    pub fn cancel_all(&self) -> Vec<(Url, String)> {
+        let uris: Vec<Url> = {
+            let jobs = lock_recovering(&self.jobs);
+            jobs.active.keys().cloned().collect()
+        };
+
+        uris.into_iter()
+            .flat_map(|uri| {
+                self.cancel_all_for_uri(&uri)
+                    .into_iter()
+                    .map(move |job_id| (uri.clone(), job_id))
+            })
+            .collect()
+    }
+
+    /// Mark a job as timed out: flag it as cancelled and release its slot.
+    ///
+    /// Returns false if the job had already finished, in which case nothing changes.
+    pub fn time_out_job(&self, uri: &Url, job_id: &str) -> bool {
+        self.finish_job(uri, job_id, JobState::TimedOut, None)
+            .is_some()
+    }
+
+    /// Current state of an active or recently finished job, or None if it is unknown.
+    pub fn job_state(&self, job_id: &str) -> Option<JobState> {
+        let jobs = lock_recovering(&self.jobs);
+        if let Some((_, job)) = jobs.get(job_id) {
+            return Some(job.state);
+        }
+        jobs.finished
+            .iter()
+            .rev()
+            .find(|finished| finished.job.job_id == job_id)
+            .map(|finished| finished.job.state)
+    }
+
+    /// Full record of an active or recently finished job, or None if it is unknown.
+    pub fn job_snapshot(&self, job_id: &str) -> Option<JobSnapshot> {
+        let jobs = lock_recovering(&self.jobs);
+        if let Some((uri, job)) = jobs.get(job_id) {
+            return Some(JobSnapshot::new(uri, job, None));
+        }
+        jobs.finished
+            .iter()
+            .rev()
+            .find(|finished| finished.job.job_id == job_id)
+            .map(|finished| JobSnapshot::new(&finished.uri, &finished.job, finished.error.clone()))
     }
 
     /// Get count of active jobs for a file
     pub fn active_job_count(&self, uri: &Url) -> usize {
-        let jobs = self.jobs.lock().unwrap();
-        jobs.get(uri).map(|fj| fj.len()).unwrap_or(0)
+        let jobs = lock_recovering(&self.jobs);
+        jobs.active.get(uri).map(|fj| fj.len()).unwrap_or(0)
+    }
+
+    /// Snapshots of every active job, grouped by file and ordered by line.
+    pub fn active_snapshots(&self) -> Vec<(Url, Vec<JobSnapshot>)> {
+        let jobs = lock_recovering(&self.jobs);
+        let mut by_uri: Vec<(Url, Vec<JobSnapshot>)> = jobs
+            .active
+            .iter()
+            .map(|(uri, file_jobs)| {
+                let mut snapshots: Vec<JobSnapshot> = file_jobs
+                    .values()
+                    .map(|job| JobSnapshot::new(uri, job, None))
+                    .collect();
+                snapshots.sort_by_key(|snapshot| snapshot.current_line);
+                (uri.clone(), snapshots)
+            })
+            .collect();
+        by_uri.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        by_uri
     }
 
     /// Get all active jobs for a file (for sending line updates)
     pub fn get_active_jobs(&self, uri: &Url) -> Vec<(String, u32)> {
-        let jobs = self.jobs.lock().unwrap();
-        jobs.get(uri)
+        let jobs = lock_recovering(&self.jobs);
+        jobs.active
+            .get(uri)
             .map(|file_jobs| {
                 file_jobs
                     .values()
@@ -167,13 +666,90 @@ impl Default for JobTracker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use lsp_types::Position;
+    use std::thread;
+
+    fn signature(raw: &str) -> FunctionSignature {
+        FunctionSignature::parse(raw, "rust")
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range {
+            start: Position {
+                line: start.0,
+                character: start.1,
+            },
+            end: Position {
+                line: end.0,
+                character: end.1,
+            },
+        }
+    }
+
+    /// Move a registered job through `Running` to `Applying`, ready to complete.
+    fn start_applying(tracker: &JobTracker, uri: &Url, job_id: &str) {
+        assert!(tracker.set_state(uri, job_id, JobState::Running));
+        assert!(tracker.set_state(uri, job_id, JobState::Applying));
+    }
+
+    #[test]
+    fn test_user_insertion_above_shifts_job() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        // Two new lines typed at the start of line 3
+        tracker.adjust_lines_for_user_edit(&uri, range((3, 0), (3, 0)), "a\nb\n");
+        assert_eq!(tracker.get_current_line("job1"), Some(12));
+
+        // Deleting lines 0-1 entirely
+        tracker.adjust_lines_for_user_edit(&uri, range((0, 0), (2, 0)), "");
+        assert_eq!(tracker.get_current_line("job1"), Some(10));
+
+        // A newline inserted at the very start of the job's own line moves it down
+        tracker.adjust_lines_for_user_edit(&uri, range((10, 0), (10, 0)), "\n");
+        assert_eq!(tracker.get_current_line("job1"), Some(11));
+    }
+
+    #[test]
+    fn test_user_insertion_below_keeps_job() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        tracker.adjust_lines_for_user_edit(&uri, range((20, 4), (20, 4)), "x\ny\nz\n");
+        tracker.adjust_lines_for_user_edit(&uri, range((11, 0), (15, 0)), "");
+
+        assert_eq!(tracker.get_current_line("job1"), Some(10));
+    }
+
+    #[test]
+    fn test_user_edit_overlapping_job_keeps_line_pinned() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        // Lines 8-12 replaced by a single line
+        tracker.adjust_lines_for_user_edit(&uri, range((8, 2), (12, 5)), "merged");
+        assert_eq!(tracker.get_current_line("job1"), Some(10));
+
+        // A newline typed in the middle of the job's own line
+        tracker.adjust_lines_for_user_edit(&uri, range((10, 6), (10, 6)), "\n");
+        assert_eq!(tracker.get_current_line("job1"), Some(10));
+    }
 
     #[test]
     fn test_register_job() {
         let tracker = JobTracker::new();
         let uri = Url::parse("file:///test.rs").unwrap();
 
-        let result = tracker.register_job(&uri, "job1", 10, "fn foo()".to_string());
+        let result = tracker.register_job(&uri, "job1", 10, signature("fn foo()"));
         assert!(result.is_ok());
         assert_eq!(tracker.active_job_count(&uri), 1);
     }
@@ -186,14 +762,14 @@ mod tests {
         // Register 10 jobs (max)
         for i in 0..10 {
             let result =
-                tracker.register_job(&uri, &format!("job{}", i), i * 10, "fn foo()".to_string());
+                tracker.register_job(&uri, &format!("job{}", i), i * 10, signature("fn foo()"));
             assert!(result.is_ok());
         }
 
         assert_eq!(tracker.active_job_count(&uri), 10);
 
         // 11th job should fail
-        let result = tracker.register_job(&uri, "job11", 100, "fn bar()".to_string());
+        let result = tracker.register_job(&uri, "job11", 100, signature("fn bar()"));
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -206,7 +782,7 @@ mod tests {
         let uri = Url::parse("file:///test.rs").unwrap();
 
         tracker
-            .register_job(&uri, "job1", 10, "fn foo()".to_string())
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
             .unwrap();
 
         assert_eq!(tracker.get_current_line("job1"), Some(10));
@@ -220,17 +796,17 @@ mod tests {
 
         // Register jobs at lines 10, 20, 30
         tracker
-            .register_job(&uri, "job1", 10, "fn foo()".to_string())
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
             .unwrap();
         tracker
-            .register_job(&uri, "job2", 20, "fn bar()".to_string())
+            .register_job(&uri, "job2", 20, signature("fn bar()"))
             .unwrap();
         tracker
-            .register_job(&uri, "job3", 30, "fn baz()".to_string())
+            .register_job(&uri, "job3", 30, signature("fn baz()"))
             .unwrap();
 
-        // Edit at lines 10-15, adding 5 lines (job1 completes)
-        tracker.adjust_lines_for_edit(&uri, 10, 15, 5, "job1");
+        // job1's lines 10-15 replaced with 11 lines, as the client reports the agent's edit
+        tracker.adjust_lines_for_user_edit(&uri, range((10, 0), (16, 0)), &"x\n".repeat(11));
 
         // job1 should be unchanged (it's the one completing)
         assert_eq!(tracker.get_current_line("job1"), Some(10));
@@ -248,14 +824,14 @@ mod tests {
         let uri = Url::parse("file:///test.rs").unwrap();
 
         tracker
-            .register_job(&uri, "job1", 10, "fn foo()".to_string())
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
             .unwrap();
         tracker
-            .register_job(&uri, "job2", 30, "fn bar()".to_string())
+            .register_job(&uri, "job2", 30, signature("fn bar()"))
             .unwrap();
 
         // Edit removes 5 lines
-        tracker.adjust_lines_for_edit(&uri, 10, 20, -5, "job1");
+        tracker.adjust_lines_for_user_edit(&uri, range((10, 0), (21, 0)), &"x\n".repeat(6));
 
         // job2 should shift from 30 to 25
         assert_eq!(tracker.get_current_line("job2"), Some(25));
@@ -267,14 +843,15 @@ mod tests {
         let uri = Url::parse("file:///test.rs").unwrap();
 
         tracker
-            .register_job(&uri, "job1", 10, "fn foo()".to_string())
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
             .unwrap();
         tracker
-            .register_job(&uri, "job2", 20, "fn bar()".to_string())
+            .register_job(&uri, "job2", 20, signature("fn bar()"))
             .unwrap();
 
         assert_eq!(tracker.active_job_count(&uri), 2);
 
+        start_applying(&tracker, &uri, "job1");
         tracker.complete_job(&uri, "job1");
         assert_eq!(tracker.active_job_count(&uri), 1);
         assert_eq!(tracker.get_current_line("job1"), None);
@@ -287,10 +864,10 @@ mod tests {
         let uri = Url::parse("file:///test.rs").unwrap();
 
         tracker
-            .register_job(&uri, "job1", 10, "fn foo()".to_string())
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
             .unwrap();
         tracker
-            .register_job(&uri, "job2", 20, "fn bar()".to_string())
+            .register_job(&uri, "job2", 20, signature("fn bar()"))
             .unwrap();
 
         let jobs = tracker.get_active_jobs(&uri);
@@ -302,6 +879,193 @@ mod tests {
         assert!(job_ids.contains(&"job2".to_string()));
     }
 
+    #[test]
+    fn test_time_out_job() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        let cancelled = tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        assert!(tracker.time_out_job(&uri, "job1"));
+        assert!(cancelled.is_cancelled());
+        assert_eq!(tracker.active_job_count(&uri), 0);
+
+        // A second timeout (or a late completion) is a no-op
+        assert!(!tracker.time_out_job(&uri, "job1"));
+        assert!(!tracker.complete_job(&uri, "job1"));
+        assert_eq!(tracker.active_job_count(&uri), 0);
+        assert_eq!(tracker.job_state("job1"), Some(JobState::TimedOut));
+    }
+
+    #[test]
+    fn test_time_out_completed_job_is_noop() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        let cancelled = tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+        start_applying(&tracker, &uri, "job1");
+        tracker.complete_job(&uri, "job1");
+
+        assert!(!tracker.time_out_job(&uri, "job1"));
+        assert!(!cancelled.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_job() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        let worker_flag = tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        let flag = tracker.cancel_job(&uri, "job1").unwrap();
+        assert!(flag.is_cancelled());
+        assert!(worker_flag.is_cancelled());
+        assert_eq!(tracker.active_job_count(&uri), 0);
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Cancelled));
+
+        // The worker finishing later must not override the cancellation
+        assert!(!tracker.complete_job(&uri, "job1"));
+        assert!(tracker.cancel_job(&uri, "job1").is_none());
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Cancelled));
+    }
+
+    #[test]
+    fn test_cancel_job_by_id() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        let flag = tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        assert_eq!(tracker.cancel_job_by_id("job1"), Some(uri.clone()));
+        assert!(flag.is_cancelled());
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Cancelled));
+        assert_eq!(tracker.cancel_job_by_id("job1"), None);
+        assert_eq!(tracker.cancel_job_by_id("unknown"), None);
+    }
+
+    #[test]
+    fn test_complete_then_cancel() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        let flag = tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        start_applying(&tracker, &uri, "job1");
+        assert!(tracker.complete_job(&uri, "job1"));
+        assert!(tracker.cancel_job(&uri, "job1").is_none());
+        assert!(!flag.is_cancelled());
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Done));
+    }
+
+    #[test]
+    fn test_cancel_all_for_uri_and_cancel_all() {
+        let tracker = JobTracker::new();
+        let uri1 = Url::parse("file:///test1.rs").unwrap();
+        let uri2 = Url::parse("file:///test2.rs").unwrap();
+
+        tracker
+            .register_job(&uri1, "job1", 10, signature("fn foo()"))
+            .unwrap();
+        tracker
+            .register_job(&uri1, "job2", 20, signature("fn bar()"))
+            .unwrap();
+        tracker
+            .register_job(&uri2, "job3", 30, signature("fn baz()"))
+            .unwrap();
+
+        let mut cancelled = tracker.cancel_all_for_uri(&uri1);
+        cancelled.sort();
+        assert_eq!(cancelled, vec!["job1".to_string(), "job2".to_string()]);
+        assert_eq!(tracker.active_job_count(&uri1), 0);
+        assert_eq!(tracker.active_job_count(&uri2), 1);
+
+        assert_eq!(
+            tracker.cancel_all(),
+            vec![(uri2.clone(), "job3".to_string())]
+        );
+        assert_eq!(tracker.active_job_count(&uri2), 0);
+        assert!(tracker.cancel_all().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_cancel_and_complete_single_winner() {
+        use std::sync::Barrier;
+        use std::thread;
+
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        for i in 0..200 {
+            let tracker = JobTracker::new();
+            let job_id = format!("job{}", i);
+            tracker
+                .register_job(&uri, &job_id, 10, signature("fn foo()"))
+                .unwrap();
+            start_applying(&tracker, &uri, &job_id);
+
+            let barrier = Arc::new(Barrier::new(2));
+
+            let cancel_handle = {
+                let (tracker, uri, job_id, barrier) =
+                    (tracker.clone(), uri.clone(), job_id.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    tracker.cancel_job(&uri, &job_id).is_some()
+                })
+            };
+            let complete_handle = {
+                let (tracker, uri, job_id, barrier) =
+                    (tracker.clone(), uri.clone(), job_id.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    tracker.complete_job(&uri, &job_id)
+                })
+            };
+
+            let cancelled = cancel_handle.join().unwrap();
+            let completed = complete_handle.join().unwrap();
+
+            assert!(cancelled ^ completed, "exactly one terminal state must win");
+            let expected = if cancelled {
+                JobState::Cancelled
+            } else {
+                JobState::Done
+            };
+            assert_eq!(tracker.job_state(&job_id), Some(expected));
+            assert_eq!(tracker.active_job_count(&uri), 0);
+        }
+    }
+
+    #[test]
+    fn test_finished_history_is_bounded() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        for i in 0..=FINISHED_JOB_HISTORY {
+            let job_id = format!("job{}", i);
+            tracker
+                .register_job(&uri, &job_id, 10, signature("fn foo()"))
+                .unwrap();
+            start_applying(&tracker, &uri, &job_id);
+            tracker.complete_job(&uri, &job_id);
+        }
+
+        assert_eq!(tracker.job_state("job0"), None);
+        assert_eq!(
+            tracker.job_state(&format!("job{}", FINISHED_JOB_HISTORY)),
+            Some(JobState::Done)
+        );
+    }
+
     #[test]
     fn test_multiple_files() {
         let tracker = JobTracker::new();
@@ -309,18 +1073,481 @@ mod tests {
         let uri2 = Url::parse("file:///test2.rs").unwrap();
 
         tracker
-            .register_job(&uri1, "job1", 10, "fn foo()".to_string())
+            .register_job(&uri1, "job1", 10, signature("fn foo()"))
             .unwrap();
         tracker
-            .register_job(&uri2, "job2", 20, "fn bar()".to_string())
+            .register_job(&uri2, "job2", 20, signature("fn bar()"))
             .unwrap();
 
         assert_eq!(tracker.active_job_count(&uri1), 1);
         assert_eq!(tracker.active_job_count(&uri2), 1);
 
         // Complete job1 shouldn't affect uri2
+        start_applying(&tracker, &uri1, "job1");
         tracker.complete_job(&uri1, "job1");
         assert_eq!(tracker.active_job_count(&uri1), 0);
         assert_eq!(tracker.active_job_count(&uri2), 1);
     }
+
+    const ALL_STATES: [JobState; 8] = [
+        JobState::Queued,
+        JobState::Running,
+        JobState::Applying,
+        JobState::Orphaned,
+        JobState::Done,
+        JobState::Failed,
+        JobState::Cancelled,
+        JobState::TimedOut,
+    ];
+
+    #[test]
+    fn test_state_transitions() {
+        use JobState::*;
+        let legal = [
+            (Queued, Running),
+            (Queued, Orphaned),
+            (Queued, Failed),
+            (Queued, Cancelled),
+            (Queued, TimedOut),
+            (Running, Applying),
+            (Running, Orphaned),
+            (Running, Failed),
+            (Running, Cancelled),
+            (Running, TimedOut),
+            (Applying, Done),
+            (Applying, Failed),
+            (Applying, Cancelled),
+            (Applying, TimedOut),
+            (Orphaned, Failed),
+            (Orphaned, Cancelled),
+            (Orphaned, TimedOut),
+        ];
+
+        for from in ALL_STATES {
+            for to in ALL_STATES {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    legal.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_terminal_states() {
+        let terminal: Vec<JobState> = ALL_STATES.into_iter().filter(|s| s.is_terminal()).collect();
+        assert_eq!(
+            terminal,
+            vec![
+                JobState::Done,
+                JobState::Failed,
+                JobState::Cancelled,
+                JobState::TimedOut
+            ]
+        );
+    }
+
+    #[test]
+    fn test_job_state_follows_lifecycle() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Queued));
+
+        assert!(tracker.set_state(&uri, "job1", JobState::Running));
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Running));
+
+        assert!(tracker.set_state(&uri, "job1", JobState::Applying));
+        assert!(tracker.complete_job(&uri, "job1"));
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Done));
+
+        // A finished job can no longer change state
+        assert!(!tracker.set_state(&uri, "job1", JobState::Running));
+        assert_eq!(tracker.job_state("unknown"), None);
+    }
+
+    #[test]
+    fn test_fail_job_from_queued_and_running() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        let flag = tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+        tracker
+            .register_job(&uri, "job2", 20, signature("fn bar()"))
+            .unwrap();
+        assert!(tracker.set_state(&uri, "job2", JobState::Running));
+
+        assert!(tracker.fail_job(&uri, "job1", "Backend error"));
+        assert!(tracker.fail_job(&uri, "job2", "Backend error"));
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Failed));
+        assert_eq!(tracker.job_state("job2"), Some(JobState::Failed));
+        // Failing is not an abandonment; the worker's own flag stays clear
+        assert!(!flag.is_cancelled());
+    }
+
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "illegal job state transition")
+    )]
+    fn test_illegal_transition_is_rejected() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        // Completing a job that never started applying is a bug in the caller
+        assert!(!tracker.complete_job(&uri, "job1"));
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Queued));
+    }
+
+    #[test]
+    fn test_job_snapshot_active_and_finished() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+        assert!(tracker.set_state(&uri, "job1", JobState::Running));
+        tracker.set_preview("job1", "fn foo() {");
+
+        let snapshot = tracker.job_snapshot("job1").unwrap();
+        assert_eq!(snapshot.uri, uri);
+        assert_eq!(snapshot.state, JobState::Running);
+        assert_eq!(snapshot.function_signature.raw, "fn foo()");
+        assert_eq!(snapshot.current_line, 10);
+        assert_eq!(snapshot.states, vec![JobState::Queued, JobState::Running]);
+        assert_eq!(snapshot.last_preview, "fn foo() {");
+        assert_eq!(snapshot.error, None);
+
+        assert!(tracker.fail_job(&uri, "job1", "Backend error"));
+        let snapshot = tracker.job_snapshot("job1").unwrap();
+        assert_eq!(snapshot.state, JobState::Failed);
+        assert_eq!(
+            snapshot.states,
+            vec![JobState::Queued, JobState::Running, JobState::Failed]
+        );
+        assert_eq!(snapshot.last_preview, "fn foo() {");
+        assert_eq!(snapshot.error.as_deref(), Some("Backend error"));
+
+        // Elapsed time stops at the terminal transition
+        let elapsed = snapshot.elapsed;
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(tracker.job_snapshot("job1").unwrap().elapsed, elapsed);
+
+        assert!(tracker.job_snapshot("unknown").is_none());
+    }
+
+    #[test]
+    fn test_finished_jobs_are_recorded_in_history() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+
+        for job_id in ["job1", "job2", "job3"] {
+            tracker
+                .register_job(&uri, job_id, 10, signature("fn foo()"))
+                .unwrap();
+        }
+        start_applying(&tracker, &uri, "job1");
+        tracker.complete_job(&uri, "job1");
+        tracker.fail_job(&uri, "job2", "Backend error");
+        tracker.cancel_job(&uri, "job3");
+        // Late finishes of abandoned jobs are not recorded twice
+        tracker.time_out_job(&uri, "job3");
+        tracker.record_rejection("job4");
+
+        let outcomes: Vec<(String, JobOutcome)> = tracker
+            .history()
+            .entries()
+            .into_iter()
+            .map(|entry| (entry.job_id, entry.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("job1".to_string(), JobOutcome::Done),
+                ("job2".to_string(), JobOutcome::Failed),
+                ("job3".to_string(), JobOutcome::Cancelled),
+                ("job4".to_string(), JobOutcome::Rejected),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_active_snapshots_grouped_and_sorted() {
+        let tracker = JobTracker::new();
+        let uri1 = Url::parse("file:///a.rs").unwrap();
+        let uri2 = Url::parse("file:///b.rs").unwrap();
+
+        tracker
+            .register_job(&uri2, "job3", 5, signature("fn baz()"))
+            .unwrap();
+        tracker
+            .register_job(&uri1, "job1", 20, signature("fn foo()"))
+            .unwrap();
+        tracker
+            .register_job(&uri1, "job2", 10, signature("fn bar()"))
+            .unwrap();
+
+        let snapshots: Vec<(Url, Vec<String>)> = tracker
+            .active_snapshots()
+            .into_iter()
+            .map(|(uri, jobs)| (uri, jobs.into_iter().map(|job| job.job_id).collect()))
+            .collect();
+        assert_eq!(
+            snapshots,
+            vec![
+                (uri1, vec!["job2".to_string(), "job1".to_string()]),
+                (uri2, vec!["job3".to_string()]),
+            ]
+        );
+    }
+
+    /// Assert the id index and the per-file maps describe exactly the same jobs.
+    fn assert_indexes_consistent(tracker: &JobTracker) {
+        let jobs = tracker.jobs.lock().unwrap();
+        let mut from_files = HashMap::new();
+        for (uri, file_jobs) in &jobs.active {
+            assert!(!file_jobs.is_empty(), "empty job map left for {}", uri);
+            for job_id in file_jobs.keys() {
+                from_files.insert(job_id.clone(), uri.clone());
+            }
+        }
+        assert_eq!(from_files, jobs.uri_by_job);
+    }
+
+    #[test]
+    fn test_job_index_follows_register_complete_cancel() {
+        let tracker = JobTracker::new();
+        let uri1 = Url::parse("file:///a.rs").unwrap();
+        let uri2 = Url::parse("file:///b.rs").unwrap();
+
+        tracker
+            .register_job(&uri1, "job1", 1, signature("fn a()"))
+            .unwrap();
+        tracker
+            .register_job(&uri2, "job2", 2, signature("fn b()"))
+            .unwrap();
+        tracker
+            .register_job(&uri1, "job3", 3, signature("fn c()"))
+            .unwrap();
+        assert_indexes_consistent(&tracker);
+        assert_eq!(tracker.get_current_line("job2"), Some(2));
+        assert_eq!(
+            tracker.get_function_signature("job3"),
+            Some(signature("fn c()"))
+        );
+
+        // Ids are unique across files
+        assert!(tracker
+            .register_job(&uri2, "job1", 9, signature("fn a()"))
+            .is_err());
+        assert_indexes_consistent(&tracker);
+
+        start_applying(&tracker, &uri1, "job1");
+        assert!(tracker.complete_job(&uri1, "job1"));
+        assert!(tracker.cancel_job(&uri2, "job2").is_some());
+        assert_indexes_consistent(&tracker);
+        assert_eq!(tracker.get_current_line("job1"), None);
+        assert_eq!(tracker.get_current_line("job2"), None);
+        assert_eq!(tracker.get_current_line("job3"), Some(3));
+
+        // A finished id can be looked up by state but not as active
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Done));
+        assert_eq!(
+            tracker.cancel_all(),
+            vec![(uri1.clone(), "job3".to_string())]
+        );
+        assert_indexes_consistent(&tracker);
+        assert!(tracker.jobs.lock().unwrap().uri_by_job.is_empty());
+    }
+
+    #[test]
+    fn test_job_index_survives_random_interleavings() {
+        // xorshift64, seeded so failures reproduce
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as usize
+        };
+
+        let tracker = JobTracker::new();
+        let uris: Vec<Url> = (0..3)
+            .map(|i| Url::parse(&format!("file:///file{}.rs", i)).unwrap())
+            .collect();
+        let mut expected: HashMap<String, Url> = HashMap::new();
+
+        for step in 0..2000 {
+            let mut active: Vec<String> = expected.keys().cloned().collect();
+            active.sort();
+            let picked = (!active.is_empty()).then(|| active[next(active.len())].clone());
+
+            match (next(6), picked) {
+                (0 | 1, _) | (_, None) => {
+                    let uri = &uris[next(uris.len())];
+                    let job_id = format!("job{}", step);
+                    if tracker
+                        .register_job(uri, &job_id, step, signature(&format!("fn f{}()", step)))
+                        .is_ok()
+                    {
+                        expected.insert(job_id, uri.clone());
+                    }
+                }
+                (2, Some(job_id)) => {
+                    let uri = expected.remove(&job_id).unwrap();
+                    start_applying(&tracker, &uri, &job_id);
+                    assert!(tracker.complete_job(&uri, &job_id));
+                }
+                (3, Some(job_id)) => {
+                    let uri = expected.remove(&job_id).unwrap();
+                    assert!(tracker.cancel_job(&uri, &job_id).is_some());
+                }
+                (4, Some(job_id)) => {
+                    let uri = expected.remove(&job_id).unwrap();
+                    assert!(tracker.fail_job(&uri, &job_id, "boom"));
+                }
+                (_, Some(job_id)) => {
+                    let uri = expected[&job_id].clone();
+                    tracker.cancel_all_for_uri(&uri);
+                    expected.retain(|_, job_uri| *job_uri != uri);
+                }
+            }
+
+            assert_indexes_consistent(&tracker);
+            assert_eq!(tracker.jobs.lock().unwrap().uri_by_job, expected);
+            for job_id in expected.keys() {
+                assert!(tracker.get_current_line(job_id).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tracker_usable_after_lock_poisoned() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+        tracker
+            .register_job(&uri, "job1", 10, signature("fn foo()"))
+            .unwrap();
+
+        thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _jobs = tracker.jobs.lock().unwrap();
+                panic!("worker panicked while holding the jobs lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(tracker.jobs.is_poisoned());
+
+        assert_eq!(tracker.get_current_line("job1"), Some(10));
+        tracker
+            .register_job(&uri, "job2", 20, signature("fn bar()"))
+            .unwrap();
+        start_applying(&tracker, &uri, "job1");
+        assert!(tracker.complete_job(&uri, "job1"));
+        assert!(tracker.cancel_job(&uri, "job2").is_some());
+        assert_eq!(tracker.active_job_count(&uri), 0);
+    }
+
+    /// Register `job_id` for the function on `line` of `text` and apply a user edit to it.
+    fn edit_job_function(
+        text: &str,
+        line: u32,
+        edit: Range,
+        new_text: &str,
+        edited_text: &str,
+    ) -> (JobTracker, Url, Vec<String>) {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let raw = crate::utils::extract_function_signature(text, line as usize).unwrap();
+        tracker
+            .register_job(&uri, "job1", line, signature(&raw))
+            .unwrap();
+        let affected = tracker.adjust_lines_for_user_edit(&uri, edit, new_text);
+        let orphaned = tracker.reanchor_jobs(&uri, &affected, edited_text);
+        (tracker, uri, orphaned)
+    }
+
+    const TWO_FUNCTIONS: &str = "fn foo() {\n    todo!()\n}\n\nfn bar() {\n    todo!()\n}\n";
+
+    #[test]
+    fn test_in_body_edit_keeps_job_anchored() {
+        let edited = "fn foo() {\n    // thinking\n    todo!()\n}\n\nfn bar() {\n    todo!()\n}\n";
+        let (tracker, _, orphaned) = edit_job_function(
+            TWO_FUNCTIONS,
+            4,
+            range((5, 0), (5, 0)),
+            "    // note\n",
+            edited,
+        );
+
+        assert!(orphaned.is_empty());
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Queued));
+        assert_eq!(tracker.get_current_line("job1"), Some(4));
+    }
+
+    #[test]
+    fn test_edit_moving_signature_re_anchors_job() {
+        // The job sits on foo's signature line; two lines are pasted above it
+        let edited = "// a\n// b\nfn foo() {\n    todo!()\n}\n\nfn bar() {\n    todo!()\n}\n";
+        let (tracker, _, orphaned) = edit_job_function(
+            TWO_FUNCTIONS,
+            0,
+            range((0, 0), (0, 2)),
+            "// a\n// b\nfn",
+            edited,
+        );
+
+        assert!(orphaned.is_empty());
+        assert_eq!(tracker.get_current_line("job1"), Some(2));
+    }
+
+    #[test]
+    fn test_renamed_function_orphans_job() {
+        let edited = "fn foo() {\n    todo!()\n}\n\nfn baz() {\n    todo!()\n}\n";
+        let (tracker, uri, orphaned) =
+            edit_job_function(TWO_FUNCTIONS, 4, range((4, 3), (4, 6)), "baz", edited);
+
+        assert_eq!(orphaned, vec!["job1".to_string()]);
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Orphaned));
+        // An orphaned job cannot start applying, but it can still fail
+        assert!(!tracker.set_state(&uri, "job1", JobState::Running));
+        assert!(tracker.fail_job(&uri, "job1", "Target function no longer exists"));
+    }
+
+    #[test]
+    fn test_deleted_function_orphans_job() {
+        let edited = "fn foo() {\n    todo!()\n}\n\n";
+        let (tracker, _, orphaned) =
+            edit_job_function(TWO_FUNCTIONS, 5, range((4, 0), (7, 0)), "", edited);
+
+        assert_eq!(orphaned, vec!["job1".to_string()]);
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Orphaned));
+    }
+
+    #[test]
+    fn test_unnamed_signature_is_not_orphaned() {
+        let tracker = JobTracker::new();
+        let uri = Url::parse("file:///test.rs").unwrap();
+        tracker
+            .register_job(&uri, "job1", 1, signature("line_1"))
+            .unwrap();
+
+        let affected = tracker.adjust_lines_for_user_edit(&uri, range((1, 0), (1, 4)), "");
+        assert_eq!(affected, vec!["job1".to_string()]);
+        assert!(tracker.reanchor_jobs(&uri, &affected, "").is_empty());
+        assert_eq!(tracker.job_state("job1"), Some(JobState::Queued));
+    }
 }
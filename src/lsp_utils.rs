@@ -1,30 +1,230 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crossbeam_channel::Sender;
-use lsp_server::{Connection, Message, Notification, Request, Response};
+use crossbeam_channel::{Receiver, SendError, Sender};
+use diffy::{DiffOptions, Line};
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
 use lsp_types::{
-    request::ApplyWorkspaceEdit, request::Request as _, ApplyWorkspaceEditParams,
-    OptionalVersionedTextDocumentIdentifier, Position, Range, TextDocumentEdit, TextEdit, Url,
-    WorkspaceEdit,
+    notification::Notification as _, notification::Progress, request::ApplyWorkspaceEdit,
+    request::WorkDoneProgressCreate, AnnotatedTextEdit, ApplyWorkspaceEditParams,
+    ApplyWorkspaceEditResponse, ChangeAnnotation, CreateFile, CreateFileOptions,
+    DocumentChangeOperation, NumberOrString, OptionalVersionedTextDocumentIdentifier, Position,
+    ProgressParams, ProgressParamsValue, Range, ResourceOp, TextDocumentEdit, TextEdit, Url,
+    WorkDoneProgress, WorkDoneProgressCreateParams, WorkspaceEdit,
 };
-use tracing::info;
+use serde::de::DeserializeOwned;
+use tracing::{error, info};
+
+use crate::config::{ENSURE_FINAL_NEWLINE, MAX_LABEL_CHARS};
+use crate::sync_utils::lock_recovering;
+use crate::utils::detect_line_ending;
 
 static REQUEST_ID_COUNTER: AtomicU64 = AtomicU64::new(1);
 
+/// Requests sent to the client whose responses someone is waiting for.
+///
+/// Shared between the main loop, which receives every response, and the workers that sent
+/// the requests.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    waiting: Mutex<HashMap<RequestId, Sender<Response>>>,
+    /// Set by `close`: no responses will be routed any more.
+    closed: AtomicBool,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start waiting for the response to request `id`. After `close`, the wait ends at once.
+    fn register(&self, id: RequestId) -> Receiver<Response> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let mut waiting = lock_recovering(&self.waiting);
+        if !self.closed.load(Ordering::SeqCst) {
+            waiting.insert(id, sender);
+        }
+        receiver
+    }
+
+    /// Stop routing responses, ending every wait for one, as the main loop that receives them
+    /// is gone.
+    pub fn close(&self) {
+        let mut waiting = lock_recovering(&self.waiting);
+        self.closed.store(true, Ordering::SeqCst);
+        waiting.clear();
+    }
+
+    /// Stop waiting for the response to request `id`.
+    fn forget(&self, id: &RequestId) {
+        lock_recovering(&self.waiting).remove(id);
+    }
+
+    /// Hand a response from the client to whoever waits for it. Returns the response back
+    /// when nobody does.
+    pub fn resolve(&self, response: Response) -> Option<Response> {
+        let Some(sender) = lock_recovering(&self.waiting).remove(&response.id) else {
+            return Some(response);
+        };
+        // The waiter may have just given up
+        let _ = sender.send(response);
+        None
+    }
+}
+
+/// Why a request sent to the client produced no result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// The client answered with an error response.
+    Response { code: i32, message: String },
+    /// The client's result does not have the request's result type.
+    InvalidResult(String),
+    /// No answer arrived in time.
+    Timeout,
+    /// Answers to this client's requests are not tracked, so none will arrive.
+    Untracked,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Response { code, message } => write!(f, "{} (code {})", message, code),
+            Self::InvalidResult(e) => write!(f, "invalid result: {}", e),
+            Self::Timeout => f.write_str("no answer in time"),
+            Self::Untracked => f.write_str("answers are not tracked"),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+/// A request sent to the client, awaiting its response with result type `T`.
+///
+/// The response can be taken once, by `wait` or `poll`. Dropping the handle stops waiting;
+/// a response arriving after that is only logged.
+pub struct PendingResponse<T> {
+    id: RequestId,
+    response: Option<(Receiver<Response>, Arc<PendingRequests>)>,
+    result: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> PendingResponse<T> {
+    pub fn id(&self) -> &RequestId {
+        &self.id
+    }
+
+    /// Block up to `timeout` for the response.
+    pub fn wait(&self, timeout: Duration) -> Result<T, RequestError> {
+        let Some((receiver, _)) = &self.response else {
+            return Err(RequestError::Untracked);
+        };
+        let response = receiver
+            .recv_timeout(timeout)
+            .map_err(|_| RequestError::Timeout)?;
+        Self::result_of(response)
+    }
+
+    fn result_of(response: Response) -> Result<T, RequestError> {
+        if let Some(error) = response.error {
+            return Err(RequestError::Response {
+                code: error.code,
+                message: error.message,
+            });
+        }
+        // A `null` result arrives as no result at all
+        serde_json::from_value(response.result.unwrap_or(serde_json::Value::Null))
+            .map_err(|e| RequestError::InvalidResult(e.to_string()))
+    }
+}
+
+impl<T> Drop for PendingResponse<T> {
+    fn drop(&mut self) {
+        if let Some((_, pending_requests)) = &self.response {
+            pending_requests.forget(&self.id);
+        }
+    }
+}
+
+/// What became of an edit sent with `workspace/applyEdit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyEditOutcome {
+    Applied,
+    /// The client refused or failed to apply the edit, for the given reason.
+    Rejected(String),
+    /// No answer arrived in time (or answers are not tracked), so the edit may or may not
+    /// have been applied.
+    Unknown,
+}
+
+impl PendingResponse<ApplyWorkspaceEditResponse> {
+    /// Wait up to `timeout` for the client to say whether it applied the edit.
+    pub fn wait_applied(&self, timeout: Duration) -> ApplyEditOutcome {
+        match self.wait(timeout) {
+            Ok(ApplyWorkspaceEditResponse { applied: true, .. }) => ApplyEditOutcome::Applied,
+            Ok(ApplyWorkspaceEditResponse { failure_reason, .. }) => ApplyEditOutcome::Rejected(
+                failure_reason.unwrap_or_else(|| "no reason given".to_string()),
+            ),
+            Err(RequestError::Response { message, .. }) => ApplyEditOutcome::Rejected(message),
+            Err(e @ RequestError::InvalidResult(_)) => ApplyEditOutcome::Rejected(e.to_string()),
+            Err(RequestError::Timeout) => {
+                error!("No answer to {} within {:?}", self.id, timeout);
+                ApplyEditOutcome::Unknown
+            }
+            Err(RequestError::Untracked) => ApplyEditOutcome::Unknown,
+        }
+    }
+}
+
+/// Whether `error`, returned by one of `LspClient`'s sends, means the client is gone: the
+/// connection's receiving end was dropped, so nothing sent later can reach it either.
+pub fn is_disconnected(error: &(dyn Error + Sync + Send + 'static)) -> bool {
+    error.downcast_ref::<SendError<Message>>().is_some()
+}
+
+/// Label for something the agent does, like `Agent (Claude Code): implement fn parse_header`,
+/// for edits and progress the client may show to the user.
+///
+/// Runs of whitespace, newlines included, become single spaces, and labels longer than
+/// `MAX_LABEL_CHARS` are cut with an ellipsis.
+pub fn agent_label(backend: &str, action: &str) -> String {
+    let label = format!("Agent ({}): {}", backend, action)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if label.chars().count() <= MAX_LABEL_CHARS {
+        return label;
+    }
+    let cut: String = label.chars().take(MAX_LABEL_CHARS - 1).collect();
+    format!("{}…", cut.trim_end())
+}
+
 pub struct LspClient {
     sender: Sender<Message>,
+    /// Where the main loop routes responses; without it, responses are not awaited.
+    pending_requests: Option<Arc<PendingRequests>>,
 }
 
 impl LspClient {
     pub fn new(connection: &Connection) -> Self {
+        Self::new_from_sender(connection.sender.clone())
+    }
+
+    pub fn new_from_sender(sender: Sender<Message>) -> Self {
         Self {
-            sender: connection.sender.clone(),
+            sender,
+            pending_requests: None,
         }
     }
 
-    pub fn new_from_sender(sender: Sender<Message>) -> Self {
-        Self { sender }
+    /// Await responses to this client's requests through `pending_requests`.
+    pub fn with_pending_requests(mut self, pending_requests: Arc<PendingRequests>) -> Self {
+        self.pending_requests = Some(pending_requests);
+        self
     }
 
     pub fn clone_sender(&self) -> Sender<Message> {
@@ -87,26 +287,79 @@ impl LspClient {
         self.send_error(req, lsp_server::ErrorCode::InvalidParams as i32, message)
     }
 
-    pub fn send_apply_edit(&self, edit: WorkspaceEdit) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let params = ApplyWorkspaceEditParams {
-            label: Some("Implement function".to_string()),
-            edit,
-        };
-
+    /// Send request `R` to the client. The returned handle awaits the response when this
+    /// client tracks responses (see `with_pending_requests`).
+    pub fn send_request<R: lsp_types::request::Request>(
+        &self,
+        params: R::Params,
+    ) -> Result<PendingResponse<R::Result>, Box<dyn Error + Sync + Send>> {
         let request_id = REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
-        let request_id = lsp_server::RequestId::from(format!("apply_edit_{}", request_id));
-
+        let request_id = RequestId::from(format!("{}#{}", R::METHOD, request_id));
         let request = Request {
-            id: request_id,
-            method: ApplyWorkspaceEdit::METHOD.to_string(),
+            id: request_id.clone(),
+            method: R::METHOD.to_string(),
             params: serde_json::to_value(params)?,
         };
 
-        info!("Sending workspace/applyEdit request");
+        // Register before sending, so even an immediate answer finds its waiter
+        let pending_response = PendingResponse {
+            id: request_id.clone(),
+            response: self.pending_requests.as_ref().map(|pending_requests| {
+                (
+                    pending_requests.register(request_id),
+                    pending_requests.clone(),
+                )
+            }),
+            result: PhantomData,
+        };
+
+        info!("Sending {} request", R::METHOD);
         self.sender.send(Message::Request(request))?;
+        Ok(pending_response)
+    }
+
+    /// Send `edit` under `label` (see `agent_label`) to the client; the returned handle can
+    /// await the client's answer.
+    pub fn send_apply_edit(
+        &self,
+        label: String,
+        edit: WorkspaceEdit,
+    ) -> Result<PendingResponse<ApplyWorkspaceEditResponse>, Box<dyn Error + Sync + Send>> {
+        self.send_request::<ApplyWorkspaceEdit>(ApplyWorkspaceEditParams {
+            label: Some(label),
+            edit,
+        })
+    }
+
+    /// Ask the client to create the work done progress `token`.
+    ///
+    /// Like other servers, progress is reported right away rather than after the client
+    /// answers; the response is only logged.
+    pub fn send_work_done_progress_create(
+        &self,
+        token: &str,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.send_request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+            token: NumberOrString::String(token.to_string()),
+        })?;
         Ok(())
     }
 
+    /// Report work done progress for `token` with `$/progress`.
+    pub fn send_work_done_progress(
+        &self,
+        token: &str,
+        progress: WorkDoneProgress,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.send_notification(
+            Progress::METHOD,
+            ProgressParams {
+                token: NumberOrString::String(token.to_string()),
+                value: ProgressParamsValue::WorkDone(progress),
+            },
+        )
+    }
+
     pub fn send_notification<T: serde::Serialize>(
         &self,
         method: &str,
@@ -121,71 +374,1052 @@ impl LspClient {
     }
 }
 
+/// The parts of the client's `WorkspaceEdit` support that decide an edit's shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EditCapabilities {
+    /// `workspace.workspaceEdit.documentChanges`: edits may be sent as `documentChanges`
+    /// rather than the legacy `changes` map.
+    pub document_changes: bool,
+    /// `textDocument.publishDiagnostics.versionSupport`: the client tracks document
+    /// versions, so edits may name the version they apply to.
+    pub version_support: bool,
+    /// `workspace.workspaceEdit.changeAnnotationSupport`: edits may carry change
+    /// annotations, which can ask the user to confirm them.
+    pub change_annotations: bool,
+    /// `workspace.workspaceEdit.resourceOperations` includes `create`: edits may create files.
+    pub create_files: bool,
+}
+
+impl EditCapabilities {
+    /// Read the capabilities from the client's `initialize` params; undeclared ones are off.
+    pub fn from_initialize_params(initialize_params: &serde_json::Value) -> Self {
+        let declared = |pointer: &str| {
+            initialize_params
+                .pointer(pointer)
+                .and_then(|supported| supported.as_bool())
+                .unwrap_or(false)
+        };
+        let resource_operations: Vec<&str> = initialize_params
+            .pointer("/capabilities/workspace/workspaceEdit/resourceOperations")
+            .and_then(|operations| operations.as_array())
+            .map(|operations| operations.iter().filter_map(|kind| kind.as_str()).collect())
+            .unwrap_or_default();
+        Self {
+            document_changes: declared("/capabilities/workspace/workspaceEdit/documentChanges"),
+            version_support: declared(
+                "/capabilities/textDocument/publishDiagnostics/versionSupport",
+            ),
+            // Declared as an object of optional settings
+            change_annotations: initialize_params
+                .pointer("/capabilities/workspace/workspaceEdit/changeAnnotationSupport")
+                .is_some_and(|support| support.is_object()),
+            create_files: resource_operations.contains(&"create"),
+        }
+    }
+}
+
+impl Default for EditCapabilities {
+    /// A client supporting every edit shape, assumed until its capabilities are known.
+    fn default() -> Self {
+        Self {
+            document_changes: true,
+            version_support: true,
+            change_annotations: true,
+            create_files: true,
+        }
+    }
+}
+
+/// Id of the change annotation on the agent's edits; each edit carries a single annotation.
+const AGENT_ANNOTATION_ID: &str = "agent";
+
 pub struct WorkspaceEditBuilder;
 
 impl WorkspaceEditBuilder {
-    pub fn create_line_insert(
+    /// Edit turning `old_text` into `new_text`, with one `TextEdit` per changed run of lines.
+    ///
+    /// Unlike a full replacement, marks, folds and undo history outside the changed lines
+    /// survive. Returns None when the texts are equal.
+    pub fn create_minimal_edits(
         uri: &Url,
-        current_text: &str,
-        line: u32,
-        implementation: &str,
-    ) -> WorkspaceEdit {
-        let line_start = Position { line, character: 0 };
-        let line_end = Position {
-            line: line + 1,
-            character: 0,
-        };
+        old_text: &str,
+        new_text: &str,
+        version: Option<i32>,
+        capabilities: EditCapabilities,
+    ) -> Option<WorkspaceEdit> {
+        let patch = DiffOptions::new()
+            .set_context_len(0)
+            .create_patch(old_text, new_text);
+        let mut edits = Vec::new();
 
-        let current_line = current_text.lines().nth(line as usize).unwrap_or("");
+        for hunk in patch.hunks() {
+            let range = hunk.old_range();
+            // Hunk ranges are 1-based, except that an empty range names the line before it
+            let mut old_line = if range.is_empty() {
+                range.start()
+            } else {
+                range.start() - 1
+            };
+            // Start line, end line (exclusive) and replacement text of the current change
+            let mut change: Option<(usize, usize, String)> = None;
 
-        let new_text = format!("{}\n{}\n", current_line, implementation);
+            for line in hunk.lines() {
+                match line {
+                    Line::Context(_) => {
+                        edits.extend(change.take().map(|c| line_range_edit(old_text, c)));
+                        old_line += 1;
+                    }
+                    Line::Delete(_) => {
+                        old_line += 1;
+                        change
+                            .get_or_insert((old_line - 1, old_line, String::new()))
+                            .1 = old_line;
+                    }
+                    Line::Insert(text) => {
+                        change
+                            .get_or_insert((old_line, old_line, String::new()))
+                            .2
+                            .push_str(text);
+                    }
+                }
+            }
+            edits.extend(change.map(|c| line_range_edit(old_text, c)));
+        }
+
+        if edits.is_empty() {
+            return None;
+        }
+        Some(Self::build(uri, edits, version, capabilities))
+    }
+
+    /// Edit replacing the function on lines `start_line..=end_line` of `old_text` with
+    /// `new_impl`, as a single `TextEdit`.
+    ///
+    /// `new_impl` must already be indented; it is written with the document's line
+    /// terminator. A function at the end of a document without a trailing newline is
+    /// replaced without adding one, unless `ENSURE_FINAL_NEWLINE` is on.
+    pub fn create_function_replace(
+        uri: &Url,
+        old_text: &str,
+        start_line: u32,
+        end_line: u32,
+        new_impl: &str,
+        version: Option<i32>,
+        capabilities: EditCapabilities,
+    ) -> WorkspaceEdit {
+        let newline = detect_line_ending(old_text);
+        let mut new_text = new_impl.lines().collect::<Vec<_>>().join(newline);
+        let end = line_start(old_text, end_line as usize + 1);
+        if ENSURE_FINAL_NEWLINE || ends_after_newline(old_text, end) {
+            new_text.push_str(newline);
+        }
 
         let edit = TextEdit {
             range: Range {
-                start: line_start,
-                end: line_end,
+                start: line_start(old_text, start_line as usize),
+                end,
             },
             new_text,
         };
+        Self::build(uri, vec![edit], version, capabilities)
+    }
+
+    /// Edit replacing exactly `range` of the document with `new_text`, as a single
+    /// `TextEdit`.
+    pub fn create_range_replace(
+        uri: &Url,
+        range: Range,
+        new_text: &str,
+        version: Option<i32>,
+        capabilities: EditCapabilities,
+    ) -> WorkspaceEdit {
+        let edit = TextEdit {
+            range,
+            new_text: new_text.to_string(),
+        };
+        Self::build(uri, vec![edit], version, capabilities)
+    }
+
+    /// Edit creating the file `uri` and then inserting `content` into it.
+    ///
+    /// The create operation always comes first, so the client has a document to insert into.
+    /// With `ignore_if_exists` an existing file is left alone (its content is still inserted
+    /// at its start); with `overwrite` it is replaced. Fails when the client cannot create
+    /// files.
+    pub fn create_file_with_content(
+        uri: &Url,
+        content: &str,
+        options: CreateFileOptions,
+        capabilities: EditCapabilities,
+    ) -> Result<WorkspaceEdit, String> {
+        if !capabilities.document_changes || !capabilities.create_files {
+            return Err(format!(
+                "Client does not support creating files, cannot create {}",
+                uri
+            ));
+        }
+
+        let insert = TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version: None,
+            },
+            edits: vec![lsp_types::OneOf::Left(TextEdit {
+                range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                new_text: content.to_string(),
+            })],
+        };
+        Ok(Self::operations(vec![
+            DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                uri: uri.clone(),
+                options: Some(options),
+                annotation_id: None,
+            })),
+            DocumentChangeOperation::Edit(insert),
+        ]))
+    }
 
+    fn operations(operations: Vec<DocumentChangeOperation>) -> WorkspaceEdit {
         WorkspaceEdit {
-            document_changes: Some(lsp_types::DocumentChanges::Edits(vec![TextDocumentEdit {
-                text_document: OptionalVersionedTextDocumentIdentifier {
-                    uri: uri.clone(),
-                    version: None,
-                },
-                edits: vec![lsp_types::OneOf::Left(edit)],
-            }])),
+            document_changes: Some(lsp_types::DocumentChanges::Operations(operations)),
             ..Default::default()
         }
     }
 
-    pub fn create_full_replace(uri: &Url, current_text: &str, new_text: &str) -> WorkspaceEdit {
-        let line_count = current_text.lines().count() as u32;
-        // Ensure we cover the whole file including the last line/newline
-        let start = Position {
-            line: 0,
-            character: 0,
-        };
-        let end = Position {
-            line: line_count + 1, // Go beyond last line to be safe
-            character: 0,
+    /// Attach `annotation` to every text edit in `edit`, for clients that support change
+    /// annotations; others get `edit` unchanged.
+    pub fn annotate(
+        edit: WorkspaceEdit,
+        annotation: ChangeAnnotation,
+        capabilities: EditCapabilities,
+    ) -> WorkspaceEdit {
+        // Annotations only exist in `documentChanges`, never in the legacy `changes` map
+        let Some(lsp_types::DocumentChanges::Edits(document_edits)) = edit.document_changes else {
+            return edit;
         };
+        if !capabilities.change_annotations {
+            return WorkspaceEdit {
+                document_changes: Some(lsp_types::DocumentChanges::Edits(document_edits)),
+                ..edit
+            };
+        }
 
-        let edit = TextEdit {
-            range: Range { start, end },
-            new_text: new_text.to_string(),
-        };
+        let annotation_id = AGENT_ANNOTATION_ID.to_string();
+        let document_edits = document_edits
+            .into_iter()
+            .map(|document_edit| TextDocumentEdit {
+                edits: document_edit
+                    .edits
+                    .into_iter()
+                    .map(|text_edit| match text_edit {
+                        lsp_types::OneOf::Left(text_edit) => {
+                            lsp_types::OneOf::Right(AnnotatedTextEdit {
+                                text_edit,
+                                annotation_id: annotation_id.clone(),
+                            })
+                        }
+                        annotated => annotated,
+                    })
+                    .collect(),
+                ..document_edit
+            })
+            .collect();
+
+        WorkspaceEdit {
+            document_changes: Some(lsp_types::DocumentChanges::Edits(document_edits)),
+            change_annotations: Some(HashMap::from([(annotation_id, annotation)])),
+            ..edit
+        }
+    }
+
+    /// Whether `edit` replaces all of `text` in one piece rather than changing parts of it.
+    pub fn is_whole_document_rewrite(edit: &WorkspaceEdit, text: &str) -> bool {
+        matches!(
+            text_edits(edit).as_slice(),
+            [text_edit] if !text.is_empty()
+                && text_edit.range == Range::new(Position::new(0, 0), end_of_document(text))
+        )
+    }
+
+    /// Whether every text edit in `edit` stays within lines `start_line..=end_line`
+    /// (an insertion right after `end_line` counts as within).
+    pub fn edits_within_lines(edit: &WorkspaceEdit, start_line: u32, end_line: u32) -> bool {
+        text_edits(edit).iter().all(|text_edit| {
+            let range = text_edit.range;
+            range.start.line >= start_line
+                && (range.end.line <= end_line
+                    || (range.end.line == end_line + 1 && range.end.character == 0))
+        })
+    }
+
+    /// Wrap `edits` to `uri` in the shape the client accepts: versioned `documentChanges`,
+    /// unversioned ones without version support, or the legacy `changes` map.
+    fn build(
+        uri: &Url,
+        edits: Vec<TextEdit>,
+        version: Option<i32>,
+        capabilities: EditCapabilities,
+    ) -> WorkspaceEdit {
+        if !capabilities.document_changes {
+            return WorkspaceEdit {
+                changes: Some(HashMap::from([(uri.clone(), edits)])),
+                ..Default::default()
+            };
+        }
 
         WorkspaceEdit {
             document_changes: Some(lsp_types::DocumentChanges::Edits(vec![TextDocumentEdit {
                 text_document: OptionalVersionedTextDocumentIdentifier {
                     uri: uri.clone(),
-                    version: None,
+                    version: version.filter(|_| capabilities.version_support),
                 },
-                edits: vec![lsp_types::OneOf::Left(edit)],
+                edits: edits.into_iter().map(lsp_types::OneOf::Left).collect(),
             }])),
             ..Default::default()
         }
     }
 }
+
+/// Every `TextEdit` in `edit`, whatever shape it was built in.
+fn text_edits(edit: &WorkspaceEdit) -> Vec<&TextEdit> {
+    match (&edit.document_changes, &edit.changes) {
+        (Some(lsp_types::DocumentChanges::Edits(document_edits)), _) => document_edits
+            .iter()
+            .flat_map(|document_edit| &document_edit.edits)
+            .map(|text_edit| match text_edit {
+                lsp_types::OneOf::Left(text_edit) => text_edit,
+                lsp_types::OneOf::Right(annotated) => &annotated.text_edit,
+            })
+            .collect(),
+        (None, Some(changes)) => changes.values().flatten().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Edit replacing lines `start..end` of `text` with `new_text`.
+fn line_range_edit(text: &str, (start, end, new_text): (usize, usize, String)) -> TextEdit {
+    TextEdit {
+        range: Range {
+            start: line_start(text, start),
+            end: line_start(text, end),
+        },
+        new_text,
+    }
+}
+
+/// Position where line `line` of `text` starts, or the end of the document for the line
+/// after the last one (which, without a trailing newline, is on the last line).
+fn line_start(text: &str, line: usize) -> Position {
+    if line < text.split_inclusive('\n').count() {
+        return Position::new(line as u32, 0);
+    }
+    end_of_document(text)
+}
+
+/// Whether the text before `end`, a position from `line_start`, ends with a newline: true
+/// except at the end of a document whose last line has none.
+fn ends_after_newline(text: &str, end: Position) -> bool {
+    end != end_of_document(text) || text.ends_with('\n')
+}
+
+/// Position just past the last character of `text`, in UTF-16 code units.
+///
+/// Text after the last newline (empty when the document ends with one) is the last line.
+fn end_of_document(text: &str) -> Position {
+    let last_line = text.rsplit('\n').next().unwrap_or("");
+    Position::new(
+        text.matches('\n').count() as u32,
+        last_line.encode_utf16().count() as u32,
+    )
+}
+
+/// Text resulting from applying every `TextEdit` in `edit` to `text`.
+#[cfg(test)]
+pub fn apply_workspace_edit(text: &str, edit: &WorkspaceEdit) -> String {
+    let mut edits: Vec<&TextEdit> = match (&edit.document_changes, &edit.changes) {
+        (Some(lsp_types::DocumentChanges::Edits(document_edits)), _) => document_edits
+            .iter()
+            .flat_map(|document_edit| &document_edit.edits)
+            .map(|edit| match edit {
+                lsp_types::OneOf::Left(edit) => edit,
+                lsp_types::OneOf::Right(annotated) => &annotated.text_edit,
+            })
+            .collect(),
+        (None, Some(changes)) => changes.values().flatten().collect(),
+        _ => panic!("Expected text edits"),
+    };
+    // Apply from the bottom up so earlier ranges stay valid
+    edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+
+    let mut result = text.to_string();
+    for edit in edits.into_iter().rev() {
+        let start = utf16_position_to_offset(&result, edit.range.start);
+        let end = utf16_position_to_offset(&result, edit.range.end);
+        result.replace_range(start..end, &edit.new_text);
+    }
+    result
+}
+
+/// The text between the positions of `range`, which are in UTF-16 code units. Positions past
+/// the end of a line or of the document are taken as those ends; None if `range` is reversed.
+pub fn text_in_range(text: &str, range: Range) -> Option<&str> {
+    let start = utf16_position_to_offset(text, range.start);
+    let end = utf16_position_to_offset(text, range.end);
+    text.get(start..end)
+}
+
+/// Byte offset of `position` (in UTF-16 code units) in `text`.
+fn utf16_position_to_offset(text: &str, position: Position) -> usize {
+    let mut line_offset = 0;
+    for _ in 0..position.line {
+        line_offset += text[line_offset..]
+            .find('\n')
+            .map_or(text.len() - line_offset, |i| i + 1);
+    }
+    let line = &text[line_offset..];
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return line_offset + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send an empty edit through a client tracking responses, returning the request id.
+    fn send_tracked_edit(
+        pending_requests: &Arc<PendingRequests>,
+    ) -> (PendingResponse<ApplyWorkspaceEditResponse>, RequestId) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let client =
+            LspClient::new_from_sender(sender).with_pending_requests(pending_requests.clone());
+        let pending_edit = client
+            .send_apply_edit("test".to_string(), WorkspaceEdit::default())
+            .unwrap();
+        let Ok(Message::Request(request)) = receiver.try_recv() else {
+            panic!("Expected the applyEdit request");
+        };
+        (pending_edit, request.id)
+    }
+
+    #[test]
+    fn test_agent_label_is_one_bounded_line() {
+        assert_eq!(
+            agent_label("Claude Code", "implement fn parse_header"),
+            "Agent (Claude Code): implement fn parse_header"
+        );
+        assert_eq!(
+            agent_label("Amp", "implement fn parse(\n    buf: &[u8],\r\n)"),
+            "Agent (Amp): implement fn parse( buf: &[u8], )"
+        );
+
+        let long = agent_label("Amp", &format!("implement fn {}", "ä".repeat(200)));
+        assert_eq!(long.chars().count(), MAX_LABEL_CHARS);
+        assert!(long.starts_with("Agent (Amp): implement fn ää"));
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn test_pending_edit_outcomes() {
+        let pending_requests = Arc::new(PendingRequests::new());
+        let timeout = Duration::from_millis(100);
+
+        let (pending_edit, id) = send_tracked_edit(&pending_requests);
+        assert!(pending_requests
+            .resolve(Response::new_ok(id, serde_json::json!({ "applied": true })))
+            .is_none());
+        assert_eq!(
+            pending_edit.wait_applied(timeout),
+            ApplyEditOutcome::Applied
+        );
+
+        let (pending_edit, id) = send_tracked_edit(&pending_requests);
+        pending_requests.resolve(Response::new_ok(
+            id,
+            serde_json::json!({ "applied": false, "failureReason": "stale" }),
+        ));
+        assert_eq!(
+            pending_edit.wait_applied(timeout),
+            ApplyEditOutcome::Rejected("stale".to_string())
+        );
+
+        let (pending_edit, id) = send_tracked_edit(&pending_requests);
+        pending_requests.resolve(Response::new_err(id, -32603, "internal".to_string()));
+        assert_eq!(
+            pending_edit.wait_applied(timeout),
+            ApplyEditOutcome::Rejected("internal".to_string())
+        );
+
+        let (pending_edit, id) = send_tracked_edit(&pending_requests);
+        assert_eq!(
+            pending_edit.wait_applied(timeout),
+            ApplyEditOutcome::Unknown
+        );
+        drop(pending_edit);
+        // A late answer is no longer awaited, so it is handed back to the caller
+        let late = Response::new_ok(id, serde_json::json!({ "applied": true }));
+        assert!(pending_requests.resolve(late).is_some());
+    }
+
+    #[test]
+    fn test_send_request_routes_typed_responses() {
+        let pending_requests = Arc::new(PendingRequests::new());
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let client =
+            LspClient::new_from_sender(sender).with_pending_requests(pending_requests.clone());
+        let params = || WorkDoneProgressCreateParams {
+            token: NumberOrString::String("job".to_string()),
+        };
+
+        let first = client
+            .send_request::<WorkDoneProgressCreate>(params())
+            .unwrap();
+        let second = client
+            .send_request::<WorkDoneProgressCreate>(params())
+            .unwrap();
+        let sent: Vec<RequestId> = receiver
+            .try_iter()
+            .map(|msg| match msg {
+                Message::Request(request) => {
+                    assert_eq!(request.method, "window/workDoneProgress/create");
+                    request.id
+                }
+                other => panic!("Expected a request, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(sent, vec![first.id().clone(), second.id().clone()]);
+        assert_ne!(first.id(), second.id());
+
+        // Each answer reaches only the request it belongs to
+        assert_eq!(first.wait(Duration::ZERO), Err(RequestError::Timeout));
+        pending_requests.resolve(Response::new_err(
+            second.id().clone(),
+            -32600,
+            "unsupported".to_string(),
+        ));
+        assert_eq!(first.wait(Duration::ZERO), Err(RequestError::Timeout));
+        pending_requests.resolve(Response::new_ok(first.id().clone(), ()));
+        assert_eq!(first.wait(Duration::ZERO), Ok(()));
+        assert_eq!(
+            second.wait(Duration::ZERO),
+            Err(RequestError::Response {
+                code: -32600,
+                message: "unsupported".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_send_request_timeout_and_invalid_result() {
+        let pending_requests = Arc::new(PendingRequests::new());
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let client =
+            LspClient::new_from_sender(sender).with_pending_requests(pending_requests.clone());
+
+        let pending = client
+            .send_apply_edit("test".to_string(), WorkspaceEdit::default())
+            .unwrap();
+        assert_eq!(
+            pending.wait(Duration::from_millis(50)),
+            Err(RequestError::Timeout)
+        );
+
+        let pending = client
+            .send_apply_edit("test".to_string(), WorkspaceEdit::default())
+            .unwrap();
+        pending_requests.resolve(Response::new_ok(pending.id().clone(), "yes"));
+        assert!(matches!(
+            pending.wait(Duration::ZERO),
+            Err(RequestError::InvalidResult(_))
+        ));
+
+        // Dropping the handle stops waiting for its answer
+        let id = client
+            .send_apply_edit("test".to_string(), WorkspaceEdit::default())
+            .unwrap()
+            .id()
+            .clone();
+        assert!(pending_requests
+            .resolve(Response::new_ok(id, serde_json::json!({ "applied": true })))
+            .is_some());
+    }
+
+    #[test]
+    fn test_untracked_edit_outcome_is_unknown() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let pending_edit = LspClient::new_from_sender(sender)
+            .send_apply_edit("test".to_string(), WorkspaceEdit::default())
+            .unwrap();
+        assert_eq!(
+            pending_edit.wait_applied(Duration::ZERO),
+            ApplyEditOutcome::Unknown
+        );
+    }
+
+    fn minimal_edits(old_text: &str, new_text: &str) -> Option<Vec<TextEdit>> {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let edit = WorkspaceEditBuilder::create_minimal_edits(
+            &uri,
+            old_text,
+            new_text,
+            Some(3),
+            EditCapabilities::default(),
+        )?;
+        assert_eq!(apply_workspace_edit(old_text, &edit), new_text);
+
+        let Some(lsp_types::DocumentChanges::Edits(document_edits)) = edit.document_changes else {
+            panic!("Expected DocumentChanges::Edits");
+        };
+        assert_eq!(document_edits[0].text_document.version, Some(3));
+        Some(
+            document_edits[0]
+                .edits
+                .iter()
+                .map(|edit| match edit {
+                    lsp_types::OneOf::Left(edit) => edit.clone(),
+                    lsp_types::OneOf::Right(annotated) => annotated.text_edit.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range {
+            start: Position::new(start.0, start.1),
+            end: Position::new(end.0, end.1),
+        }
+    }
+
+    #[test]
+    fn test_edit_shape_follows_capabilities() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let edit_with = |document_changes, version_support| {
+            let capabilities = EditCapabilities {
+                document_changes,
+                version_support,
+                change_annotations: false,
+                create_files: false,
+            };
+            let edit = WorkspaceEditBuilder::create_minimal_edits(
+                &uri,
+                "a\nb\n",
+                "a\nc\n",
+                Some(4),
+                capabilities,
+            )
+            .unwrap();
+            assert_eq!(apply_workspace_edit("a\nb\n", &edit), "a\nc\n");
+            edit
+        };
+        let version = |edit: WorkspaceEdit| match edit.document_changes {
+            Some(lsp_types::DocumentChanges::Edits(document_edits)) => {
+                document_edits[0].text_document.version
+            }
+            other => panic!("Expected DocumentChanges::Edits, got {:?}", other),
+        };
+
+        let edit = edit_with(true, true);
+        assert!(edit.changes.is_none());
+        assert_eq!(version(edit), Some(4));
+
+        let edit = edit_with(true, false);
+        assert!(edit.changes.is_none());
+        assert_eq!(version(edit), None);
+
+        // Without documentChanges support, versions cannot be sent at all
+        for version_support in [true, false] {
+            let edit = edit_with(false, version_support);
+            assert!(edit.document_changes.is_none());
+            let changes = edit.changes.unwrap();
+            assert_eq!(changes.len(), 1);
+            assert_eq!(changes[&uri].len(), 1);
+            assert_eq!(changes[&uri][0].new_text, "c\n");
+        }
+    }
+
+    #[test]
+    fn test_edit_capabilities_from_initialize_params() {
+        let capabilities = EditCapabilities::from_initialize_params(&serde_json::json!({
+            "capabilities": {
+                "workspace": { "workspaceEdit": { "documentChanges": true } },
+                "textDocument": { "publishDiagnostics": { "versionSupport": false } }
+            }
+        }));
+        assert!(capabilities.document_changes);
+        assert!(!capabilities.version_support);
+        assert!(!capabilities.change_annotations);
+        assert!(!capabilities.create_files);
+
+        let capabilities =
+            EditCapabilities::from_initialize_params(&serde_json::json!({ "capabilities": {} }));
+        assert!(!capabilities.document_changes);
+        assert!(!capabilities.version_support);
+
+        let capabilities = EditCapabilities::from_initialize_params(&serde_json::json!({
+            "capabilities": {
+                "workspace": { "workspaceEdit": { "resourceOperations": ["create", "delete"] } }
+            }
+        }));
+        assert!(capabilities.create_files);
+    }
+
+    #[test]
+    fn test_create_file_comes_before_its_content() {
+        let uri = Url::parse("file:///project/tests/foo_test.rs").unwrap();
+        let edit = WorkspaceEditBuilder::create_file_with_content(
+            &uri,
+            "#[test]\nfn foo() {}\n",
+            CreateFileOptions {
+                overwrite: Some(false),
+                ignore_if_exists: Some(true),
+            },
+            EditCapabilities::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&edit).unwrap(),
+            serde_json::json!({
+                "documentChanges": [
+                    {
+                        "kind": "create",
+                        "uri": "file:///project/tests/foo_test.rs",
+                        "options": { "overwrite": false, "ignoreIfExists": true }
+                    },
+                    {
+                        "textDocument": { "uri": "file:///project/tests/foo_test.rs", "version": null },
+                        "edits": [{
+                            "range": {
+                                "start": { "line": 0, "character": 0 },
+                                "end": { "line": 0, "character": 0 }
+                            },
+                            "newText": "#[test]\nfn foo() {}\n"
+                        }]
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_resource_operations_fail_without_client_support() {
+        let uri = Url::parse("file:///project/new.rs").unwrap();
+        let create_options = || CreateFileOptions {
+            overwrite: None,
+            ignore_if_exists: None,
+        };
+        let no_resources = EditCapabilities {
+            create_files: false,
+            ..EditCapabilities::default()
+        };
+        let error = WorkspaceEditBuilder::create_file_with_content(
+            &uri,
+            "",
+            create_options(),
+            no_resources,
+        )
+        .unwrap_err();
+        assert!(
+            error.contains("does not support creating files"),
+            "{}",
+            error
+        );
+
+        // Resource operations only exist in documentChanges
+        let legacy = EditCapabilities {
+            document_changes: false,
+            ..EditCapabilities::default()
+        };
+        assert!(
+            WorkspaceEditBuilder::create_file_with_content(&uri, "", create_options(), legacy)
+                .is_err()
+        );
+    }
+
+    fn annotated_edit(capabilities: EditCapabilities) -> WorkspaceEdit {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let edit = WorkspaceEditBuilder::create_minimal_edits(
+            &uri,
+            "a\nb\nc\nd\n",
+            "a\nB\nc\nD\n",
+            Some(2),
+            capabilities,
+        )
+        .unwrap();
+        WorkspaceEditBuilder::annotate(
+            edit,
+            ChangeAnnotation {
+                label: "Implement function".to_string(),
+                needs_confirmation: Some(true),
+                description: Some("fn foo() {".to_string()),
+            },
+            capabilities,
+        )
+    }
+
+    #[test]
+    fn test_annotated_edits_reference_existing_annotations() {
+        let edit = annotated_edit(EditCapabilities::default());
+        assert_eq!(apply_workspace_edit("a\nb\nc\nd\n", &edit), "a\nB\nc\nD\n");
+
+        let annotations = edit.change_annotations.clone().unwrap();
+        let Some(lsp_types::DocumentChanges::Edits(document_edits)) = edit.document_changes else {
+            panic!("Expected DocumentChanges::Edits");
+        };
+        assert_eq!(document_edits[0].edits.len(), 2);
+        for text_edit in &document_edits[0].edits {
+            let lsp_types::OneOf::Right(annotated) = text_edit else {
+                panic!("Expected AnnotatedTextEdit, got {:?}", text_edit);
+            };
+            let annotation = &annotations[&annotated.annotation_id];
+            assert_eq!(annotation.needs_confirmation, Some(true));
+        }
+    }
+
+    #[test]
+    fn test_unsupported_clients_get_unannotated_edits() {
+        let capabilities = EditCapabilities {
+            change_annotations: false,
+            ..EditCapabilities::default()
+        };
+        let edit = annotated_edit(capabilities);
+        assert!(edit.change_annotations.is_none());
+        let Some(lsp_types::DocumentChanges::Edits(document_edits)) = edit.document_changes else {
+            panic!("Expected DocumentChanges::Edits");
+        };
+        assert!(document_edits[0]
+            .edits
+            .iter()
+            .all(|text_edit| matches!(text_edit, lsp_types::OneOf::Left(_))));
+
+        // The legacy changes map cannot carry annotations at all
+        let edit = annotated_edit(EditCapabilities {
+            document_changes: false,
+            ..EditCapabilities::default()
+        });
+        assert!(edit.change_annotations.is_none());
+        assert!(edit.changes.is_some());
+    }
+
+    #[test]
+    fn test_is_whole_document_rewrite() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let capabilities = EditCapabilities::default();
+        let rewrite =
+            WorkspaceEditBuilder::create_minimal_edits(&uri, "a\nb", "x\ny\n", None, capabilities)
+                .unwrap();
+        assert!(WorkspaceEditBuilder::is_whole_document_rewrite(
+            &rewrite, "a\nb"
+        ));
+
+        let partial = WorkspaceEditBuilder::create_minimal_edits(
+            &uri,
+            "a\nb\n",
+            "a\nc\n",
+            None,
+            capabilities,
+        )
+        .unwrap();
+        assert!(!WorkspaceEditBuilder::is_whole_document_rewrite(
+            &partial, "a\nb\n"
+        ));
+    }
+
+    #[test]
+    fn test_end_of_document_empty_document() {
+        assert_eq!(end_of_document(""), Position::new(0, 0));
+    }
+
+    #[test]
+    fn test_end_of_document_single_line() {
+        assert_eq!(end_of_document("fn foo() {}"), Position::new(0, 11));
+        assert_eq!(end_of_document("fn foo() {}\n"), Position::new(1, 0));
+    }
+
+    #[test]
+    fn test_end_of_document_with_and_without_trailing_newline() {
+        assert_eq!(end_of_document("a\nbc"), Position::new(1, 2));
+        assert_eq!(end_of_document("a\nbc\n"), Position::new(2, 0));
+        assert_eq!(end_of_document("a\nbc\n\n\n"), Position::new(4, 0));
+        assert_eq!(end_of_document("\n"), Position::new(1, 0));
+    }
+
+    #[test]
+    fn test_end_of_document_measures_last_line_in_utf16() {
+        assert_eq!(end_of_document("a\r\n\"😀é\""), Position::new(1, 5));
+        assert_eq!(end_of_document("a\r\nb\r\n"), Position::new(2, 0));
+    }
+
+    #[test]
+    fn test_text_in_range_counts_utf16_units() {
+        let text = "fn a() {\n    let s = \"😀é\";\n}\n";
+        assert_eq!(text_in_range(text, range((1, 13), (1, 16))), Some("😀é"));
+        assert_eq!(
+            text_in_range(text, range((0, 8), (2, 1))),
+            Some("\n    let s = \"😀é\";\n}")
+        );
+        // Past the end of a line or of the document
+        assert_eq!(text_in_range(text, range((2, 0), (9, 0))), Some("}\n"));
+        assert_eq!(text_in_range(text, range((1, 0), (0, 0))), None);
+    }
+
+    #[test]
+    fn test_function_replace_matches_replace_function_in_document() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let three_functions = "fn first() {\n    todo!()\n}\n\nfn middle(x: i32) -> i32 {\n    todo!()\n}\n\nfn last() {\n}";
+        let cases: &[(&str, &[(usize, &str)])] = &[
+            (
+                three_functions,
+                &[
+                    (0, "fn first() {\n    1\n}"),
+                    (
+                        5,
+                        "fn middle(x: i32) -> i32 {\n    let y = x;\n    y * 2\n}",
+                    ),
+                    (8, "fn last() {}"),
+                ],
+            ),
+            (
+                "fn only() {\n    todo!()\n}",
+                &[(1, "fn only() {\n    42\n}")],
+            ),
+        ];
+
+        for (functions, replacements) in cases {
+            for trailing_newline in [false, true] {
+                for newline in ["\n", "\r\n"] {
+                    let mut old_text = functions.replace('\n', newline);
+                    if trailing_newline {
+                        old_text.push_str(newline);
+                    }
+                    for (line, new_impl) in replacements.iter() {
+                        let (expected, start_line, end_line, _) =
+                            crate::utils::replace_function_in_document(
+                                &old_text, *line, new_impl, None,
+                            )
+                            .unwrap();
+                        let edit = WorkspaceEditBuilder::create_function_replace(
+                            &uri,
+                            &old_text,
+                            start_line,
+                            end_line,
+                            new_impl,
+                            Some(1),
+                            EditCapabilities::default(),
+                        );
+                        assert_eq!(text_edits(&edit).len(), 1);
+                        assert_eq!(
+                            apply_workspace_edit(&old_text, &edit),
+                            expected,
+                            "{:?} at line {}",
+                            old_text,
+                            line
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_edits_within_lines() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let old_text = "a\nb\nc\nd\n";
+        let within = |new_text: &str, start_line: u32, end_line: u32| {
+            let edit = WorkspaceEditBuilder::create_minimal_edits(
+                &uri,
+                old_text,
+                new_text,
+                None,
+                EditCapabilities::default(),
+            )
+            .unwrap();
+            WorkspaceEditBuilder::edits_within_lines(&edit, start_line, end_line)
+        };
+
+        assert!(within("a\nB\nc\nd\n", 1, 2));
+        // Appending right after the last line still belongs to the range
+        assert!(within("a\nb\nc\nx\nd\n", 1, 2));
+        assert!(!within("a\nb\nc\nD\n", 1, 2));
+        assert!(!within("A\nb\nc\nd\n", 1, 2));
+    }
+
+    #[test]
+    fn test_minimal_edits_identical_text() {
+        assert!(minimal_edits("fn foo() {}\n", "fn foo() {}\n").is_none());
+        assert!(minimal_edits("", "").is_none());
+    }
+
+    #[test]
+    fn test_minimal_edits_single_changed_line() {
+        let edits =
+            minimal_edits("fn foo() {\n    todo!()\n}\n", "fn foo() {\n    42\n}\n").unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range, range((1, 0), (2, 0)));
+        assert_eq!(edits[0].new_text, "    42\n");
+    }
+
+    #[test]
+    fn test_minimal_edits_one_edit_per_hunk() {
+        let old_text = "a\nb\nc\nd\ne\nf\n";
+        let new_text = "a\nB\nc\nd\nE\nF\nf\n";
+        let edits = minimal_edits(old_text, new_text).unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].range, range((1, 0), (2, 0)));
+        assert_eq!(edits[1].range, range((4, 0), (5, 0)));
+        assert_eq!(edits[1].new_text, "E\nF\n");
+    }
+
+    #[test]
+    fn test_minimal_edits_pure_insertions() {
+        let edits = minimal_edits("b\n", "a\nb\n").unwrap();
+        assert_eq!(edits[0].range, range((0, 0), (0, 0)));
+
+        let edits = minimal_edits("a\n", "a\nb\n").unwrap();
+        assert_eq!(edits[0].range, range((1, 0), (1, 0)));
+
+        minimal_edits("a\nc\n", "a\nb\nc\n").unwrap();
+        minimal_edits("", "a\nb\n").unwrap();
+    }
+
+    #[test]
+    fn test_minimal_edits_pure_deletions() {
+        let edits = minimal_edits("a\nb\nc\n", "a\nc\n").unwrap();
+        assert_eq!(edits[0].range, range((1, 0), (2, 0)));
+        assert_eq!(edits[0].new_text, "");
+
+        minimal_edits("a\nb\n", "").unwrap();
+    }
+
+    #[test]
+    fn test_minimal_edits_trailing_newline_changes() {
+        // Adding the final newline rewrites only the last line
+        let edits = minimal_edits("a\nb", "a\nb\n").unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range, range((1, 0), (1, 1)));
+
+        let edits = minimal_edits("a\nb\n", "a\nb").unwrap();
+        assert_eq!(edits[0].range, range((1, 0), (2, 0)));
+
+        minimal_edits("a\nb", "a\nb\nc").unwrap();
+        minimal_edits("a\nb", "a\nc").unwrap();
+    }
+
+    #[test]
+    fn test_minimal_edits_crlf_and_non_ascii() {
+        minimal_edits(
+            "fn a() {\r\n    todo!()\r\n}\r\n",
+            "fn a() {\r\n    \"é\"\r\n}\r\n",
+        )
+        .unwrap();
+
+        // Without a trailing newline the end of the document is measured in UTF-16 units
+        let edits = minimal_edits("x\nlet s = \"😀é\";", "x\nlet s = \"😀é\";\ny\n").unwrap();
+        assert_eq!(edits[0].range, range((1, 0), (1, 14)));
+    }
+}
@@ -1,36 +1,85 @@
 mod amp;
+mod apply_coordinator;
 mod backend;
+mod cancellation;
 mod claude_code;
 mod config;
 mod document_store;
+mod formatter;
 mod handlers;
+mod imports;
+mod job_history;
+mod job_queue;
 mod job_tracker;
 mod lsp_utils;
 mod opencode;
+mod path_filter;
+mod rate_limiter;
+mod recorder;
+mod session;
+mod signature;
+mod sync_utils;
+mod timer;
 mod utils;
+mod validation;
+mod worker_pool;
+
+#[cfg(all(feature = "test-hooks", not(debug_assertions)))]
+compile_error!(
+    "the test-hooks feature is for tests only and must not be enabled in release builds"
+);
 
 use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 
-use lsp_server::{Connection, Message};
+use lsp_server::{Connection, Message, Response};
+use lsp_types::request::{Initialize, Request as _};
 use lsp_types::{
-    CodeActionKind, CodeActionOptions, CodeActionProviderCapability, CompletionOptions,
-    ExecuteCommandOptions, InitializeParams, ServerCapabilities, TextDocumentSyncCapability,
-    TextDocumentSyncKind,
+    CodeActionOptions, CodeActionProviderCapability, CompletionOptions, ExecuteCommandOptions,
+    InitializeParams, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
 };
-use tracing::{info, Level};
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use crate::apply_coordinator::ApplyCoordinator;
+use crate::backend::{create_backend, Backend, BackendSettings};
+use crate::config::{
+    CompletionMode, APPLY_BATCH_WINDOW_MS, COMPLETION_POOL_SIZE, MAX_PENDING_JOBS,
+    MAX_RUNNING_JOBS, SHUTDOWN_FLUSH_TIMEOUT_MS, WORKER_POOL_SIZE,
+};
 use crate::document_store::DocumentStore;
 use crate::handlers::{
-    send_backend_info_notification, NotificationHandler, RequestHandler, COMMAND_IMPL_FUNCTION,
+    cancel_all_jobs, MethodNames, NotificationHandler, PendingApply, RequestHandler,
 };
+use crate::job_queue::JobQueue;
 use crate::job_tracker::JobTracker;
+use crate::lsp_utils::PendingRequests;
+use crate::path_filter::PathFilter;
+use crate::session::SessionSettings;
+use crate::timer::Timer;
+use crate::worker_pool::WorkerPool;
 
 struct Server {
     connection: Connection,
     document_store: Arc<DocumentStore>,
     job_tracker: Arc<JobTracker>,
+    job_queue: Arc<JobQueue>,
+    backend: Arc<dyn Backend>,
+    session: Arc<SessionSettings>,
+    /// Command and notification names, fixed at initialize.
+    methods: Arc<MethodNames>,
+    /// Files the agent may work on, fixed at initialize.
+    path_filter: Arc<PathFilter>,
+    worker_pool: Arc<WorkerPool>,
+    /// Requests sent to the client whose responses workers are waiting for.
+    pending_requests: Arc<PendingRequests>,
+    /// Groups finished jobs on the same document into one edit.
+    apply_coordinator: Arc<ApplyCoordinator<PendingApply>>,
+    /// Threads asking the backend for completions, apart from the implementation workers.
+    completion_pool: Arc<WorkerPool>,
+    /// Runs deadlines, such as the `null` answer to a completion the backend is late with.
+    timer: Arc<Timer>,
 }
 
 impl Server {
@@ -39,32 +88,77 @@ impl Server {
             connection,
             document_store: Arc::new(DocumentStore::new()),
             job_tracker: Arc::new(JobTracker::new()),
+            job_queue: Arc::new(JobQueue::new(MAX_RUNNING_JOBS, MAX_PENDING_JOBS)),
+            backend: create_backend(BackendSettings::default()),
+            session: Arc::new(SessionSettings::new()),
+            methods: Arc::new(MethodNames::default()),
+            path_filter: Arc::new(PathFilter::default()),
+            worker_pool: Arc::new(WorkerPool::new(WORKER_POOL_SIZE)),
+            pending_requests: Arc::new(PendingRequests::new()),
+            apply_coordinator: Arc::new(ApplyCoordinator::new(Duration::from_millis(
+                APPLY_BATCH_WINDOW_MS,
+            ))),
+            completion_pool: Arc::new(WorkerPool::new(COMPLETION_POOL_SIZE)),
+            timer: Arc::new(Timer::new()),
         }
     }
 
-    fn initialize(&self) -> Result<serde_json::Value, Box<dyn Error + Sync + Send>> {
+    /// The `initialize` response: capabilities advertising the namespaced commands.
+    fn initialize_result(&self) -> Result<serde_json::Value, Box<dyn Error + Sync + Send>> {
         let capabilities = ServerCapabilities {
             text_document_sync: Some(TextDocumentSyncCapability::Kind(
                 TextDocumentSyncKind::INCREMENTAL,
             )),
-            completion_provider: Some(CompletionOptions {
-                resolve_provider: Some(false),
-                trigger_characters: Some(vec![".".to_string()]),
-                ..Default::default()
-            }),
+            // Clients that are never offered completion do not pop up empty lists
+            completion_provider: (self.session.completion_mode() != CompletionMode::Disabled).then(
+                || CompletionOptions {
+                    resolve_provider: Some(false),
+                    trigger_characters: Some(vec![".".to_string()]),
+                    ..Default::default()
+                },
+            ),
             code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
-                code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+                code_action_kinds: Some(vec![self.session.code_action_kind().code_action_kind()]),
                 ..Default::default()
             })),
             execute_command_provider: Some(ExecuteCommandOptions {
-                commands: vec![COMMAND_IMPL_FUNCTION.to_string()],
+                commands: self.methods.commands(),
                 ..Default::default()
             }),
             ..Default::default()
         };
 
-        let server_capabilities = serde_json::to_value(capabilities)?;
-        let initialization_params = self.connection.initialize(server_capabilities)?;
+        Ok(serde_json::json!({
+            "capabilities": serde_json::to_value(capabilities)?,
+        }))
+    }
+
+    fn initialize(&mut self) -> Result<serde_json::Value, Box<dyn Error + Sync + Send>> {
+        let (initialize_id, initialization_params) = self.connection.initialize_start()?;
+        // Settings may also come from the workspace's config file and the environment
+        let initialization_params = config::resolve_initialize_params(&initialization_params);
+
+        // The command namespace must be known before capabilities are advertised
+        self.methods = Arc::new(MethodNames::from_initialization_options(
+            initialization_params.get("initializationOptions"),
+        ));
+        info!("Advertising commands {:?}", self.methods.commands());
+        self.path_filter = Arc::new(PathFilter::from_initialize_params(&initialization_params));
+        self.session.set_client_capabilities(&initialization_params);
+        let backend_settings = BackendSettings::from_initialize_params(&initialization_params);
+        // No job exists before initialize, so the tracker and queue can start over for the
+        // backend the session picked
+        self.job_tracker = Arc::new(JobTracker::new().with_backend(backend_settings.backend));
+        self.job_queue = Arc::new(
+            JobQueue::new(MAX_RUNNING_JOBS, MAX_PENDING_JOBS)
+                .with_backend(backend_settings.backend),
+        );
+        self.backend = create_backend(backend_settings);
+
+        // The client's `initialized` is left to the main loop, which handles it like the
+        // one a restarted client session sends
+        let response = Response::new_ok(initialize_id, self.initialize_result()?);
+        self.connection.sender.send(response.into())?;
 
         info!(
             "Server initialized with params: {:?}",
@@ -77,34 +171,83 @@ impl Server {
     fn run(&self, params: serde_json::Value) -> Result<(), Box<dyn Error + Sync + Send>> {
         let _init_params: InitializeParams = serde_json::from_value(params)?;
 
-        // Send backend info notification to inform client which backend is being used
-        send_backend_info_notification(&self.connection)?;
-
         for msg in &self.connection.receiver {
             match msg {
                 Message::Request(req) => {
                     if self.connection.handle_shutdown(&req)? {
+                        let cancelled = cancel_all_jobs(
+                            &self.connection,
+                            &self.methods,
+                            &self.job_tracker,
+                            "Server is shutting down",
+                        )?;
+                        info!("Shutting down, cancelled {} active jobs", cancelled);
                         break;
                     }
+                    // A restarted client session re-runs the handshake on the same server;
+                    // its `initialized` notification triggers the catch-up notifications
+                    // like the first one
+                    if req.method == Initialize::METHOD {
+                        info!("Client re-initialized");
+                        let params = config::resolve_initialize_params(&req.params);
+                        self.session.set_client_capabilities(&params);
+                        let response = Response::new_ok(req.id, self.initialize_result()?);
+                        self.connection.sender.send(response.into())?;
+                        continue;
+                    }
                     let handler = RequestHandler::new(
                         &self.connection,
                         self.document_store.clone(),
                         self.job_tracker.clone(),
+                        self.job_queue.clone(),
+                        self.backend.clone(),
+                        self.session.clone(),
+                        self.methods.clone(),
+                        self.path_filter.clone(),
+                        self.worker_pool.clone(),
+                        self.pending_requests.clone(),
+                        self.apply_coordinator.clone(),
+                        self.completion_pool.clone(),
+                        self.timer.clone(),
                     );
                     handler.handle(&req)?;
                 }
                 Message::Notification(notification) => {
-                    let handler = NotificationHandler::new(&self.document_store);
+                    let handler = NotificationHandler::new(
+                        &self.connection,
+                        &self.document_store,
+                        &self.job_tracker,
+                        self.backend.as_ref(),
+                        &self.session,
+                        &self.methods,
+                    );
                     handler.handle(&notification)?;
                 }
                 Message::Response(resp) => {
-                    info!("Received response: {:?}", resp);
+                    // Answers to requests a worker is waiting on go to that worker
+                    if let Some(resp) = self.pending_requests.resolve(resp) {
+                        info!("Received response: {:?}", resp);
+                    }
                 }
             }
         }
 
         Ok(())
     }
+
+    /// Let the workers finish sending what they have (the notifications of the jobs shutdown
+    /// cancelled) for up to `SHUTDOWN_FLUSH_TIMEOUT_MS`. Returns whether they all finished.
+    fn flush_workers(&self) -> bool {
+        // No responses arrive after the main loop ends, so nobody should wait for one
+        self.pending_requests.close();
+        let running = self
+            .worker_pool
+            .shutdown(Duration::from_millis(SHUTDOWN_FLUSH_TIMEOUT_MS));
+        if running > 0 {
+            warn!("{} worker tasks still running at shutdown", running);
+        }
+        running == 0
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
@@ -118,12 +261,19 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     info!("Starting agent-lsp server");
 
     let (connection, io_threads) = Connection::stdio();
+    let connection = recorder::record_from_env(connection)?;
 
-    let server = Server::new(connection);
+    let mut server = Server::new(connection);
     let params = server.initialize()?;
     server.run(params)?;
 
-    io_threads.join()?;
+    // The writer thread ends once every sender of the connection is gone: the server's, and
+    // those of workers that have finished
+    let flushed = server.flush_workers();
+    drop(server);
+    if flushed {
+        io_threads.join()?;
+    }
 
     info!("Server shutting down");
 
@@ -1,11 +1,18 @@
 use std::error::Error;
-use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Stdio};
 
+use lsp_types::Range;
 use serde::Deserialize;
-use tracing::info;
-
-use crate::backend::Backend;
+use tracing::{info, warn};
+
+use crate::backend::{
+    build_selection_prompt, check_cli_installed, cli_command, ignore_model_params,
+    prepend_system_prompt, prompt_context, stop_cancelled_cli, stop_unreadable_cli, Backend,
+    LimitedOutput, ModelParams,
+};
+use crate::cancellation::CancellationToken;
+use crate::config::{BackendType, MAX_OUTPUT_BYTES, OPENCODE_JSON_EVENTS, OPENCODE_MODEL};
 use crate::utils::strip_markdown_code_block;
 
 /// OpenCode JSON event structure.
@@ -43,7 +50,7 @@ struct Part {
 fn build_prompt(
     line: u32,
     character: u32,
-    language_id: &str,
+    _language_id: &str,
     file_contents: &str,
     output_path: &str,
     function_signature: &str,
@@ -70,90 +77,90 @@ Describe your steps before performing them.\n\
     )
 }
 
-pub struct OpenCodeClient;
+pub struct OpenCodeClient {
+    /// Most output the CLI may write for one job before it is killed.
+    max_output_bytes: usize,
+    /// Sampling parameters, which the CLI has no way to take.
+    model_params: ModelParams,
+    /// Run the CLI with `--format json` and preview only the text of its events.
+    json_events: bool,
+    /// Instructions put before every prompt.
+    system_prompt: Option<String>,
+}
 
 impl OpenCodeClient {
     pub fn new() -> Self {
-        Self
+        Self {
+            max_output_bytes: MAX_OUTPUT_BYTES,
+            model_params: ModelParams::default(),
+            json_events: OPENCODE_JSON_EVENTS,
+            system_prompt: None,
+        }
     }
-}
 
-impl Default for OpenCodeClient {
-    fn default() -> Self {
-        Self::new()
+    /// Cap the CLI's output per job at `max_output_bytes`.
+    pub fn with_max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.max_output_bytes = max_output_bytes;
+        self
     }
-}
-
-impl Backend for OpenCodeClient {
-    fn implement_function(
-        &self,
-        file_path: &str,
-        line: u32,
-        character: u32,
-        language_id: &str,
-        file_contents: &str,
-    ) -> Result<String, Box<dyn Error + Sync + Send>> {
-        info!(
-            "Calling opencode CLI - file: {}, line: {}, character: {}, language: {}",
-            file_path, line, character, language_id
-        );
-
-        // NOTE: implement_function is deprecated in favor of streaming, passing dummy path and signature
-        let prompt = build_prompt(line, character, language_id, file_contents, "/tmp/dummy", "unknown");
 
-        let output = Command::new("opencode")
-            .arg("run")
-            .arg("--format")
-            .arg("json")
-            .arg("--attach")
-            .arg("http://localhost:1337")
-            .arg(&prompt)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("opencode CLI failed: {}", stderr).into());
-        }
-
-        let stdout = String::from_utf8(output.stdout)?;
-        let result = extract_text_from_events(&stdout)?;
+    pub fn with_model_params(mut self, model_params: ModelParams) -> Self {
+        self.model_params = model_params;
+        self
+    }
 
-        let result = strip_markdown_code_block(&result);
-        info!("OpenCode CLI returned {} bytes", result.len());
-        Ok(result.trim().to_string())
+    pub fn with_system_prompt(mut self, system_prompt: Option<String>) -> Self {
+        self.system_prompt = system_prompt;
+        self
     }
 
-    fn implement_function_streaming(
+    /// The prompt for one job: `build_prompt`'s with its `prompt_context`, after the system
+    /// prompt.
+    fn prompt(
         &self,
-        file_path: &str,
         line: u32,
         character: u32,
         language_id: &str,
         file_contents: &str,
         output_path: &str,
         function_signature: &str,
-        mut on_progress: Box<dyn FnMut(&str) + Send>,
-    ) -> Result<(), Box<dyn Error + Sync + Send>> {
-        info!(
-            "Calling opencode CLI (streaming) - file: {}, line: {}, character: {}, language: {}, function: {}",
-            file_path, line, character, language_id, function_signature
+    ) -> String {
+        let prompt = build_prompt(
+            line,
+            character,
+            language_id,
+            file_contents,
+            output_path,
+            function_signature,
         );
+        prepend_system_prompt(
+            self.system_prompt.as_deref(),
+            prompt + &prompt_context(file_contents, line, function_signature),
+        )
+    }
 
-        let prompt = build_prompt(line, character, language_id, file_contents, output_path, function_signature);
+    /// Run the CLI on `prompt`, forwarding its output to `on_progress` until it exits.
+    fn run_streaming(
+        &self,
+        prompt: &str,
+        output_path: &str,
+        cancellation: &CancellationToken,
+        on_progress: &mut Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        ignore_model_params("opencode", &self.model_params);
 
-        let mut child = Command::new("opencode")
-            .arg("run")
-            // .arg("--format")
-            // .arg("json")
+        let mut command = cli_command("opencode", output_path)?;
+        command.arg("run");
+        if self.json_events {
+            command.arg("--format").arg("json");
+        }
+        let mut child = command
             // .arg("--attach")
             // .arg("http://localhost:1337")
             .arg("--model")
-            .arg("anthropic/claude-sonnet-4-5")
+            .arg(OPENCODE_MODEL)
             // .arg("opencode/claude-sonnet-4-5")
-            .arg(&prompt)
+            .arg(prompt)
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -161,23 +168,13 @@ impl Backend for OpenCodeClient {
 
         let stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
         let stderr = child.stderr.take().ok_or("Failed to capture stderr")?;
-        let reader = BufReader::new(stdout);
-
-        let mut accumulated_text = String::new();
-
-        for line_result in reader.lines() {
-            let line = line_result?;
-            info!("opencode output line: {}", line);
-            accumulated_text.push_str(&line);
-            accumulated_text.push('\n');
-            on_progress(accumulated_text.trim());
-
-            // if let Some(text) = extract_text_from_line(&line) {
-            //     accumulated_text.push_str(&text);
-            //     let preview = strip_markdown_code_block(&accumulated_text);
-            //     on_progress(preview.trim());
-            // }
-        }
+        let stdout = LimitedOutput::new(stdout, self.max_output_bytes);
+
+        let accumulated_text = if self.json_events {
+            stream_json_events(&mut child, stdout, cancellation, on_progress)?
+        } else {
+            stream_plain_output(&mut child, stdout, cancellation, on_progress)?
+        };
 
         let status = child.wait()?;
         if !status.success() {
@@ -202,100 +199,288 @@ impl Backend for OpenCodeClient {
     }
 }
 
-/// Extract text content from a single JSON line.
-///
-/// OpenCode JSON format:
-/// ```json
-/// {"type":"text","part":{"type":"text","text":"content"}}
-/// ```
-fn extract_text_from_line(line: &str) -> Option<String> {
-    let event: OpenCodeEvent = serde_json::from_str(line).ok()?;
+impl Default for OpenCodeClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    // Only process "text" events
-    if event.event_type != "text" {
-        return None;
+impl Backend for OpenCodeClient {
+    fn check_available(&self) -> Result<(), String> {
+        check_cli_installed("opencode")
     }
 
-    // Extract text from the part
-    if let Some(part) = event.part {
-        if part.part_type.as_deref() == Some("text") {
-            return part.text;
-        }
+    fn backend_type(&self) -> BackendType {
+        BackendType::OpenCode
     }
 
-    None
+    fn implement_function_streaming(
+        &self,
+        file_path: &str,
+        line: u32,
+        character: u32,
+        language_id: &str,
+        file_contents: &str,
+        output_path: &str,
+        function_signature: &str,
+        cancellation: &CancellationToken,
+        mut on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        info!(
+            "Calling opencode CLI (streaming) - file: {}, line: {}, character: {}, language: {}, function: {}",
+            file_path, line, character, language_id, function_signature
+        );
+
+        let prompt = self.prompt(line, character, language_id, file_contents, output_path, function_signature);
+        self.run_streaming(&prompt, output_path, cancellation, &mut on_progress)
+    }
+
+    fn implement_selection_streaming(
+        &self,
+        file_path: &str,
+        range: Range,
+        selection: &str,
+        language_id: &str,
+        file_contents: &str,
+        output_path: &str,
+        cancellation: &CancellationToken,
+        mut on_progress: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        info!(
+            "Calling opencode CLI (streaming) - file: {}, selection: {:?}, language: {}",
+            file_path, range, language_id
+        );
+
+        let prompt = prepend_system_prompt(
+            self.system_prompt.as_deref(),
+            build_selection_prompt(range, selection, language_id, file_contents, output_path),
+        );
+        self.run_streaming(&prompt, output_path, cancellation, &mut on_progress)
+    }
 }
 
-/// Extract all text content from newline-delimited JSON events.
-fn extract_text_from_events(output: &str) -> Result<String, Box<dyn Error + Sync + Send>> {
+/// Forward each line of the CLI's plain output to `on_progress`, returning all of it.
+fn stream_plain_output(
+    child: &mut Child,
+    stdout: impl Read,
+    cancellation: &CancellationToken,
+    on_progress: &mut Box<dyn FnMut(&str) + Send>,
+) -> Result<String, Box<dyn Error + Sync + Send>> {
     let mut accumulated_text = String::new();
 
-    for line in output.lines() {
-        if let Some(text) = extract_text_from_line(line) {
-            accumulated_text.push_str(&text);
+    for line_result in BufReader::new(stdout).lines() {
+        let line = line_result.map_err(|e| stop_unreadable_cli(child, e))?;
+        if cancellation.is_cancelled() {
+            info!("Stopping opencode CLI for a cancelled job");
+            return Err(stop_cancelled_cli(child));
         }
+        info!("opencode output line: {}", line);
+        accumulated_text.push_str(&line);
+        accumulated_text.push('\n');
+        on_progress(accumulated_text.trim());
     }
 
-    if accumulated_text.is_empty() {
-        return Err("No text content found in opencode output".into());
+    Ok(accumulated_text)
+}
+
+/// Forward the text parts of the CLI's JSON events to `on_progress`, returning all of it.
+fn stream_json_events(
+    child: &mut Child,
+    mut stdout: impl Read,
+    cancellation: &CancellationToken,
+    on_progress: &mut Box<dyn FnMut(&str) + Send>,
+) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let mut events = EventStream::default();
+    let mut accumulated_text = String::new();
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let read = stdout
+            .read(&mut buffer)
+            .map_err(|e| stop_unreadable_cli(child, e))?;
+        if read == 0 {
+            break;
+        }
+        if cancellation.is_cancelled() {
+            info!("Stopping opencode CLI for a cancelled job");
+            return Err(stop_cancelled_cli(child));
+        }
+
+        let texts = events.push(&buffer[..read]);
+        if !texts.is_empty() {
+            accumulated_text.extend(texts);
+            let preview = strip_markdown_code_block(&accumulated_text);
+            on_progress(preview.trim());
+        }
     }
+    events.finish();
 
     Ok(accumulated_text)
 }
 
+/// Reassembles newline-delimited JSON events from output read in arbitrary chunks.
+///
+/// An event may be split across reads (or lines); its bytes are kept until the rest
+/// arrives. Lines that are not JSON are dropped rather than shown as text.
+#[derive(Debug, Default)]
+struct EventStream {
+    /// Bytes read but not yet parsed into an event.
+    pending: Vec<u8>,
+}
+
+impl EventStream {
+    /// Add a chunk of output, returning the text of every `text` event it completed.
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut texts = Vec::new();
+
+        loop {
+            let mut values = serde_json::Deserializer::from_slice(&self.pending)
+                .into_iter::<serde_json::Value>();
+            match values.next() {
+                // Only whitespace is left
+                None => {
+                    self.pending.clear();
+                    break;
+                }
+                Some(Ok(value)) => {
+                    let consumed = values.byte_offset();
+                    self.pending.drain(..consumed);
+                    let text = serde_json::from_value::<OpenCodeEvent>(value)
+                        .ok()
+                        .and_then(event_text);
+                    texts.extend(text);
+                }
+                // The event continues in a later chunk
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => {
+                    // Not JSON: drop the rest of the line once it is complete
+                    let Some(newline) = self.pending.iter().position(|&b| b == b'\n') else {
+                        break;
+                    };
+                    let dropped: Vec<u8> = self.pending.drain(..=newline).collect();
+                    warn!(
+                        "Dropping malformed opencode output ({}): {}",
+                        e,
+                        String::from_utf8_lossy(&dropped).trim()
+                    );
+                }
+            }
+        }
+
+        texts
+    }
+
+    /// Log any incomplete event left when the output ended.
+    fn finish(self) {
+        let rest = String::from_utf8_lossy(&self.pending);
+        if !rest.trim().is_empty() {
+            warn!("Dropping incomplete opencode output: {}", rest.trim());
+        }
+    }
+}
+
+/// The text of a `text` event's text part.
+fn event_text(event: OpenCodeEvent) -> Option<String> {
+    // Only process "text" events
+    if event.event_type != "text" {
+        return None;
+    }
+
+    // Extract text from the part
+    let part = event.part?;
+    if part.part_type.as_deref() == Some("text") {
+        return part.text;
+    }
+
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_extract_text_from_line_with_text_event() {
+    fn test_event_text_with_text_event() {
         // Actual OpenCode JSON format
         let json = r#"{"type":"text","timestamp":1766840249580,"sessionID":"ses_abc","part":{"id":"prt_123","sessionID":"ses_abc","messageID":"msg_456","type":"text","text":"hello world"}}"#;
         assert_eq!(
-            extract_text_from_line(json),
+            event_text(serde_json::from_str(json).unwrap()),
             Some("hello world".to_string())
         );
     }
 
     #[test]
-    fn test_extract_text_from_line_step_start_event() {
+    fn test_event_text_step_start_event() {
         // step_start events should not return text
         let json = r#"{"type":"step_start","timestamp":1766840240795,"sessionID":"ses_abc","part":{"id":"prt_123","type":"step-start","snapshot":"abc123"}}"#;
-        assert_eq!(extract_text_from_line(json), None);
+        assert_eq!(event_text(serde_json::from_str(json).unwrap()), None);
     }
 
     #[test]
-    fn test_extract_text_from_line_step_finish_event() {
+    fn test_event_text_step_finish_event() {
         // step_finish events should not return text
         let json = r#"{"type":"step_finish","timestamp":1766840249620,"sessionID":"ses_abc","part":{"id":"prt_123","type":"step-finish","reason":"stop"}}"#;
-        assert_eq!(extract_text_from_line(json), None);
+        assert_eq!(event_text(serde_json::from_str(json).unwrap()), None);
+    }
+
+    fn text_event(text: &str) -> String {
+        serde_json::json!({
+            "type": "text",
+            "sessionID": "ses_abc",
+            "part": { "type": "text", "text": text }
+        })
+        .to_string()
     }
 
     #[test]
-    fn test_extract_text_from_line_invalid_json() {
-        let json = "not valid json";
-        assert_eq!(extract_text_from_line(json), None);
+    fn test_event_stream_reassembles_event_split_across_reads() {
+        let mut events = EventStream::default();
+        let line = format!("{}\n", text_event("fn foo() {}"));
+        let (first, second) = line.as_bytes().split_at(line.len() / 2);
+
+        assert!(events.push(first).is_empty());
+        assert_eq!(events.push(second), vec!["fn foo() {}".to_string()]);
+        assert!(events.pending.is_empty());
     }
 
     #[test]
-    fn test_extract_text_from_events_full_session() {
-        // Simulate a full OpenCode session output
-        let output = r#"{"type":"step_start","timestamp":1766840240795,"sessionID":"ses_abc","part":{"id":"prt_1","type":"step-start","snapshot":"abc"}}
-{"type":"text","timestamp":1766840249580,"sessionID":"ses_abc","part":{"id":"prt_2","type":"text","text":"use std::fs;\n"}}
-{"type":"text","timestamp":1766840249581,"sessionID":"ses_abc","part":{"id":"prt_3","type":"text","text":"use uuid::Uuid;\n"}}
-{"type":"step_finish","timestamp":1766840249620,"sessionID":"ses_abc","part":{"id":"prt_4","type":"step-finish","reason":"stop"}}
-"#;
-        let result = extract_text_from_events(output).unwrap();
-        assert_eq!(result, "use std::fs;\nuse uuid::Uuid;\n");
+    fn test_event_stream_splits_inside_multibyte_character() {
+        let mut events = EventStream::default();
+        let line = format!("{}\n", text_event("héllo"));
+        let split = line.find('é').unwrap() + 1;
+
+        assert!(events.push(&line.as_bytes()[..split]).is_empty());
+        assert_eq!(
+            events.push(&line.as_bytes()[split..]),
+            vec!["héllo".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_event_stream_drops_malformed_lines() {
+        let mut events = EventStream::default();
+        let output = format!(
+            "{}\nnot json at all\n{{\"type\": \"text\", broken\n{}\n{}\n",
+            text_event("a"),
+            r#"{"type":"step_finish","part":{"type":"step-finish"}}"#,
+            text_event("b")
+        );
+
+        assert_eq!(
+            events.push(output.as_bytes()),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert!(events.pending.is_empty());
     }
 
     #[test]
-    fn test_extract_text_from_events_no_text() {
-        let output = r#"{"type":"step_start","timestamp":1,"sessionID":"s","part":{"type":"step-start"}}
-{"type":"step_finish","timestamp":2,"sessionID":"s","part":{"type":"step-finish"}}
-"#;
-        let result = extract_text_from_events(output);
-        assert!(result.is_err());
+    fn test_event_stream_waits_for_the_end_of_a_malformed_line() {
+        let mut events = EventStream::default();
+
+        assert!(events.push(b"Loading mod").is_empty());
+        let line = format!("els...\n{}\n", text_event("done"));
+        assert_eq!(events.push(line.as_bytes()), vec!["done".to_string()]);
     }
 
     #[test]
@@ -309,4 +494,47 @@ mod tests {
         assert!(prompt.contains("fn foo()"));
         assert!(prompt.contains("IMPORTANT: Implement ONLY the function"));
     }
+
+    #[test]
+    fn test_prompt_starts_with_system_prompt() {
+        let client =
+            OpenCodeClient::new().with_system_prompt(Some("Prefer iterators.".to_string()));
+        let prompt = client.prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs",
+            "fn calculate_sum()",
+        );
+        assert!(prompt.starts_with("Prefer iterators.\n\n"));
+        assert!(prompt.ends_with(&build_prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs",
+            "fn calculate_sum()"
+        )));
+
+        let prompt = OpenCodeClient::new().prompt(
+            9,
+            4,
+            "rust",
+            "fn main() {}",
+            "/tmp/output.rs",
+            "fn calculate_sum()",
+        );
+        assert_eq!(
+            prompt,
+            build_prompt(
+                9,
+                4,
+                "rust",
+                "fn main() {}",
+                "/tmp/output.rs",
+                "fn calculate_sum()"
+            )
+        );
+    }
 }
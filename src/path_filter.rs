@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+use lsp_types::Url;
+use tracing::warn;
+
+use crate::config::{EXCLUDE_GLOBS, INCLUDE_GLOBS};
+
+/// `*` stays within one path component; `**` crosses directories.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which files the agent may work on, from include and exclude globs.
+///
+/// Globs match paths relative to the workspace root; files outside the root (or every file,
+/// when there is no root) are matched by their absolute path.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    root: Option<PathBuf>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    /// Compile the globs, skipping (and logging) any that are invalid.
+    pub fn new(root: Option<PathBuf>, include: &[&str], exclude: &[&str]) -> Self {
+        Self {
+            root,
+            include: compile(include),
+            exclude: compile(exclude),
+        }
+    }
+
+    /// Filter for the client's `initialize` params.
+    ///
+    /// The root is the `rootUri`; `initializationOptions.include_globs` and
+    /// `initializationOptions.exclude_globs` replace `INCLUDE_GLOBS` and `EXCLUDE_GLOBS`.
+    pub fn from_initialize_params(params: &serde_json::Value) -> Self {
+        let root = params
+            .get("rootUri")
+            .and_then(|uri| uri.as_str())
+            .and_then(|uri| Url::parse(uri).ok())
+            .and_then(|uri| uri.to_file_path().ok());
+        let options = params.get("initializationOptions");
+        let include = string_list(options, "include_globs");
+        let exclude = string_list(options, "exclude_globs");
+
+        Self::new(
+            root,
+            &globs_or_default(&include, INCLUDE_GLOBS),
+            &globs_or_default(&exclude, EXCLUDE_GLOBS),
+        )
+    }
+
+    /// Whether the agent may work on the file at `uri`. Non-file URIs are always allowed.
+    pub fn allows(&self, uri: &Url) -> bool {
+        let Ok(path) = uri.to_file_path() else {
+            return true;
+        };
+        let path = self
+            .root
+            .as_deref()
+            .and_then(|root| path.strip_prefix(root).ok())
+            .unwrap_or(&path);
+
+        (self.include.is_empty() || matches_any(&self.include, path))
+            && !matches_any(&self.exclude, path)
+    }
+}
+
+fn compile(globs: &[&str]) -> Vec<Pattern> {
+    globs
+        .iter()
+        .filter_map(|glob| match Pattern::new(glob) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                warn!("Ignoring invalid glob '{}': {}", glob, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn matches_any(patterns: &[Pattern], path: &Path) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_path_with(path, MATCH_OPTIONS))
+}
+
+/// A list of strings under `key` in `options`, or None if the key is absent.
+fn string_list(options: Option<&serde_json::Value>, key: &str) -> Option<Vec<String>> {
+    let list = options?.get(key)?.as_array()?;
+    Some(
+        list.iter()
+            .filter_map(|glob| glob.as_str().map(str::to_string))
+            .collect(),
+    )
+}
+
+fn globs_or_default<'a>(globs: &'a Option<Vec<String>>, default: &[&'a str]) -> Vec<&'a str> {
+    match globs {
+        Some(globs) => globs.iter().map(String::as_str).collect(),
+        None => default.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn uri(path: &str) -> Url {
+        Url::from_file_path(path).unwrap()
+    }
+
+    #[test]
+    fn test_default_filter_allows_everything() {
+        let filter = PathFilter::default();
+        assert!(filter.allows(&uri("/work/src/main.rs")));
+    }
+
+    #[test]
+    fn test_exclude_matches_relative_to_root() {
+        let filter = PathFilter::new(
+            Some(PathBuf::from("/work")),
+            &[],
+            &["vendor/**", "**/*.generated.rs", ".env"],
+        );
+
+        assert!(filter.allows(&uri("/work/src/main.rs")));
+        assert!(!filter.allows(&uri("/work/vendor/lib/mod.rs")));
+        assert!(!filter.allows(&uri("/work/src/api.generated.rs")));
+        assert!(!filter.allows(&uri("/work/.env")));
+        // Relative globs do not match files outside the root by accident
+        assert!(filter.allows(&uri("/other/vendor/lib.rs")));
+    }
+
+    #[test]
+    fn test_include_limits_files_and_exclude_wins() {
+        let filter = PathFilter::new(
+            Some(PathBuf::from("/work")),
+            &["src/**/*.rs"],
+            &["src/secrets/**"],
+        );
+
+        assert!(filter.allows(&uri("/work/src/lib.rs")));
+        assert!(filter.allows(&uri("/work/src/a/b.rs")));
+        assert!(!filter.allows(&uri("/work/build.rs")));
+        assert!(!filter.allows(&uri("/work/src/notes.md")));
+        assert!(!filter.allows(&uri("/work/src/secrets/keys.rs")));
+    }
+
+    #[test]
+    fn test_single_star_stays_in_one_directory() {
+        let filter = PathFilter::new(Some(PathBuf::from("/work")), &[], &["gen/*.rs"]);
+
+        assert!(!filter.allows(&uri("/work/gen/a.rs")));
+        assert!(filter.allows(&uri("/work/gen/nested/a.rs")));
+    }
+
+    #[test]
+    fn test_invalid_globs_are_ignored() {
+        let filter = PathFilter::new(None, &[], &["[", "/tmp/*.rs"]);
+
+        assert!(!filter.allows(&uri("/tmp/a.rs")));
+        assert!(filter.allows(&uri("/tmp/a.py")));
+    }
+
+    #[test]
+    fn test_from_initialize_params() {
+        let filter = PathFilter::from_initialize_params(&json!({
+            "rootUri": "file:///work",
+            "initializationOptions": { "exclude_globs": ["target/**"] }
+        }));
+
+        assert!(!filter.allows(&uri("/work/target/debug/build.rs")));
+        assert!(filter.allows(&uri("/work/src/main.rs")));
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cancellation::CancellationToken;
+use crate::config::{BackendType, RateLimit, RATE_LIMITS};
+use crate::sync_utils::lock_recovering;
+
+/// How often a job waiting for a token re-checks its cancellation token.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A rate limiter's state, as reported by the status command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimiterState {
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    /// Whole tokens in the bucket.
+    pub available: u32,
+    /// Milliseconds until the next token is added, 0 when the bucket is full.
+    pub next_token_ms: u64,
+    /// Jobs waiting for a token.
+    pub waiting: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    waiting: usize,
+}
+
+/// Token bucket pacing backend runs; see `RATE_LIMITS`.
+///
+/// The bucket starts full, so up to `burst` runs start at once, and refills continuously.
+/// Without a limit every `acquire` returns at once.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: Option<RateLimit>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// A limiter for `limit`; a limit of 0 requests per minute means no limit.
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        let limit = limit
+            .filter(|limit| limit.requests_per_minute > 0)
+            .map(|limit| RateLimit {
+                burst: limit.burst.max(1),
+                ..limit
+            });
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.map_or(0.0, |limit| limit.burst as f64),
+                updated: Instant::now(),
+                waiting: 0,
+            }),
+        }
+    }
+
+    /// A limiter for `backend`'s entry in `RATE_LIMITS`.
+    pub fn for_backend(backend: BackendType) -> Self {
+        let limit = RATE_LIMITS
+            .iter()
+            .find(|(limited, _)| *limited == backend)
+            .map(|(_, limit)| *limit);
+        Self::new(limit)
+    }
+
+    /// Block until a token is available and take it. Returns false, without a token, if
+    /// `cancellation` is tripped first.
+    ///
+    /// `on_wait` is called once, with the expected wait, when the job has to wait.
+    pub fn acquire(
+        &self,
+        cancellation: &CancellationToken,
+        mut on_wait: impl FnMut(Duration),
+    ) -> bool {
+        let mut waiting = false;
+        let acquired = loop {
+            if cancellation.is_cancelled() {
+                break false;
+            }
+            let wait = match self.try_acquire_at(Instant::now()) {
+                Ok(()) => break true,
+                Err(wait) => wait,
+            };
+            if !waiting {
+                waiting = true;
+                lock_recovering(&self.bucket).waiting += 1;
+                on_wait(wait);
+            }
+            thread::sleep(wait.min(CANCEL_POLL_INTERVAL));
+        };
+        if waiting {
+            lock_recovering(&self.bucket).waiting -= 1;
+        }
+        acquired
+    }
+
+    /// Take a token at `now` if there is one; otherwise how long until there will be.
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let mut bucket = lock_recovering(&self.bucket);
+        refill(&mut bucket, limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(time_for(1.0 - bucket.tokens, limit))
+    }
+
+    /// The limiter's state, or None when it does not limit.
+    pub fn state(&self) -> Option<RateLimiterState> {
+        self.state_at(Instant::now())
+    }
+
+    fn state_at(&self, now: Instant) -> Option<RateLimiterState> {
+        let limit = self.limit?;
+        let mut bucket = lock_recovering(&self.bucket);
+        refill(&mut bucket, limit, now);
+        let next_token = if bucket.tokens >= limit.burst as f64 {
+            Duration::ZERO
+        } else {
+            time_for(1.0 - bucket.tokens.fract(), limit)
+        };
+        Some(RateLimiterState {
+            requests_per_minute: limit.requests_per_minute,
+            burst: limit.burst,
+            available: bucket.tokens as u32,
+            next_token_ms: next_token.as_millis() as u64,
+            waiting: bucket.waiting,
+        })
+    }
+}
+
+/// Add the tokens earned since the bucket was last updated, up to `limit.burst`.
+fn refill(bucket: &mut Bucket, limit: RateLimit, now: Instant) {
+    let elapsed = now.saturating_duration_since(bucket.updated);
+    let earned = elapsed.as_secs_f64() * limit.requests_per_minute as f64 / 60.0;
+    bucket.tokens = (bucket.tokens + earned).min(limit.burst as f64);
+    bucket.updated = now;
+}
+
+/// How long `limit` takes to earn `tokens`.
+fn time_for(tokens: f64, limit: RateLimit) -> Duration {
+    Duration::from_secs_f64(tokens * 60.0 / limit.requests_per_minute as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(requests_per_minute: u32, burst: u32) -> RateLimiter {
+        RateLimiter::new(Some(RateLimit {
+            requests_per_minute,
+            burst,
+        }))
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = limited(60, 2);
+        let start = lock_recovering(&limiter.bucket).updated;
+
+        assert_eq!(limiter.try_acquire_at(start), Ok(()));
+        assert_eq!(limiter.try_acquire_at(start), Ok(()));
+        // One token a second
+        assert_eq!(limiter.try_acquire_at(start), Err(Duration::from_secs(1)));
+        let wait = limiter
+            .try_acquire_at(start + Duration::from_millis(400))
+            .unwrap_err();
+        assert!(wait.abs_diff(Duration::from_millis(600)) < Duration::from_millis(1));
+        assert_eq!(
+            limiter.try_acquire_at(start + Duration::from_secs(1)),
+            Ok(())
+        );
+        // The bucket never holds more than the burst
+        let state = limiter.state_at(start + Duration::from_secs(60)).unwrap();
+        assert_eq!(state.available, 2);
+        assert_eq!(state.next_token_ms, 0);
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let limiter = RateLimiter::new(None);
+        assert!(limiter.state().is_none());
+        for _ in 0..100 {
+            assert!(limiter.acquire(&CancellationToken::new(), |_| panic!("waited")));
+        }
+        // 0 requests per minute is no limit either
+        assert!(limited(0, 1).state().is_none());
+    }
+
+    #[test]
+    fn test_acquire_spaces_out_runs() {
+        // One run every 100 ms
+        let limiter = limited(600, 1);
+        let cancellation = CancellationToken::new();
+        let start = Instant::now();
+        let mut waits = 0;
+        let times: Vec<Duration> = (0..4)
+            .map(|_| {
+                assert!(limiter.acquire(&cancellation, |_| waits += 1));
+                start.elapsed()
+            })
+            .collect();
+
+        assert_eq!(waits, 3);
+        assert!(times[0] < Duration::from_millis(50), "{:?}", times);
+        for pair in times.windows(2) {
+            assert!(
+                pair[1] - pair[0] >= Duration::from_millis(90),
+                "{:?}",
+                times
+            );
+        }
+    }
+
+    #[test]
+    fn test_cancelled_wait_takes_no_token() {
+        let limiter = limited(1, 1);
+        let cancellation = CancellationToken::new();
+        assert!(limiter.acquire(&cancellation, |_| {}));
+
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        assert!(!limiter.acquire(&cancelled, |_| {}));
+        let state = limiter.state().unwrap();
+        assert_eq!(state.available, 0);
+        assert_eq!(state.waiting, 0);
+    }
+}
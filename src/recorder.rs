@@ -0,0 +1,101 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+
+use crossbeam_channel::Sender;
+use lsp_server::{Connection, Message};
+use tracing::{error, info};
+
+/// Environment variable naming a JSONL file that receives a copy of every outgoing message.
+///
+/// Meant for tests, which can assert on the recorded transcript instead of racing to read
+/// the server's stdout.
+pub const RECORD_ENV_VAR: &str = "AGENT_LSP_RECORD";
+
+/// Wrap `sender` so every message sent through the returned sender is first appended to the
+/// file at `path` as one JSON line, then forwarded unchanged.
+pub fn record_outgoing(
+    sender: Sender<Message>,
+    path: &Path,
+) -> Result<Sender<Message>, Box<dyn Error + Sync + Send>> {
+    let mut file = File::create(path)?;
+    let (recording_sender, receiver) = crossbeam_channel::unbounded::<Message>();
+
+    thread::spawn(move || {
+        for msg in receiver {
+            match serde_json::to_string(&msg) {
+                Ok(line) => {
+                    if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                        error!("Failed to record outgoing message: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to serialize outgoing message: {}", e),
+            }
+            if sender.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(recording_sender)
+}
+
+/// Record the connection's outgoing messages if `RECORD_ENV_VAR` is set.
+pub fn record_from_env(connection: Connection) -> Result<Connection, Box<dyn Error + Sync + Send>> {
+    let Some(path) = std::env::var_os(RECORD_ENV_VAR) else {
+        return Ok(connection);
+    };
+
+    info!("Recording outgoing messages to {:?}", path);
+    Ok(Connection {
+        sender: record_outgoing(connection.sender, Path::new(&path))?,
+        receiver: connection.receiver,
+    })
+}
+
+/// Read a transcript written by `record_outgoing`.
+#[cfg(test)]
+pub fn read_transcript(path: &Path) -> Vec<Message> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_server::{Notification, RequestId, Response};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_record_outgoing_records_and_forwards_in_order() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("transcript.jsonl");
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        let recording = record_outgoing(sender, &path).unwrap();
+        recording
+            .send(Message::Response(Response::new_ok(RequestId::from(1), ())))
+            .unwrap();
+        recording
+            .send(Message::Notification(Notification::new(
+                "agent/test".to_string(),
+                (),
+            )))
+            .unwrap();
+
+        for _ in 0..2 {
+            receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+
+        let transcript = read_transcript(&path);
+        assert_eq!(transcript.len(), 2);
+        assert!(matches!(transcript[0], Message::Response(_)));
+        assert!(matches!(&transcript[1], Message::Notification(n) if n.method == "agent/test"));
+    }
+}
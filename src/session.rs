@@ -0,0 +1,258 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tracing::warn;
+
+use crate::config::{
+    CompletionMode, ImplementActionKind, MergeStrategy, CODE_ACTION_KIND, COMPLETION_MODE,
+    MERGE_STRATEGY, SEND_BACKEND_INFO,
+};
+use crate::lsp_utils::EditCapabilities;
+use crate::sync_utils::lock_recovering;
+use crate::utils::OutputShape;
+
+/// Settings the client can change at runtime, shared by all requests of a session.
+#[derive(Debug, Default)]
+pub struct SessionSettings {
+    output_shape: Mutex<Option<OutputShape>>,
+    /// Whether the client accepts server-initiated `$/progress` (set at initialize).
+    work_done_progress: AtomicBool,
+    /// Which `WorkspaceEdit` shapes the client accepts (set at initialize).
+    edit_capabilities: Mutex<EditCapabilities>,
+    /// Kind of the implement code action chosen by the client, or `None` for the default.
+    code_action_kind: Mutex<Option<ImplementActionKind>>,
+    /// Conflict resolution of 3-way merges chosen by the client, or `None` for the default.
+    merge_strategy: Mutex<Option<MergeStrategy>>,
+    /// How completion requests are handled, chosen by the client, or `None` for the default.
+    completion_mode: Mutex<Option<CompletionMode>>,
+    /// Whether the client wants `agent/backendInfo`, or `None` for the default.
+    backend_info: Mutex<Option<bool>>,
+    /// Whether the client sent `initialized` since its last `initialize`.
+    initialized: AtomicBool,
+}
+
+impl SessionSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The output shape forced by the client, or `None` to detect it from the backend output.
+    pub fn output_shape(&self) -> Option<OutputShape> {
+        *lock_recovering(&self.output_shape)
+    }
+
+    pub fn set_output_shape(&self, output_shape: Option<OutputShape>) {
+        *lock_recovering(&self.output_shape) = output_shape;
+    }
+
+    /// Whether jobs should report `window/workDoneProgress` to the client.
+    pub fn work_done_progress(&self) -> bool {
+        self.work_done_progress.load(Ordering::SeqCst)
+    }
+
+    /// How edits sent to the client must be shaped.
+    pub fn edit_capabilities(&self) -> EditCapabilities {
+        *lock_recovering(&self.edit_capabilities)
+    }
+
+    /// The kind the implement code action is advertised and returned under.
+    pub fn code_action_kind(&self) -> ImplementActionKind {
+        lock_recovering(&self.code_action_kind).unwrap_or(CODE_ACTION_KIND)
+    }
+
+    /// How 3-way merges resolve conflicting edits.
+    pub fn merge_strategy(&self) -> MergeStrategy {
+        lock_recovering(&self.merge_strategy).unwrap_or(MERGE_STRATEGY)
+    }
+
+    /// How `textDocument/completion` is advertised and answered.
+    pub fn completion_mode(&self) -> CompletionMode {
+        lock_recovering(&self.completion_mode).unwrap_or(COMPLETION_MODE)
+    }
+
+    /// Whether `agent/backendInfo` is sent once the client is initialized.
+    pub fn backend_info(&self) -> bool {
+        lock_recovering(&self.backend_info).unwrap_or(SEND_BACKEND_INFO)
+    }
+
+    /// Record the client's `initialized` notification. Returns false when it was already
+    /// received since the last `initialize`.
+    pub fn mark_initialized(&self) -> bool {
+        !self.initialized.swap(true, Ordering::SeqCst)
+    }
+
+    /// Record the capabilities from the client's `initialize` params.
+    pub fn set_client_capabilities(&self, initialize_params: &serde_json::Value) {
+        self.initialized.store(false, Ordering::SeqCst);
+        let work_done_progress = initialize_params
+            .pointer("/capabilities/window/workDoneProgress")
+            .and_then(|supported| supported.as_bool())
+            .unwrap_or(false);
+        self.work_done_progress
+            .store(work_done_progress, Ordering::SeqCst);
+        *lock_recovering(&self.edit_capabilities) =
+            EditCapabilities::from_initialize_params(initialize_params);
+
+        let code_action_kind = initialize_params
+            .pointer("/initializationOptions/code_action_kind")
+            .and_then(|kind| kind.as_str())
+            .and_then(|name| {
+                let kind = ImplementActionKind::from_name(name);
+                if kind.is_none() {
+                    warn!("Ignoring unsupported code_action_kind {:?}", name);
+                }
+                kind
+            });
+        *lock_recovering(&self.code_action_kind) = code_action_kind;
+
+        let merge_strategy = initialize_params
+            .pointer("/initializationOptions/merge_strategy")
+            .and_then(|strategy| strategy.as_str())
+            .and_then(|name| {
+                let strategy = MergeStrategy::from_name(name);
+                if strategy.is_none() {
+                    warn!("Ignoring unsupported merge_strategy {:?}", name);
+                }
+                strategy
+            });
+        *lock_recovering(&self.merge_strategy) = merge_strategy;
+
+        let completion_mode = initialize_params
+            .pointer("/initializationOptions/completion_mode")
+            .and_then(|mode| mode.as_str())
+            .and_then(|name| {
+                let mode = CompletionMode::from_name(name);
+                if mode.is_none() {
+                    warn!("Ignoring unsupported completion_mode {:?}", name);
+                }
+                mode
+            });
+        *lock_recovering(&self.completion_mode) = completion_mode;
+
+        let backend_info = initialize_params
+            .pointer("/initializationOptions/backend_info")
+            .and_then(|enabled| enabled.as_bool());
+        *lock_recovering(&self.backend_info) = backend_info;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_work_done_progress_capability() {
+        let session = SessionSettings::new();
+        assert!(!session.work_done_progress());
+
+        session.set_client_capabilities(&json!({
+            "capabilities": { "window": { "workDoneProgress": true } }
+        }));
+        assert!(session.work_done_progress());
+
+        // A client re-initializing without the capability turns it off again
+        session.set_client_capabilities(&json!({ "capabilities": {} }));
+        assert!(!session.work_done_progress());
+    }
+
+    #[test]
+    fn test_code_action_kind_from_initialization_options() {
+        let session = SessionSettings::new();
+        assert_eq!(session.code_action_kind(), CODE_ACTION_KIND);
+
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "code_action_kind": "refactor.rewrite" }
+        }));
+        assert_eq!(
+            session.code_action_kind(),
+            ImplementActionKind::RefactorRewrite
+        );
+
+        // Unknown kinds fall back to the default
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "code_action_kind": "refactor.extract" }
+        }));
+        assert_eq!(session.code_action_kind(), CODE_ACTION_KIND);
+    }
+
+    #[test]
+    fn test_merge_strategy_from_initialization_options() {
+        let session = SessionSettings::new();
+        assert_eq!(session.merge_strategy(), MERGE_STRATEGY);
+
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "merge_strategy": "abort" }
+        }));
+        assert_eq!(session.merge_strategy(), MergeStrategy::Abort);
+
+        // Unknown strategies fall back to the default
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "merge_strategy": "ours" }
+        }));
+        assert_eq!(session.merge_strategy(), MERGE_STRATEGY);
+    }
+
+    #[test]
+    fn test_completion_mode_from_initialization_options() {
+        let session = SessionSettings::new();
+        assert_eq!(session.completion_mode(), COMPLETION_MODE);
+
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "completion_mode": "ai_inline" }
+        }));
+        assert_eq!(session.completion_mode(), CompletionMode::AiInline);
+
+        // Unknown modes fall back to the default
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "completion_mode": "popup" }
+        }));
+        assert_eq!(session.completion_mode(), COMPLETION_MODE);
+    }
+
+    #[test]
+    fn test_initialized_is_marked_once_per_initialize() {
+        let session = SessionSettings::new();
+        assert!(session.mark_initialized());
+        assert!(!session.mark_initialized());
+
+        // A restarted client session initializes again
+        session.set_client_capabilities(&json!({ "capabilities": {} }));
+        assert!(session.mark_initialized());
+        assert!(!session.mark_initialized());
+    }
+
+    #[test]
+    fn test_backend_info_from_initialization_options() {
+        let session = SessionSettings::new();
+        assert_eq!(session.backend_info(), SEND_BACKEND_INFO);
+
+        session.set_client_capabilities(&json!({
+            "initializationOptions": { "backend_info": false }
+        }));
+        assert!(!session.backend_info());
+
+        // A client re-initializing without the option gets the default again
+        session.set_client_capabilities(&json!({ "capabilities": {} }));
+        assert_eq!(session.backend_info(), SEND_BACKEND_INFO);
+    }
+
+    #[test]
+    fn test_edit_capabilities_follow_initialize() {
+        let session = SessionSettings::new();
+        assert_eq!(session.edit_capabilities(), EditCapabilities::default());
+
+        session.set_client_capabilities(&json!({
+            "capabilities": { "workspace": { "workspaceEdit": { "documentChanges": true } } }
+        }));
+        assert_eq!(
+            session.edit_capabilities(),
+            EditCapabilities {
+                document_changes: true,
+                version_support: false,
+                change_annotations: false,
+                create_files: false,
+            }
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use std::fmt;
+
+use crate::utils::{
+    extract_function_name, normalize_signature, short_signature, signature_match, split_parameters,
+    SignatureMatch,
+};
+
+/// Languages whose return type comes before the function's name (`int add(int a)`).
+const RETURN_TYPE_FIRST: &[&str] = &[
+    "c",
+    "cpp",
+    "cuda",
+    "objective-c",
+    "objective-cpp",
+    "java",
+    "csharp",
+    "dart",
+];
+
+/// Keywords before a C-family function's name that are not part of its return type.
+const MODIFIERS: &[&str] = &[
+    "public",
+    "private",
+    "protected",
+    "internal",
+    "static",
+    "final",
+    "abstract",
+    "synchronized",
+    "native",
+    "default",
+    "inline",
+    "extern",
+    "virtual",
+    "explicit",
+    "friend",
+    "constexpr",
+    "consteval",
+    "override",
+    "async",
+    "unsafe",
+    "new",
+    "sealed",
+    "partial",
+];
+
+/// A function signature as the client sent it or as it was read from the document, with
+/// the parts the server compares and shows already picked out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionSignature {
+    /// The signature as it was given, possibly wrapped over several lines.
+    pub raw: String,
+    /// The function's name; None for a `line_N` fallback that names no function.
+    pub name: Option<String>,
+    /// The parameters as written, without surrounding whitespace.
+    pub params: Vec<String>,
+    /// The declared return type, when the signature has one.
+    pub return_type: Option<String>,
+    /// The LSP language id the signature was parsed as.
+    pub language: String,
+}
+
+impl FunctionSignature {
+    /// Parse `raw`, written in the language with LSP id `language`.
+    pub fn parse(raw: &str, language: &str) -> Self {
+        let normalized = normalize_signature(raw);
+        let name = extract_function_name(&normalized).map(str::to_string);
+        let (params, return_type) = match name
+            .as_deref()
+            .and_then(|name| Some((name, split_parameters(&normalized, name)?)))
+        {
+            Some((name, (params, rest))) => {
+                let before_name = &normalized[..normalized.find(name).unwrap_or(0)];
+                (
+                    params
+                        .iter()
+                        .map(|param| param.trim().to_string())
+                        .collect(),
+                    return_type(language, before_name, rest),
+                )
+            }
+            None => (Vec::new(), None),
+        };
+        FunctionSignature {
+            raw: raw.to_string(),
+            name,
+            params,
+            return_type,
+            language: language.to_string(),
+        }
+    }
+
+    /// Whether the signature names a function that can be searched for.
+    pub fn has_name(&self) -> bool {
+        self.name.is_some()
+    }
+
+    /// The part of the signature that names the function, for progress titles.
+    pub fn short(&self) -> String {
+        short_signature(&self.raw)
+    }
+
+    /// Whether `other` is the same function as this one: the same name, allowing for a
+    /// receiver or namespace on either side, or the same signature once normalized.
+    pub fn matches(&self, other: &FunctionSignature) -> bool {
+        self.match_quality(other).is_some()
+    }
+
+    /// How closely `other` matches this signature, ranking overloads with the same
+    /// parameter count and types above those that only share the name.
+    pub fn match_quality(&self, other: &FunctionSignature) -> Option<SignatureMatch> {
+        signature_match(&other.raw, &self.raw)
+    }
+}
+
+/// The normalized signature on one line, as it is shown in prompts and job descriptions.
+impl fmt::Display for FunctionSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&normalize_signature(&self.raw))
+    }
+}
+
+/// The return type of a signature, given the text before the function's name and after its
+/// parameter list.
+fn return_type(language: &str, before_name: &str, after_params: &str) -> Option<String> {
+    // What closes the signature: a body, an arrow function, Kotlin's `=` or Python's `:`
+    let mut rest = after_params.trim();
+    while let Some(stripped) = ["{", "=>", "=", ":", ";"]
+        .iter()
+        .find_map(|end| rest.strip_suffix(end))
+    {
+        rest = stripped.trim_end();
+    }
+    let rest = rest.split(" where ").next().unwrap_or(rest);
+
+    let return_type = if let Some((_, return_type)) = rest.split_once("->") {
+        // Rust, Python, Swift and C++'s trailing return types
+        return_type
+    } else if let Some(return_type) = rest.strip_prefix(':') {
+        // TypeScript and Kotlin
+        return_type
+    } else if language == "go" {
+        rest
+    } else if RETURN_TYPE_FIRST.contains(&language) {
+        return Some(
+            before_name
+                .split_whitespace()
+                .filter(|token| !MODIFIERS.contains(token) && !token.starts_with('@'))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+        .filter(|return_type| !return_type.is_empty());
+    } else {
+        ""
+    };
+    Some(return_type.trim().to_string()).filter(|return_type| !return_type.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(signature: &FunctionSignature) -> (Option<&str>, Vec<&str>, Option<&str>) {
+        (
+            signature.name.as_deref(),
+            signature.params.iter().map(String::as_str).collect(),
+            signature.return_type.as_deref(),
+        )
+    }
+
+    fn assert_parses(
+        language: &str,
+        raw: &str,
+        name: Option<&str>,
+        params: &[&str],
+        return_type: Option<&str>,
+    ) {
+        let signature = FunctionSignature::parse(raw, language);
+        assert_eq!(
+            parts(&signature),
+            (name, params.to_vec(), return_type),
+            "{}: {}",
+            language,
+            raw
+        );
+        assert_eq!(signature.has_name(), name.is_some());
+        assert_eq!(signature.raw, raw);
+        assert_eq!(signature.language, language);
+    }
+
+    #[test]
+    fn test_parse_per_language() {
+        assert_parses(
+            "rust",
+            "pub fn add(a: i32, b: i32) -> i32 {",
+            Some("add"),
+            &["a: i32", "b: i32"],
+            Some("i32"),
+        );
+        assert_parses(
+            "rust",
+            "fn get<T>(map: &HashMap<String, T>) -> Option<&T> where T: Clone {",
+            Some("get"),
+            &["map: &HashMap<String, T>"],
+            Some("Option<&T>"),
+        );
+        assert_parses("rust", "fn main() {", Some("main"), &[], None);
+        assert_parses(
+            "python",
+            "def add(self, a: int, b: int = 0) -> int:",
+            Some("add"),
+            &["self", "a: int", "b: int = 0"],
+            Some("int"),
+        );
+        assert_parses(
+            "python",
+            "async def fetch(url):",
+            Some("fetch"),
+            &["url"],
+            None,
+        );
+        assert_parses(
+            "typescript",
+            "function add(a: number, b: number): number {",
+            Some("add"),
+            &["a: number", "b: number"],
+            Some("number"),
+        );
+        assert_parses(
+            "typescript",
+            "const load = async (id: string): Promise<User> => {",
+            Some("load"),
+            &["id: string"],
+            Some("Promise<User>"),
+        );
+        assert_parses(
+            "javascript",
+            "function add(a, b) {",
+            Some("add"),
+            &["a", "b"],
+            None,
+        );
+        assert_parses(
+            "go",
+            "func (r *Repo) Save(ctx context.Context, item Item) (int, error) {",
+            Some("Save"),
+            &["ctx context.Context", "item Item"],
+            Some("(int, error)"),
+        );
+        assert_parses("go", "func main() {", Some("main"), &[], None);
+        assert_parses(
+            "cpp",
+            "static std::vector<int> split(const std::string& s, char sep) {",
+            Some("split"),
+            &["const std::string& s", "char sep"],
+            Some("std::vector<int>"),
+        );
+        assert_parses(
+            "cpp",
+            "auto area() const -> double {",
+            Some("area"),
+            &[],
+            Some("double"),
+        );
+        assert_parses(
+            "java",
+            "public static int add(int a, int b) {",
+            Some("add"),
+            &["int a", "int b"],
+            Some("int"),
+        );
+        assert_parses(
+            "kotlin",
+            "fun add(a: Int, b: Int): Int {",
+            Some("add"),
+            &["a: Int", "b: Int"],
+            Some("Int"),
+        );
+        assert_parses(
+            "swift",
+            "func add(_ a: Int, to b: Int) throws -> Int {",
+            Some("add"),
+            &["_ a: Int", "to b: Int"],
+            Some("Int"),
+        );
+        assert_parses("rust", "line_12", None, &[], None);
+    }
+
+    #[test]
+    fn test_wrapped_signature_is_parsed_and_displayed_on_one_line() {
+        let raw = "fn configure(\n    name: &str,\n    retries: u32,\n) -> Config {";
+        let signature = FunctionSignature::parse(raw, "rust");
+        assert_eq!(signature.params, vec!["name: &str", "retries: u32"]);
+        assert_eq!(signature.return_type.as_deref(), Some("Config"));
+        assert_eq!(
+            signature.to_string(),
+            "fn configure(name: &str, retries: u32) -> Config {"
+        );
+        assert_eq!(signature.short(), "fn configure");
+    }
+
+    #[test]
+    fn test_parse_display_parse_round_trip() {
+        let signatures = [
+            (
+                "rust",
+                "pub async fn fetch(\n    url: &str,\n) -> Result<String> {",
+            ),
+            ("python", "def add(self,  a: int) -> int:"),
+            ("typescript", "function add(a: number, b: number): number {"),
+            ("go", "func (r *Repo) Save(ctx context.Context) error {"),
+            ("cpp", "int Shape::area( int scale ) const {"),
+            (
+                "java",
+                "public List<String> names(Map<String, Integer> counts) {",
+            ),
+            ("kotlin", "fun add(a: Int, b: Int): Int ="),
+            ("swift", "func add(_ a: Int) -> Int {"),
+            ("rust", "line_3"),
+        ];
+        for (language, raw) in signatures {
+            let parsed = FunctionSignature::parse(raw, language);
+            let reparsed = FunctionSignature::parse(&parsed.to_string(), language);
+            assert_eq!(parts(&reparsed), parts(&parsed), "{}", raw);
+            assert_eq!(reparsed.to_string(), parsed.to_string(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let rust = |raw| FunctionSignature::parse(raw, "rust");
+        assert!(rust("fn foo() {").matches(&rust("fn foo(\n) {")));
+        assert!(rust("fn foo(a: u8) {").matches(&rust("pub fn foo(a: u16) -> u8 {")));
+        assert!(!rust("fn foo() {").matches(&rust("fn bar() {")));
+        assert!(!rust("line_3").matches(&rust("fn foo() {")));
+
+        // Overloads rank by their parameters
+        let cpp = |raw| FunctionSignature::parse(raw, "cpp");
+        let expected = cpp("int add(int a, int b) {");
+        let same = cpp("int add(int x, int y) {");
+        let other = cpp("double add(double a, double b) {");
+        let fewer = cpp("int add(int a) {");
+        assert!(expected.matches(&other) && expected.matches(&fewer));
+        assert!(expected.match_quality(&same) > expected.match_quality(&other));
+        assert!(expected.match_quality(&other) > expected.match_quality(&fewer));
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use tracing::warn;
+
+/// Lock `mutex`, recovering its data if a thread panicked while holding it.
+///
+/// The shared structures here stay valid after a panic mid-operation (at worst a record is
+/// stale), so one panicking worker must not take every later request down with it. The
+/// poison flag is cleared, so each poisoning is logged once.
+pub fn lock_recovering<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering a lock poisoned by a panicking thread");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// `Condvar::wait_timeout` that recovers a poisoned lock like `lock_recovering`.
+pub fn wait_timeout_recovering<'a, T>(
+    condvar: &Condvar,
+    guard: MutexGuard<'a, T>,
+    timeout: Duration,
+) -> MutexGuard<'a, T> {
+    condvar
+        .wait_timeout(guard, timeout)
+        .unwrap_or_else(PoisonError::into_inner)
+        .0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_lock_recovering_after_poisoning() {
+        let mutex = Mutex::new(vec![1]);
+
+        thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let mut data = mutex.lock().unwrap();
+                data.push(2);
+                panic!("poison the lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(mutex.is_poisoned());
+
+        lock_recovering(&mutex).push(3);
+        assert_eq!(*lock_recovering(&mutex), vec![1, 2, 3]);
+        assert!(!mutex.is_poisoned());
+    }
+}
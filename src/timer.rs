@@ -0,0 +1,149 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Instant;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use tracing::{error, info};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Runs short tasks at their deadlines, all on one shared thread.
+///
+/// Tasks must not block: a slow one delays every task due after it. Tasks still scheduled
+/// when the timer is dropped never run.
+pub struct Timer {
+    sender: Sender<Scheduled>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        thread::spawn(move || run_timer(receiver));
+        Self { sender }
+    }
+
+    /// Run `task` on the timer thread once `deadline` has passed.
+    pub fn schedule(&self, deadline: Instant, task: impl FnOnce() + Send + 'static) {
+        // The timer thread only exits once this sender is dropped
+        let _ = self.sender.send(Scheduled {
+            deadline,
+            task: Box::new(task),
+        });
+    }
+}
+
+struct Scheduled {
+    deadline: Instant,
+    task: Task,
+}
+
+// Ordered so the max-heap yields the earliest deadline first
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Scheduled {}
+
+fn run_timer(receiver: Receiver<Scheduled>) {
+    let mut scheduled = BinaryHeap::new();
+    loop {
+        let received = match scheduled.peek() {
+            Some(Scheduled { deadline, .. }) => receiver.recv_deadline(*deadline),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(task) => scheduled.push(task),
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                while scheduled.peek().is_some_and(|next| next.deadline <= now) {
+                    let Some(Scheduled { task, .. }) = scheduled.pop() else {
+                        break;
+                    };
+                    // Keep the thread for later tasks even if this one panics
+                    if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+                        error!("Timer thread recovered from a panicking task");
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    info!(
+        "Timer stopped, dropping {} scheduled tasks",
+        scheduled.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tasks_run_in_deadline_order() {
+        let timer = Timer::new();
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let start = Instant::now();
+
+        for (i, delay_ms) in [(0, 60), (1, 20), (2, 40)] {
+            let done_tx = done_tx.clone();
+            timer.schedule(start + Duration::from_millis(delay_ms), move || {
+                done_tx.send(i).unwrap();
+            });
+        }
+
+        let order: Vec<i32> = (0..3)
+            .map(|_| done_rx.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect();
+        assert_eq!(order, vec![1, 2, 0]);
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_later_task_does_not_delay_earlier_one() {
+        let timer = Timer::new();
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let start = Instant::now();
+
+        let late_tx = done_tx.clone();
+        timer.schedule(start + Duration::from_secs(5), move || {
+            late_tx.send("late").unwrap();
+        });
+        timer.schedule(start + Duration::from_millis(20), move || {
+            done_tx.send("early").unwrap();
+        });
+
+        assert_eq!(
+            done_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "early"
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_panicking_task_keeps_timer_running() {
+        let timer = Timer::new();
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+
+        timer.schedule(Instant::now(), || panic!("task failed"));
+        timer.schedule(Instant::now(), move || done_tx.send(()).unwrap());
+
+        done_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+}
@@ -1,14 +1,26 @@
-use crate::lsp_utils::WorkspaceEditBuilder;
+use crate::config::{
+    MergeStrategy, DELETE_TEMP_FILES, ENSURE_FINAL_NEWLINE, MERGE_THEIRS_DIR,
+    TRIM_TRAILING_WHITESPACE,
+};
+use crate::imports::is_import_line;
+use crate::signature::FunctionSignature;
 use diffy::merge;
-use lsp_types::{Url, WorkspaceEdit};
-use std::io::Write;
-use tempfile::NamedTempFile;
-use tracing::info;
-
-/// Strip markdown code block wrapper from a string if present.
+use lsp_types::Url;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Extract the code from a backend's markdown-formatted output.
 ///
-/// If the string is wrapped in triple backticks (with optional language identifier),
-/// returns the content between them. Otherwise returns the original string.
+/// Finds fenced code blocks anywhere in the text (```` ``` ```` or `~~~` fences, indented by
+/// at most three spaces, with an optional info string like `rust`) and returns the content
+/// of the largest one, dropping prose around it ("Here's the implementation:") and extra
+/// blocks such as usage examples. An unterminated fence runs to the end of the text. Text
+/// without fences is returned as is.
 ///
 /// # Examples
 ///
@@ -19,24 +31,99 @@ use tracing::info;
 /// assert_eq!(strip_markdown_code_block("plain text"), "plain text");
 /// ```
 pub fn strip_markdown_code_block(s: &str) -> String {
-    let trimmed = s.trim();
+    // The first of equally large blocks
+    let block = fenced_blocks(s)
+        .into_iter()
+        .rev()
+        .max_by_key(|block| block.iter().map(|line| line.len()).sum::<usize>());
+    match block {
+        Some(block) => block.join("\n"),
+        None => s.to_string(),
+    }
+}
 
-    // Check if wrapped in markdown code block
-    if trimmed.starts_with("```") && trimmed.ends_with("```") {
-        let lines: Vec<&str> = trimmed.lines().collect();
-        if lines.len() >= 2 {
-            // Skip first line (```lang) and last line (```)
-            return lines[1..lines.len() - 1].join("\n");
+/// The content lines of every fenced code block in `text`, in order.
+fn fenced_blocks(text: &str) -> Vec<Vec<&str>> {
+    let mut blocks = Vec::new();
+    // The open fence's character and length, and the block's lines so far
+    let mut open: Option<(char, usize, Vec<&str>)> = None;
+    for line in text.lines() {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let fence = fence_run(line.trim()).filter(|_| indent <= 3);
+        match open.as_mut() {
+            Some((fence_char, fence_len, content)) => {
+                // A closing fence has no info string and is at least as long as the opening
+                let closes = fence.is_some_and(|(c, len)| {
+                    c == *fence_char && len >= *fence_len && line.trim().len() == len
+                });
+                if closes {
+                    blocks.push(std::mem::take(content));
+                    open = None;
+                } else {
+                    content.push(line);
+                }
+            }
+            None => {
+                if let Some((fence_char, fence_len)) = fence {
+                    open = Some((fence_char, fence_len, Vec::new()));
+                }
+            }
         }
     }
+    if let Some((_, _, content)) = open {
+        blocks.push(content);
+    }
+    blocks
+}
 
-    s.to_string()
+/// The character and length of the fence (three or more backticks or tildes) starting
+/// `line`.
+fn fence_run(line: &str) -> Option<(char, usize)> {
+    let fence_char = line.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = line.len() - line.trim_start_matches(fence_char).len();
+    // A backtick fence's info string can't hold backticks (that's inline code)
+    let inline_code = fence_char == '`' && line[len..].contains('`');
+    (len >= 3 && !inline_code).then_some((fence_char, len))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_short_signature() {
+        assert_eq!(
+            short_signature("pub fn parse_header(buf: &[u8]) -> Header {"),
+            "pub fn parse_header"
+        );
+        assert_eq!(short_signature("def flush(self):"), "def flush");
+        assert_eq!(
+            short_signature("static int\n    compute (int a)"),
+            "static int compute"
+        );
+        assert_eq!(short_signature("impl Foo {"), "impl Foo");
+        assert_eq!(short_signature("end:"), "end");
+    }
+
+    #[test]
+    fn test_preview_tail() {
+        let mut preview = String::new();
+        for chunk in 0..500 {
+            preview.push_str(&format!("let x{} = {};\n", chunk, chunk));
+        }
+        let (tail, truncated) = preview_tail(&preview, 2000);
+        assert!(truncated);
+        assert_eq!(tail.chars().count(), 2000);
+        assert!(tail.ends_with("let x499 = 499;\n"));
+
+        assert_eq!(preview_tail("fn a() {}", 9), ("fn a() {}", false));
+        assert_eq!(preview_tail("fn a() {}", 100), ("fn a() {}", false));
+        // Cuts on character boundaries
+        assert_eq!(preview_tail("é→ü", 2), ("→ü", true));
+        assert_eq!(preview_tail("abc", 0), ("", true));
+        assert_eq!(preview_tail("", 0), ("", false));
+    }
+
     #[test]
     fn test_strip_markdown_code_block_with_language() {
         let input = "```rust\nfn foo() {\n    println!(\"hello\");\n}\n```";
@@ -77,550 +164,4532 @@ mod tests {
         let expected = "const x = 1;\nconst y = 2;\nreturn x + y;";
         assert_eq!(strip_markdown_code_block(input), expected);
     }
-}
 
-/// Extract a function signature for tracking purposes.
-/// This is used to identify functions when line numbers may have shifted.
-///
-/// Supports multiple languages: Rust, C++, Python, Java, etc.
-pub fn extract_function_signature(text: &str, line: usize) -> Option<String> {
-    let lines: Vec<&str> = text.lines().collect();
-    if line >= lines.len() {
-        return None;
+    #[test]
+    fn test_strip_markdown_code_block_surrounding_prose() {
+        let input = "Here's the implementation:\n\n```rust\nfn foo() {}\n```\n\nIt does nothing.";
+        assert_eq!(strip_markdown_code_block(input), "fn foo() {}");
     }
 
-    // Find function start from the given line
-    let start_line = find_function_start(&lines, line)?;
+    #[test]
+    fn test_strip_markdown_code_block_multiple_blocks() {
+        let input = "```rust\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n```\n\nUsage:\n\n```rust\nadd(1, 2);\n```";
+        assert_eq!(
+            strip_markdown_code_block(input),
+            "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}"
+        );
+        let input = "Usage:\n```\nadd(1, 2);\n```\n```\nfn add() {}\n```";
+        assert_eq!(strip_markdown_code_block(input), "fn add() {}");
+        // Equally large blocks go to the first
+        assert_eq!(strip_markdown_code_block("```\na\n```\n```\nb\n```"), "a");
+    }
 
-    // Return the line containing the function declaration
-    // This is a simple identifier that should remain stable
-    Some(lines[start_line].trim().to_string())
+    #[test]
+    fn test_strip_markdown_code_block_fence_variants() {
+        // Tildes, and a longer fence holding a shorter one
+        assert_eq!(strip_markdown_code_block("~~~python\npass\n~~~"), "pass");
+        assert_eq!(
+            strip_markdown_code_block("````md\n```\ncode\n```\n````"),
+            "```\ncode\n```"
+        );
+        // A fence of the other character doesn't close the block
+        assert_eq!(strip_markdown_code_block("~~~\n```\n~~~"), "```");
+        // Info strings with attributes
+        assert_eq!(
+            strip_markdown_code_block("``` rust title=\"lib.rs\"\nfn foo() {}\n```"),
+            "fn foo() {}"
+        );
+        // An unterminated fence runs to the end
+        assert_eq!(
+            strip_markdown_code_block("Sure:\n```go\nreturn a + b"),
+            "return a + b"
+        );
+    }
+
+    #[test]
+    fn test_strip_markdown_code_block_ignores_non_fences() {
+        // Fences indented as code, as in a docstring, are content
+        let input = "```python\ndef f():\n    \"\"\"\n        ```\n        f()\n        ```\n    \"\"\"\n```";
+        assert_eq!(
+            strip_markdown_code_block(input),
+            "def f():\n    \"\"\"\n        ```\n        f()\n        ```\n    \"\"\""
+        );
+        // Inline code and short runs aren't fences
+        let input = "```rust fn foo() {}```";
+        assert_eq!(strip_markdown_code_block(input), input);
+        let input = "``\nx\n``";
+        assert_eq!(strip_markdown_code_block(input), input);
+    }
 }
 
-/// Find the start line of the function containing or at the given line.
+/// The shape of the code a backend produced for a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum OutputShape {
+    /// Only the statements inside the function body.
+    BodyOnly,
+    /// The whole function, including its signature.
+    FullFunction,
+}
+
+/// Classify whether a backend returned a whole function or just its body.
 ///
-/// Scans backwards from `line` to find a line with function keywords.
-/// Supports: Rust (fn), C++ (void, int, etc.), Python (def), Java (public/private/void/etc.)
-pub fn find_function_start(lines: &[&str], start_search_line: usize) -> Option<usize> {
-    let mut current_line = start_search_line;
-    if current_line >= lines.len() {
-        return None;
+/// Strips a surrounding code fence and skips leading attributes, decorators and
+/// comments, then treats the output as a full function if the first remaining line
+/// looks like a function signature in `language_id`.
+pub fn classify_output_shape(text: &str, language_id: &str) -> OutputShape {
+    let text = strip_markdown_code_block(text);
+    let first_code_line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !is_decoration_line(line, language_id));
+
+    match first_code_line {
+        Some(line) if looks_like_signature(line, language_id) => OutputShape::FullFunction,
+        _ => OutputShape::BodyOnly,
     }
+}
 
-    loop {
-        let line = lines[current_line].trim();
+/// Cut the target function out of backend output that holds a whole file.
+///
+/// Backends sometimes write the entire source file (imports, other functions) instead of
+/// the requested function. When `output` starts with imports or has several top-level
+/// functions, only the function matching `signature` is kept, along with the attributes,
+/// decorators and doc comments directly above it. Other output is returned unchanged.
+/// Fails if a whole file is returned without the target function.
+pub fn extract_target_function(
+    output: &str,
+    signature: &FunctionSignature,
+) -> Result<String, String> {
+    let language_id = signature.language.as_str();
+    let lines: Vec<&str> = output.lines().collect();
+    let leading_imports = lines
+        .iter()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !is_decoration_line(line, language_id))
+        .is_some_and(|line| is_import_line(line, language_id));
+    // Functions at the outermost indentation any function has, e.g. a class's methods
+    let starts: Vec<usize> = lines
+        .iter()
+        .filter(|line| is_function_start(line.trim()))
+        .map(|line| leading_whitespace(line).len())
+        .collect();
+    let top_level = starts.iter().min().map_or(0, |min| {
+        starts.iter().filter(|indent| *indent == min).count()
+    });
+    if !leading_imports && top_level < 2 {
+        return Ok(output.to_string());
+    }
 
-        // Check for function keywords in various languages
-        // Rust: fn, pub fn, async fn, etc.
-        if line.starts_with("fn ")
-            || line.starts_with("pub fn ")
-            || line.starts_with("async fn ")
-            || line.starts_with("pub async fn ")
-            || line.starts_with("pub(crate) fn ")
-            || line.contains(" fn ")
-        {
-            return Some(current_line);
+    let start = find_function_by_signature(&lines, signature, 0).ok_or_else(|| {
+        format!(
+            "Agent output is a whole file without the target function `{}`",
+            signature.short()
+        )
+    })?;
+    let end = find_function_end(&lines, start)
+        .ok_or("Could not find the target function's end in the agent output")?;
+    let mut first = template_header_start(&lines, start);
+    while first > 0 {
+        let above = lines[first - 1].trim();
+        if above.is_empty() || !is_decoration_line(above, language_id) {
+            break;
         }
+        first -= 1;
+    }
+    info!(
+        "Extracted lines {}-{} of a {}-line agent output as the implementation",
+        first,
+        end,
+        lines.len()
+    );
+    Ok(lines[first..=end].join("\n"))
+}
 
-        // Python: def
-        if line.starts_with("def ") || line.starts_with("async def ") {
-            return Some(current_line);
+/// How a language's functions, bodies and ends are detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionQuality {
+    /// Line-based signature matching and brace counting.
+    Heuristic,
+}
+
+/// A language the function detectors handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LanguageSupport {
+    pub language_id: &'static str,
+    pub detection: DetectionQuality,
+}
+
+/// Languages whose function signatures `looks_like_signature` recognizes, by LSP
+/// `languageId`. Keep in sync with the detectors when adding a language.
+pub const SUPPORTED_LANGUAGES: &[LanguageSupport] = &[
+    heuristic("rust"),
+    heuristic("python"),
+    heuristic("go"),
+    heuristic("lua"),
+    heuristic("ruby"),
+    heuristic("javascript"),
+    heuristic("javascriptreact"),
+    heuristic("typescript"),
+    heuristic("typescriptreact"),
+    heuristic("c"),
+    heuristic("cpp"),
+    heuristic("java"),
+    heuristic("kotlin"),
+    heuristic("swift"),
+    heuristic("csharp"),
+];
+
+const fn heuristic(language_id: &'static str) -> LanguageSupport {
+    LanguageSupport {
+        language_id,
+        detection: DetectionQuality::Heuristic,
+    }
+}
+
+/// Check if a line is an attribute, decorator or comment that may precede a function.
+fn is_decoration_line(line: &str, language_id: &str) -> bool {
+    match language_id {
+        "python" => line.starts_with('@') || line.starts_with('#'),
+        "lua" => line.starts_with("--"),
+        "ruby" => line.starts_with('#'),
+        // C# attributes: `[HttpGet]`
+        "csharp" => line.starts_with('[') || line.starts_with("//"),
+        _ => {
+            line.starts_with("#[")
+                || line.starts_with('@')
+                || line.starts_with("//")
+                || line.starts_with("/*")
+                || line.starts_with('*')
         }
+    }
+}
 
-        // C++/Java: return types and modifiers
-        if line.starts_with("void ")
-            || line.starts_with("int ")
-            || line.starts_with("bool ")
-            || line.starts_with("char ")
-            || line.starts_with("float ")
-            || line.starts_with("double ")
-            || line.starts_with("public ")
-            || line.starts_with("private ")
-            || line.starts_with("protected ")
-            || line.starts_with("static ")
-            || line.contains("(") && (line.contains("void ") || line.contains("int "))
-        {
-            // Verify it looks like a function (has parentheses)
-            if line.contains('(') {
-                return Some(current_line);
-            }
+/// Check if a line starts a function definition in the given language.
+fn looks_like_signature(line: &str, language_id: &str) -> bool {
+    match language_id {
+        "rust" => is_rust_signature(line),
+        "python" => line.starts_with("def ") || line.starts_with("async def "),
+        "go" => is_go_signature(line),
+        "kotlin" => kotlin_function_name(line).is_some(),
+        "swift" => swift_function_name(line).is_some(),
+        "csharp" => cpp_function_name(line).is_some(),
+        "lua" => lua_function_name(line).is_some(),
+        "ruby" => ruby_function_name(line).is_some(),
+        "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => {
+            is_js_signature(line)
         }
+        _ => is_function_start(line),
+    }
+}
 
-        if current_line == 0 {
-            break;
+/// `fn` preceded only by qualifiers such as `pub(crate)`, `async`, `unsafe` or `extern "C"`.
+fn is_rust_signature(line: &str) -> bool {
+    for word in line.split_whitespace() {
+        if word == "fn" {
+            return true;
+        }
+        let is_qualifier = word.starts_with("pub")
+            || word.starts_with('"')
+            || matches!(word, "async" | "unsafe" | "const" | "extern" | "default");
+        if !is_qualifier {
+            return false;
         }
-        current_line -= 1;
     }
-    None
+    false
 }
 
-/// Check if two function signatures match.
-///
-/// Compares trimmed versions and extracts function name for comparison.
-/// Handles cases where signatures may have minor formatting differences.
-fn signatures_match(found: &str, expected: &str) -> bool {
-    let found = found.trim();
-    let expected = expected.trim();
+/// Go `func` declarations, with or without a method receiver; not function literals.
+fn is_go_signature(line: &str) -> bool {
+    go_function_name(line).is_some()
+}
 
-    // Exact match
-    if found == expected {
-        return true;
-    }
+/// Name of the function a Go declaration like `func (r *Repo) Save(ctx context.Context) error {`
+/// or `func Map[T, U any](xs []T) []U {` declares, skipping the optional receiver.
+fn go_function_name(sig: &str) -> Option<&str> {
+    let rest = sig.strip_prefix("func ")?.trim_start();
+    let rest = match rest.strip_prefix('(') {
+        // Method receiver: `(r *Repo)`
+        Some(receiver) => receiver[receiver.find(')')? + 1..].trim_start(),
+        None => rest,
+    };
+    let name_end = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    let name = &rest[..name_end];
+    let after_name = rest[name_end..].trim_start();
+    (!name.is_empty() && (after_name.starts_with('(') || after_name.starts_with('[')))
+        .then_some(name)
+}
 
-    // Extract function names and compare
-    let found_name = extract_function_name(found);
-    let expected_name = extract_function_name(expected);
+/// Name of the function a Kotlin `fun` declaration declares, skipping modifiers, type
+/// parameters and an extension receiver: `shout` for `fun <T> List<T>.shout(): String {`.
+fn kotlin_function_name(sig: &str) -> Option<&str> {
+    let mut rest = sig;
+    while let Some(after) = [
+        "public ",
+        "private ",
+        "internal ",
+        "protected ",
+        "override ",
+        "open ",
+        "final ",
+        "abstract ",
+        "suspend ",
+        "inline ",
+        "operator ",
+        "infix ",
+        "tailrec ",
+        "external ",
+        "actual ",
+        "expect ",
+    ]
+    .iter()
+    .find_map(|modifier| rest.strip_prefix(modifier))
+    {
+        rest = after;
+    }
+    let mut rest = rest.strip_prefix("fun ")?.trim_start();
+    if rest.starts_with('<') {
+        rest = skip_template_parameters(rest)?.trim_start();
+    }
+    let paren = rest.find('(')?;
+    // The receiver type, if any, ends at the last `.` outside its type arguments
+    let mut depth = 0;
+    let mut name_start = 0;
+    for (i, c) in rest[..paren].char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            '.' if depth == 0 => name_start = i + 1,
+            _ => {}
+        }
+    }
+    let name = rest[name_start..paren].trim_end();
+    let (identifier, leftover) = split_js_identifier(name.trim_matches('`'));
+    (!identifier.is_empty() && leftover.is_empty()).then_some(name)
+}
 
-    if let (Some(f), Some(e)) = (found_name, expected_name) {
-        return f == e;
+/// Name of the function a Swift `func` declaration declares, after attributes and
+/// modifiers: `load` for `@MainActor public static func load<T: Decodable>(from url: URL) {`.
+fn swift_function_name(sig: &str) -> Option<&str> {
+    let mut rest = sig;
+    loop {
+        let attribute = rest
+            .strip_prefix('@')
+            .and_then(|after| Some(&after[after.find(' ')? + 1..]));
+        let modifier = [
+            "public ",
+            "private ",
+            "fileprivate ",
+            "internal ",
+            "open ",
+            "static ",
+            "class ",
+            "final ",
+            "override ",
+            "mutating ",
+            "nonmutating ",
+            "dynamic ",
+            "nonisolated ",
+        ]
+        .iter()
+        .find_map(|modifier| rest.strip_prefix(modifier));
+        match attribute.or(modifier) {
+            Some(after) => rest = after.trim_start(),
+            None => break,
+        }
     }
+    let rest = rest.strip_prefix("func ")?.trim_start();
+    let (name, after_name) = split_js_identifier(rest);
+    let after_name = after_name.trim_start();
+    (!name.is_empty() && (after_name.starts_with('(') || after_name.starts_with('<')))
+        .then_some(name)
+}
 
-    false
+/// Name of the method a Ruby `def` line defines: `valid?` for `def valid?(input)`, `build`
+/// for the singleton `def self.build`, and `reset` for the one-line `def reset; end`.
+/// Python's `def name(args):` is not a Ruby method.
+fn ruby_function_name(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("def ")?.trim_start();
+    let rest = rest.strip_prefix("self.").unwrap_or(rest);
+    let end = rest
+        .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '?' | '!' | '=')))
+        .unwrap_or(rest.len());
+    let (name, after) = rest.split_at(end);
+    let after = after.trim_start();
+    let after = match after.strip_prefix('(') {
+        Some(params) => skip_to_closing_paren(params)?.trim_start(),
+        None => after,
+    };
+    // `def name(self) -> int:`
+    let is_python = after.starts_with(':') || after.starts_with("->");
+    (!name.is_empty() && !is_python).then_some(name)
 }
 
-/// Extract the function name from a signature line.
-fn extract_function_name(sig: &str) -> Option<&str> {
-    // Handle Rust: fn name, pub fn name, async fn name, etc.
-    if let Some(pos) = sig.find(" fn ") {
-        let after_fn = &sig[pos + 4..];
-        return after_fn.split(&['(', '<', ' '][..]).next();
+/// Name of the function a Lua line declares, qualified as written: `parse` for
+/// `local function parse(s)`, `M.setup` for `function M.setup(opts)`, and `handler` for an
+/// anonymous function assigned to a name, `local handler = function(err)`.
+fn lua_function_name(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("local ").unwrap_or(line).trim_start();
+    let (name, is_function) = match rest.strip_prefix("function ") {
+        Some(after) => {
+            let after = after.trim_start();
+            (after[..after.find('(')?].trim_end(), true)
+        }
+        None => {
+            let eq = rest.find('=')?;
+            let value = rest[eq + 1..].trim_start();
+            let is_function = value
+                .strip_prefix("function")
+                .is_some_and(|params| params.trim_start().starts_with('('));
+            (rest[..eq].trim_end(), is_function)
+        }
+    };
+    let is_name = name
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | ':'))
+        && name.starts_with(|c: char| c.is_alphabetic() || c == '_');
+    (is_function && is_name).then_some(name)
+}
+
+/// `function` declarations (optionally exported or async), class methods and arrow functions
+/// assigned to a name.
+fn is_js_signature(line: &str) -> bool {
+    let mut rest = line;
+    for prefix in ["export ", "default ", "async "] {
+        rest = rest.strip_prefix(prefix).unwrap_or(rest);
     }
-    if sig.starts_with("fn ") {
-        return sig[3..].split(&['(', '<', ' '][..]).next();
+    // Anonymous `export default function() {` has no name to extract
+    rest.starts_with("function(")
+        || rest.starts_with("function*(")
+        || js_function_name(line).is_some()
+}
+
+/// Name of the function a JavaScript/TypeScript line declares: `function foo(`, a class
+/// method `async foo(args): T {`, or an arrow function `const foo = async (req) => {`.
+fn js_function_name(line: &str) -> Option<&str> {
+    let mut rest = line;
+    for prefix in ["export ", "default ", "declare "] {
+        rest = rest.strip_prefix(prefix).unwrap_or(rest);
     }
 
-    // Handle Python: def name, async def name
-    if let Some(pos) = sig.find("def ") {
-        let after_def = &sig[pos + 4..];
-        return after_def.split(&['(', ' ', ':'][..]).next();
+    // `function foo(`, `async function* gen<T>(`
+    let declaration = rest.strip_prefix("async ").unwrap_or(rest);
+    if let Some(after) = declaration.strip_prefix("function") {
+        let after = after.trim_start_matches('*').trim_start();
+        let (name, after_name) = split_js_identifier(after);
+        let after_name = after_name.trim_start();
+        return (!name.is_empty() && (after_name.starts_with('(') || after_name.starts_with('<')))
+            .then_some(name);
     }
 
-    // Handle C/C++/Java: type name(
-    // Look for identifier followed by (
-    if let Some(paren_pos) = sig.find('(') {
-        let before_paren = sig[..paren_pos].trim();
-        // Get the last word before (
-        return before_paren.split_whitespace().last();
+    // `const foo = (`, `let foo: Handler = async req =>`
+    for keyword in ["const ", "let ", "var "] {
+        if let Some(after) = rest.strip_prefix(keyword) {
+            let (name, after_name) = split_js_identifier(after.trim_start());
+            return (!name.is_empty() && is_js_function_value(after_name)).then_some(name);
+        }
     }
 
-    None
+    // Class members: `static async #load<T>(id: string): Promise<T> {`, `onClick = () => {`
+    loop {
+        let stripped = [
+            "public ",
+            "private ",
+            "protected ",
+            "static ",
+            "async ",
+            "override ",
+            "readonly ",
+            "abstract ",
+            "get ",
+            "set ",
+            "*",
+        ]
+        .iter()
+        .find_map(|modifier| rest.strip_prefix(modifier));
+        match stripped {
+            Some(after) => rest = after.trim_start(),
+            None => break,
+        }
+    }
+    let (name, after_name) = split_js_identifier(rest);
+    if name.is_empty()
+        || matches!(
+            name,
+            "if" | "for"
+                | "while"
+                | "switch"
+                | "catch"
+                | "with"
+                | "function"
+                | "return"
+                | "synchronized"
+        )
+    {
+        return None;
+    }
+    if is_js_function_value(after_name) {
+        return Some(name);
+    }
+    let params = after_name.trim_start();
+    let params = match params.strip_prefix('<') {
+        Some(generics) => &generics[generics.find('>')? + 1..],
+        None => params,
+    };
+    let after_params = params
+        .strip_prefix('(')
+        .and_then(skip_to_closing_paren)?
+        .trim();
+    let is_body = after_params == "{"
+        || (after_params.starts_with(':')
+            && after_params.ends_with('{')
+            && !after_params.contains("=>"));
+    is_body.then_some(name)
 }
 
-/// Search forward from a line to find a function with the expected signature.
-fn find_function_start_forward(
-    lines: &[&str],
-    start_search_line: usize,
-    expected_signature: &str,
-) -> Option<usize> {
-    let expected_name = extract_function_name(expected_signature)?;
-
-    for i in start_search_line..lines.len() {
-        let line = lines[i].trim();
+/// Name of the function a C/C++/Java declaration line declares, qualified as written:
+/// `compute` for `static inline int compute(int a) {`, `Stack<T>::pop` for
+/// `T Stack<T>::pop() const {` and `operator==` for `bool operator==(const Foo& o) const {`.
+///
+/// A same-line `template<...>` prefix is skipped. Statements (`;`-terminated, assignments,
+/// member calls, `return f(x)`), preprocessor lines and bare macro invocations like
+/// `DEFINE_FOO(bar)` are not declarations.
+fn cpp_function_name(line: &str) -> Option<&str> {
+    if line.starts_with('#')
+        || line.ends_with('\\')
+        || (line.ends_with(';') && !line.contains("=>"))
+    {
+        return None;
+    }
+    let rest = match line.strip_prefix("template") {
+        Some(after) if after.trim_start().starts_with('<') => {
+            skip_template_parameters(after.trim_start())?.trim_start()
+        }
+        _ => line,
+    };
 
-        // Check if this line looks like a function start
-        if is_function_start(line) {
-            if let Some(found_name) = extract_function_name(line) {
-                if found_name == expected_name {
-                    return Some(i);
+    // The parameter list's `(`, past the name of an `operator()`
+    let operator = rest.find("operator").filter(|&pos| {
+        !rest[pos + "operator".len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    });
+    let search_from = match operator {
+        Some(pos) => {
+            let after = &rest[pos + "operator".len()..];
+            let call_operator = after.trim_start().starts_with("()");
+            pos + "operator".len()
+                + if call_operator {
+                    after.find(')')? + 1
+                } else {
+                    0
                 }
-            }
         }
+        None => 0,
+    };
+    let paren = search_from + rest[search_from..].find('(')?;
+    let before = rest[..paren].trim_end();
+    if (operator.is_none() && (before.contains('=') || before.contains("<<")))
+        || before.contains('.')
+        || before.contains("->")
+        || before.contains('"')
+    {
+        return None;
     }
 
-    None
-}
+    // The declarator ends the text before `(`; template arguments may contain spaces
+    let scan_end = operator.unwrap_or(before.len());
+    let mut depth = 0;
+    let mut name_start = 0;
+    for (i, c) in before[..scan_end].char_indices().rev() {
+        match c {
+            '>' => depth += 1,
+            '<' => depth -= 1,
+            c if depth == 0 && (c.is_whitespace() || c == '*' || c == '&') => {
+                name_start = i + c.len_utf8();
+                break;
+            }
+            _ => {}
+        }
+    }
+    let name = &before[name_start..];
+    let return_type = before[..name_start].trim();
+    // `::` qualifies a name, but a lone `:` ends a Kotlin/Swift/TS binding like `val f: (Int)`
+    if name.replace("::", "").contains(':') {
+        return None;
+    }
 
-/// Search the entire document for a function matching the expected signature.
-fn find_function_by_signature(lines: &[&str], expected_signature: &str) -> Option<usize> {
-    let expected_name = extract_function_name(expected_signature)?;
+    let last_segment = name.rsplit("::").next().unwrap_or(name);
+    let is_identifier = last_segment
+        .trim_start_matches('~')
+        .starts_with(|c: char| c.is_alphabetic() || c == '_');
+    if !is_identifier
+        || matches!(
+            last_segment,
+            "if" | "for" | "while" | "switch" | "catch" | "return" | "sizeof" | "decltype"
+        )
+    {
+        return None;
+    }
+    let is_type = return_type.chars().all(|c| {
+        c.is_alphanumeric()
+            || c.is_whitespace()
+            || matches!(c, '_' | ':' | '<' | '>' | ',' | '*' | '&' | '[' | ']')
+    });
+    // Statements in C-family and other languages' bodies: `return f(x)`, `if check(x):`
+    let has_statement_keyword = return_type.split_whitespace().any(|word| {
+        matches!(
+            word,
+            "return"
+                | "new"
+                | "delete"
+                | "else"
+                | "throw"
+                | "case"
+                | "goto"
+                | "co_return"
+                | "co_await"
+                | "co_yield"
+                | "if"
+                | "elif"
+                | "while"
+                | "for"
+                | "not"
+                | "and"
+                | "or"
+                | "in"
+                | "is"
+                | "await"
+                | "yield"
+                | "assert"
+                | "raise"
+                | "with"
+                | "match"
+                | "defer"
+                | "go"
+                | "typeof"
+                // Type declarations with primary constructors: `class Repo(val name: String)`
+                | "class"
+                | "struct"
+                | "enum"
+                | "interface"
+                | "object"
+                | "record"
+                | "val"
+                | "var"
+                | "let"
+        )
+    });
+    if !is_type || has_statement_keyword {
+        return None;
+    }
+    if return_type.is_empty() && !name.contains("operator") {
+        // Only out-of-class constructors and destructors (`Foo::Foo(`, `Foo::~Foo(`) and
+        // conversion operators (`operator bool(`) have no return type; anything else is a
+        // call or a macro invocation
+        let mut segments = name.rsplit("::");
+        let (member, class) = (segments.next()?, segments.next()?);
+        let class = class.split('<').next().unwrap_or(class);
+        if member.trim_start_matches('~') != class {
+            return None;
+        }
+    }
 
-    for (i, line) in lines.iter().enumerate() {
-        let line = line.trim();
+    // Only qualifiers, a trailing return type, a constructor's initializer list or the body
+    // may follow the parameters (which may also continue on the next line)
+    let Some(after) = skip_to_closing_paren(&rest[paren + 1..]) else {
+        return Some(name);
+    };
+    let after = after.trim();
+    // A C# expression-bodied member ends with its `;`
+    if after.starts_with("=>") {
+        return Some(name);
+    }
+    let is_declaration_tail = after.is_empty()
+        || (after.starts_with(':') && after.len() > 1)
+        || [
+            "{", "const", "override", "final", "noexcept", "->", "throw", "volatile", "&",
+            "requires", "where",
+        ]
+        .iter()
+        .any(|tail| after.starts_with(tail));
+    (is_declaration_tail && !after.contains(';')).then_some(name)
+}
 
-        if is_function_start(line) {
-            if let Some(found_name) = extract_function_name(line) {
-                if found_name == expected_name {
-                    return Some(i);
+/// Text after a `<...>` template parameter list at the start of `text`.
+fn skip_template_parameters(text: &str) -> Option<&str> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[i + 1..]);
                 }
             }
+            _ if depth == 0 => return None,
+            _ => {}
         }
     }
-
     None
 }
 
-/// Check if a line looks like a function start.
-fn is_function_start(line: &str) -> bool {
-    // Rust
-    if line.starts_with("fn ")
-        || line.starts_with("pub fn ")
-        || line.starts_with("async fn ")
-        || line.starts_with("pub async fn ")
-        || line.starts_with("pub(crate) fn ")
-        || line.contains(" fn ")
-    {
-        return true;
-    }
+/// A function defined through a macro, like GoogleTest's `TEST(Math, Adds) {`, named by the
+/// whole invocation since the macro name alone is shared by every such function.
+fn macro_function_name(line: &str) -> Option<&str> {
+    let (macro_name, after) = split_js_identifier(line);
+    let is_macro = macro_name.len() > 1
+        && macro_name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+    let after_args = skip_to_closing_paren(after.strip_prefix('(')?)?;
+    (is_macro && after_args.trim() == "{").then(|| &line[..line.len() - after_args.len()])
+}
 
-    // Python
-    if line.starts_with("def ") || line.starts_with("async def ") {
-        return true;
+/// First line of the `template<...>` header lines directly above the declaration at `line`.
+fn template_header_start(lines: &[&str], line: usize) -> usize {
+    let mut start = line;
+    while start > 0 && is_template_header(lines[start - 1].trim()) {
+        start -= 1;
     }
+    start
+}
 
-    // C++/Java: return types and modifiers with parentheses
-    if (line.starts_with("void ")
-        || line.starts_with("int ")
-        || line.starts_with("bool ")
-        || line.starts_with("char ")
-        || line.starts_with("float ")
-        || line.starts_with("double ")
-        || line.starts_with("public ")
-        || line.starts_with("private ")
-        || line.starts_with("protected ")
-        || line.starts_with("static "))
-        && line.contains('(')
-    {
-        return true;
-    }
+/// A `template<...>` line with the declaration it introduces on the following line.
+fn is_template_header(line: &str) -> bool {
+    line.strip_prefix("template")
+        .and_then(|after| skip_template_parameters(after.trim_start()))
+        .is_some_and(|after| after.trim().is_empty())
+}
 
-    false
+/// Whether the line is part of a preprocessor directive, including the continuation lines
+/// of a multi-line `#define`, where function-like text is never a definition to replace.
+fn is_preprocessor_line(lines: &[&str], line: usize) -> bool {
+    let text = lines[line].trim();
+    (text.starts_with('#') && !text.starts_with("#[") && !text.starts_with("#!"))
+        || (line > 0 && lines[line - 1].trim_end().ends_with('\\'))
 }
 
-/// Find the end line of a function based on brace counting.
-///
-/// Returns the line number (0-indexed) of the closing brace.
-pub fn find_function_end(lines: &[&str], start_line: usize) -> Option<usize> {
-    let mut open_braces = 0;
-    let mut found_start = false;
+/// Split a leading JavaScript identifier (`$el`, `#private`, `_id`) off `text`.
+fn split_js_identifier(text: &str) -> (&str, &str) {
+    let end = text
+        .char_indices()
+        .find(|&(i, c)| !(c.is_alphanumeric() || c == '_' || c == '$' || (i == 0 && c == '#')))
+        .map_or(text.len(), |(i, _)| i);
+    text.split_at(end)
+}
 
-    for (i, line) in lines.iter().enumerate().skip(start_line) {
-        // Simple brace counting - ignores comments/strings for now which is a limitation
-        // but likely sufficient for mostly-correct code.
-        for char in line.chars() {
-            match char {
-                '{' => {
-                    open_braces += 1;
-                    found_start = true;
+/// The text after the `(` opening a parameter list, from just past its matching `)`.
+fn skip_to_closing_paren(text: &str) -> Option<&str> {
+    let mut depth = 1;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[i + 1..]);
                 }
-                '}' => {
-                    open_braces -= 1;
-                }
-                _ => {}
             }
-        }
-
-        if found_start && open_braces == 0 {
-            return Some(i);
-        }
-
-        // Safety valve for unbalanced braces going negative
-        if found_start && open_braces < 0 {
-            return Some(i);
+            _ => {}
         }
     }
-
     None
 }
 
-/// Replace a function in the file content with a new implementation.
-pub fn replace_function(
-    file_content: &str,
-    start_line: usize,
-    new_implementation: &str,
-) -> Option<String> {
-    let lines: Vec<&str> = file_content.lines().collect();
+/// Whether `text`, following a name, assigns it a function: an optional type annotation,
+/// `=`, then an arrow function or `function` expression.
+fn is_js_function_value(text: &str) -> bool {
+    let Some(eq) = text.find('=') else {
+        return false;
+    };
+    let annotation = text[..eq].trim();
+    if !(annotation.is_empty() || annotation.starts_with(':')) || text[eq..].starts_with("==") {
+        return false;
+    }
+    let value = text[eq + 1..].trim_start();
+    let value = value.strip_prefix("async").map_or(value, str::trim_start);
+    if value.starts_with("function") {
+        return true;
+    }
+    let arrow = value.find("=>");
+    let is_arrow_head = |head: &str| {
+        let head = head.trim();
+        head.starts_with('(')
+            || head.starts_with('<')
+            || (!head.is_empty() && split_js_identifier(head).1.is_empty())
+    };
+    arrow.is_some_and(|arrow| is_arrow_head(&value[..arrow]))
+}
 
-    if start_line >= lines.len() {
+/// Extract a function signature for tracking purposes.
+/// This is used to identify functions when line numbers may have shifted.
+///
+/// Supports multiple languages: Rust, C++, Python, Java, etc.
+pub fn extract_function_signature(text: &str, line: usize) -> Option<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    if line >= lines.len() {
         return None;
     }
 
-    let end_line = find_function_end(&lines, start_line)?;
-
-    let mut new_lines = Vec::new();
-    // extend from start to start_line (exclusive)
-    new_lines.extend_from_slice(&lines[0..start_line]);
-
-    // add new implementation lines
-    new_lines.extend(new_implementation.lines());
-
-    // extend from end_line + 1 to end
-    if end_line + 1 < lines.len() {
-        new_lines.extend_from_slice(&lines[end_line + 1..]);
-    }
+    // Find function start from the given line
+    let start_line = find_function_start(&lines, line)?;
 
-    Some(new_lines.join("\n") + "\n") // Add trailing newline
+    // Return the whole declaration on one line, even if it is wrapped
+    // This is a simple identifier that should remain stable
+    Some(signature_at(&lines, start_line))
 }
 
-/// Replace a function in the current document, handling concurrent edits.
-/// Returns (new_text, start_line, end_line, lines_delta)
+/// The signature of the function starting at `start_line`, normalized to one line.
 ///
-/// The `expected_signature` parameter is used to verify we found the correct function.
-/// This is critical for concurrent implementations where line numbers may have shifted.
-pub fn replace_function_in_document(
-    current_text: &str,
-    current_line: usize,
-    new_implementation: &str,
-    expected_signature: Option<&str>,
-) -> Result<(String, u32, u32, i32), String> {
-    use tracing::info;
+/// Reads on through wrapped parameter lists and `where` clauses until the body opens with
+/// `{` (or Python's `:`), a declaration ends with `;`, or the line holds an expression body
+/// or a Ruby/Lua signature. Stops at a blank line.
+fn signature_at(lines: &[&str], start_line: usize) -> String {
+    let mut signature = String::new();
+    let mut depth = 0;
+    for line in lines[start_line..].iter().map(|l| l.trim()) {
+        if line.is_empty() {
+            break;
+        }
+        signature.push(' ');
+        signature.push_str(line);
+        for c in line.chars() {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                _ => {}
+            }
+        }
+        if depth > 0 {
+            continue;
+        }
+        // `{` or `;` anywhere covers one-line bodies; `): ` a one-line Python def
+        let body_opens = line.contains(['{', ';']) || line.ends_with(':') || line.contains("): ");
+        if body_opens
+            || expression_body_start(line).is_some()
+            || end_keyword_language(lines, start_line).is_some()
+        {
+            break;
+        }
+    }
+    normalize_signature(&signature)
+}
 
-    let lines: Vec<&str> = current_text.lines().collect();
+/// Collapse the whitespace of a (possibly wrapped) signature to single spaces, dropping the
+/// padding inside brackets and trailing commas left by one-parameter-per-line formatting:
+/// `fn f(\n    a: u8,\n) {` becomes `fn f(a: u8) {`.
+pub fn normalize_signature(signature: &str) -> String {
+    let mut normalized = signature.split_whitespace().collect::<Vec<_>>().join(" ");
+    for (from, to) in [
+        ("( ", "("),
+        ("[ ", "["),
+        (" )", ")"),
+        (" ]", "]"),
+        (",)", ")"),
+        (",]", "]"),
+        (", {", " {"),
+    ] {
+        normalized = normalized.replace(from, to);
+    }
+    normalized
+}
 
-    info!(
-        "replace_function_in_document: current_line={}, expected_signature={:?}, total_lines={}",
-        current_line,
-        expected_signature,
-        lines.len()
-    );
+/// Statements that mark a function body as not yet written.
+const PLACEHOLDER_STATEMENTS: &[&str] = &[
+    "todo!()",
+    "unimplemented!()",
+    "pass",
+    "...",
+    "raise NotImplementedError",
+    "raise NotImplementedError()",
+    "panic(\"not implemented\")",
+    "panic(\"unimplemented\")",
+    "panic(\"TODO\")",
+    "TODO()",
+    "fatalError()",
+    "throw new NotImplementedException()",
+    "throw NotImplementedError()",
+    "error(\"not implemented\")",
+];
+
+/// Check whether the function at `line` already has a real implementation.
+///
+/// A body counts as a placeholder when, ignoring blank lines and comments, it is empty or
+/// only holds placeholder statements such as `todo!()` or `pass`. Returns false when the
+/// function cannot be located, leaving that case to the backend.
+pub fn has_non_placeholder_body(text: &str, line: usize) -> bool {
+    let lines: Vec<&str> = text.lines().collect();
+    let Ok((start_line, end_line)) = locate_function(&lines, line, None) else {
+        return false;
+    };
+    let function_text = lines[start_line..=end_line].join("\n");
+
+    let braces = code_braces(&function_text);
+    let close_brace = braces
+        .last()
+        .filter(|&&(_, brace)| brace == '}')
+        .map(|&(i, _)| i);
+    let body = match (body_open_brace(&braces), close_brace) {
+        _ if end_keyword_language(&lines, start_line).is_some() => keyword_body(&function_text),
+        _ if expression_body_start(lines[start_line].trim()).is_some() => {
+            let signature = lines[start_line].trim();
+            let offset = function_text.find(signature).unwrap_or(0);
+            &function_text[offset + expression_body_start(signature).unwrap_or(0)..]
+        }
+        (Some(open), Some(close)) if close > open => &function_text[open + 1..close],
+        _ => match function_text.find(":\n") {
+            // Brace-less languages (Python): everything after the signature line
+            Some(colon) => &function_text[colon + 2..],
+            None => "",
+        },
+    };
 
-    if current_line >= lines.len() {
-        return Err("Line out of bounds".to_string());
+    body.lines()
+        .flat_map(|l| l.split(';'))
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && !is_comment(s))
+        .any(|s| !is_placeholder_statement(s))
+}
+
+/// Start lines of every function in `text` whose body is still a placeholder, in document
+/// order.
+pub fn find_placeholder_functions(text: &str) -> Vec<usize> {
+    let lines: Vec<&str> = text.lines().collect();
+    (0..lines.len())
+        .filter(|&line| is_function_start(lines[line].trim()))
+        .filter(|&line| locate_function(&lines, line, None).is_ok())
+        .filter(|&line| !has_non_placeholder_body(text, line))
+        .collect()
+}
+
+/// Byte offset of the brace opening a function's body, given its `code_braces`: the last
+/// one opened at depth zero, skipping balanced braces in the signature such as Go's
+/// `interface{}`.
+fn body_open_brace(braces: &[(usize, char)]) -> Option<usize> {
+    let mut depth = 0;
+    let mut open = None;
+    for &(i, brace) in braces {
+        if brace == '{' {
+            if depth == 0 {
+                open = Some(i);
+            }
+            depth += 1;
+        } else {
+            depth -= 1;
+        }
     }
+    open
+}
 
-    // Find the actual function start (in case cursor is inside function)
-    // First try backwards search from current_line
-    let mut start_line = find_function_start(&lines, current_line);
+/// The body of a function closed by `end`: the text between its signature line (or the `;`
+/// of a one-line `def reset; end`) and the final `end`.
+fn keyword_body(function_text: &str) -> &str {
+    let end = function_text.rfind("end").unwrap_or(function_text.len());
+    let start = function_text[..end]
+        .find('\n')
+        .or_else(|| function_text[..end].find(';'))
+        .map_or(end, |i| i + 1);
+    &function_text[start..end]
+}
 
-    info!(
-        "Backward search from line {} found function at line {:?}",
-        current_line, start_line
-    );
+fn is_comment(statement: &str) -> bool {
+    ["//", "#", "/*", "*", "-- "]
+        .iter()
+        .any(|prefix| statement.starts_with(prefix))
+}
 
-    // Verify we found the correct function using signature matching
-    if let (Some(start), Some(expected_sig)) = (start_line, expected_signature) {
-        let found_sig = lines[start].trim();
-        info!(
-            "Comparing found_sig='{}' with expected_sig='{}'",
-            found_sig, expected_sig
-        );
-        // Check if the found signature matches the expected one
-        // We compare trimmed versions and check for containment to handle minor differences
-        if !signatures_match(found_sig, expected_sig) {
-            info!("Signatures don't match! Searching forward and globally...");
-            // Wrong function found! Search forward from current_line instead
-            start_line = find_function_start_forward(&lines, current_line, expected_sig);
-            info!("Forward search result: {:?}", start_line);
-            if start_line.is_none() {
-                // Try searching the entire document for the matching signature
-                start_line = find_function_by_signature(&lines, expected_sig);
-                info!("Global search result: {:?}", start_line);
+fn is_placeholder_statement(statement: &str) -> bool {
+    PLACEHOLDER_STATEMENTS.contains(&statement)
+        || statement.starts_with("todo!(")
+        || statement.starts_with("unimplemented!(")
+        || statement.starts_with("TODO(")
+        || statement.starts_with("fatalError(")
+        || statement.starts_with("raise NotImplementedError,")
+}
+
+/// Find the start line of the function containing or at the given line.
+///
+/// Scans backwards from `line` to find a line with function keywords.
+/// Supports: Rust (fn), C++ (void, int, etc.), Python (def), Java (public/private/void/etc.)
+///
+/// A nested function (a Rust `fn` or Python `def` inside another, a JS closure assigned to
+/// a name) that ends above `line` does not contain it, so the innermost enclosing function
+/// is returned instead: the nearest start above that is less indented and whose end is at
+/// or below `line`. Without one, the nearest start is returned.
+pub fn find_function_start(lines: &[&str], start_search_line: usize) -> Option<usize> {
+    if start_search_line >= lines.len() {
+        return None;
+    }
+
+    // A `template<...>` header belongs to the declaration below it
+    if is_template_header(lines[start_search_line].trim()) {
+        let declaration = (start_search_line + 1..lines.len())
+            .find(|&line| !is_template_header(lines[line].trim()))?;
+        if cpp_function_name(lines[declaration].trim()).is_some() {
+            return Some(declaration);
+        }
+    }
+
+    let nearest = nearest_function_start(lines, start_search_line)?;
+    let contains =
+        |start: usize| find_function_end(lines, start).is_none_or(|end| end >= start_search_line);
+    if contains(nearest) {
+        return Some(nearest);
+    }
+    let mut candidate = nearest;
+    let mut indent = leading_whitespace(lines[nearest]).len();
+    while indent > 0 && candidate > 0 {
+        let Some(start) = nearest_function_start(lines, candidate - 1) else {
+            break;
+        };
+        candidate = start;
+        let candidate_indent = leading_whitespace(lines[candidate]).len();
+        if candidate_indent >= indent {
+            continue;
+        }
+        indent = candidate_indent;
+        if contains(candidate) {
+            return Some(candidate);
+        }
+    }
+    Some(nearest)
+}
+
+/// The nearest line at or above `start_search_line` that starts a function.
+fn nearest_function_start(lines: &[&str], start_search_line: usize) -> Option<usize> {
+    let mut current_line = start_search_line;
+    loop {
+        let line = lines[current_line].trim();
+
+        // Macro definitions can look like anything
+        if is_preprocessor_line(lines, current_line) {
+            if current_line == 0 {
+                break;
             }
-        } else {
-            info!("Signatures match!");
+            current_line -= 1;
+            continue;
+        }
+
+        // A closure inside the function is not where it starts
+        if is_closure_start(line) {
+            if current_line == 0 {
+                break;
+            }
+            current_line -= 1;
+            continue;
+        }
+
+        // Check for function keywords in various languages
+        // Rust: fn, pub fn, async fn, etc.
+        if line.starts_with("fn ")
+            || line.starts_with("pub fn ")
+            || line.starts_with("async fn ")
+            || line.starts_with("pub async fn ")
+            || line.starts_with("pub(crate) fn ")
+            || line.contains(" fn ")
+        {
+            return Some(current_line);
+        }
+
+        // Python: def
+        if line.starts_with("def ") || line.starts_with("async def ") {
+            return Some(current_line);
         }
+
+        // Go: func name(, func (recv T) name(, func Name[T any](
+        if is_go_signature(line) {
+            return Some(current_line);
+        }
+
+        // Kotlin: fun name(; Swift: func name<T>(
+        if kotlin_function_name(line).is_some() || swift_function_name(line).is_some() {
+            return Some(current_line);
+        }
+
+        // JavaScript/TypeScript: function foo(, class methods, const foo = () => {
+        if js_function_name(line).is_some() {
+            return Some(current_line);
+        }
+
+        // Lua: local function foo(, function M.foo(, local foo = function(
+        if lua_function_name(line).is_some() {
+            return Some(current_line);
+        }
+
+        // C/C++/Java: `[modifiers] ReturnType name(`, with namespaces and templates
+        if cpp_function_name(line).is_some() {
+            return Some(current_line);
+        }
+
+        if current_line == 0 {
+            break;
+        }
+        current_line -= 1;
     }
+    None
+}
 
-    let start_line = start_line.ok_or_else(|| "Could not find function start".to_string())?;
-    info!("Final start_line: {}", start_line);
+/// Check if two function signatures name the same function (see `signature_match`).
+#[cfg(test)]
+fn signatures_match(found: &str, expected: &str) -> bool {
+    signature_match(found, expected).is_some()
+}
 
-    // Find the function end
-    let end_line = find_function_end(&lines, start_line)
-        .ok_or_else(|| "Could not find function end".to_string())?;
+/// How closely a function's signature matches the one a job expects, from worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignatureMatch {
+    /// Same name only: the parameter lists differ in length or one of them is missing.
+    Name,
+    /// Same name and number of parameters, with the summed similarity of their types (100
+    /// per parameter whose type has exactly the same tokens).
+    Arity(usize),
+    /// Same signature once normalized.
+    Exact,
+}
 
-    // Calculate lines delta
-    let old_function_lines = (end_line - start_line + 1) as i32;
-    let new_function_lines = new_implementation.lines().count() as i32;
-    let lines_delta = new_function_lines - old_function_lines;
+/// How `found` matches `expected`, or None when they name different functions.
+///
+/// Overloads (C++, Java, TypeScript, Kotlin, Swift) share a name, so when both signatures
+/// have a parameter list the one with the same parameter count and types ranks higher.
+pub fn signature_match(found: &str, expected: &str) -> Option<SignatureMatch> {
+    let found = normalize_signature(found);
+    let expected = normalize_signature(expected);
+
+    // Exact match
+    if found == expected {
+        return Some(SignatureMatch::Exact);
+    }
 
-    // Build new document
-    let mut new_lines = Vec::new();
+    // Extract function names and compare
+    let found_name = extract_function_name(&found)?;
+    let expected_name = extract_function_name(&expected)?;
+    if !function_names_match(found_name, expected_name) {
+        return None;
+    }
 
-    // Lines before function
-    new_lines.extend_from_slice(&lines[0..start_line]);
+    match (
+        parameter_types(&found, found_name),
+        parameter_types(&expected, expected_name),
+    ) {
+        (Some(found), Some(expected)) if found.len() == expected.len() => {
+            let similarity = found
+                .iter()
+                .zip(&expected)
+                .map(|(f, e)| token_similarity(f, e))
+                .sum();
+            Some(SignatureMatch::Arity(similarity))
+        }
+        _ => Some(SignatureMatch::Name),
+    }
+}
 
-    // New implementation
-    new_lines.extend(new_implementation.lines());
+/// The type tokens of each parameter of the function `name` in `signature`, or None when
+/// it has no complete parameter list.
+///
+/// Types follow a `:` (`a: i32`, `a: number`) or precede the parameter's name (`int a`,
+/// `const std::string& s`); default values are ignored.
+fn parameter_types<'a>(signature: &'a str, name: &str) -> Option<Vec<Vec<&'a str>>> {
+    let (params, _) = split_parameters(signature, name)?;
+    Some(params.into_iter().map(parameter_type).collect())
+}
 
-    // Lines after function
-    if end_line + 1 < lines.len() {
-        new_lines.extend_from_slice(&lines[end_line + 1..]);
+/// The parameters of the function `name` in `signature`, split at their top-level commas,
+/// and the text after the closing `)`. None when it has no complete parameter list.
+pub fn split_parameters<'a>(signature: &'a str, name: &str) -> Option<(Vec<&'a str>, &'a str)> {
+    let after_name = signature.find(name)? + name.len();
+    let open = after_name + signature[after_name..].find('(')?;
+    let mut params = Vec::new();
+    let mut depth = 0;
+    let mut param_start = open + 1;
+    let mut prev = '(';
+    let mut close = None;
+    for (i, c) in signature[open + 1..].char_indices() {
+        let i = open + 1 + i;
+        match c {
+            '(' | '[' | '{' | '<' => depth += 1,
+            ')' if depth == 0 => {
+                close = Some(i);
+                break;
+            }
+            // `->` in a closure type is not a closing bracket
+            '>' if prev == '-' => {}
+            ')' | ']' | '}' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                params.push(&signature[param_start..i]);
+                param_start = i + 1;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+    let close = close?;
+    let last = &signature[param_start..close];
+    if !last.trim().is_empty() {
+        params.push(last);
     }
+    Some((params, &signature[close + 1..]))
+}
 
-    let new_text = new_lines.join("\n") + "\n";
+/// The identifier tokens of a parameter's type.
+fn parameter_type(param: &str) -> Vec<&str> {
+    let param = param.split('=').next().unwrap_or(param).trim();
+    let colon = param
+        .char_indices()
+        .find(|&(i, c)| c == ':' && !param[i..].starts_with("::") && !param[..i].ends_with(':'));
+    let ty = match colon {
+        Some((i, _)) => &param[i + 1..],
+        // `Type name`: everything before the name
+        None => param
+            .rfind(|c: char| c.is_whitespace() || c == '*' || c == '&')
+            .map_or(param, |pos| &param[..=pos]),
+    };
+    ty.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|token| !token.is_empty())
+        .collect()
+}
 
-    Ok((new_text, start_line as u32, end_line as u32, lines_delta))
+/// How many percent of the distinct tokens of `a` and `b` they share.
+fn token_similarity(a: &[&str], b: &[&str]) -> usize {
+    let a: HashSet<&str> = a.iter().copied().collect();
+    let b: HashSet<&str> = b.iter().copied().collect();
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 100;
+    }
+    a.intersection(&b).count() * 100 / union
 }
 
-/// Create a 3-way merge edit.
+/// Whether two names from `extract_function_name` name the same function.
 ///
-/// 1. Constructs "Theirs" by applying `implementation` to `base_text`.
-/// 2. Writes "Theirs" to a temporary file.
-/// 3. Merges `base_text`, `current_text`, and `theirs_text`.
-/// 4. Returns a full-file replacement WorkspaceEdit and the number of lines added.
-pub fn create_3way_merge_edit(
-    uri: &Url,
-    base_text: &str,
-    current_text: &str,
-    implementation: &str,
-    line: usize,
-) -> Result<(WorkspaceEdit, i32), String> {
-    // 1. Construct "Theirs" version
-    let theirs_text = replace_function(base_text, line, implementation)
-        .ok_or_else(|| "Failed to replace function in base text".to_string())?;
-
-    // 2. Write to temporary file
-    match NamedTempFile::new() {
-        Ok(mut file) => {
-            if let Err(e) = file.write_all(theirs_text.as_bytes()) {
-                // Log but don't fail the operation just because temp write failed?
-                // The requirements emphasize writing to temp file.
-                // We'll log to stderr/tracing if possible, but here we just proceed.
-                eprintln!("Failed to write to temp file: {}", e);
-            } else {
-                info!("Wrote agent implementation to temp file: {:?}", file.path());
+/// C++ names are compared by their `::` segments without spaces or template arguments, and
+/// a qualified name matches a less qualified one it ends with, so `void Widget::resize(`
+/// defined out of class matches `void resize(` declared in it.
+fn function_names_match(found: &str, expected: &str) -> bool {
+    if found == expected {
+        return true;
+    }
+    let (found, expected) = (name_segments(found), name_segments(expected));
+    let (shorter, longer) = if found.len() <= expected.len() {
+        (&found, &expected)
+    } else {
+        (&expected, &found)
+    };
+    longer.ends_with(shorter)
+}
+
+/// A C++ name's `::` segments, without whitespace or template arguments
+/// (`Widget<T>::operator ==` is `["Widget", "operator=="]`).
+fn name_segments(name: &str) -> Vec<String> {
+    let name: String = name.split_whitespace().collect();
+    // An operator's symbol may hold `<`, `>` or `:` of its own
+    let (qualified, operator) = match name.find("operator") {
+        Some(pos) => name.split_at(pos),
+        None => (name.as_str(), ""),
+    };
+    let mut depth = 0;
+    let qualified: String = qualified
+        .chars()
+        .filter(|&c| {
+            match c {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                _ => return depth == 0,
             }
+            false
+        })
+        .collect();
+    let mut segments: Vec<String> = qualified.split("::").map(str::to_string).collect();
+    if let Some(last) = segments.last_mut() {
+        last.push_str(operator);
+    }
+    segments
+}
+
+/// Extract the function name from a signature line.
+pub fn extract_function_name(sig: &str) -> Option<&str> {
+    // Handle Go first: parameters like `fn func()` would look like Rust
+    if let Some(name) = go_function_name(sig) {
+        return Some(name);
+    }
+    if let Some(name) = swift_function_name(sig).or_else(|| kotlin_function_name(sig)) {
+        return Some(name);
+    }
+    if let Some(name) = macro_function_name(sig) {
+        return Some(name);
+    }
+    // Ruby's `def self.name` and `def name; end`, and Lua's `local function name(`
+    if let Some(name) = ruby_function_name(sig).or_else(|| lua_function_name(sig)) {
+        return Some(name);
+    }
+    // Then JavaScript/TypeScript, whose arrow functions have no keyword before the name
+    if let Some(name) = js_function_name(sig) {
+        return Some(name);
+    }
+
+    // Handle Rust: fn name, pub fn name, async fn name, etc.
+    if let Some(pos) = sig.find(" fn ") {
+        let after_fn = &sig[pos + 4..];
+        return after_fn.split(&['(', '<', ' '][..]).next();
+    }
+    if let Some(after_fn) = sig.strip_prefix("fn ") {
+        return after_fn.split(&['(', '<', ' '][..]).next();
+    }
+
+    // Handle Python: def name, async def name
+    if let Some(pos) = sig.find("def ") {
+        let after_def = &sig[pos + 4..];
+        return after_def.split(&['(', ' ', ':'][..]).next();
+    }
+
+    // Handle C/C++/Java: type name(, with namespaces and templates
+    if let Some(name) = cpp_function_name(sig) {
+        return Some(name);
+    }
+
+    // Otherwise the identifier followed by (
+    if let Some(paren_pos) = sig.find('(') {
+        let before_paren = sig[..paren_pos].trim();
+        // Get the last word before (
+        return before_paren.split_whitespace().last();
+    }
+
+    None
+}
+
+/// Up to `max` lines of `text` that call the function with `signature`, as
+/// (line, trimmed text) pairs in document order.
+///
+/// A plain textual search: the function's name (the last segment of a qualified C++ name)
+/// followed by `(`, as a whole word. Definitions of a function with that name and comment
+/// lines are skipped.
+pub fn find_call_sites<'a>(text: &'a str, signature: &str, max: usize) -> Vec<(usize, &'a str)> {
+    let signature = normalize_signature(signature);
+    let Some(name) = extract_function_name(&signature) else {
+        return Vec::new();
+    };
+    let name = name.rsplit("::").next().unwrap_or(name);
+    let is_identifier = name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if name.is_empty() || !is_identifier || max == 0 {
+        return Vec::new();
+    }
+
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| {
+            let is_definition = is_function_start(line)
+                && extract_function_name(line)
+                    .is_some_and(|found| function_names_match(found, name));
+            !is_definition && !is_comment(line) && calls(line, name)
+        })
+        .take(max)
+        .collect()
+}
+
+/// Whether `line` has `name` as a whole word followed by `(`.
+fn calls(line: &str, name: &str) -> bool {
+    line.match_indices(name).any(|(pos, _)| {
+        let before = line[..pos].chars().next_back();
+        let after = line[pos + name.len()..].trim_start();
+        !before.is_some_and(|c| c.is_alphanumeric() || c == '_') && after.starts_with('(')
+    })
+}
+
+/// The part of `signature` that names the function, on one line: everything before its
+/// parameter list (`pub fn parse_header` for `pub fn parse_header(buf: &[u8]) -> Header {`).
+/// A signature without parameters is kept whole, minus a trailing `{` or `:`.
+pub fn short_signature(signature: &str) -> String {
+    let name = match signature.find('(') {
+        Some(paren_pos) if !signature[..paren_pos].trim().is_empty() => &signature[..paren_pos],
+        _ => signature.trim_end().trim_end_matches(['{', ':']),
+    };
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The last `max_chars` characters of `preview`, and whether anything was cut off before them.
+pub fn preview_tail(preview: &str, max_chars: usize) -> (&str, bool) {
+    if max_chars == 0 {
+        return ("", !preview.is_empty());
+    }
+    match preview.char_indices().rev().nth(max_chars - 1) {
+        Some((start, _)) if start > 0 => (&preview[start..], true),
+        _ => (preview, false),
+    }
+}
+
+/// Find the line to track for the function with `signature` after the document changed.
+///
+/// Returns `near_line` itself while it is still inside a function with that name and no
+/// other function matches `signature` better (see `SignatureMatch`); otherwise the start of
+/// the best match, the nearest one first. Returns None if no function with that name is left.
+pub fn relocate_function(
+    text: &str,
+    near_line: usize,
+    signature: &FunctionSignature,
+) -> Option<usize> {
+    let lines: Vec<&str> = text.lines().collect();
+    let clamped_line = near_line.min(lines.len().checked_sub(1)?);
+
+    let best = find_function_by_signature(&lines, signature, clamped_line);
+    if let Some(start) = find_function_start(&lines, clamped_line) {
+        let found = signature_match_at(&lines, start, signature);
+        let best_match = best.and_then(|best| signature_match_at(&lines, best, signature));
+        if found.is_some() && found >= best_match {
+            let line = if clamped_line == near_line {
+                near_line
+            } else {
+                start
+            };
+            return Some(line);
+        }
+    }
+    best
+}
+
+/// Search the entire document for the function that best matches the expected signature
+/// (see `SignatureMatch`).
+///
+/// Among equally good matches the one nearest `near_line` (the job's current line) wins,
+/// and the later one when two are as near. Commented-out copies and declarations without a
+/// body (trait methods, prototypes) are not candidates.
+fn find_function_by_signature(
+    lines: &[&str],
+    expected_signature: &FunctionSignature,
+    near_line: usize,
+) -> Option<usize> {
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| is_function_start(line.trim()))
+        .filter_map(|(i, _)| Some((signature_match_at(lines, i, expected_signature)?, i)))
+        .max_by_key(|&(found, i)| (found, Reverse(i.abs_diff(near_line)), i >= near_line))
+        .map(|(_, i)| i)
+}
+
+/// How the function starting at `start` matches `expected_signature`; None when it is
+/// commented out or only declared there, as neither has a body to replace.
+fn signature_match_at(
+    lines: &[&str],
+    start: usize,
+    expected_signature: &FunctionSignature,
+) -> Option<SignatureMatch> {
+    if is_comment_line(lines[start].trim()) || is_bodyless_declaration(lines, start) {
+        return None;
+    }
+    expected_signature.match_quality(&parsed_signature_at(lines, start, expected_signature))
+}
+
+/// The signature of the function starting at `start`, parsed in the language of `expected`.
+fn parsed_signature_at(
+    lines: &[&str],
+    start: usize,
+    expected: &FunctionSignature,
+) -> FunctionSignature {
+    FunctionSignature::parse(&signature_at(lines, start), &expected.language)
+}
+
+/// Whether the function starting at `start` is declared without a body: a trait or
+/// interface method, or a C/C++ prototype, ending in `;`.
+fn is_bodyless_declaration(lines: &[&str], start: usize) -> bool {
+    let signature = signature_at(lines, start);
+    signature.ends_with(';') && !signature.contains('{') && !signature.contains("=>")
+}
+
+/// Whether a line opens a closure or lambda rather than declaring a function: a Rust
+/// `|args|` closure, an arrow function passed or returned rather than assigned to a name
+/// (`items.map((x) => {`), or a Python `lambda`.
+fn is_closure_start(line: &str) -> bool {
+    if line.starts_with("def ") || line.starts_with("async def ") {
+        return false;
+    }
+    // `key=lambda x: x.name`, `lambda: {`; not a parameter named `lambda`
+    if line.match_indices("lambda").any(|(pos, _)| {
+        let at_word_start = !line[..pos].ends_with(|c: char| c.is_alphanumeric() || c == '_');
+        at_word_start && line[pos + "lambda".len()..].starts_with([' ', ':'])
+    }) {
+        return true;
+    }
+
+    // `const load = async (id) => {` and `onClick = () => {` are the functions themselves;
+    // a C# member's `=>` comes before any lambda in its body
+    if let Some((_, body)) = line.split_once("=>") {
+        let body = body.trim_start();
+        if (body.starts_with('{') || body.starts_with('('))
+            && !line.contains("function")
+            && js_function_name(line).is_none()
+        {
+            return true;
         }
-        Err(e) => eprintln!("Failed to create temp file: {}", e),
     }
 
-    // 3. Perform 3-way merge
-    let merged_text = match merge(base_text, current_text, &theirs_text) {
-        Ok(text) => text,
-        Err(text) => text, // Use conflict markers
-    };
+    // `|x| {`, `= move |req: Request| {`, `.map(|x| {`, `spawn(|| {`, but not `a || b`
+    !is_rust_signature(line)
+        && line.match_indices('|').any(|(pos, _)| {
+            let before = line[..pos].trim_end();
+            let opens = before.is_empty()
+                || before.ends_with(['=', '(', ','])
+                || before.ends_with(" move")
+                || before == "move"
+                || before.ends_with("return");
+            opens && line[pos + 1..].contains('|')
+        })
+}
+
+/// Check if a line looks like a function start.
+fn is_function_start(line: &str) -> bool {
+    if is_closure_start(line) {
+        return false;
+    }
+
+    // Rust
+    if line.starts_with("fn ")
+        || line.starts_with("pub fn ")
+        || line.starts_with("async fn ")
+        || line.starts_with("pub async fn ")
+        || line.starts_with("pub(crate) fn ")
+        || line.contains(" fn ")
+    {
+        return true;
+    }
+
+    // Python
+    if line.starts_with("def ") || line.starts_with("async def ") {
+        return true;
+    }
+
+    // Go
+    if is_go_signature(line) {
+        return true;
+    }
+
+    // Kotlin and Swift
+    if kotlin_function_name(line).is_some() || swift_function_name(line).is_some() {
+        return true;
+    }
+
+    // JavaScript/TypeScript
+    if js_function_name(line).is_some() {
+        return true;
+    }
+
+    // Lua
+    if lua_function_name(line).is_some() {
+        return true;
+    }
+
+    // C/C++/Java
+    cpp_function_name(line).is_some()
+}
+
+/// Find the end line of a function based on brace counting.
+///
+/// Returns the line number (0-indexed) of the closing brace, or of the closing `end` for
+/// Ruby and Lua functions. When the braces never balance (code mid-edit), falls back to
+/// `fallback_function_end` and logs a warning.
+pub fn find_function_end(lines: &[&str], start_line: usize) -> Option<usize> {
+    if let Some(end) = balanced_function_end(lines, start_line) {
+        return Some(end);
+    }
+    let end = fallback_function_end(lines, start_line)?;
+    warn!(
+        "Braces never balanced for function at line {}, using best-effort end line {}",
+        start_line, end
+    );
+    Some(end)
+}
+
+/// End line of the function starting at `start_line`, or None when its braces never balance
+/// before EOF.
+fn balanced_function_end(lines: &[&str], start_line: usize) -> Option<usize> {
+    if let Some(end) = expression_body_end(lines, start_line) {
+        return Some(end);
+    }
+
+    match end_keyword_language(lines, start_line) {
+        Some(language_id) => keyword_function_end(lines, start_line, language_id),
+        None => indented_function_end(lines, start_line)
+            .or_else(|| brace_function_end(lines, start_line)),
+    }
+}
+
+/// Line of the brace closing the function starting at `start_line`, or None if its braces
+/// never balance.
+fn brace_function_end(lines: &[&str], start_line: usize) -> Option<usize> {
+    let mut scanner = BraceScanner::new(BraceDialect::of(lines.get(start_line)?));
+    let mut open_braces = 0;
+    let mut found_start = false;
+
+    for (i, line) in lines.iter().enumerate().skip(start_line) {
+        for (_, brace) in scanner.braces(line) {
+            if brace == '{' {
+                open_braces += 1;
+                found_start = true;
+            } else {
+                open_braces -= 1;
+            }
+        }
+
+        if found_start && open_braces == 0 {
+            return Some(i);
+        }
+
+        // Safety valve for unbalanced braces going negative
+        if found_start && open_braces < 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// The first bracket in `text` that breaks the nesting, as its (line, character): a `}`, `)`
+/// or `]` closing nothing or the wrong bracket, or else the outermost bracket never closed.
+/// Strings and comments are skipped as in brace counting. None when every bracket pairs up,
+/// or for languages without a brace dialect.
+pub fn unbalanced_delimiter(text: &str, language_id: &str) -> Option<(usize, char)> {
+    let mut scanner = BraceScanner::with_all_delimiters(BraceDialect::for_language(language_id)?);
+    let mut open: Vec<(usize, char)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        for (_, delimiter) in scanner.braces(line) {
+            let opener = match delimiter {
+                '}' => '{',
+                ')' => '(',
+                ']' => '[',
+                _ => {
+                    open.push((i, delimiter));
+                    continue;
+                }
+            };
+            if open.pop().map(|(_, c)| c) != Some(opener) {
+                return Some((i, delimiter));
+            }
+        }
+    }
+    open.first().copied()
+}
+
+/// Last line of the Python `def` starting at `start_line`: the last non-blank line before
+/// the first line below its signature that is indented no deeper than the `def`. None for
+/// other languages, or a signature that never closes.
+fn indented_function_end(lines: &[&str], start_line: usize) -> Option<usize> {
+    let line = lines.get(start_line)?;
+    let trimmed = line.trim();
+    if !(trimmed.starts_with("def ") || trimmed.starts_with("async def ")) {
+        return None;
+    }
+    let indent = leading_whitespace(line).len();
+
+    // A wrapped signature's `):` may sit at the `def`'s own indentation
+    let mut depth = 0;
+    let mut signature_end = None;
+    for (i, line) in lines.iter().enumerate().skip(start_line) {
+        for c in line.chars() {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                _ => {}
+            }
+        }
+        if depth <= 0 && line.contains(':') {
+            signature_end = Some(i);
+            break;
+        }
+    }
+    let signature_end = signature_end?;
+
+    let mut end = signature_end;
+    for (i, line) in lines.iter().enumerate().skip(signature_end + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if leading_whitespace(line).len() <= indent {
+            break;
+        }
+        end = i;
+    }
+    Some(end)
+}
+
+/// How a C-family language writes the strings, char literals and comments whose braces
+/// brace counting must skip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BraceDialect {
+    /// Raw strings (`r#"..."#`), nested block comments, and lifetimes (`'a`) beside char
+    /// literals.
+    Rust,
+    /// Single-quoted strings and template literals spanning lines.
+    JavaScript,
+    /// Raw strings in backticks spanning lines, and rune literals.
+    Go,
+    /// C, C++, Java, C#, Kotlin and Swift: char literals, C++ raw strings (`R"x(...)x"`), C#
+    /// verbatim strings (`@"..."`) and Kotlin/Swift triple-quoted strings.
+    CFamily,
+}
+
+impl BraceDialect {
+    /// The dialect of the function whose signature (or `template<...>` header) is `line`.
+    fn of(line: &str) -> Self {
+        let line = line.trim();
+        if is_go_signature(line) {
+            BraceDialect::Go
+        } else if is_rust_signature(line) {
+            BraceDialect::Rust
+        } else if is_js_signature(line) {
+            BraceDialect::JavaScript
+        } else {
+            BraceDialect::CFamily
+        }
+    }
+
+    /// The dialect of code in the language with LSP id `language_id`, or None for languages
+    /// that do not delimit blocks with braces or comment with `//`.
+    fn for_language(language_id: &str) -> Option<Self> {
+        match language_id {
+            "rust" => Some(BraceDialect::Rust),
+            "go" => Some(BraceDialect::Go),
+            "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => {
+                Some(BraceDialect::JavaScript)
+            }
+            "c" | "cpp" | "cuda" | "objective-c" | "objective-cpp" | "java" | "csharp"
+            | "kotlin" | "swift" | "dart" | "scala" => Some(BraceDialect::CFamily),
+            _ => None,
+        }
+    }
+}
+
+/// Where a brace scanner is: in code, or inside something whose braces do not count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LexState {
+    Code,
+    /// Inside `/* */`, nested this deep (only Rust nests them).
+    BlockComment(usize),
+    /// Inside a string or template literal closed by this quote, with backslash escapes.
+    Quoted(char),
+    /// Inside a string without escapes, closed by this text: `"#` for Rust's `r#"`,
+    /// `)x"` for C++'s `R"x(`, `"""` for triple-quoted strings and a backtick for Go's.
+    Raw(String),
+    /// Inside a C# verbatim string, where `""` is an escaped quote.
+    Verbatim,
+}
+
+/// Finds the braces of C-family code line by line, skipping strings, char literals and
+/// comments, and carrying the ones that span lines over to the next line.
+struct BraceScanner {
+    dialect: BraceDialect,
+    state: LexState,
+    /// Whether parentheses and square brackets are found too, not only braces.
+    all_delimiters: bool,
+}
+
+impl BraceScanner {
+    fn new(dialect: BraceDialect) -> Self {
+        Self {
+            dialect,
+            state: LexState::Code,
+            all_delimiters: false,
+        }
+    }
+
+    /// A scanner that finds parentheses and square brackets as well as braces.
+    fn with_all_delimiters(dialect: BraceDialect) -> Self {
+        Self {
+            all_delimiters: true,
+            ..Self::new(dialect)
+        }
+    }
+
+    /// Byte offsets in `line` of its braces that are code.
+    fn braces(&mut self, line: &str) -> Vec<(usize, char)> {
+        let mut braces = Vec::new();
+        let mut i = 0;
+        while let Some(c) = line[i..].chars().next() {
+            let rest = &line[i..];
+            let (advance, next) = match &self.state {
+                LexState::BlockComment(depth) if rest.starts_with("*/") => (
+                    2,
+                    Some(match depth - 1 {
+                        0 => LexState::Code,
+                        depth => LexState::BlockComment(depth),
+                    }),
+                ),
+                LexState::BlockComment(depth)
+                    if self.dialect == BraceDialect::Rust && rest.starts_with("/*") =>
+                {
+                    (2, Some(LexState::BlockComment(depth + 1)))
+                }
+                LexState::Quoted(_) if c == '\\' => {
+                    (1 + rest[1..].chars().next().map_or(0, char::len_utf8), None)
+                }
+                LexState::Quoted(quote) if c == *quote => (1, Some(LexState::Code)),
+                LexState::Raw(close) if rest.starts_with(close.as_str()) => {
+                    (close.len(), Some(LexState::Code))
+                }
+                LexState::Verbatim if rest.starts_with("\"\"") => (2, None),
+                LexState::Verbatim if c == '"' => (1, Some(LexState::Code)),
+                LexState::Code => {
+                    if rest.starts_with("//") {
+                        break;
+                    }
+                    self.code_step(line, i, &mut braces)
+                }
+                _ => (c.len_utf8(), None),
+            };
+            if let Some(next) = next {
+                self.state = next;
+            }
+            i += advance;
+        }
+
+        // Only Rust strings and template literals go on past the end of a line unescaped
+        if let LexState::Quoted(quote) = self.state {
+            let spans_lines = self.dialect == BraceDialect::Rust || quote == '`';
+            if !spans_lines && !line.ends_with('\\') {
+                self.state = LexState::Code;
+            }
+        }
+        braces
+    }
+
+    /// Scan the code at byte `i` of `line`: how far to advance, and the state it enters.
+    fn code_step(
+        &self,
+        line: &str,
+        i: usize,
+        braces: &mut Vec<(usize, char)>,
+    ) -> (usize, Option<LexState>) {
+        let rest = &line[i..];
+        let c = rest.chars().next().unwrap_or(' ');
+        let after_identifier = line[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|p| p.is_alphanumeric() || p == '_');
+        if rest.starts_with("/*") {
+            return (2, Some(LexState::BlockComment(1)));
+        }
+        if let Some((open, close)) = self.raw_string_start(rest).filter(|_| !after_identifier) {
+            return (open, Some(LexState::Raw(close)));
+        }
+        match (self.dialect, c) {
+            (_, '{' | '}') => {
+                braces.push((i, c));
+                (1, None)
+            }
+            (_, '(' | ')' | '[' | ']') if self.all_delimiters => {
+                braces.push((i, c));
+                (1, None)
+            }
+            (_, '"') => (1, Some(LexState::Quoted('"'))),
+            (BraceDialect::JavaScript, '\'' | '`') => (1, Some(LexState::Quoted(c))),
+            (BraceDialect::CFamily, '@') if rest.starts_with("@\"") => {
+                (2, Some(LexState::Verbatim))
+            }
+            (_, '\'') => (char_literal_len(rest).unwrap_or(1), None),
+            _ => (c.len_utf8(), None),
+        }
+    }
+
+    /// Length of the opening of a raw string at the start of `rest`, and the text closing it.
+    fn raw_string_start(&self, rest: &str) -> Option<(usize, String)> {
+        match self.dialect {
+            BraceDialect::Rust => {
+                let after_prefix = rest.strip_prefix("br").or_else(|| rest.strip_prefix('r'))?;
+                let hashes = after_prefix.len() - after_prefix.trim_start_matches('#').len();
+                after_prefix[hashes..].starts_with('"').then(|| {
+                    let open = rest.len() - after_prefix.len() + hashes + 1;
+                    (open, format!("\"{}", "#".repeat(hashes)))
+                })
+            }
+            BraceDialect::Go => rest.starts_with('`').then(|| (1, "`".to_string())),
+            BraceDialect::JavaScript => None,
+            BraceDialect::CFamily => {
+                if rest.starts_with("\"\"\"") {
+                    return Some((3, "\"\"\"".to_string()));
+                }
+                let after_prefix = ["u8R\"", "uR\"", "UR\"", "LR\"", "R\""]
+                    .iter()
+                    .find_map(|prefix| rest.strip_prefix(prefix))?;
+                let delimiter = &after_prefix[..after_prefix.find('(')?];
+                let is_delimiter = delimiter.len() <= 16
+                    && !delimiter.contains(|c: char| c.is_whitespace() || c == '\\' || c == ')');
+                is_delimiter.then(|| {
+                    let open = rest.len() - after_prefix.len() + delimiter.len() + 1;
+                    (open, format!("){}\"", delimiter))
+                })
+            }
+        }
+    }
+}
+
+/// Length of the char literal at the start of `rest` (`'{'`, `'\''`, `'\u{7D}'`), or None
+/// for a quote that starts none, like a Rust lifetime.
+fn char_literal_len(rest: &str) -> Option<usize> {
+    let mut chars = rest.char_indices().skip(1);
+    let (_, first) = chars.next()?;
+    if first == '\\' {
+        let (escaped_at, escaped) = chars.next()?;
+        let close = rest[escaped_at + escaped.len_utf8()..].find('\'')?;
+        let len = escaped_at + escaped.len_utf8() + close + 1;
+        return (len <= 12).then_some(len);
+    }
+    let (close_at, close) = chars.next()?;
+    (close == '\'').then_some(close_at + 1)
+}
+
+/// Byte offsets of the code braces of `text`, a function starting with its signature line.
+fn code_braces(text: &str) -> Vec<(usize, char)> {
+    let mut scanner = BraceScanner::new(BraceDialect::of(text.lines().next().unwrap_or("")));
+    let mut braces = Vec::new();
+    let mut offset = 0;
+    for line in text.split('\n') {
+        braces.extend(
+            scanner
+                .braces(line)
+                .into_iter()
+                .map(|(i, brace)| (offset + i, brace)),
+        );
+        offset += line.len() + 1;
+    }
+    braces
+}
+
+/// Byte offset in a signature line where its expression body starts, after the `=>` of a C#
+/// expression-bodied member (`public int Twice(int x) => x * 2;`) or the `=` of a Kotlin
+/// single-expression function (`fun twice(x: Int) = x * 2`). None for block bodies.
+fn expression_body_start(line: &str) -> Option<usize> {
+    let params = line.find('(')? + 1;
+    let after = skip_to_closing_paren(&line[params..])?;
+    let after_start = line.len() - after.len();
+    let (arrow, skip) = match after.find("=>") {
+        Some(arrow) if after[..arrow].trim().is_empty() => (arrow, 2),
+        _ => {
+            // `): Int = x`, but not a Kotlin/Swift `-> T {` block or an `==` comparison
+            let eq = after.find('=')?;
+            let is_assignment = !after[eq..].starts_with("==") && !after[eq..].starts_with("=>");
+            if !is_assignment || after[..eq].contains(['{', '(']) {
+                return None;
+            }
+            (eq, 1)
+        }
+    };
+    let body = &after[arrow + skip..];
+    if body.trim_start().starts_with('{') {
+        return None;
+    }
+    Some(after_start + arrow + skip)
+}
+
+/// Last line of an expression-bodied function starting at `start_line`: the line with its
+/// terminating `;` for `=>` members, or the end of the expression before the next blank line.
+fn expression_body_end(lines: &[&str], start_line: usize) -> Option<usize> {
+    let line = lines.get(start_line)?.trim();
+    expression_body_start(line)?;
+    let mut end = start_line;
+    for (i, line) in lines.iter().enumerate().skip(start_line) {
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        end = i;
+        if line.ends_with(';') {
+            break;
+        }
+    }
+    Some(end)
+}
+
+/// The language whose `end` keyword closes the function starting at `start_line`: "ruby"
+/// for a `def`, "lua" for a `function` without a brace-delimited body.
+fn end_keyword_language(lines: &[&str], start_line: usize) -> Option<&'static str> {
+    let line = lines.get(start_line)?.trim();
+    if ruby_function_name(line).is_some() {
+        return Some("ruby");
+    }
+    // JavaScript's `function foo()` with its `{` on the next line is not Lua
+    let next = lines[start_line + 1..]
+        .iter()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty());
+    let is_lua = lua_function_name(line).is_some()
+        && !line.contains('{')
+        && !next.is_some_and(|l| l.starts_with('{'));
+    is_lua.then_some("lua")
+}
+
+/// End line of a function whose blocks close with `end`, counting the block-opening keywords
+/// of `language_id` against `end` from `start_line`. Returns None if they never balance.
+///
+/// Ruby's `def`, `class`, `module`, `begin`, `case` and `do` always open a block, while
+/// `if`, `unless`, `while`, `until` and `for` only do at the start of a statement (not as
+/// `return x if y` modifiers), and a loop's optional `do` shares the loop's `end`. Lua's
+/// `function`, `if` and `do` open blocks; its `for` and `while` loops open with their `do`.
+fn keyword_function_end(lines: &[&str], start_line: usize, language_id: &str) -> Option<usize> {
+    let comment = if language_id == "lua" { "--" } else { "#" };
+    let mut depth = 0;
+    let mut opened = false;
+    for (i, line) in lines.iter().enumerate().skip(start_line) {
+        let mut loop_header = false;
+        for (word, statement_start) in keyword_words(line, comment) {
+            let opens = match (language_id, word) {
+                (_, "end") => {
+                    depth -= 1;
+                    false
+                }
+                ("lua", "function" | "if" | "do") => true,
+                ("ruby", "def" | "class" | "module" | "begin" | "case") => true,
+                ("ruby", "if" | "unless") => statement_start,
+                ("ruby", "while" | "until" | "for") if statement_start => {
+                    loop_header = true;
+                    true
+                }
+                ("ruby", "do") => !std::mem::take(&mut loop_header),
+                _ => false,
+            };
+            if opens {
+                depth += 1;
+                opened = true;
+            }
+        }
+        if opened && depth <= 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// The words of a line of Ruby or Lua code outside strings and the trailing comment, each
+/// with whether it starts a statement. Method calls like `range.end` and symbols are skipped.
+fn keyword_words<'a>(line: &'a str, comment: &str) -> Vec<(&'a str, bool)> {
+    let mut words = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut word_start = None;
+    // The last non-space character before the current word
+    let mut previous = None;
+    for (i, c) in line
+        .char_indices()
+        .chain(std::iter::once((line.len(), ' ')))
+    {
+        let is_word_char = c.is_alphanumeric() || matches!(c, '_' | '?' | '!');
+        if let Some(start) = word_start.filter(|_| !is_word_char) {
+            let statement_start = matches!(previous, None | Some(';' | '=' | '(' | '|' | '&'));
+            let is_member = matches!(previous, Some('.' | ':'));
+            if !is_member {
+                words.push((&line[start..i], statement_start));
+            }
+            previous = line[..i].chars().last();
+            word_start = None;
+        }
+        if let Some(q) = quote {
+            if c == q && !escaped {
+                quote = None;
+                previous = Some(c);
+            }
+            escaped = c == '\\' && !escaped;
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            _ if line[i..].starts_with(comment) => break,
+            _ if is_word_char => {
+                word_start.get_or_insert(i);
+            }
+            _ if !c.is_whitespace() => previous = Some(c),
+            _ => {}
+        }
+    }
+    words
+}
+
+/// Best-effort end of a function whose braces never balance.
+///
+/// Returns the last non-blank line before the next function start, or the last
+/// non-blank line of the document if no other function follows.
+fn fallback_function_end(lines: &[&str], start_line: usize) -> Option<usize> {
+    if start_line >= lines.len() {
+        return None;
+    }
+
+    let limit = lines
+        .iter()
+        .enumerate()
+        .skip(start_line + 1)
+        .find(|(_, line)| is_function_start(line.trim()))
+        .map(|(i, _)| i)
+        .unwrap_or(lines.len());
+
+    (start_line..limit)
+        .rev()
+        .find(|&i| !lines[i].trim().is_empty())
+        .or(Some(start_line))
+}
+
+/// A file's indentation unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    /// This many spaces per level.
+    Spaces(usize),
+    /// One tab per level.
+    Tabs,
+}
+
+impl Indent {
+    /// The whitespace for one level of indentation.
+    pub fn unit(self) -> String {
+        match self {
+            Indent::Spaces(width) => " ".repeat(width),
+            Indent::Tabs => "\t".to_string(),
+        }
+    }
+}
+
+/// Infer the indentation unit of `text` from its leading whitespace: tabs if most indented
+/// lines start with one, otherwise the most common step between a line and a more indented
+/// line below it. One-space steps (comment continuations, alignment) are ignored, and text
+/// without indentation gets 4 spaces.
+pub fn detect_indent(text: &str) -> Indent {
+    let mut tab_lines = 0;
+    let mut space_lines = 0;
+    let mut steps = [0usize; 9];
+    let mut previous_width = 0;
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let whitespace = leading_whitespace(line);
+        if whitespace.starts_with('\t') {
+            tab_lines += 1;
+            continue;
+        }
+        if !whitespace.is_empty() {
+            space_lines += 1;
+        }
+        let width = whitespace.len();
+        if let Some(count) = width
+            .checked_sub(previous_width)
+            .filter(|&step| step >= 2)
+            .and_then(|step| steps.get_mut(step))
+        {
+            *count += 1;
+        }
+        previous_width = width;
+    }
+
+    if tab_lines > space_lines {
+        return Indent::Tabs;
+    }
+    match (0..steps.len()).max_by_key(|&step| (steps[step], step)) {
+        Some(step) if steps[step] > 0 => Indent::Spaces(step),
+        _ => Indent::Spaces(4),
+    }
+}
+
+/// Indent `body` to `base`, converting the nesting it was written with (its own
+/// `detect_indent`) to `indent` so nested blocks follow the file's style. Blank lines are
+/// kept as they are.
+fn reindent(body: &str, base: &str, indent: Indent) -> Vec<String> {
+    let written_width = indent_width(detect_indent(body));
+    body.lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                return line.to_string();
+            }
+            nest_line(line, base, indent, written_width, 0)
+        })
+        .collect()
+}
+
+/// Move the function `implementation` to `base`, the indentation of the function it
+/// replaces.
+///
+/// Lines keep their indentation relative to the implementation's first line. An
+/// implementation indented with spaces going into a tab-indented file (or the other way
+/// around) has its nesting converted to the file's `indent` instead. Blank lines and lines
+/// inside multi-line string literals are kept as they are.
+fn indent_function(implementation: &str, base: &str, indent: Indent) -> Vec<String> {
+    let lines: Vec<&str> = implementation.lines().collect();
+    let Some(first) = lines.iter().find(|line| !line.trim().is_empty()) else {
+        return lines.iter().map(|line| line.to_string()).collect();
+    };
+    let written_base = leading_whitespace(first);
+    let written = detect_indent(implementation);
+    let convert = (written == Indent::Tabs) != (indent == Indent::Tabs);
+    let written_width = indent_width(written);
+    let base_width = whitespace_width(written_base, written_width);
+
+    let in_string = string_continuations(&lines);
+    lines
+        .iter()
+        .zip(in_string)
+        .map(|(line, in_string)| {
+            if line.trim().is_empty() || in_string {
+                line.to_string()
+            } else if convert {
+                nest_line(line, base, indent, written_width, base_width)
+            } else {
+                // Replace the part of the line's indentation it shares with the first line's
+                let shared: usize = leading_whitespace(line)
+                    .chars()
+                    .zip(written_base.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(c, _)| c.len_utf8())
+                    .sum();
+                format!("{}{}", base, &line[shared..])
+            }
+        })
+        .collect()
+}
+
+/// Columns one level of `indent` takes, counting a tab as one.
+fn indent_width(indent: Indent) -> usize {
+    match indent {
+        Indent::Spaces(width) => width,
+        Indent::Tabs => 1,
+    }
+}
+
+/// Width of `whitespace` with tabs as `tab_width` columns.
+fn whitespace_width(whitespace: &str, tab_width: usize) -> usize {
+    whitespace
+        .chars()
+        .map(|c| if c == '\t' { tab_width } else { 1 })
+        .sum()
+}
+
+/// `line` at `base` plus its nesting below `skip_width` columns, converted from levels of
+/// `written_width` columns to `indent`.
+fn nest_line(
+    line: &str,
+    base: &str,
+    indent: Indent,
+    written_width: usize,
+    skip_width: usize,
+) -> String {
+    let width =
+        whitespace_width(leading_whitespace(line), written_width).saturating_sub(skip_width);
+    format!(
+        "{}{}{}{}",
+        base,
+        indent.unit().repeat(width / written_width),
+        " ".repeat(width % written_width),
+        line.trim_start()
+    )
+}
+
+/// For each of a function's `lines`, whether it starts inside a string literal opened on an
+/// earlier line (a Rust raw string, a JavaScript template literal), where leading
+/// whitespace is part of the string. Only brace languages are scanned: Python docstrings
+/// and Ruby/Lua strings are indented along with the code.
+fn string_continuations(lines: &[&str]) -> Vec<bool> {
+    let signature = lines.iter().position(|line| {
+        let line = line.trim();
+        !line.is_empty() && !is_decoration_line(line, "")
+    });
+    let Some(signature) = signature.filter(|&start| {
+        let line = lines[start].trim();
+        !line.starts_with("def ")
+            && !line.starts_with("async def ")
+            && end_keyword_language(lines, start).is_none()
+    }) else {
+        return vec![false; lines.len()];
+    };
+
+    let mut scanner = BraceScanner::new(BraceDialect::of(lines[signature]));
+    lines
+        .iter()
+        .map(|line| {
+            let in_string = !matches!(scanner.state, LexState::Code | LexState::BlockComment(_));
+            scanner.braces(line);
+            in_string
+        })
+        .collect()
+}
+
+/// First line of the attributes, decorators and doc comments directly above the function
+/// starting at `start_line` (`#[test]`, `@staticmethod`, `///` docs), or `start_line` itself
+/// when there are none.
+pub fn find_decorated_start(lines: &[&str], start_line: usize) -> usize {
+    let language_id = decoration_language(lines, start_line);
+    let mut start = start_line.min(lines.len());
+    while start > 0 {
+        let line = lines[start - 1].trim();
+        if line.is_empty() || !is_decoration_line(line, language_id) {
+            break;
+        }
+        start -= 1;
+    }
+    start
+}
+
+/// The `is_decoration_line` language for the function starting at `start_line`: Python and
+/// Ruby `def`s take `#` comments, Lua functions `--` ones, others C-family attributes and
+/// comments.
+fn decoration_language(lines: &[&str], start_line: usize) -> &'static str {
+    let Some(line) = lines.get(start_line).map(|line| line.trim()) else {
+        return "";
+    };
+    if line.starts_with("def ") || line.starts_with("async def ") {
+        "python"
+    } else if end_keyword_language(lines, start_line) == Some("lua") {
+        "lua"
+    } else {
+        ""
+    }
+}
+
+/// Take the function's attributes, decorators and doc comments (above `start_line`) into the
+/// replaced region when `implementation` starts with its own, so they are not duplicated.
+///
+/// Original attributes the implementation has no identical copy of are kept above it, and
+/// original comments are kept unless the implementation brings a comment of its own.
+/// Returns the region's new first line and its lines.
+fn merge_decorations(
+    lines: &[&str],
+    start_line: usize,
+    implementation: Vec<String>,
+) -> (usize, Vec<String>) {
+    let decorated_start = find_decorated_start(lines, start_line);
+    let language_id = decoration_language(lines, start_line);
+    let new_decorations: Vec<String> = implementation
+        .iter()
+        .map(|line| line.trim())
+        .take_while(|line| line.is_empty() || is_decoration_line(line, language_id))
+        .filter(|line| !line.is_empty())
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    if decorated_start == start_line || new_decorations.is_empty() {
+        return (start_line, implementation);
+    }
+
+    let new_comment = new_decorations.iter().any(|line| is_comment_line(line));
+    let kept = lines[decorated_start..start_line].iter().filter(|line| {
+        let line = line.trim();
+        if is_comment_line(line) {
+            !new_comment
+        } else {
+            let line: String = line.split_whitespace().collect();
+            !new_decorations.contains(&line)
+        }
+    });
+    let merged = kept
+        .map(|line| line.to_string())
+        .chain(implementation)
+        .collect();
+    (decorated_start, merged)
+}
+
+/// Whether a decoration line is a comment rather than an attribute or decorator.
+fn is_comment_line(line: &str) -> bool {
+    line.starts_with("//")
+        || line.starts_with("/*")
+        || line.starts_with('*')
+        || line.starts_with("--")
+        || (line.starts_with('#') && !line.starts_with("#["))
+}
+
+/// The indentation to move a replacement for the function whose first line is
+/// `function_line` to: that line's own, with nesting in the file's unit (tabs if the line
+/// is tab-indented).
+fn function_indent(text: &str, function_line: &str) -> (String, Indent) {
+    let base = leading_whitespace(function_line);
+    let indent = match detect_indent(text) {
+        _ if base.starts_with('\t') => Indent::Tabs,
+        Indent::Tabs if !base.is_empty() => Indent::Spaces(4),
+        indent => indent,
+    };
+    (base.to_string(), indent)
+}
+
+/// The predominant line terminator of `text`: `"\r\n"` if most lines end with it, else `"\n"`.
+pub fn detect_line_ending(text: &str) -> &'static str {
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+    if crlf > lf {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Replace the function starting at `start_line` in the file content with a new
+/// implementation, also returning how many lines the function spans once it is in place,
+/// with the decorations it kept or brought along.
+fn splice_function(
+    file_content: &str,
+    start_line: usize,
+    new_implementation: &str,
+) -> Option<(String, usize)> {
+    let lines: Vec<&str> = file_content.lines().collect();
+
+    if start_line >= lines.len() {
+        return None;
+    }
+
+    let end_line = find_function_end(&lines, start_line)?;
+
+    // the new implementation, at the original function's indentation
+    let (base, indent) = function_indent(file_content, lines[start_line]);
+    let implementation = tidy_implementation(
+        indent_function(new_implementation, &base, indent),
+        TRIM_TRAILING_WHITESPACE,
+        ENSURE_FINAL_NEWLINE,
+    );
+    let (start_line, implementation) = merge_decorations(&lines, start_line, implementation);
+
+    let newline = detect_line_ending(file_content);
+    let text = splice_lines(file_content, start_line, end_line, &implementation, newline);
+    Some((text, implementation.len()))
+}
+
+/// Replace a function in the current document, handling concurrent edits.
+/// Returns (new_text, start_line, end_line, lines_delta)
+///
+/// The `expected_signature` parameter is used to verify we found the correct function.
+/// This is critical for concurrent implementations where line numbers may have shifted.
+pub fn replace_function_in_document(
+    current_text: &str,
+    current_line: usize,
+    new_implementation: &str,
+    expected_signature: Option<&FunctionSignature>,
+) -> Result<(String, u32, u32, i32), String> {
+    let lines: Vec<&str> = current_text.lines().collect();
+
+    info!(
+        "replace_function_in_document: current_line={}, expected_signature={:?}, total_lines={}",
+        current_line,
+        expected_signature.map(|signature| &signature.raw),
+        lines.len()
+    );
+
+    let (start_line, end_line) = locate_function(&lines, current_line, expected_signature)?;
+
+    // The new implementation, at the original function's indentation
+    let (base, indent) = function_indent(current_text, lines[start_line]);
+    let implementation = tidy_implementation(
+        indent_function(new_implementation, &base, indent),
+        TRIM_TRAILING_WHITESPACE,
+        ENSURE_FINAL_NEWLINE,
+    );
+    let (start_line, implementation) = merge_decorations(&lines, start_line, implementation);
+
+    // Calculate lines delta
+    let old_function_lines = (end_line - start_line + 1) as i32;
+    let new_function_lines = implementation.len() as i32;
+    let lines_delta = new_function_lines - old_function_lines;
+
+    let newline = detect_line_ending(current_text);
+    let new_text = splice_lines(current_text, start_line, end_line, &implementation, newline);
+
+    Ok((new_text, start_line as u32, end_line as u32, lines_delta))
+}
+
+/// Append the function `new_implementation` to the end of the current document, at column
+/// zero and after a blank line. Returns (new_text, start_line) where `start_line` is the
+/// line the implementation starts on.
+///
+/// Used for jobs implementing a declaration into another file (a C prototype into its
+/// source file, a trait method into the file with the impl), where there is no function
+/// to replace.
+pub fn append_function_to_document(current_text: &str, new_implementation: &str) -> (String, u32) {
+    let mut new_lines: Vec<&str> = current_text.lines().collect();
+    while new_lines.last().is_some_and(|line| line.trim().is_empty()) {
+        new_lines.pop();
+    }
+    if !new_lines.is_empty() {
+        new_lines.push("");
+    }
+    let start_line = new_lines.len() as u32;
+
+    let implementation = tidy_implementation(
+        indent_function(new_implementation, "", detect_indent(current_text)),
+        TRIM_TRAILING_WHITESPACE,
+        ENSURE_FINAL_NEWLINE,
+    );
+    new_lines.extend(implementation.iter().map(String::as_str));
+
+    let newline = detect_line_ending(current_text);
+    let final_newline = current_text.ends_with('\n') || ENSURE_FINAL_NEWLINE;
+    (join_lines(&new_lines, newline, final_newline), start_line)
+}
+
+/// Replace only the body of a function in the current document, keeping its signature.
+/// Returns (new_text, start_line, end_line, lines_delta) like `replace_function_in_document`.
+///
+/// Used when the backend returned just the statements of the body. The original signature
+/// (up to and including the opening brace, or the `:` line for Python) and the closing brace
+/// (or Ruby/Lua `end`) line are kept. A body starting at column zero is indented to match the
+/// original body, with its nested blocks re-indented to the file's indentation unit.
+pub fn replace_function_body_in_document(
+    current_text: &str,
+    current_line: usize,
+    new_body: &str,
+    expected_signature: Option<&FunctionSignature>,
+) -> Result<(String, u32, u32, i32), String> {
+    let lines: Vec<&str> = current_text.lines().collect();
+
+    info!(
+        "replace_function_body_in_document: current_line={}, expected_signature={:?}, total_lines={}",
+        current_line,
+        expected_signature.map(|signature| &signature.raw),
+        lines.len()
+    );
+
+    let (start_line, end_line) = locate_function(&lines, current_line, expected_signature)?;
+    let function_lines = &lines[start_line..=end_line];
+    let braces = code_braces(&function_lines.join("\n"));
+
+    // Split the function into the lines kept before the body, the old body and the lines kept after it
+    let mut head: Vec<String> = Vec::new();
+    let mut tail: Vec<String> = Vec::new();
+    let old_body: &[&str];
+
+    if end_keyword_language(&lines, start_line).is_some() {
+        // Ruby and Lua: keep the signature line and the closing `end` line
+        let last = function_lines.len() - 1;
+        if last == 0 {
+            // One-line function: `def reset; end`, `function M.noop() end`
+            let line = function_lines[0];
+            let end_pos = line.rfind("end").unwrap_or(line.len());
+            let sig_end = match line.find('(') {
+                Some(open) if !line[..end_pos].contains(';') => {
+                    skip_to_closing_paren(&line[open + 1..end_pos])
+                        .map_or(end_pos, |after| end_pos - after.len())
+                }
+                _ => line.find(';').unwrap_or(end_pos),
+            };
+            head.push(line[..sig_end].trim_end().to_string());
+            tail.push(format!("{}{}", leading_whitespace(line), &line[end_pos..]));
+            old_body = &[];
+        } else {
+            head.push(function_lines[0].to_string());
+            tail.push(function_lines[last].to_string());
+            old_body = &function_lines[1..last];
+        }
+    } else if let Some(open) = body_open_brace(&braces) {
+        let (open_offset, open_pos) = line_and_column(function_lines, open);
+        let open_line = function_lines[open_offset];
+        if open_offset == function_lines.len() - 1 {
+            // Single-line function: `fn foo() { todo!() }`
+            let close_pos = braces
+                .last()
+                .filter(|&&(i, brace)| brace == '}' && i > open)
+                .map(|&(i, _)| line_and_column(function_lines, i).1);
+            head.extend(function_lines[..open_offset].iter().map(|l| l.to_string()));
+            head.push(open_line[..=open_pos].trim_end().to_string());
+            if let Some(close_pos) = close_pos {
+                let indent = leading_whitespace(function_lines[0]);
+                tail.push(format!("{}{}", indent, &open_line[close_pos..]));
+            }
+            old_body = &[];
+        } else {
+            head.extend(function_lines[..=open_offset].iter().map(|l| l.to_string()));
+            tail.push(function_lines[function_lines.len() - 1].to_string());
+            old_body = &function_lines[open_offset + 1..function_lines.len() - 1];
+        }
+    } else {
+        // Brace-less languages (Python): keep everything up to the line ending with ':'
+        let sig_end = function_lines
+            .iter()
+            .position(|l| l.trim_end().ends_with(':'))
+            .unwrap_or(0);
+        head.extend(function_lines[..=sig_end].iter().map(|l| l.to_string()));
+        old_body = &function_lines[sig_end + 1..];
+    }
+
+    let indent = detect_indent(current_text);
+    let body_indent = old_body
+        .iter()
+        .find(|l| !l.trim().is_empty())
+        .map(|l| leading_whitespace(l).to_string())
+        .unwrap_or_else(|| format!("{}{}", leading_whitespace(function_lines[0]), indent.unit()));
+    let needs_indent = new_body
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .any(|l| leading_whitespace(l).is_empty());
+
+    let body: Vec<String> = if needs_indent {
+        reindent(new_body, &body_indent, indent)
+    } else {
+        new_body.lines().map(str::to_string).collect()
+    };
+    let body = tidy_implementation(body, TRIM_TRAILING_WHITESPACE, ENSURE_FINAL_NEWLINE);
+
+    let old_function_lines = (end_line - start_line + 1) as i32;
+    let new_function_lines = (head.len() + body.len() + tail.len()) as i32;
+    let lines_delta = new_function_lines - old_function_lines;
+
+    let function: Vec<String> = head.into_iter().chain(body).chain(tail).collect();
+    let newline = detect_line_ending(current_text);
+    let new_text = splice_lines(current_text, start_line, end_line, &function, newline);
+
+    Ok((new_text, start_line as u32, end_line as u32, lines_delta))
+}
+
+/// Whether the function on lines `start_line..=end_line` of `old_text` reads the same in
+/// `new_text`, where it spans `lines_delta` more lines.
+///
+/// Line terminators, trailing whitespace and blank lines around the function are ignored,
+/// so an implementation that only differs in those is no change either.
+pub fn region_unchanged(
+    old_text: &str,
+    new_text: &str,
+    start_line: u32,
+    end_line: u32,
+    lines_delta: i32,
+) -> bool {
+    let region = |text: &str, line_count: i32| -> Vec<String> {
+        let mut lines: Vec<String> = text
+            .lines()
+            .skip(start_line as usize)
+            .take(line_count.max(0) as usize)
+            .map(|line| line.trim_end().to_string())
+            .collect();
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        let leading_blank = lines.iter().take_while(|line| line.is_empty()).count();
+        lines.split_off(leading_blank)
+    };
+    let old_lines = (end_line - start_line + 1) as i32;
+    region(old_text, old_lines) == region(new_text, old_lines + lines_delta)
+}
+
+/// `text` with its lines `start_line..=end_line` replaced by `replacement`, joined with
+/// `newline`.
+///
+/// The text around the region is copied verbatim, separators included, into a single
+/// allocation. The replacement ends with a newline if the region did, or when it reaches
+/// the end of a document that gets a final newline (see `final_newline`).
+fn splice_lines(
+    text: &str,
+    start_line: usize,
+    end_line: usize,
+    replacement: &[String],
+    newline: &str,
+) -> String {
+    let start = line_offset(text, start_line);
+    let end = line_offset(text, end_line + 1);
+    let terminated = text[..end].ends_with('\n');
+    let newline_after = !replacement.is_empty()
+        && (terminated || final_newline(text, end_line) && end == text.len());
+
+    let replacement_len =
+        replacement.iter().map(String::len).sum::<usize>() + newline.len() * replacement.len();
+    let mut new_text = String::with_capacity(text.len() - (end - start) + replacement_len);
+    new_text.push_str(&text[..start]);
+    for (i, line) in replacement.iter().enumerate() {
+        if i > 0 {
+            new_text.push_str(newline);
+        }
+        new_text.push_str(line);
+    }
+    if newline_after {
+        new_text.push_str(newline);
+    }
+    new_text.push_str(&text[end..]);
+    new_text
+}
+
+/// Byte offset of the start of line `line` of `text` (lines as `str::lines` splits them),
+/// or the length of `text` past its last line.
+fn line_offset(text: &str, line: usize) -> usize {
+    if line == 0 {
+        return 0;
+    }
+    text.match_indices('\n')
+        .nth(line - 1)
+        .map_or(text.len(), |(i, _)| i + 1)
+}
+
+/// Reassemble `lines` with `newline`, ending with a newline if `final_newline`.
+fn join_lines(lines: &[&str], newline: &str, final_newline: bool) -> String {
+    let mut text = lines.join(newline);
+    if final_newline {
+        text.push_str(newline);
+    }
+    text
+}
+
+/// Whether a document `original` whose lines `replaced_end` (inclusive) was the last one
+/// replaced ends with a newline: if it did, or under `ENSURE_FINAL_NEWLINE` when the
+/// replacement reaches the end of the document.
+fn final_newline(original: &str, replaced_end: usize) -> bool {
+    original.ends_with('\n') || ENSURE_FINAL_NEWLINE && replaced_end + 1 >= original.lines().count()
+}
+
+/// Tidy the lines of an implementation before it is spliced in: strip their trailing
+/// whitespace if `trim_trailing_whitespace`, and drop trailing blank lines if
+/// `ensure_final_newline`, so it ends with exactly one newline.
+fn tidy_implementation(
+    lines: Vec<String>,
+    trim_trailing_whitespace: bool,
+    ensure_final_newline: bool,
+) -> Vec<String> {
+    let mut lines: Vec<String> = if trim_trailing_whitespace {
+        lines
+            .into_iter()
+            .map(|line| line.trim_end().to_string())
+            .collect()
+    } else {
+        lines
+    };
+    if ensure_final_newline {
+        while lines.last().is_some_and(|line| line.trim().is_empty()) {
+            lines.pop();
+        }
+    }
+    lines
+}
+
+/// The (line, column) of byte `offset` in `lines` joined with `\n`.
+fn line_and_column(lines: &[&str], mut offset: usize) -> (usize, usize) {
+    for (i, line) in lines.iter().enumerate() {
+        if offset <= line.len() {
+            return (i, offset);
+        }
+        offset -= line.len() + 1;
+    }
+    (lines.len().saturating_sub(1), offset)
+}
+
+/// Leading whitespace of a line.
+pub fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// The (start_line, end_line) of the function at `line`, found as
+/// `replace_function_in_document` finds the function it replaces.
+pub fn function_range(
+    text: &str,
+    line: usize,
+    expected_signature: Option<&FunctionSignature>,
+) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = text.lines().collect();
+    locate_function(&lines, line, expected_signature).ok()
+}
+
+/// Locate the function to replace, returning its (start_line, end_line).
+///
+/// Searches backwards from `current_line` and verifies the result against
+/// `expected_signature`, falling back to forward and global signature searches.
+fn locate_function(
+    lines: &[&str],
+    current_line: usize,
+    expected_signature: Option<&FunctionSignature>,
+) -> Result<(usize, usize), String> {
+    if current_line >= lines.len() {
+        return Err("Line out of bounds".to_string());
+    }
+
+    // Find the actual function start (in case cursor is inside function)
+    // First try backwards search from current_line
+    let mut start_line = find_function_start(lines, current_line);
+
+    info!(
+        "Backward search from line {} found function at line {:?}",
+        current_line, start_line
+    );
+
+    // Verify we found the correct function using signature matching
+    if let (Some(start), Some(expected)) = (start_line, expected_signature) {
+        let found_signature = parsed_signature_at(lines, start, expected);
+        info!(
+            "Comparing found signature '{}' with expected '{}'",
+            found_signature, expected
+        );
+        if expected.matches(&found_signature) {
+            // Another function with the same name may match better, e.g. the overload with
+            // the expected parameters when the job's line drifted onto its sibling
+            let found = signature_match_at(lines, start, expected);
+            let best = find_function_by_signature(lines, expected, current_line);
+            let best_match = best.and_then(|best| signature_match_at(lines, best, expected));
+            if found.is_none() || best_match > found {
+                info!(
+                    "Found signature matches as {:?}, searching the document: {:?} at {:?}",
+                    found, best_match, best
+                );
+                start_line = best;
+            } else {
+                info!("Signatures match!");
+            }
+        } else {
+            info!("Signatures differ, searching the document");
+            start_line = find_function_by_signature(lines, expected, current_line);
+        }
+    }
+
+    let start_line = start_line.ok_or_else(|| "Could not find function start".to_string())?;
+    // The function's `template<...>` header is replaced along with it
+    let start_line = template_header_start(lines, start_line);
+    info!("Final start_line: {}", start_line);
+
+    // Find the function end
+    let end_line = find_function_end(lines, start_line)
+        .ok_or_else(|| "Could not find function end".to_string())?;
+
+    Ok((start_line, end_line))
+}
+
+/// Both sides of a 3-way merge whose edits conflict, for clients to show as a diff.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeConflict {
+    /// The document as the user has it.
+    pub current: String,
+    /// The document as the agent's change leaves it, without the user's edits.
+    pub agent: String,
+}
+
+/// How a job fails when the user's edits conflict with the agent's change under
+/// `MergeStrategy::Abort`.
+pub const MERGE_CONFLICT_ERROR: &str = "Your edits conflict with the agent's change";
+
+/// Why `merge_3way` produced no merged text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// The agent's change could not be applied to the base text.
+    Failed(String),
+    /// The edits conflict and the strategy is `MergeStrategy::Abort`.
+    Conflict(MergeConflict),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed(e) => f.write_str(e),
+            Self::Conflict(_) => f.write_str(MERGE_CONFLICT_ERROR),
+        }
+    }
+}
+
+impl Error for MergeError {}
+
+impl From<String> for MergeError {
+    fn from(error: String) -> Self {
+        Self::Failed(error)
+    }
+}
+
+/// What `merge_3way` produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedEdit {
+    /// The current text with the agent's change merged in.
+    pub text: String,
+    /// How many lines the merge adds to the current document (negative when it removes
+    /// some); this is what jobs below the function shift by.
+    pub lines_delta: i32,
+    /// How many lines the agent's function spans, with its decorations.
+    pub function_lines: usize,
+    /// The kept "theirs" file, if any.
+    pub theirs_path: Option<PathBuf>,
+}
+
+/// How `merge_3way` merges and what it keeps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOptions<'a> {
+    pub strategy: MergeStrategy,
+    /// Job the merge is for; names the kept "theirs" file.
+    pub job_id: &'a str,
+    /// Whether to keep the agent's side of the merge on disk.
+    pub keep_theirs: bool,
+    /// Where kept "theirs" files go.
+    pub theirs_dir: PathBuf,
+    /// Signature of the function being merged, to find it in the current text when the
+    /// agent's version replaces the user's.
+    pub signature: Option<&'a FunctionSignature>,
+}
+
+impl<'a> MergeOptions<'a> {
+    /// Options for `job_id` from the configuration: "theirs" files are kept in
+    /// `MERGE_THEIRS_DIR` unless `DELETE_TEMP_FILES` is on.
+    pub fn new(job_id: &'a str, strategy: MergeStrategy) -> Self {
+        Self {
+            strategy,
+            job_id,
+            keep_theirs: !DELETE_TEMP_FILES,
+            theirs_dir: std::env::temp_dir().join(MERGE_THEIRS_DIR),
+            signature: None,
+        }
+    }
+}
+
+/// Write the agent's side of a merge to `<theirs_dir>/theirs_<job_id>.<ext>`, with the
+/// extension of `uri` so editors open it with the right filetype. Returns its path, or None
+/// if it could not be written.
+fn keep_theirs_file(uri: &Url, theirs_text: &str, options: &MergeOptions) -> Option<PathBuf> {
+    let extension = Path::new(uri.path())
+        .extension()
+        .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    let path = options
+        .theirs_dir
+        .join(format!("theirs_{}{}", options.job_id, extension));
+    let written = std::fs::create_dir_all(&options.theirs_dir)
+        .and_then(|_| std::fs::write(&path, theirs_text));
+    match written {
+        Ok(()) => {
+            info!("Kept the agent's side of the merge in {}", path.display());
+            Some(path)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to keep the agent's side of the merge in {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Merge the function `implementation` into `current_text`, the document the user kept
+/// editing since the agent started from `base_text`.
+///
+/// 1. Constructs "Theirs" by replacing the function starting at `base_line` of `base_text`.
+/// 2. Keeps "Theirs" on disk if `options` say so; failing to write it does not fail the merge.
+/// 3. Merges `base_text`, `current_text`, and `theirs_text`, resolving conflicts by the
+///    options' strategy; `Theirs` replaces the function at `current_line` wholesale.
+/// 4. Returns the merged text, with the line counts measured on it rather than on the raw
+///    implementation, whose fences, blank lines and decorations may not all land.
+pub fn merge_3way(
+    uri: &Url,
+    base_text: &str,
+    current_text: &str,
+    implementation: &str,
+    base_line: usize,
+    current_line: usize,
+    options: &MergeOptions,
+) -> Result<MergedEdit, MergeError> {
+    // 1. Construct "Theirs" version
+    let (theirs_text, mut function_lines) =
+        splice_function(base_text, base_line, implementation)
+            .ok_or_else(|| MergeError::Failed("Failed to replace function in base text".into()))?;
+
+    // 2. Keep it for post-mortem debugging
+    let theirs_path = if options.keep_theirs {
+        keep_theirs_file(uri, &theirs_text, options)
+    } else {
+        None
+    };
+
+    // 3. Perform 3-way merge
+    let merged_text = match merge(base_text, current_text, &theirs_text) {
+        Ok(text) => text,
+        Err(text) => match options.strategy {
+            MergeStrategy::Markers => text,
+            MergeStrategy::Abort => {
+                return Err(MergeError::Conflict(MergeConflict {
+                    current: current_text.to_string(),
+                    agent: theirs_text,
+                }))
+            }
+            // The agent's function replaces the user's version of it wholesale
+            MergeStrategy::Theirs => {
+                let (text, start_line, end_line, lines_delta) = replace_function_in_document(
+                    current_text,
+                    current_line,
+                    implementation,
+                    options.signature,
+                )
+                .map_err(MergeError::Failed)?;
+                function_lines = ((end_line - start_line + 1) as i32 + lines_delta) as usize;
+                text
+            }
+        },
+    };
+
+    let lines_delta = merged_text.lines().count() as i32 - current_text.lines().count() as i32;
+
+    Ok(MergedEdit {
+        text: merged_text,
+        lines_delta,
+        function_lines,
+        theirs_path,
+    })
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_function_signature_rust() {
+        let code = "fn foo(x: i32) -> i32 {\n    todo!()\n}";
+        let sig = extract_function_signature(code, 0);
+        assert_eq!(sig, Some("fn foo(x: i32) -> i32 {".to_string()));
+    }
+
+    #[test]
+    fn test_placeholder_bodies_are_not_implementations() {
+        let cases = [
+            "fn foo() {\n    todo!()\n}",
+            "fn foo() {\n    todo!(\"later\");\n}",
+            "fn foo() -> i32 {\n    // TODO\n    unimplemented!()\n}",
+            "fn foo() {\n}",
+            "fn foo() { todo!() }",
+            "pub fn foo() {\n\n}",
+            "def foo():\n    pass\n",
+            "def foo():\n    raise NotImplementedError\n",
+            "func foo() error {\n\tpanic(\"not implemented\")\n}",
+            "func (r *Repo) Print(v interface{}) {\n\t// TODO\n\tpanic(\"TODO\")\n}",
+        ];
+        for code in cases {
+            assert!(!has_non_placeholder_body(code, 0), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn test_real_bodies_are_implementations() {
+        let cases = [
+            ("fn foo() -> i32 {\n    42\n}", 0),
+            ("fn foo() {\n    let x = 1;\n    todo!()\n}", 1),
+            ("fn foo() { bar() }", 0),
+            ("def foo():\n    return 1\n", 0),
+            (
+                "func (r *Repo) Print(v interface{}) {\n\tfmt.Println(v)\n}",
+                0,
+            ),
+        ];
+        for (code, line) in cases {
+            assert!(has_non_placeholder_body(code, line), "{:?}", code);
+        }
+        // Only the function at the cursor counts
+        let code = "fn foo() {\n    42\n}\n\nfn bar() {\n    todo!()\n}\n";
+        assert!(has_non_placeholder_body(code, 1));
+        assert!(!has_non_placeholder_body(code, 5));
+    }
+
+    #[test]
+    fn test_find_placeholder_functions() {
+        let code = "fn a() {\n    todo!()\n}\n\nfn b() -> i32 {\n    1\n}\n\npub fn c() {}\n";
+        assert_eq!(find_placeholder_functions(code), vec![0, 8]);
+
+        let code = "def a():\n    pass\n\ndef b():\n    return 1\n";
+        assert_eq!(find_placeholder_functions(code), vec![0]);
+        assert!(find_placeholder_functions("").is_empty());
+    }
+
+    #[test]
+    fn test_supported_languages_are_detected() {
+        let sample_signature = |language_id| match language_id {
+            "rust" => "pub fn add(a: i32, b: i32) -> i32 {",
+            "python" => "def add(a, b):",
+            "go" => "func add(a, b int) int {",
+            "lua" => "local function add(a, b)",
+            "ruby" => "def add(a, b)",
+            "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => {
+                "export function add(a, b) {"
+            }
+            "c" | "cpp" | "java" => "int add(int a, int b) {",
+            "kotlin" => "fun add(a: Int, b: Int): Int {",
+            "swift" => "func add(_ a: Int, _ b: Int) -> Int {",
+            "csharp" => "public int Add(int a, int b) => a + b;",
+            other => panic!("No sample signature for supported language {}", other),
+        };
+
+        for language in SUPPORTED_LANGUAGES {
+            assert!(
+                looks_like_signature(sample_signature(language.language_id), language.language_id),
+                "{} signature not detected",
+                language.language_id
+            );
+            assert_eq!(language.detection, DetectionQuality::Heuristic);
+        }
+        let ids: Vec<&str> = SUPPORTED_LANGUAGES.iter().map(|l| l.language_id).collect();
+        for language_id in ["rust", "python", "cpp", "java", "go", "typescript"] {
+            assert!(ids.contains(&language_id), "{} missing", language_id);
+        }
+    }
+
+    #[test]
+    fn test_extract_wrapped_rust_signature() {
+        let code = "pub fn merge<K, V>(\n    left: HashMap<K, V>,\n    right: HashMap<K, V>,\n) -> HashMap<K, V>\nwhere\n    K: Eq + Hash,\n{\n    todo!()\n}\n";
+        let expected = "pub fn merge<K, V>(left: HashMap<K, V>, right: HashMap<K, V>) -> HashMap<K, V> where K: Eq + Hash {";
+        for line in [0, 2, 5, 7] {
+            assert_eq!(
+                extract_function_signature(code, line).as_deref(),
+                Some(expected),
+                "line {}",
+                line
+            );
+        }
+        assert_eq!(short_signature(expected), "pub fn merge<K, V>");
+    }
+
+    #[test]
+    fn test_extract_wrapped_python_signature() {
+        let code = "def fetch(\n    url: str,\n    timeout: float = 1.0,\n) -> bytes:\n    pass\n";
+        assert_eq!(
+            extract_function_signature(code, 4).as_deref(),
+            Some("def fetch(url: str, timeout: float = 1.0) -> bytes:")
+        );
+        assert!(signatures_match(
+            "def fetch(\n    url: str,\n    timeout: float = 1.0,\n) -> bytes:",
+            "def fetch(url: str, timeout: float = 1.0) -> bytes:"
+        ));
+    }
+
+    #[test]
+    fn test_wrapped_signature_anchors_replacement() {
+        // The job's line now points into `first`, which was inserted above `second`
+        let code = "fn first(\n    a: u8,\n) {\n    todo!()\n}\n\nfn second(\n    b: u8,\n) -> u8 {\n    todo!()\n}\n";
+        let signature = extract_function_signature(code, 7).unwrap();
+        assert_eq!(signature, "fn second(b: u8) -> u8 {");
+        let signature = FunctionSignature::parse(&signature, "rust");
+
+        let (new_text, start, end, _) = replace_function_in_document(
+            code,
+            1,
+            "fn second(b: u8) -> u8 {\n    b\n}",
+            Some(&signature),
+        )
+        .unwrap();
+        assert_eq!((start, end), (6, 10));
+        assert!(new_text.starts_with("fn first(\n    a: u8,\n) {\n    todo!()\n}\n"));
+        assert!(new_text.ends_with("fn second(b: u8) -> u8 {\n    b\n}\n"));
+        assert_eq!(relocate_function(code, 7, &signature), Some(7));
+    }
+
+    #[test]
+    fn test_extract_function_signature_python() {
+        let code = "def calculate(a, b):\n    return a + b";
+        let sig = extract_function_signature(code, 0);
+        assert_eq!(sig, Some("def calculate(a, b):".to_string()));
+    }
+
+    #[test]
+    fn test_extract_function_signature_cpp() {
+        let code = "int add(int a, int b) {\n    return a + b;\n}";
+        let sig = extract_function_signature(code, 0);
+        assert_eq!(sig, Some("int add(int a, int b) {".to_string()));
+    }
+
+    #[test]
+    fn test_find_function_start_rust() {
+        let code = r#"
+#[test]
+fn foo() {
+    let x = 1;
+    println!("{}", x);
+}
+
+pub async fn bar() {
+    // comment
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        // Line 3 is 'fn foo() {' (depending on split, let's trace)
+        // 0: ""
+        // 1: "#[test]"
+        // 2: "fn foo() {" --> Start
+        // 3: "    let x = 1;"
+
+        assert_eq!(find_function_start(&lines, 2), Some(2));
+        assert_eq!(find_function_start(&lines, 3), Some(2)); // Inside foo
+
+        // bar is at line 7
+        // 7: "pub async fn bar() {"
+        assert_eq!(find_function_start(&lines, 7), Some(7));
+        assert_eq!(find_function_start(&lines, 8), Some(7)); // Inside bar
+    }
+
+    #[test]
+    fn test_find_function_start_python() {
+        let code = r#"
+def foo():
+    x = 1
+    print(x)
+
+async def bar():
+    pass
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_start(&lines, 1), Some(1)); // def foo
+        assert_eq!(find_function_start(&lines, 2), Some(1)); // Inside foo
+        assert_eq!(find_function_start(&lines, 5), Some(5)); // async def bar
+    }
+
+    #[test]
+    fn test_find_function_start_cpp() {
+        let code = r#"
+int add(int a, int b) {
+    return a + b;
+}
+
+void process() {
+    // code
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_start(&lines, 1), Some(1)); // int add
+        assert_eq!(find_function_start(&lines, 2), Some(1)); // Inside add
+        assert_eq!(find_function_start(&lines, 5), Some(5)); // void process
+    }
+
+    #[test]
+    fn test_find_function_start_cpp_template() {
+        let code = r#"
+#include <vector>
+
+template <typename T>
+T max_of(const std::vector<T>& items) {
+    return items[0];
+}
+
+template<typename K, typename V>
+std::map<K, V> ns::Index<K, V>::build(int size) const {
+    return {};
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        // The header resolves to the function it introduces
+        assert_eq!(find_function_start(&lines, 3), Some(4));
+        assert_eq!(find_function_start(&lines, 5), Some(4));
+        assert_eq!(find_function_start(&lines, 10), Some(9));
+        assert_eq!(extract_function_name(lines[4]), Some("max_of"));
+        assert_eq!(
+            extract_function_name(lines[9]),
+            Some("ns::Index<K, V>::build")
+        );
+        assert_eq!(
+            extract_function_name("template <typename T> T twice(T x) {"),
+            Some("twice")
+        );
+        // Replacing the function replaces its header too
+        let (_, start, end, _) =
+            replace_function_in_document(code, 5, "int max_of() {\n    return 0;\n}", None)
+                .unwrap();
+        assert_eq!((start, end), (3, 6));
+    }
+
+    #[test]
+    fn test_find_function_start_nested_functions() {
+        // (name, code, outer start, inner start, line inside the inner function, line
+        // between the inner function's end and the outer's)
+        let cases = [
+            (
+                "rust",
+                "fn outer() -> i32 {\n    fn helper(x: i32) -> i32 {\n        x + 1\n    }\n    helper(1)\n}\n",
+                0,
+                1,
+                2,
+                4,
+            ),
+            (
+                "python",
+                "def outer():\n    def helper(x):\n        return x + 1\n\n    return helper(1)\n",
+                0,
+                1,
+                2,
+                4,
+            ),
+            (
+                "javascript",
+                "function outer() {\n  const helper = (x) => {\n    return x + 1;\n  };\n  return helper(1);\n}\n",
+                0,
+                1,
+                2,
+                4,
+            ),
+        ];
+        for (name, code, outer, inner, in_inner, after_inner) in cases {
+            let lines: Vec<&str> = code.lines().collect();
+            assert_eq!(find_function_start(&lines, outer), Some(outer), "{}", name);
+            assert_eq!(
+                find_function_start(&lines, in_inner),
+                Some(inner),
+                "{}",
+                name
+            );
+            assert_eq!(
+                find_function_start(&lines, after_inner),
+                Some(outer),
+                "{}",
+                name
+            );
+            // Replacing from below the helper replaces the outer function
+            let (_, start, _, _) =
+                replace_function_in_document(code, after_inner, "fn outer() {}", None).unwrap();
+            assert_eq!(start as usize, outer, "{}", name);
+        }
+
+        // Between two top-level functions the nearest one above is kept
+        let lines = ["fn a() {", "}", "", "fn b() {", "}"];
+        assert_eq!(find_function_start(&lines, 2), Some(0));
+
+        // A Python function ends with its indented block, even when its signature wraps
+        let lines = ["def a(", "    x,", "):", "    return x", "", "X = 5"];
+        assert_eq!(find_function_end(&lines, 0), Some(3));
+    }
+
+    #[test]
+    fn test_find_function_start_skips_closures() {
+        // (name, code, line inside a closure); the outer function starts at line 0
+        let cases = [
+            (
+                "rust closure",
+                "fn outer(items: &[u32]) -> Vec<u32> {\n    items\n        .iter()\n        .map(|x| {\n            x + 1\n        })\n        .collect()\n}\n",
+                4,
+            ),
+            (
+                "rust closure bound with a fn pointer type",
+                "fn outer() -> u32 {\n    let add: fn (u32) -> u32 = |x| {\n        x + 1\n    };\n    add(1)\n}\n",
+                2,
+            ),
+            (
+                "rust move closure",
+                "fn outer() {\n    let handle = thread::spawn(move || {\n        work();\n    });\n    handle.join();\n}\n",
+                2,
+            ),
+            (
+                "arrow function passed as an argument",
+                "function outer(items) {\n  items.forEach((item) => {\n    print(item);\n  });\n}\n",
+                2,
+            ),
+            (
+                "arrow function in an object literal",
+                "function routes() {\n  return {\n    handler: async (req) => {\n      return req;\n    },\n  };\n}\n",
+                3,
+            ),
+            (
+                "python lambda",
+                "def outer(items):\n    return sorted(\n        items,\n        key=lambda item: (\n            item.priority,\n        ),\n    )\n",
+                4,
+            ),
+        ];
+        for (name, code, in_closure) in cases {
+            let lines: Vec<&str> = code.lines().collect();
+            assert_eq!(find_function_start(&lines, in_closure), Some(0), "{}", name);
+        }
+
+        for closure in [
+            "let handler = |req: Request| {",
+            "let add: fn (u32) -> u32 = |x| {",
+            "    handler: async (req) => {",
+            "export default () => {",
+            "key = lambda x: (",
+        ] {
+            assert!(!is_function_start(closure.trim()), "{}", closure);
+        }
+        // Arrow functions assigned to a name, and functions merely mentioning closures
+        for function in [
+            "const load = async (id) => {",
+            "onClick = () => {",
+            "fn apply(f: impl Fn(u32)) -> bool { run(|x| x > 1) }",
+            "public Task Run() => Task.Run(() => {",
+            "void apply(Callback lambda) {",
+            "def lambda_handler(event, context):",
+        ] {
+            assert!(is_function_start(function), "{}", function);
+        }
+    }
+
+    #[test]
+    fn test_find_function_start_cpp_macros() {
+        let code = r#"
+#define DEFINE_GETTER(type, name) \
+    type get_##name(void) {       \
+        return name;              \
+    }
+
+DEFINE_GETTER(int, count)
+
+TEST(MathTest, Adds) {
+    EXPECT_EQ(add(1, 2), 3);
+}
+
+int add(int a, int b) {
+    int sum = compute(a, b);
+    return sum;
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        // Macro bodies and invocations are not functions
+        assert_eq!(find_function_start(&lines, 3), None);
+        assert_eq!(find_function_start(&lines, 6), None);
+        assert!(!is_function_start(lines[6]));
+        assert_eq!(find_function_start(&lines, 9), Some(8));
+        assert_eq!(
+            extract_function_name(lines[8]),
+            Some("TEST(MathTest, Adds)")
+        );
+        // Statements inside the body are not declarations
+        assert_eq!(find_function_start(&lines, 14), Some(12));
+        assert_eq!(extract_function_name(lines[12]), Some("add"));
+        for statement in [
+            "if check(x):",
+            "match parse(input) {",
+            "with open(path) as f:",
+            "std::cout << compute(x)",
+            "defer close(ch)",
+        ] {
+            assert!(!is_function_start(statement), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn test_kotlin_function_forms() {
+        let code = r#"
+class Repo(private val name: String) {
+    val size: Int = 0
+    val transform: (Int) -> Int = { it * 2 }
+
+    override suspend fun save(item: String): Boolean {
+        TODO()
+    }
+
+    fun <T> List<T>.second(): T {
+        return this[1]
+    }
+
+    private fun twice(x: Int) = x * 2
+
+    fun load() {
+        items.forEach { item -> println(item) }
+    }
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        // (line inside the function, start, end, name)
+        for (inside, start, end, name) in [
+            (6, 5, 7, "save"),
+            (10, 9, 11, "second"),
+            (13, 13, 13, "twice"),
+            (16, 15, 17, "load"),
+        ] {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(find_function_end(&lines, start), Some(end), "{}", name);
+            assert_eq!(extract_function_name(lines[start].trim()), Some(name));
+        }
+        assert!(!has_non_placeholder_body(code, 6));
+        assert!(has_non_placeholder_body(code, 13));
+        // Properties, lambdas and constructors in the class header are not functions
+        for line in [1, 2, 3, 16] {
+            assert!(!is_function_start(lines[line].trim()), "{}", lines[line]);
+        }
+        assert!(looks_like_signature("fun add(a: Int): Int {", "kotlin"));
+        assert!(!looks_like_signature("val add = fun(a: Int) = a", "kotlin"));
+    }
+
+    #[test]
+    fn test_swift_function_forms() {
+        let code = r#"
+struct Stack<Element> {
+    var items: [Element] = []
+    var count: Int { items.count }
+    let compare = { (a: Int, b: Int) -> Bool in a < b }
+
+    @discardableResult
+    public mutating func push(_ item: Element) -> Element {
+        fatalError("not implemented")
+    }
+
+    static func make<T: Decodable>(from data: Data) throws -> T {
+        return try JSONDecoder().decode(T.self, from: data)
+    }
+
+    init(items: [Element]) {
+        self.items = items
+    }
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        for (inside, start, end, name) in [
+            (8, 7, 9, "push"),
+            (12, 11, 13, "make"),
+            (16, 15, 17, "init"),
+        ] {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(find_function_end(&lines, start), Some(end), "{}", name);
+            assert_eq!(extract_function_name(lines[start].trim()), Some(name));
+        }
+        assert!(!has_non_placeholder_body(code, 8));
+        for line in [1, 2, 3, 4] {
+            assert!(!is_function_start(lines[line].trim()), "{}", lines[line]);
+        }
+        assert!(looks_like_signature("@objc private func tap() {", "swift"));
+        assert!(!looks_like_signature("let tap = { () in }", "swift"));
+    }
+
+    #[test]
+    fn test_csharp_function_forms() {
+        let code = r#"
+public class Repo
+{
+    public string Name { get; set; }
+    public int Count => items.Count;
+    private readonly Func<int, int> square = x => x * x;
+
+    public Repo(string name) : base(name)
+    {
+        Name = name;
+    }
+
+    public async Task<Item> GetAsync(int id)
+    {
+        throw new NotImplementedException();
+    }
+
+    public override string ToString() =>
+        $"Repo {Name}";
+
+    public static int Twice(int x) => x * 2;
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        for (inside, start, end, name) in [
+            (9, 7, 10, "Repo"),
+            (14, 12, 15, "GetAsync"),
+            (18, 17, 18, "ToString"),
+            (20, 20, 20, "Twice"),
+        ] {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(find_function_end(&lines, start), Some(end), "{}", name);
+            assert_eq!(extract_function_name(lines[start].trim()), Some(name));
+        }
+        assert!(!has_non_placeholder_body(code, 14));
+        assert!(has_non_placeholder_body(code, 20));
+        // Properties and lambda fields are not functions
+        for line in [1, 3, 4, 5] {
+            assert!(!is_function_start(lines[line].trim()), "{}", lines[line]);
+        }
+        assert!(is_decoration_line("[HttpGet(\"{id}\")]", "csharp"));
+    }
+
+    #[test]
+    fn test_find_function_start_java() {
+        let code = r#"
+public int calculate(int x) {
+    return x * 2;
+}
+
+private void helper() {
+    // code
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_start(&lines, 1), Some(1)); // public int
+        assert_eq!(find_function_start(&lines, 5), Some(5)); // private void
+    }
+
+    #[test]
+    fn test_find_function_start_go() {
+        let code = r#"
+func Add(a, b int) int {
+	return a + b
+}
+
+func (r *Repo) Save(ctx context.Context) error {
+	panic("not implemented")
+}
+
+func Map[T, U any](xs []T, f func(T) U) []U {
+	out := make([]U, 0, len(xs))
+	return out
+}
+
+func Divide(a, b float64) (float64, error) {
+	handler := func(x int) {
+	}
+	return a / b, nil
+}
+"#;
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_start(&lines, 1), Some(1)); // plain function
+        assert_eq!(find_function_start(&lines, 2), Some(1)); // inside Add
+        assert_eq!(find_function_start(&lines, 6), Some(5)); // inside the method
+        assert_eq!(find_function_start(&lines, 11), Some(9)); // inside the generic
+        assert_eq!(find_function_start(&lines, 16), Some(14)); // function literals don't count
+        assert_eq!(find_function_end(&lines, 5), Some(7));
+        assert_eq!(find_function_end(&lines, 9), Some(12));
+        assert_eq!(find_function_end(&lines, 14), Some(18));
+        assert_eq!(find_placeholder_functions(code), vec![5]);
+    }
+
+    #[test]
+    fn test_typescript_function_forms() {
+        let code = include_str!("../tests/fixtures/forms.ts");
+        let lines: Vec<&str> = code.lines().collect();
+        // (line inside the function, start, end, name)
+        let functions = [
+            (3, 2, 4, "add"),
+            (7, 6, 8, "load"),
+            (11, 10, 12, "stream"),
+            (15, 14, 16, "handler"),
+            (19, 18, 20, "double"),
+            (23, 22, 24, "greet"),
+            (30, 29, 31, "constructor"),
+            (35, 33, 37, "save"),
+            (40, 39, 41, "create"),
+            (44, 43, 45, "onChange"),
+        ];
+        for (inside, start, end, name) in functions {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(find_function_end(&lines, start), Some(end), "{}", name);
+            assert_eq!(extract_function_name(lines[start].trim()), Some(name));
+        }
+        // Fields, calls and control flow are not functions
+        for line in [0, 26, 27, 34, 40] {
+            assert!(!is_function_start(lines[line].trim()), "{}", lines[line]);
+        }
+    }
+
+    #[test]
+    fn test_ruby_functions_end_at_balanced_end() {
+        let code = include_str!("../tests/fixtures/blocks.rb");
+        let lines: Vec<&str> = code.lines().collect();
+        // (line inside the function, start, end, name)
+        let functions = [
+            (8, 6, 9, "parse"),
+            (16, 11, 22, "total"),
+            (24, 24, 24, "reset"),
+            (29, 26, 32, "valid?"),
+            (35, 34, 36, "label"),
+        ];
+        for (inside, start, end, name) in functions {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(balanced_function_end(&lines, start), Some(end), "{}", name);
+            assert_eq!(extract_function_name(lines[start].trim()), Some(name));
+        }
+        assert_eq!(find_placeholder_functions(code), vec![24, 34]);
+    }
+
+    #[test]
+    fn test_lua_functions_end_at_balanced_end() {
+        let code = include_str!("../tests/fixtures/blocks.lua");
+        let lines: Vec<&str> = code.lines().collect();
+        let functions = [
+            (4, 2, 9, "clamp"),
+            (14, 11, 17, "M.sum"),
+            (20, 19, 21, "on_exit"),
+            (26, 23, 29, "M.map"),
+            (31, 31, 31, "M.noop"),
+        ];
+        for (inside, start, end, name) in functions {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(balanced_function_end(&lines, start), Some(end), "{}", name);
+            assert_eq!(extract_function_name(lines[start].trim()), Some(name));
+        }
+        assert_eq!(find_placeholder_functions(code), vec![19, 31]);
+        assert_eq!(
+            classify_output_shape("local function clamp(x)\n  return x\nend", "lua"),
+            OutputShape::FullFunction
+        );
+    }
+
+    #[test]
+    fn test_ruby_and_lua_bodies_keep_signature_and_end() {
+        let code = include_str!("../tests/fixtures/blocks.rb");
+        let (new_text, start, end, _) =
+            replace_function_body_in_document(code, 24, "@items = []", None).unwrap();
+        assert_eq!((start, end), (24, 24));
+        assert!(new_text.contains("    def reset\n      @items = []\n    end\n"));
+
+        let (new_text, start, end, _) =
+            replace_function_body_in_document(code, 35, "items.join(\", \")", None).unwrap();
+        assert_eq!((start, end), (34, 36));
+        assert!(new_text.contains("    def label\n      items.join(\", \")\n    end\n  end\n"));
+
+        let code = include_str!("../tests/fixtures/blocks.lua");
+        let (new_text, _, _, _) =
+            replace_function_body_in_document(code, 31, "return nil", None).unwrap();
+        assert!(new_text.contains("function M.noop()\n  return nil\nend\n"));
+    }
+
+    #[test]
+    fn test_ruby_and_python_def_are_told_apart() {
+        assert_eq!(ruby_function_name("def self.build(attrs)"), Some("build"));
+        assert_eq!(ruby_function_name("def save!"), Some("save!"));
+        assert_eq!(ruby_function_name("def reset; end"), Some("reset"));
+        assert_eq!(ruby_function_name("def parse(text):"), None);
+        assert_eq!(ruby_function_name("def parse(text) -> str:"), None);
+        assert_eq!(lua_function_name("local function parse(s)"), Some("parse"));
+        assert_eq!(
+            lua_function_name("M.on_exit = function(code)"),
+            Some("M.on_exit")
+        );
+        assert_eq!(lua_function_name("local x = compute(y)"), None);
+
+        // Python bodies are not balanced against `end`
+        let python = "def f(a):\n    end = a\n    return end\n";
+        let lines: Vec<&str> = python.lines().collect();
+        assert_eq!(end_keyword_language(&lines, 0), None);
+    }
+
+    #[test]
+    fn test_braces_in_strings_and_comments_are_not_counted() {
+        let code = include_str!("../tests/fixtures/braces.rs");
+        let lines: Vec<&str> = code.lines().collect();
+        // (line inside the function, start, end, name)
+        let functions = [
+            (4, 1, 7, "render"),
+            (11, 9, 15, "raw_strings"),
+            (21, 18, 23, "lifetimes"),
+            (27, 25, 30, "unicode"),
+            (33, 32, 34, "after"),
+        ];
+        for (inside, start, end, name) in functions {
+            assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+            assert_eq!(balanced_function_end(&lines, start), Some(end), "{}", name);
+        }
+        assert_eq!(find_placeholder_functions(code), vec![32]);
+
+        // Replacing `render` leaves `raw_strings` alone
+        let (new_text, start, end, _) = replace_function_in_document(
+            code,
+            4,
+            "fn render(name: &str) -> String {\n    name.to_string()\n}",
+            None,
+        )
+        .unwrap();
+        assert_eq!((start, end), (1, 7));
+        assert!(
+            new_text.contains("    name.to_string()\n}\n\nfn raw_strings() -> &'static str {\n")
+        );
+
+        for (fixture, functions) in [
+            (
+                include_str!("../tests/fixtures/braces.cpp"),
+                [(5, 3, 9, "render"), (12, 11, 13, "after")],
+            ),
+            (
+                include_str!("../tests/fixtures/braces.js"),
+                [(4, 0, 6, "render"), (9, 8, 10, "after")],
+            ),
+        ] {
+            let lines: Vec<&str> = fixture.lines().collect();
+            for (inside, start, end, name) in functions {
+                assert_eq!(find_function_start(&lines, inside), Some(start), "{}", name);
+                assert_eq!(balanced_function_end(&lines, start), Some(end), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_brace_scanner_literals() {
+        let mut rust = BraceScanner::new(BraceDialect::Rust);
+        assert_eq!(
+            rust.braces("let c = '{'; let s = \"}\"; {"),
+            vec![(26, '{')]
+        );
+        assert_eq!(
+            rust.braces("fn f<'a>(x: &'a str) -> &'a str { // }"),
+            vec![(32, '{')]
+        );
+        assert!(rust.braces("let s = \"{").is_empty());
+        // The string goes on until it is closed
+        assert_eq!(rust.braces("}\"; }"), vec![(4, '}')]);
+
+        let mut cs = BraceScanner::new(BraceDialect::CFamily);
+        assert!(cs.braces("var path = @\"C:\\{dir}\"\"\";").is_empty());
+        assert!(cs.braces("val s = \"\"\"").is_empty());
+        assert!(cs.braces("  } still in the string").is_empty());
+        assert_eq!(cs.braces("\"\"\" {"), vec![(4, '{')]);
+
+        let mut go = BraceScanner::new(BraceDialect::Go);
+        assert!(go.braces("q := `{").is_empty());
+        assert_eq!(go.braces("}` + string('}') {"), vec![(17, '{')]);
+    }
+
+    #[test]
+    fn test_js_signatures_match_across_modifiers() {
+        assert!(signatures_match(
+            "export async function load(id: string) {",
+            "function load(id) {"
+        ));
+        assert!(signatures_match(
+            "export const handler = async (req) => {",
+            "const handler = (req, res) => {"
+        ));
+        assert!(signatures_match(
+            "static async save(item) {",
+            "save(item) {"
+        ));
+        assert!(!signatures_match("function load() {", "function save() {"));
+    }
+
+    #[test]
+    fn test_find_function_end() {
+        let code = r#"fn foo() {
+    let x = {
+        1
+    };
+    println!("{}", x);
+}
+
+fn bar() {}"#;
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_end(&lines, 0), Some(5));
+        assert_eq!(find_function_end(&lines, 7), Some(7));
+    }
+
+    #[test]
+    fn test_find_function_end_unbalanced_strict() {
+        let code = "fn foo() {\n    let x = 1;\n    if x > 0 {\n        println!(\"{}\", x);\n";
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(balanced_function_end(&lines, 0), None);
+    }
+
+    #[test]
+    fn test_find_function_end_unbalanced_lenient_at_eof() {
+        // Missing closing brace, trailing blank lines
+        let code =
+            "fn foo() {\n    let x = 1;\n    if x > 0 {\n        println!(\"{}\", x);\n    }\n\n\n";
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_end(&lines, 0), Some(4));
+    }
+
+    #[test]
+    fn test_find_function_end_unbalanced_lenient_stops_before_next_function() {
+        let code = r#"fn foo() {
+    let x = 1;
+    if x > 0 {
+        println!("{}", x);
+
+fn bar() {
+    todo!()
+}"#;
+        let lines: Vec<&str> = code.lines().collect();
+        // Should not swallow bar()
+        assert_eq!(find_function_end(&lines, 0), Some(3));
+    }
+
+    #[test]
+    fn test_broken_code_around_cursor_still_locates_function() {
+        // Mid-edit Rust: a missing `)`, a half-typed expression and an unclosed block
+        let code = "fn ok() -> i32 {\n    1\n}\n\nfn broken(a: i32 -> i32 {\n    let x = (a + ;\n    if x {\n        todo!()\n\nfn after() {}\n";
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_start(&lines, 6), Some(4));
+        assert_eq!(
+            locate_function(
+                &lines,
+                6,
+                Some(&FunctionSignature::parse(
+                    "fn broken(a: i32 -> i32 {",
+                    "rust"
+                ))
+            ),
+            Ok((4, 7))
+        );
+    }
+
+    #[test]
+    fn test_replace_function_in_document_with_unbalanced_braces() {
+        let code = "fn foo() {\n    if true {\n        todo!()\n\nfn bar() {}\n";
+        let new_impl = "fn foo() {\n    42\n}";
+
+        let (new_text, start_line, end_line, _) =
+            replace_function_in_document(code, 0, new_impl, None).unwrap();
+
+        assert_eq!(start_line, 0);
+        assert_eq!(end_line, 2);
+        assert_eq!(new_text, "fn foo() {\n    42\n}\n\nfn bar() {}\n");
+    }
+
+    #[test]
+    fn test_classify_output_shape() {
+        const FULL: OutputShape = OutputShape::FullFunction;
+        const BODY: OutputShape = OutputShape::BodyOnly;
+
+        let cases = [
+            ("rust", "fn foo() -> i32 {\n    42\n}", FULL),
+            ("rust", "\n#[inline]\n/// Docs\npub fn foo() {\n}", FULL),
+            ("rust", "pub(crate) async unsafe fn foo() {}", FULL),
+            ("rust", "```rust\nfn foo() {\n    42\n}\n```", FULL),
+            ("rust", "    let x = 1;\n    x + 1", BODY),
+            ("rust", "// compute the sum\na + b", BODY),
+            ("rust", "let f: fn(i32) -> i32 = double;\nf(2)", BODY),
+            ("rust", "```rust\nlet x = 1;\nx\n```", BODY),
+            (
+                "python",
+                "@staticmethod\ndef foo(a, b):\n    return a + b",
+                FULL,
+            ),
+            ("python", "# sum them\nreturn a + b", BODY),
+            ("python", "default = 1\nreturn default", BODY),
+            ("go", "func add(a, b int) int {\n\treturn a + b\n}", FULL),
+            ("go", "return a + b", BODY),
+            (
+                "typescript",
+                "export async function foo(): Promise<void> {\n}",
+                FULL,
+            ),
+            ("javascript", "render(props) {\n  return null;\n}", FULL),
+            ("javascript", "if (x) {\n  return 1;\n}", BODY),
+            ("javascript", "console.log(x);", BODY),
+            ("lua", "local function foo(a)\n  return a\nend", FULL),
+            ("cpp", "int add(int a, int b) {\n    return a + b;\n}", FULL),
+            ("cpp", "return a + b;", BODY),
+        ];
+
+        for (language_id, text, expected) in cases {
+            assert_eq!(
+                classify_output_shape(text, language_id),
+                expected,
+                "{}: {:?}",
+                language_id,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_target_function_from_whole_file() {
+        let output = "use std::fmt;\n\nfn helper() -> i32 {\n    1\n}\n\n/// Adds one.\n#[inline]\npub fn add_one(x: i32) -> i32 {\n    x + helper()\n}\n\nfn main() {}\n";
+        assert_eq!(
+            extract_target_function(
+                output,
+                &FunctionSignature::parse("pub fn add_one(x: i32) -> i32 {", "rust")
+            )
+            .unwrap(),
+            "/// Adds one.\n#[inline]\npub fn add_one(x: i32) -> i32 {\n    x + helper()\n}"
+        );
+
+        // Leading imports alone mark a whole file
+        let output = "import math\n\n@cache\ndef area(r):\n    return math.pi * r * r\n";
+        assert_eq!(
+            extract_target_function(output, &FunctionSignature::parse("def area(r):", "python"))
+                .unwrap(),
+            "@cache\ndef area(r):\n    return math.pi * r * r"
+        );
+
+        // Methods of a class the whole file was written around keep their indentation
+        let output = "class Shape:\n    def area(self):\n        return 0\n\n    def name(self):\n        return 'shape'\n";
+        assert_eq!(
+            extract_target_function(
+                output,
+                &FunctionSignature::parse("def name(self):", "python")
+            )
+            .unwrap(),
+            "    def name(self):\n        return 'shape'"
+        );
+
+        let output =
+            "package main\n\nimport \"fmt\"\n\nfunc add(a, b int) int {\n\treturn a + b\n}\n";
+        assert_eq!(
+            extract_target_function(
+                output,
+                &FunctionSignature::parse("func add(a, b int) int {", "go")
+            )
+            .unwrap(),
+            "func add(a, b int) int {\n\treturn a + b\n}"
+        );
+    }
+
+    #[test]
+    fn test_extract_target_function_keeps_function_only_output() {
+        let cases = [
+            (
+                "rust",
+                "fn add(a: i32) -> i32 {",
+                "/// Docs\nfn add(a: i32) -> i32 {\n    a + 1\n}",
+            ),
+            (
+                "rust",
+                "fn add(a: i32) -> i32 {",
+                "    let b = 1;\n    a + b",
+            ),
+            // A closure or nested function inside the target is not a second function
+            (
+                "python",
+                "def outer():",
+                "def outer():\n    def inner():\n        return 1\n    return inner()",
+            ),
+            ("go", "func add(a, b int) int {", "return a + b"),
+        ];
+        for (language_id, signature, output) in cases {
+            assert_eq!(
+                extract_target_function(output, &FunctionSignature::parse(signature, language_id))
+                    .unwrap(),
+                output,
+                "{}: {:?}",
+                language_id,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_target_function_missing_function() {
+        let output = "use std::fmt;\n\nfn helper() {}\n\nfn other() {}\n";
+        let error = extract_target_function(
+            output,
+            &FunctionSignature::parse("pub fn add_one(x: i32) -> i32 {", "rust"),
+        )
+        .unwrap_err();
+        assert!(error.contains("pub fn add_one"), "{}", error);
+
+        let output = "def helper():\n    pass\n\ndef other():\n    pass\n";
+        assert!(extract_target_function(
+            output,
+            &FunctionSignature::parse("def area(r):", "python")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_detect_line_ending() {
+        assert_eq!(detect_line_ending("a\nb\n"), "\n");
+        assert_eq!(detect_line_ending("a\r\nb\r\nc\n"), "\r\n");
+        assert_eq!(detect_line_ending("a\r\nb\nc\n"), "\n");
+        assert_eq!(detect_line_ending("single line"), "\n");
+    }
+
+    #[test]
+    fn test_detect_indent() {
+        assert_eq!(
+            detect_indent(include_str!("../tests/fixtures/blocks.rb")),
+            Indent::Spaces(2)
+        );
+        assert_eq!(
+            detect_indent(include_str!("../tests/fixtures/forms.ts")),
+            Indent::Spaces(2)
+        );
+
+        let python = "class Repo:\n    \"\"\"Stores items.\"\"\"\n\n    def save(self, item):\n        if item:\n            self.items.append(item)\n";
+        assert_eq!(detect_indent(python), Indent::Spaces(4));
+        // Doc comment continuations step by one space
+        let rust = "/**\n * Adds.\n */\nfn add() {\n    if a {\n        b();\n    }\n}\n";
+        assert_eq!(detect_indent(rust), Indent::Spaces(4));
+
+        let go = "func add(a, b int) int {\n\tif a > b {\n\t\treturn a\n\t}\n\treturn b\n}\n";
+        assert_eq!(detect_indent(go), Indent::Tabs);
+        assert_eq!(Indent::Tabs.unit(), "\t");
+
+        assert_eq!(detect_indent("fn main() {}\n"), Indent::Spaces(4));
+    }
+
+    #[test]
+    fn test_body_nesting_follows_file_indent() {
+        // The backend nests with 4 spaces; the file uses 2
+        let code = include_str!("../tests/fixtures/blocks.rb");
+        let body = "if items.empty?\n    \"none\"\nelse\n    items.join(\", \")\nend";
+        let (new_text, _, _, _) = replace_function_body_in_document(code, 35, body, None).unwrap();
+        assert!(new_text.contains(
+            "    def label\n      if items.empty?\n        \"none\"\n      else\n        items.join(\", \")\n      end\n    end\n"
+        ));
+
+        let go = "func add(a, b int) int {\n\treturn 0\n}\n";
+        let body = "if a > b {\n    return a\n}\nreturn b";
+        let (new_text, _, _, _) = replace_function_body_in_document(go, 0, body, None).unwrap();
+        assert_eq!(
+            new_text,
+            "func add(a, b int) int {\n\tif a > b {\n\t\treturn a\n\t}\n\treturn b\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_full_function_moves_to_original_indent() {
+        // A flush-left implementation of a method nested in a module's impl block
+        let code = "mod shapes {\n    impl Circle {\n        fn area(&self) -> f64 {\n            todo!()\n        }\n    }\n}\n";
+        let implementation = "/// Area of the circle.\nfn area(&self) -> f64 {\n    let r = self.r;\n\n    PI * r * r\n}";
+        let (new_text, _, _, _) = replace_function_in_document(
+            code,
+            2,
+            implementation,
+            Some(&FunctionSignature::parse("fn area(&self) -> f64 {", "rust")),
+        )
+        .unwrap();
+        assert_eq!(
+            new_text,
+            "mod shapes {\n    impl Circle {\n        /// Area of the circle.\n        fn area(&self) -> f64 {\n            let r = self.r;\n\n            PI * r * r\n        }\n    }\n}\n"
+        );
+
+        // Over-indented output comes back out, keeping its own 2-space nesting
+        let implementation = "            fn area(&self) -> f64 {\n              PI\n            }";
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 2, implementation, None).unwrap();
+        assert!(new_text.contains("\n        fn area(&self) -> f64 {\n          PI\n        }\n"));
+
+        // Python class methods, docstring included
+        let code = "class Circle:\n    def area(self):\n        pass\n";
+        let implementation = "@property\ndef area(self):\n    \"\"\"Area.\n\n    Of the circle.\n    \"\"\"\n    return PI * self.r ** 2";
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 2, implementation, None).unwrap();
+        assert_eq!(
+            new_text,
+            "class Circle:\n    @property\n    def area(self):\n        \"\"\"Area.\n\n        Of the circle.\n        \"\"\"\n        return PI * self.r ** 2\n"
+        );
+    }
+
+    #[test]
+    fn test_find_decorated_start() {
+        let code =
+            "use x;\n\n/// Adds.\n#[inline]\n#[must_use]\nfn add() {}\n\n#[test]\n\nfn bare() {}\n";
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_decorated_start(&lines, 5), 2);
+        // A blank line ends the block
+        assert_eq!(find_decorated_start(&lines, 9), 9);
+        assert_eq!(find_decorated_start(&lines, 0), 0);
+
+        let code = "class A:\n    # Cached.\n    @property\n    def area(self):\n        pass\n";
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_decorated_start(&lines, 3), 1);
+        let code = "x = 1\n-- Adds.\nlocal function add(a, b)\n  return a + b\nend\n";
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_decorated_start(&lines, 2), 1);
+    }
+
+    #[test]
+    fn test_replacement_merges_decorations() {
+        // (document, line of the function, implementation, expected document)
+        let cases = [
+            // Kept when the implementation has none
+            (
+                "#[test]\n/// Docs.\nfn foo() {\n    todo!()\n}\n",
+                2,
+                "fn foo() {\n    assert!(true);\n}",
+                "#[test]\n/// Docs.\nfn foo() {\n    assert!(true);\n}\n",
+            ),
+            // Not duplicated when it has the same ones, and its doc comment wins
+            (
+                "/// Old docs.\n#[test]\nfn foo() {\n    todo!()\n}\n",
+                2,
+                "/// New docs.\n#[test]\nfn foo() {\n    assert!(true);\n}",
+                "/// New docs.\n#[test]\nfn foo() {\n    assert!(true);\n}\n",
+            ),
+            // Attributes it has no copy of stay above it
+            (
+                "impl A {\n    #[tracing::instrument]\n    /// Docs.\n    fn foo(&self) {\n        todo!()\n    }\n}\n",
+                3,
+                "#[must_use]\nfn foo(&self) {\n    1\n}",
+                "impl A {\n    #[tracing::instrument]\n    /// Docs.\n    #[must_use]\n    fn foo(&self) {\n        1\n    }\n}\n",
+            ),
+            // None in the original
+            (
+                "fn foo() {\n    todo!()\n}\n",
+                0,
+                "/// Docs.\n#[inline]\nfn foo() {\n    1\n}",
+                "/// Docs.\n#[inline]\nfn foo() {\n    1\n}\n",
+            ),
+            (
+                "class A:\n    # Area.\n    @staticmethod\n    def area(r):\n        pass\n",
+                3,
+                "@staticmethod\ndef area(r):\n    return r * r",
+                "class A:\n    # Area.\n    @staticmethod\n    def area(r):\n        return r * r\n",
+            ),
+            (
+                "class A:\n    @property\n    def area(self):\n        pass\n",
+                2,
+                "def area(self):\n    return 1",
+                "class A:\n    @property\n    def area(self):\n        return 1\n",
+            ),
+            (
+                "-- Adds.\nlocal function add(a, b)\n  error(\"not implemented\")\nend\n",
+                1,
+                "-- Sum of a and b.\nlocal function add(a, b)\n  return a + b\nend",
+                "-- Sum of a and b.\nlocal function add(a, b)\n  return a + b\nend\n",
+            ),
+            (
+                "class Api {\n  @Get()\n  list() {\n    return null;\n  }\n}\n",
+                2,
+                "@Get()\n@Auth()\nlist() {\n  return [];\n}",
+                "class Api {\n  @Get()\n  @Auth()\n  list() {\n    return [];\n  }\n}\n",
+            ),
+        ];
+        for (code, line, implementation, expected) in cases {
+            let (new_text, start_line, end_line, lines_delta) =
+                replace_function_in_document(code, line, implementation, None).unwrap();
+            assert_eq!(new_text, expected, "{:?}", code);
+            assert_eq!(
+                code.lines().count() as i32 + lines_delta,
+                new_text.lines().count() as i32
+            );
+            assert!(start_line as usize <= line && line <= end_line as usize);
+            assert_eq!(
+                splice_function(code, line, implementation).unwrap().0,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_full_function_follows_tab_indented_file() {
+        let code = "type Circle struct{}\n\nfunc (c Circle) Area() float64 {\n\treturn 0\n}\n\nfunc init() {\n\tif true {\n\t\tx()\n\t}\n}\n";
+        let implementation = "func (c Circle) Area() float64 {\n    if c.r > 0 {\n        return c.r * c.r\n    }\n    return 0\n}";
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 2, implementation, None).unwrap();
+        assert!(new_text.contains(
+            "func (c Circle) Area() float64 {\n\tif c.r > 0 {\n\t\treturn c.r * c.r\n\t}\n\treturn 0\n}\n"
+        ));
+
+        // A tab-indented method in a class, from a flush-left implementation
+        let code = "class Circle {\n\tarea() {\n\t\treturn 0;\n\t}\n}\n";
+        let implementation = "area() {\n\tif (this.r) {\n\t\treturn this.r;\n\t}\n}";
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 1, implementation, None).unwrap();
+        assert_eq!(
+            new_text,
+            "class Circle {\n\tarea() {\n\t\tif (this.r) {\n\t\t\treturn this.r;\n\t\t}\n\t}\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_full_function_keeps_multiline_strings() {
+        let code = "impl Help {\n    fn text(&self) -> &str {\n        todo!()\n    }\n}\n";
+        let implementation = "fn text(&self) -> &str {\n    r#\"usage:\n  help [topic]\nend\"#\n}";
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 1, implementation, None).unwrap();
+        assert_eq!(
+            new_text,
+            "impl Help {\n    fn text(&self) -> &str {\n        r#\"usage:\n  help [topic]\nend\"#\n    }\n}\n"
+        );
+
+        let code = "class Help {\n  text() {\n    return null;\n  }\n}\n";
+        let implementation = "text() {\n  return `usage:\n  help`;\n}";
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 1, implementation, None).unwrap();
+        assert_eq!(
+            new_text,
+            "class Help {\n  text() {\n    return `usage:\n  help`;\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_function_in_crlf_document() {
+        let code = "fn foo() {\r\n    todo!()\r\n}\r\n\r\nfn bar() {}\r\n";
 
-    // 4. Create Edit
-    let edit = WorkspaceEditBuilder::create_full_replace(uri, current_text, &merged_text);
+        // The implementation uses LF even though the document is CRLF
+        let (new_text, _, _, _) =
+            replace_function_in_document(code, 0, "fn foo() {\n    42\n}", None).unwrap();
 
-    // Calculate lines added
-    let new_lines_count = implementation.lines().count() as i32;
-    let old_lines_count = find_function_end(&base_text.lines().collect::<Vec<_>>(), line)
-        .map(|end| (end - line + 1) as i32)
-        .unwrap_or(0);
-    let lines_added = new_lines_count - old_lines_count;
+        assert_eq!(new_text, "fn foo() {\r\n    42\r\n}\r\n\r\nfn bar() {}\r\n");
+        assert_eq!(
+            new_text.matches('\n').count(),
+            new_text.matches("\r\n").count()
+        );
+    }
 
-    Ok((edit, lines_added))
-}
+    #[test]
+    fn test_replace_function_body_in_crlf_document() {
+        let code = "fn foo() -> i32 {\r\n    todo!()\r\n}\r\n";
 
-#[cfg(test)]
-mod diff_tests {
-    use super::*;
+        let (new_text, _, _, _) =
+            replace_function_body_in_document(code, 0, "let x = 41;\nx + 1", None).unwrap();
 
-    #[test]
-    fn test_extract_function_signature_rust() {
-        let code = "fn foo(x: i32) -> i32 {\n    todo!()\n}";
-        let sig = extract_function_signature(code, 0);
-        assert_eq!(sig, Some("fn foo(x: i32) -> i32 {".to_string()));
+        assert_eq!(
+            new_text,
+            "fn foo() -> i32 {\r\n    let x = 41;\r\n    x + 1\r\n}\r\n"
+        );
     }
 
     #[test]
-    fn test_extract_function_signature_python() {
-        let code = "def calculate(a, b):\n    return a + b";
-        let sig = extract_function_signature(code, 0);
-        assert_eq!(sig, Some("def calculate(a, b):".to_string()));
+    fn test_replace_function_body_in_document() {
+        let code = "fn foo() -> i32 {\n    todo!()\n}\n\nfn bar() {}\n";
+
+        let (new_text, start_line, end_line, lines_delta) =
+            replace_function_body_in_document(code, 1, "let x = 41;\nx + 1", None).unwrap();
+
+        assert_eq!(start_line, 0);
+        assert_eq!(end_line, 2);
+        assert_eq!(lines_delta, 1);
+        assert_eq!(
+            new_text,
+            "fn foo() -> i32 {\n    let x = 41;\n    x + 1\n}\n\nfn bar() {}\n"
+        );
     }
 
     #[test]
-    fn test_extract_function_signature_cpp() {
-        let code = "int add(int a, int b) {\n    return a + b;\n}";
-        let sig = extract_function_signature(code, 0);
-        assert_eq!(sig, Some("int add(int a, int b) {".to_string()));
+    fn test_replace_function_body_in_document_keeps_indented_body() {
+        let code = "impl Foo {\n    fn foo(&self) {\n        todo!()\n    }\n}\n";
+
+        let (new_text, _, _, _) = replace_function_body_in_document(
+            code,
+            1,
+            "        self.bar();",
+            Some(&FunctionSignature::parse("fn foo(&self) {", "rust")),
+        )
+        .unwrap();
+
+        assert_eq!(
+            new_text,
+            "impl Foo {\n    fn foo(&self) {\n        self.bar();\n    }\n}\n"
+        );
     }
 
     #[test]
-    fn test_find_function_start_rust() {
-        let code = r#"
-#[test]
-fn foo() {
-    let x = 1;
-    println!("{}", x);
-}
-
-pub async fn bar() {
-    // comment
-}
-"#;
-        let lines: Vec<&str> = code.lines().collect();
-        // Line 3 is 'fn foo() {' (depending on split, let's trace)
-        // 0: ""
-        // 1: "#[test]"
-        // 2: "fn foo() {" --> Start
-        // 3: "    let x = 1;"
+    fn test_replace_function_body_in_document_single_line() {
+        let code = "fn foo() { todo!() }\n";
 
-        assert_eq!(find_function_start(&lines, 2), Some(2));
-        assert_eq!(find_function_start(&lines, 3), Some(2)); // Inside foo
+        let (new_text, _, _, lines_delta) =
+            replace_function_body_in_document(code, 0, "42", None).unwrap();
 
-        // bar is at line 7
-        // 7: "pub async fn bar() {"
-        assert_eq!(find_function_start(&lines, 7), Some(7));
-        assert_eq!(find_function_start(&lines, 8), Some(7)); // Inside bar
+        assert_eq!(new_text, "fn foo() {\n    42\n}\n");
+        assert_eq!(lines_delta, 2);
     }
 
     #[test]
-    fn test_find_function_start_python() {
-        let code = r#"
-def foo():
-    x = 1
-    print(x)
+    fn test_replace_function_body_in_document_python() {
+        let code = "def foo(a, b):\n    pass\n\ndef bar():\n    pass\n";
+
+        let (new_text, _, _, _) = replace_function_body_in_document(
+            code,
+            0,
+            "return a + b",
+            Some(&FunctionSignature::parse("def foo(a, b):", "python")),
+        )
+        .unwrap();
 
-async def bar():
-    pass
-"#;
-        let lines: Vec<&str> = code.lines().collect();
-        assert_eq!(find_function_start(&lines, 1), Some(1)); // def foo
-        assert_eq!(find_function_start(&lines, 2), Some(1)); // Inside foo
-        assert_eq!(find_function_start(&lines, 5), Some(5)); // async def bar
+        assert_eq!(
+            new_text,
+            "def foo(a, b):\n    return a + b\n\ndef bar():\n    pass\n"
+        );
     }
 
     #[test]
-    fn test_find_function_start_cpp() {
-        let code = r#"
-int add(int a, int b) {
-    return a + b;
-}
+    fn test_replacements_keep_the_rest_of_the_document() {
+        let function = "fn f() {\n    todo!()\n}";
+        let new_function = "fn f() {\n    42\n}";
+        // (before the function, after it): trailing newline or not, function at the end of
+        // the document or not, empty last lines
+        let fixtures = [
+            ("", "\n"),
+            ("", ""),
+            ("use a;\n\n", "\n\nfn g() {}\n"),
+            ("use a;\n\n", "\n\nfn g() {}"),
+            ("use a;\n", "\n\n"),
+            ("", "\n\n\n"),
+        ];
+
+        for (before, after) in fixtures {
+            let text = format!("{}{}{}", before, function, after);
+            let line = before.matches('\n').count();
+            // A function ending the document gets a final newline
+            let after = if after.is_empty() && ENSURE_FINAL_NEWLINE {
+                "\n"
+            } else {
+                after
+            };
+            let expected = format!("{}{}{}", before, new_function, after);
+
+            assert_eq!(
+                splice_function(&text, line, new_function).unwrap().0,
+                expected,
+                "{:?}",
+                text
+            );
+            let (new_text, ..) =
+                replace_function_in_document(&text, line, new_function, None).unwrap();
+            assert_eq!(new_text, expected, "{:?}", text);
+            let (new_text, ..) =
+                replace_function_body_in_document(&text, line, "42", None).unwrap();
+            assert_eq!(new_text, expected, "{:?}", text);
+        }
+    }
 
-void process() {
-    // code
-}
-"#;
-        let lines: Vec<&str> = code.lines().collect();
-        assert_eq!(find_function_start(&lines, 1), Some(1)); // int add
-        assert_eq!(find_function_start(&lines, 2), Some(1)); // Inside add
-        assert_eq!(find_function_start(&lines, 5), Some(5)); // void process
+    #[test]
+    fn test_append_function_to_document() {
+        let implementation = "    int add(int a, int b) {\n        return a + b;   \n    }\n";
+        let appended = "int add(int a, int b) {\n    return a + b;\n}\n";
+
+        let (new_text, start_line) =
+            append_function_to_document("#include \"add.h\"\n", implementation);
+        assert_eq!(new_text, format!("#include \"add.h\"\n\n{}", appended));
+        assert_eq!(start_line, 2);
+
+        // Trailing blank lines are not piled up, and an empty document gets no leading one
+        let (new_text, start_line) =
+            append_function_to_document("#include \"add.h\"\n\n\n", implementation);
+        assert_eq!(new_text, format!("#include \"add.h\"\n\n{}", appended));
+        assert_eq!(start_line, 2);
+        let (new_text, start_line) = append_function_to_document("", implementation);
+        assert_eq!(new_text, appended);
+        assert_eq!(start_line, 0);
+
+        // The document's line endings are kept
+        let (new_text, _) = append_function_to_document("int x;\r\n", "int f() {}");
+        assert_eq!(new_text, "int x;\r\n\r\nint f() {}\r\n");
+    }
+
+    /// How documents were rebuilt before `splice_lines`: every line collected, the region
+    /// swapped out and everything joined again.
+    fn splice_lines_by_joining(
+        text: &str,
+        start_line: usize,
+        end_line: usize,
+        replacement: &[String],
+        newline: &str,
+    ) -> String {
+        let lines: Vec<&str> = text.lines().collect();
+        let mut new_lines = lines[..start_line].to_vec();
+        new_lines.extend(replacement.iter().map(String::as_str));
+        if end_line + 1 < lines.len() {
+            new_lines.extend_from_slice(&lines[end_line + 1..]);
+        }
+        join_lines(&new_lines, newline, final_newline(text, end_line))
     }
 
     #[test]
-    fn test_find_function_start_java() {
-        let code = r#"
-public int calculate(int x) {
-    return x * 2;
-}
+    fn test_splice_lines_matches_joining() {
+        let documents = [
+            "fn f() {\n    todo!()\n}\n",
+            "fn f() {\n    todo!()\n}",
+            "use a;\n\nfn f() {\n    todo!()\n}\n\nfn g() {}\n",
+            "use a;\n\nfn f() {\n    todo!()\n}\n\nfn g() {}",
+            "\n\nfn f() {\n}\n\n\n",
+            "a\n",
+            "a",
+        ];
+        let replacements: [&[&str]; 4] = [
+            &["fn f() {", "    42", "}"],
+            &["fn f() {}"],
+            &["", "fn f() {", "", "}", ""],
+            &["x"],
+        ];
+        for text in documents {
+            let line_count = text.lines().count();
+            for start_line in 0..line_count {
+                for end_line in start_line..line_count {
+                    for replacement in replacements {
+                        let replacement: Vec<String> =
+                            replacement.iter().map(|line| line.to_string()).collect();
+                        assert_eq!(
+                            splice_lines(text, start_line, end_line, &replacement, "\n"),
+                            splice_lines_by_joining(text, start_line, end_line, &replacement, "\n"),
+                            "{:?} lines {}-{} with {:?}",
+                            text,
+                            start_line,
+                            end_line,
+                            replacement
+                        );
+                    }
+                }
+            }
+        }
+    }
 
-private void helper() {
-    // code
-}
-"#;
-        let lines: Vec<&str> = code.lines().collect();
-        assert_eq!(find_function_start(&lines, 1), Some(1)); // public int
-        assert_eq!(find_function_start(&lines, 5), Some(5)); // private void
+    #[test]
+    fn test_splice_lines_keeps_separators_outside_the_region() {
+        let replacement = vec!["y".to_string()];
+        // Mixed line endings around the region survive as they are
+        assert_eq!(
+            splice_lines("a\r\nb\nc\r\nd\n", 1, 2, &replacement, "\n"),
+            "a\r\ny\nd\n"
+        );
+        assert_eq!(
+            splice_lines("a\r\nb\r\nc", 1, 1, &replacement, "\r\n"),
+            "a\r\ny\r\nc"
+        );
+        assert_eq!(line_offset("a\r\nb\nc", 2), 5);
+        assert_eq!(line_offset("a\r\nb\nc", 3), 6);
     }
 
+    /// Run with `cargo test --release -- --ignored bench_splice_lines --nocapture`.
     #[test]
-    fn test_find_function_end() {
-        let code = r#"fn foo() {
-    let x = {
-        1
-    };
-    println!("{}", x);
-}
+    #[ignore]
+    fn bench_splice_lines() {
+        let text: String = (0..40_000)
+            .map(|i| format!("fn f{}() {{\n    let x = {};\n}}\n\n", i, i))
+            .collect();
+        let replacement: Vec<String> = ["fn f20000() {", "    42", "}"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        let (start_line, end_line) = (80_000, 80_002);
+        let runs = 50;
+
+        let started = std::time::Instant::now();
+        for _ in 0..runs {
+            std::hint::black_box(splice_lines_by_joining(
+                &text,
+                start_line,
+                end_line,
+                &replacement,
+                "\n",
+            ));
+        }
+        let joining = started.elapsed() / runs;
+        let started = std::time::Instant::now();
+        for _ in 0..runs {
+            std::hint::black_box(splice_lines(
+                &text,
+                start_line,
+                end_line,
+                &replacement,
+                "\n",
+            ));
+        }
+        let splicing = started.elapsed() / runs;
 
-fn bar() {}"#;
-        let lines: Vec<&str> = code.lines().collect();
-        assert_eq!(find_function_end(&lines, 0), Some(5));
-        assert_eq!(find_function_end(&lines, 7), Some(7));
+        println!(
+            "{} KB document: joining lines {:?}, splicing bytes {:?}",
+            text.len() / 1024,
+            joining,
+            splicing
+        );
+        assert!(splicing < joining);
     }
 
     #[test]
-    fn test_replace_function() {
+    fn test_splice_function() {
         let code = "fn foo() {\n    todo!()\n}\n\nfn bar() {}";
         let new_impl = "fn foo() {\n    println!(\"implemented\");\n}";
 
-        let result = replace_function(code, 0, new_impl).unwrap();
-        let expected = "fn foo() {\n    println!(\"implemented\");\n}\n\nfn bar() {}\n";
+        let result = splice_function(code, 0, new_impl).unwrap().0;
+        // The document had no trailing newline, and still has none
+        let expected = "fn foo() {\n    println!(\"implemented\");\n}\n\nfn bar() {}";
 
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_region_unchanged() {
+        let old = "use a;\n\nfn f() {\n    42\n}\n";
+        assert!(region_unchanged(old, old, 2, 4, 0));
+        // Line terminators and trailing whitespace do not count
+        assert!(region_unchanged(
+            old,
+            "use a;\r\n\r\nfn f() {  \r\n    42\r\n}\r\n",
+            2,
+            4,
+            0
+        ));
+        assert!(!region_unchanged(
+            old,
+            "use a;\n\nfn f() {\n    43\n}\n",
+            2,
+            4,
+            0
+        ));
+        assert!(!region_unchanged(
+            old,
+            "use a;\n\nfn f() {\n        42\n}\n",
+            2,
+            4,
+            0
+        ));
+        assert!(!region_unchanged(
+            old,
+            "use a;\n\nfn f() {\n    42\n    43\n}\n",
+            2,
+            4,
+            1
+        ));
+    }
+
+    #[test]
+    fn test_implementation_whitespace_is_tidied() {
+        // Trailing spaces, and blank lines after the function, in a document that ends
+        // without a newline
+        let code = "use a;\n\nfn f() {\n    todo!()\n}";
+        let new_impl = "fn f() {  \n    let x = 1;\t\n\n    x\n}\n\n\n";
+
+        let (new_text, _, _, lines_delta) =
+            replace_function_in_document(code, 2, new_impl, None).unwrap();
+        assert_eq!(new_text, "use a;\n\nfn f() {\n    let x = 1;\n\n    x\n}\n");
+        assert_eq!(lines_delta, 2);
+
+        let (new_text, ..) =
+            replace_function_body_in_document(code, 2, "let x = 1;  \nx \n\n", None).unwrap();
+        assert_eq!(new_text, "use a;\n\nfn f() {\n    let x = 1;\n    x\n}\n");
+    }
+
+    #[test]
+    fn test_tidy_implementation_can_be_turned_off() {
+        let lines = vec!["fn f() { ".to_string(), "}".to_string(), " ".to_string()];
+        assert_eq!(tidy_implementation(lines.clone(), false, false), lines);
+        assert_eq!(
+            tidy_implementation(lines.clone(), true, false),
+            ["fn f() {", "}", ""]
+        );
+        assert_eq!(tidy_implementation(lines, false, true), ["fn f() { ", "}"]);
+    }
+
     #[test]
     fn test_replace_function_in_document() {
         let code = "fn foo() {\n    todo!()\n}\n\nfn bar() {\n    todo!()\n}";
@@ -701,6 +4770,264 @@ fn bar() {}"#;
         assert!(!signatures_match("int add() {", "int multiply() {"));
     }
 
+    #[test]
+    fn test_cpp_function_names() {
+        let cases = [
+            ("void Widget::resize(int w, int h) {", "Widget::resize"),
+            ("std::vector<int> Widget::items() const {", "Widget::items"),
+            ("template <typename T> T max(T a, T b) {", "max"),
+            ("bool operator==(const Widget& other) const {", "operator=="),
+            ("bool operator == (const Widget &o) const {", "operator =="),
+            (
+                "bool Widget::operator<(const Widget &o) const {",
+                "Widget::operator<",
+            ),
+            ("void operator()(int x) {", "operator()"),
+            ("operator bool() const {", "operator bool"),
+            (
+                "friend std::ostream &operator<<(std::ostream &os, const Widget &w) {",
+                "operator<<",
+            ),
+            ("~Widget() {", "~Widget"),
+            ("virtual ~Widget() {", "~Widget"),
+            ("Widget::~Widget() {", "Widget::~Widget"),
+            ("char *strdup(const char *s) {", "strdup"),
+            ("char * strdup(const char *s) {", "strdup"),
+            ("const std::string &name() const {", "name"),
+        ];
+        for (signature, name) in cases {
+            assert_eq!(
+                extract_function_name(signature),
+                Some(name),
+                "{}",
+                signature
+            );
+        }
+
+        // Pairs naming the same function, compared as found and as expected signatures
+        let same = [
+            ("void Widget::resize(int w) {", "void resize(int w) {"),
+            (
+                "void Widget<T>::resize(int w) {",
+                "void Widget::resize(int w) {",
+            ),
+            (
+                "void ns::Widget::resize(int w) {",
+                "void Widget::resize(int w) {",
+            ),
+            (
+                "template <typename T>\nT max(T a, T b) {",
+                "template <typename T> T max(T a, T b) {",
+            ),
+            (
+                "bool Widget::operator==(const Widget& o) const {",
+                "bool operator == (const Widget &o) const {",
+            ),
+            ("Widget::~Widget() {", "~Widget() {"),
+            (
+                "char *strdup(const char *s) {",
+                "char* strdup(const char* s) {",
+            ),
+        ];
+        for (found, expected) in same {
+            assert!(
+                signatures_match(found, expected),
+                "{} / {}",
+                found,
+                expected
+            );
+            assert!(
+                signatures_match(expected, found),
+                "{} / {}",
+                expected,
+                found
+            );
+        }
+        let different = [
+            (
+                "void Widget::resize(int w) {",
+                "void Panel::resize(int w) {",
+            ),
+            (
+                "bool operator==(const Widget& o) const {",
+                "bool operator!=(const Widget& o) const {",
+            ),
+            (
+                "bool operator<(const Widget& o) const {",
+                "bool operator<<(const Widget& o) const {",
+            ),
+            ("Widget::~Widget() {", "Widget::Widget() {"),
+            ("operator bool() const {", "operator int() const {"),
+        ];
+        for (found, expected) in different {
+            assert!(
+                !signatures_match(found, expected),
+                "{} / {}",
+                found,
+                expected
+            );
+            assert!(
+                !signatures_match(expected, found),
+                "{} / {}",
+                expected,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn test_overloads_match_by_parameters() {
+        assert_eq!(
+            signature_match("int add(int a, int b) {", "int add(int x, int y) {"),
+            Some(SignatureMatch::Arity(200))
+        );
+        assert_eq!(
+            signature_match("int add(int a) {", "int add(int a, int b) {"),
+            Some(SignatureMatch::Name)
+        );
+        assert!(
+            signature_match(
+                "double add(double a, double b) {",
+                "int add(int a, int b) {"
+            ) < signature_match("int add(int a, int b) {", "int add(int a, int b) {")
+        );
+        assert_eq!(
+            signature_match(
+                "add(a: number, b: number): number {",
+                "add(a: string, b: string): string {"
+            ),
+            Some(SignatureMatch::Arity(0))
+        );
+        assert_eq!(
+            parameter_types("void f(const std::string& s, Map<K, V> m = {}) {", "f"),
+            Some(vec![vec!["const", "std", "string"], vec!["Map", "K", "V"]])
+        );
+        assert_eq!(parameter_types("fn f() {", "f"), Some(vec![]));
+    }
+
+    #[test]
+    fn test_overload_is_chosen_over_its_sibling() {
+        let code = "int add(int a) {\n    return 0;\n}\n\nint add(int a, int b) {\n    return 0;\n}\n\ndouble add(double a, double b) {\n    return 0;\n}\n";
+        let lines: Vec<&str> = code.lines().collect();
+        let expected = &FunctionSignature::parse("int add(int a, int b) {", "cpp");
+
+        // The job's line drifted onto the one-parameter overload
+        let (start, end) = locate_function(&lines, 1, Some(expected)).unwrap();
+        assert_eq!((start, end), (4, 6));
+        assert_eq!(relocate_function(code, 1, expected), Some(4));
+        // The overload itself stays put
+        assert_eq!(relocate_function(code, 5, expected), Some(5));
+
+        let (new_text, ..) = replace_function_in_document(
+            code,
+            1,
+            "int add(int a, int b) {\n    return a + b;\n}",
+            Some(expected),
+        )
+        .unwrap();
+        assert!(new_text.contains("int add(int a) {\n    return 0;"));
+        assert!(new_text.contains("int add(int a, int b) {\n    return a + b;"));
+        assert!(new_text.contains("double add(double a, double b) {\n    return 0;"));
+    }
+
+    #[test]
+    fn test_nearest_of_equal_matches_is_chosen() {
+        let code =
+            "int add(int a) {\n}\nint add(int b) {\n}\nint sub() {\n}\n\nint add(int c) {\n}\n";
+        let lines: Vec<&str> = code.lines().collect();
+        let add = FunctionSignature::parse("int add() {", "cpp");
+        // All name-only matches: the nearest wins
+        assert_eq!(find_function_by_signature(&lines, &add, 5), Some(7));
+        assert_eq!(find_function_by_signature(&lines, &add, 3), Some(2));
+        // Equally near: the one below wins
+        assert_eq!(find_function_by_signature(&lines, &add, 1), Some(2));
+        assert_eq!(find_function_by_signature(&lines, &add, 0), Some(0));
+    }
+
+    #[test]
+    fn test_trait_declaration_above_impl_is_not_replaced() {
+        let code = "trait Shape {\n    fn area(&self) -> f64;\n}\n\nstruct Square(f64);\n\nimpl Shape for Square {\n    fn area(&self) -> f64 {\n        todo!()\n    }\n}\n";
+        // The client's signature has no `{`, so the declaration matches as well as the impl
+        let expected = &FunctionSignature::parse("fn area(&self) -> f64", "rust");
+
+        let (new_text, start, end, _) = replace_function_in_document(
+            code,
+            1,
+            "fn area(&self) -> f64 {\n    self.0 * self.0\n}",
+            Some(expected),
+        )
+        .unwrap();
+        assert_eq!((start, end), (7, 9));
+        assert!(new_text.contains("trait Shape {\n    fn area(&self) -> f64;\n}"));
+        assert!(new_text.contains("    fn area(&self) -> f64 {\n        self.0 * self.0\n    }"));
+        assert_eq!(relocate_function(code, 1, expected), Some(7));
+    }
+
+    #[test]
+    fn test_nearest_duplicated_helper_is_replaced() {
+        let code = "mod a_tests {\n    fn setup() -> u32 {\n        1\n    }\n}\n\nmod b_tests {\n    // fn setup() -> u32 {\n    //     2\n    // }\n    fn other() {}\n\n    fn setup() -> u32 {\n        todo!()\n    }\n}\n";
+        let expected = &FunctionSignature::parse("fn setup() -> u32", "rust");
+
+        // The job's line drifted into the commented-out copy
+        let (new_text, start, end, _) =
+            replace_function_in_document(code, 9, "fn setup() -> u32 {\n    3\n}", Some(expected))
+                .unwrap();
+        assert_eq!((start, end), (12, 14));
+        assert!(new_text.contains("    fn setup() -> u32 {\n        1\n    }"));
+        assert!(new_text.contains("    // fn setup() -> u32 {\n    //     2\n    // }"));
+        assert!(new_text.contains("    fn setup() -> u32 {\n        3\n    }"));
+
+        // Nearest to the job's line, in either module, skipping the commented-out copy
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(find_function_by_signature(&lines, expected, 8), Some(12));
+        assert_eq!(find_function_by_signature(&lines, expected, 5), Some(1));
+    }
+
+    #[test]
+    fn test_relocate_function() {
+        let code = "fn foo() {\n    todo!()\n}\n\nfn bar() {\n    todo!()\n}\n\nfn foo() {\n}\n";
+        let rust = |raw| FunctionSignature::parse(raw, "rust");
+
+        // A line still inside a matching function stays put
+        assert_eq!(relocate_function(code, 1, &rust("fn foo() {")), Some(1));
+        assert_eq!(relocate_function(code, 4, &rust("fn bar() {")), Some(4));
+        // Otherwise the first match below, then anywhere
+        assert_eq!(relocate_function(code, 5, &rust("fn foo() {")), Some(8));
+        assert_eq!(relocate_function(code, 9, &rust("fn bar() {")), Some(4));
+        // Lines past the end (after a deletion) search from the last line
+        assert_eq!(relocate_function(code, 100, &rust("fn foo() {")), Some(8));
+        assert_eq!(relocate_function(code, 1, &rust("fn baz() {")), None);
+        assert_eq!(relocate_function("", 0, &rust("fn foo() {")), None);
+    }
+
+    #[test]
+    fn test_find_call_sites() {
+        let code = "fn add(a: i32, b: i32) -> i32 {\n    todo!()\n}\n\nfn main() {\n    let x = add(1, 2);\n    // add(3, 4) in a comment\n    let y = self.add (x, 3) + padd(1) + add_all(2);\n    let z = add(x, y);\n}\n";
+        assert_eq!(
+            find_call_sites(code, "fn add(a: i32, b: i32) -> i32 {", 5),
+            vec![
+                (5, "let x = add(1, 2);"),
+                (7, "let y = self.add (x, 3) + padd(1) + add_all(2);"),
+                (8, "let z = add(x, y);"),
+            ]
+        );
+        assert_eq!(
+            find_call_sites(code, "fn add(a: i32, b: i32) -> i32 {", 1),
+            vec![(5, "let x = add(1, 2);")]
+        );
+        assert!(find_call_sites(code, "fn add() {", 0).is_empty());
+        assert!(find_call_sites(code, "fn sub() {", 5).is_empty());
+        assert!(find_call_sites(code, "line_3", 5).is_empty());
+
+        // Qualified C++ definitions are called by their last segment
+        let code =
+            "void Widget::resize(int w) {\n}\n\nvoid grow(Widget &w) {\n    w.resize(2);\n}\n";
+        assert_eq!(
+            find_call_sites(code, "void Widget::resize(int w) {", 5),
+            vec![(4, "w.resize(2);")]
+        );
+    }
+
     #[test]
     fn test_extract_function_name() {
         // Rust
@@ -708,15 +5035,46 @@ fn bar() {}"#;
         assert_eq!(extract_function_name("pub fn bar() {"), Some("bar"));
         assert_eq!(extract_function_name("async fn baz() {"), Some("baz"));
         assert_eq!(extract_function_name("pub async fn qux() {"), Some("qux"));
-        assert_eq!(extract_function_name("pub(crate) fn internal() {"), Some("internal"));
+        assert_eq!(
+            extract_function_name("pub(crate) fn internal() {"),
+            Some("internal")
+        );
 
         // Python
-        assert_eq!(extract_function_name("def calculate(a, b):"), Some("calculate"));
+        assert_eq!(
+            extract_function_name("def calculate(a, b):"),
+            Some("calculate")
+        );
         assert_eq!(extract_function_name("async def fetch():"), Some("fetch"));
 
         // C++/Java
-        assert_eq!(extract_function_name("int add(int a, int b) {"), Some("add"));
+        assert_eq!(
+            extract_function_name("int add(int a, int b) {"),
+            Some("add")
+        );
         assert_eq!(extract_function_name("void process() {"), Some("process"));
+
+        // Go
+        assert_eq!(
+            extract_function_name("func Add(a, b int) int {"),
+            Some("Add")
+        );
+        assert_eq!(
+            extract_function_name("func (r *Repo) Save(ctx context.Context) error {"),
+            Some("Save")
+        );
+        assert_eq!(
+            extract_function_name("func (s Set[T]) Has(v T) bool {"),
+            Some("Has")
+        );
+        assert_eq!(
+            extract_function_name("func Map[T, U any](xs []T, f func(T) U) []U {"),
+            Some("Map")
+        );
+        assert_eq!(
+            extract_function_name("func Apply(x int, fn func(int) int) (int, error) {"),
+            Some("Apply")
+        );
     }
 
     #[test]
@@ -756,8 +5114,13 @@ fn bar() {
         // (originally at line 4, foo added 10 lines)
         let adjusted_line = 14;
 
-        let (new_text, start_line, _end_line, _lines_delta) =
-            replace_function_in_document(code_after_foo_impl, adjusted_line, bar_impl, Some("fn bar() {")).unwrap();
+        let (new_text, start_line, _end_line, _lines_delta) = replace_function_in_document(
+            code_after_foo_impl,
+            adjusted_line,
+            bar_impl,
+            Some(&FunctionSignature::parse("fn bar() {", "rust")),
+        )
+        .unwrap();
 
         // Key assertion: bar() should be replaced, not foo()
         // foo()'s implementation should still be intact
@@ -811,16 +5174,29 @@ fn third() {
 
         // Search from line 10, but with signature "fn third()"
         // Should find third() at line 12, not second() at line 8
-        let (new_text, start_line, _, _) =
-            replace_function_in_document(code, 10, third_impl, Some("fn third() {")).unwrap();
+        let (new_text, start_line, _, _) = replace_function_in_document(
+            code,
+            10,
+            third_impl,
+            Some(&FunctionSignature::parse("fn third() {", "rust")),
+        )
+        .unwrap();
 
         assert_eq!(start_line, 12);
         assert!(new_text.contains("fn second() {\n    todo!()\n}"));
         assert!(new_text.contains("fn third() {\n    println!(\"third\");\n}"));
     }
 
+    /// Options that keep no "theirs" file.
+    fn merge_options(strategy: MergeStrategy) -> MergeOptions<'static> {
+        MergeOptions {
+            keep_theirs: false,
+            ..MergeOptions::new("job1", strategy)
+        }
+    }
+
     #[test]
-    fn test_create_3way_merge_edit() {
+    fn test_merge_3way() {
         let uri = Url::parse("file:///test.rs").unwrap();
         let base_text = "fn foo() {\n    todo!()\n}\n\nfn bar() {}\n";
         // User added a comment to bar()
@@ -828,42 +5204,93 @@ fn third() {
         // Agent implements foo()
         let implementation = "fn foo() {\n    implemented();\n}";
 
-        let (edit, lines_added) = create_3way_merge_edit(
+        let MergedEdit {
+            text: new_content,
+            lines_delta,
+            function_lines,
+            ..
+        } = merge_3way(
             &uri,
             base_text,
             current_text,
             implementation,
             0, // line of foo()
+            0,
+            &merge_options(MergeStrategy::Markers),
         )
         .expect("Failed to create edit");
 
-        // lines_added: new(3) - old(3) = 0
-        assert_eq!(lines_added, 0);
+        // new(3) - old(3) = 0
+        assert_eq!(lines_delta, 0);
+        assert_eq!(function_lines, 3);
 
         // Verify Content
-        // We need to inspect the edit. The WorkspaceEdit struct is complex but we can check the new_text.
-        let changes = edit.document_changes.unwrap();
-        if let lsp_types::DocumentChanges::Edits(edits) = changes {
-            let doc_edit = &edits[0];
-            let changes = &doc_edit.edits;
-            let text_edit = match &changes[0] {
-                lsp_types::OneOf::Left(e) => e,
-                _ => panic!("Expected TextEdit"),
-            };
+        // Should contain implementation
+        assert!(new_content.contains("implemented();"));
+        // Should contain user edit
+        assert!(new_content.contains("// comment"));
+    }
+
+    #[test]
+    fn test_merge_3way_counts_lines_that_land() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let merge = |base_text: &str, current_text: &str, implementation: &str, strategy| {
+            let merged = merge_3way(
+                &uri,
+                base_text,
+                current_text,
+                implementation,
+                1,
+                1,
+                &merge_options(strategy),
+            )
+            .unwrap();
+            assert_eq!(
+                merged.lines_delta,
+                merged.text.lines().count() as i32 - current_text.lines().count() as i32
+            );
+            (merged.lines_delta, merged.function_lines)
+        };
+
+        // The implementation repeats the doc comment the function already has
+        let base_text = "/// Adds one.\nfn foo() {\n    todo!()\n}\n";
+        let implementation = "/// Adds one.\nfn foo() {\n    implemented();\n}";
+        assert_eq!(
+            merge(base_text, base_text, implementation, MergeStrategy::Markers),
+            (0, 4)
+        );
 
-            let new_content = &text_edit.new_text;
+        // It brings a doc comment and an attribute the function did not have
+        let base_text = "\nfn foo() {\n    todo!()\n}\n";
+        let implementation = "/// Adds one.\n#[inline]\nfn foo() {\n    implemented();\n}";
+        assert_eq!(
+            merge(base_text, base_text, implementation, MergeStrategy::Markers),
+            (2, 5)
+        );
 
-            // Should contain implementation
-            assert!(new_content.contains("implemented();"));
-            // Should contain user edit
-            assert!(new_content.contains("// comment"));
-        } else {
-            panic!("Expected DocumentChanges::Edits");
-        }
+        // Blank lines after it are trimmed before it lands
+        let implementation = "fn foo() {\n    implemented();\n}\n\n\n";
+        assert_eq!(
+            merge(base_text, base_text, implementation, MergeStrategy::Markers),
+            (0, 3)
+        );
+
+        // `Theirs` drops the lines the user added to the function
+        let current_text = "\nfn foo() {\n    one();\n    two();\n    three();\n}\n";
+        let implementation = "fn foo() {\n    implemented();\n}";
+        assert_eq!(
+            merge(
+                base_text,
+                current_text,
+                implementation,
+                MergeStrategy::Theirs
+            ),
+            (-2, 3)
+        );
     }
 
     #[test]
-    fn test_create_3way_merge_conflict() {
+    fn test_merge_3way_conflict() {
         let uri = Url::parse("file:///test.rs").unwrap();
         let base_text = "fn foo() {\n    todo!()\n}\n";
         // User changed foo() body
@@ -871,23 +5298,135 @@ fn third() {
         // Agent implements foo() differently
         let implementation = "fn foo() {\n    agent_change();\n}";
 
-        let (edit, _) = create_3way_merge_edit(&uri, base_text, current_text, implementation, 0)
-            .expect("Failed to create edit");
+        let new_content = merge_3way(
+            &uri,
+            base_text,
+            current_text,
+            implementation,
+            0,
+            0,
+            &merge_options(MergeStrategy::Markers),
+        )
+        .expect("Failed to create edit")
+        .text;
 
-        let changes = edit.document_changes.unwrap();
-        if let lsp_types::DocumentChanges::Edits(edits) = changes {
-            let doc_edit = &edits[0];
-            let changes = &doc_edit.edits;
-            let text_edit = match &changes[0] {
-                lsp_types::OneOf::Left(e) => e,
-                _ => panic!("Expected TextEdit"),
-            };
-            let new_content = &text_edit.new_text;
+        // Should contain conflict markers
+        assert!(new_content.contains("<<<<<<<"));
+        assert!(new_content.contains("user_change();"));
+        assert!(new_content.contains("agent_change();"));
 
-            // Should contain conflict markers
-            assert!(new_content.contains("<<<<<<<"));
-            assert!(new_content.contains("user_change();"));
-            assert!(new_content.contains("agent_change();"));
-        }
+        // Abort reports both versions instead of editing
+        let error = merge_3way(
+            &uri,
+            base_text,
+            current_text,
+            implementation,
+            0,
+            0,
+            &merge_options(MergeStrategy::Abort),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            MergeError::Conflict(MergeConflict {
+                current: current_text.to_string(),
+                agent: "fn foo() {\n    agent_change();\n}\n".to_string(),
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "Your edits conflict with the agent's change"
+        );
+
+        // Theirs takes the agent's function
+        let merged = merge_3way(
+            &uri,
+            base_text,
+            current_text,
+            implementation,
+            0,
+            0,
+            &merge_options(MergeStrategy::Theirs),
+        )
+        .expect("Failed to create edit");
+        assert_eq!(merged.text, "fn foo() {\n    agent_change();\n}\n");
+    }
+
+    #[test]
+    fn test_merge_3way_keeps_theirs_file() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let base_text = "fn foo() {\n    todo!()\n}\n";
+        let implementation = "fn foo() {\n    implemented();\n}";
+        let dir = tempfile::TempDir::new().unwrap();
+        let theirs_dir = dir.path().join("merges");
+
+        let options = MergeOptions {
+            strategy: MergeStrategy::Markers,
+            job_id: "job42",
+            keep_theirs: true,
+            theirs_dir: theirs_dir.clone(),
+            signature: None,
+        };
+        let path = merge_3way(&uri, base_text, base_text, implementation, 0, 0, &options)
+            .unwrap()
+            .theirs_path;
+        let path = path.expect("Expected the theirs file to be kept");
+        assert_eq!(path, theirs_dir.join("theirs_job42.rs"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "fn foo() {\n    implemented();\n}\n"
+        );
+
+        // Without retention nothing is written
+        std::fs::remove_dir_all(&theirs_dir).unwrap();
+        let options = MergeOptions {
+            keep_theirs: false,
+            ..options
+        };
+        let path = merge_3way(&uri, base_text, base_text, implementation, 0, 0, &options)
+            .unwrap()
+            .theirs_path;
+        assert_eq!(path, None);
+        assert!(!theirs_dir.exists());
+
+        // A directory that cannot be created does not fail the merge
+        let options = MergeOptions {
+            keep_theirs: true,
+            theirs_dir: dir.path().join("file").join("merges"),
+            ..options
+        };
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let MergedEdit {
+            text,
+            theirs_path: path,
+            ..
+        } = merge_3way(&uri, base_text, base_text, implementation, 0, 0, &options).unwrap();
+        assert_eq!(path, None);
+        assert!(text.contains("implemented();"));
+    }
+
+    #[test]
+    fn test_merge_3way_theirs_keeps_other_user_edits() {
+        let uri = Url::parse("file:///test.rs").unwrap();
+        let base_text = "fn foo() {\n    todo!()\n}\n\nfn bar() {}\n";
+        // The user changed foo() and, away from it, bar()
+        let current_text = "fn foo() {\n    user_change();\n}\n\nfn bar() {\n    // comment\n}\n";
+        let implementation = "fn foo() {\n    agent_change();\n}";
+
+        let merged = merge_3way(
+            &uri,
+            base_text,
+            current_text,
+            implementation,
+            0,
+            0,
+            &merge_options(MergeStrategy::Theirs),
+        )
+        .expect("Failed to create edit");
+
+        assert_eq!(
+            merged.text,
+            "fn foo() {\n    agent_change();\n}\n\nfn bar() {\n    // comment\n}\n"
+        );
     }
 }
@@ -0,0 +1,273 @@
+use std::fmt;
+
+use crate::utils::{leading_whitespace, unbalanced_delimiter, OutputShape};
+
+/// How models open an answer that talks about the code instead of only being the code;
+/// matched case-insensitively at the start of a line.
+const PROMPT_ECHOES: &[&str] = &[
+    "here is the",
+    "here's the",
+    "here is an",
+    "here's an",
+    "here is my",
+    "here's my",
+    "sure, here",
+    "sure! here",
+    "certainly! here",
+    "certainly, here",
+    "below is the",
+    "the following is",
+];
+
+/// Lines git and the 3-way merge mark conflicts with.
+const CONFLICT_MARKERS: &[&str] = &["<<<<<<<", "|||||||", "=======", ">>>>>>>"];
+
+/// The check an implementation failed, with what violated it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Nothing is left once code fences are removed.
+    Empty,
+    /// A bracket is never closed, or closes nothing or the wrong bracket (0-indexed line).
+    Unbalanced { line: usize, delimiter: char },
+    /// The function the job implements is not named in the implementation.
+    MissingName(String),
+    /// A conflict marker line (0-indexed).
+    ConflictMarker(usize),
+    /// A line of prose echoing the prompt (0-indexed).
+    PromptEcho(usize),
+    /// A line indented less than the block it belongs to (0-indexed).
+    Indentation(usize),
+}
+
+impl ValidationError {
+    /// Short name of the check, as errors report it.
+    pub fn check(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::Unbalanced { .. } => "balance",
+            Self::MissingName(_) => "name",
+            Self::ConflictMarker(_) => "conflict marker",
+            Self::PromptEcho(_) => "prompt echo",
+            Self::Indentation(_) => "indentation",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Implementation failed the {} check: ", self.check())?;
+        match self {
+            Self::Empty => f.write_str("nothing is left after removing code fences"),
+            Self::Unbalanced { line, delimiter } => {
+                write!(f, "unbalanced `{}` on line {}", delimiter, line + 1)
+            }
+            Self::MissingName(name) => write!(f, "it does not define `{}`", name),
+            Self::ConflictMarker(line) => write!(f, "conflict marker on line {}", line + 1),
+            Self::PromptEcho(line) => write!(f, "prose instead of code on line {}", line + 1),
+            Self::Indentation(line) => {
+                write!(f, "line {} is indented less than its block", line + 1)
+            }
+        }
+    }
+}
+
+/// Check that `implementation`, as it will be spliced in as `output_shape`, looks like code
+/// for the function `name` in `language_id` before any edit is built from it.
+///
+/// `name` is checked only for whole functions, and may be qualified (`Widget::resize`) or
+/// None for a job without a named function.
+pub fn validate_implementation(
+    implementation: &str,
+    name: Option<&str>,
+    language_id: &str,
+    output_shape: OutputShape,
+) -> Result<(), ValidationError> {
+    if implementation.trim().is_empty() {
+        return Err(ValidationError::Empty);
+    }
+
+    for (i, line) in implementation.lines().enumerate() {
+        let line = line.trim();
+        if CONFLICT_MARKERS
+            .iter()
+            .any(|marker| line == *marker || line.starts_with(&format!("{} ", marker)))
+        {
+            return Err(ValidationError::ConflictMarker(i));
+        }
+        let lowercase = line.to_lowercase();
+        if PROMPT_ECHOES.iter().any(|echo| lowercase.starts_with(echo)) {
+            return Err(ValidationError::PromptEcho(i));
+        }
+    }
+
+    if let Some((line, delimiter)) = unbalanced_delimiter(implementation, language_id) {
+        return Err(ValidationError::Unbalanced { line, delimiter });
+    }
+
+    if output_shape == OutputShape::FullFunction {
+        if let Some(name) = name {
+            // The implementation may leave out the class or receiver the name carries
+            let short_name = name.rsplit([':', '.']).next().unwrap_or(name);
+            if !implementation.contains(short_name) {
+                return Err(ValidationError::MissingName(name.to_string()));
+            }
+        }
+    }
+
+    if language_id == "python" {
+        if let Some(line) = python_dedented_line(implementation, output_shape) {
+            return Err(ValidationError::Indentation(line));
+        }
+    }
+    Ok(())
+}
+
+/// The first line of a Python implementation that falls out of its block: in a whole
+/// function, a body line not indented deeper than the `def`; in a body, a line indented
+/// less than the body's first line.
+fn python_dedented_line(implementation: &str, output_shape: OutputShape) -> Option<usize> {
+    let lines: Vec<(usize, &str)> = implementation
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .collect();
+    let indent = |line: &str| leading_whitespace(line).len();
+
+    let (block_start, min_indent) = match output_shape {
+        OutputShape::FullFunction => {
+            let def = lines.iter().position(|(_, line)| {
+                let line = line.trim_start();
+                line.starts_with("def ") || line.starts_with("async def ")
+            })?;
+            // The body starts after the line closing the (possibly wrapped) signature
+            let signature_end = def
+                + lines[def..]
+                    .iter()
+                    .position(|(_, line)| line.trim_end().ends_with(':'))?;
+            (signature_end + 1, indent(lines[def].1) + 1)
+        }
+        OutputShape::BodyOnly => (0, indent(lines.first()?.1)),
+    };
+    lines[block_start..]
+        .iter()
+        .find(|(_, line)| indent(line) < min_indent)
+        .map(|(i, _)| *i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(implementation: &str, language_id: &str) -> Result<(), ValidationError> {
+        validate_implementation(
+            implementation,
+            Some("add"),
+            language_id,
+            OutputShape::FullFunction,
+        )
+    }
+
+    #[test]
+    fn test_valid_implementations_pass() {
+        assert_eq!(
+            validate("fn add(a: i32, b: i32) -> i32 {\n    a + b\n}", "rust"),
+            Ok(())
+        );
+        // Brackets in strings, chars and comments do not count
+        assert_eq!(
+            validate(
+                "fn add(a: &str) -> String {\n    // (unclosed\n    format!(\"{{}}}\", a, ')')\n}",
+                "rust"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate(
+                "def add(\n    a,\n    b,\n):\n    if a:\n        return a + b\n    return b\n",
+                "python"
+            ),
+            Ok(())
+        );
+        // A qualified name matches the method it names
+        assert_eq!(
+            validate_implementation(
+                "int Math::add(int a, int b) {\n    return a + b;\n}",
+                Some("Math::add"),
+                "cpp",
+                OutputShape::FullFunction,
+            ),
+            Ok(())
+        );
+        // Bodies are not expected to name the function
+        assert_eq!(
+            validate_implementation("    a + b", Some("add"), "rust", OutputShape::BodyOnly),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_each_rejection_reason() {
+        assert_eq!(validate("  \n\n", "rust"), Err(ValidationError::Empty));
+        assert_eq!(
+            validate("fn add(a: i32) -> i32 {\n    a + 1\n", "rust"),
+            Err(ValidationError::Unbalanced {
+                line: 0,
+                delimiter: '{'
+            })
+        );
+        assert_eq!(
+            validate("function add(a) {\n  return [a + 1);\n}", "javascript"),
+            Err(ValidationError::Unbalanced {
+                line: 1,
+                delimiter: ')'
+            })
+        );
+        assert_eq!(
+            validate("fn sum(a: i32) -> i32 {\n    a\n}", "rust"),
+            Err(ValidationError::MissingName("add".to_string()))
+        );
+        assert_eq!(
+            validate(
+                "fn add() {\n<<<<<<< current\n    1\n=======\n    2\n>>>>>>> agent\n}",
+                "rust"
+            ),
+            Err(ValidationError::ConflictMarker(1))
+        );
+        assert_eq!(
+            validate(
+                "Here is the implementation:\nfn add() -> i32 {\n    1\n}",
+                "rust"
+            ),
+            Err(ValidationError::PromptEcho(0))
+        );
+        assert_eq!(
+            validate("def add(a, b):\nreturn a + b\n", "python"),
+            Err(ValidationError::Indentation(1))
+        );
+        assert_eq!(
+            validate_implementation(
+                "    total = a + b\n  return total\n",
+                Some("add"),
+                "python",
+                OutputShape::BodyOnly,
+            ),
+            Err(ValidationError::Indentation(1))
+        );
+    }
+
+    #[test]
+    fn test_errors_name_the_failed_check() {
+        assert_eq!(
+            ValidationError::Unbalanced {
+                line: 2,
+                delimiter: '}'
+            }
+            .to_string(),
+            "Implementation failed the balance check: unbalanced `}` on line 3"
+        );
+        assert_eq!(
+            ValidationError::MissingName("add".to_string()).to_string(),
+            "Implementation failed the name check: it does not define `add`"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use tracing::{error, info, warn};
+
+use crate::sync_utils::{lock_recovering, wait_timeout_recovering};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Fixed-size pool of reusable worker threads.
+///
+/// Threads are started on demand, when more tasks are waiting than threads are idle, and
+/// never more than `max_threads`; tasks submitted while every thread is busy wait in FIFO
+/// order.
+pub struct WorkerPool {
+    sender: Sender<Task>,
+    receiver: Receiver<Task>,
+    max_threads: usize,
+    /// Threads started so far.
+    spawned: AtomicUsize,
+    /// Started threads currently waiting for a task.
+    idle: Arc<AtomicUsize>,
+    /// Tasks submitted and not finished yet, signalled whenever one finishes.
+    outstanding: Arc<(Mutex<usize>, Condvar)>,
+    /// Set by `shutdown`; no new tasks are accepted after it.
+    closed: AtomicBool,
+}
+
+impl WorkerPool {
+    pub fn new(max_threads: usize) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self {
+            sender,
+            receiver,
+            max_threads: max_threads.max(1),
+            spawned: AtomicUsize::new(0),
+            idle: Arc::new(AtomicUsize::new(0)),
+            outstanding: Arc::new((Mutex::new(0), Condvar::new())),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Run `task` on a pool thread. Tasks submitted after `shutdown` are dropped.
+    pub fn execute(&self, task: impl FnOnce() + Send + 'static) {
+        if self.closed.load(Ordering::SeqCst) {
+            warn!("Worker pool is shut down, dropping a task");
+            return;
+        }
+        *lock_recovering(&self.outstanding.0) += 1;
+        // The pool owns a receiver, so the channel never disconnects
+        let _ = self.sender.send(Box::new(task));
+
+        // Every waiting task needs its own thread: tasks may block on each other (a worker
+        // waits in the job queue for a job that is still waiting here for a thread)
+        if self.receiver.len() > self.idle.load(Ordering::SeqCst) {
+            self.spawn_thread();
+        }
+    }
+
+    /// Stop taking tasks and wait up to `timeout` for the submitted ones to finish. Returns
+    /// how many are still queued or running.
+    pub fn shutdown(&self, timeout: Duration) -> usize {
+        self.closed.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        let (count, finished) = &*self.outstanding;
+        let mut outstanding = lock_recovering(count);
+        while *outstanding > 0 {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            outstanding = wait_timeout_recovering(finished, outstanding, left);
+        }
+        *outstanding
+    }
+
+    /// Number of threads the pool has started.
+    #[cfg(test)]
+    pub fn spawned_threads(&self) -> usize {
+        self.spawned.load(Ordering::SeqCst)
+    }
+
+    fn spawn_thread(&self) {
+        let claimed = self
+            .spawned
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |spawned| {
+                (spawned < self.max_threads).then_some(spawned + 1)
+            });
+        let Ok(index) = claimed else {
+            return;
+        };
+
+        info!(
+            "Starting worker thread {} of {}",
+            index + 1,
+            self.max_threads
+        );
+        let receiver = self.receiver.clone();
+        let idle = self.idle.clone();
+        let outstanding = self.outstanding.clone();
+        thread::spawn(move || loop {
+            idle.fetch_add(1, Ordering::SeqCst);
+            let task = receiver.recv();
+            idle.fetch_sub(1, Ordering::SeqCst);
+            let Ok(task) = task else {
+                break;
+            };
+            // Keep the thread for the next task even if this one panics
+            if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+                error!(
+                    "Worker thread {} recovered from a panicking task",
+                    index + 1
+                );
+            }
+            let (count, finished) = &*outstanding;
+            *lock_recovering(count) -= 1;
+            finished.notify_all();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_many_tasks_share_bounded_threads() {
+        let pool = WorkerPool::new(4);
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..40 {
+            let done_tx = done_tx.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            pool.execute(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                done_tx.send(i).unwrap();
+            });
+        }
+
+        let mut finished: Vec<i32> = (0..40)
+            .map(|_| done_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        finished.sort();
+        assert_eq!(finished, (0..40).collect::<Vec<_>>());
+        assert!(pool.spawned_threads() <= 4);
+        assert!(max_running.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[test]
+    fn test_idle_thread_is_reused() {
+        let pool = WorkerPool::new(4);
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+
+        for _ in 0..3 {
+            let done_tx = done_tx.clone();
+            pool.execute(move || done_tx.send(()).unwrap());
+            done_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            // Let the thread go back to waiting before the next submission
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(pool.spawned_threads(), 1);
+    }
+
+    #[test]
+    fn test_panicking_task_keeps_thread_alive() {
+        let pool = WorkerPool::new(1);
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+
+        pool.execute(|| panic!("task failed"));
+        pool.execute(move || done_tx.send(()).unwrap());
+
+        done_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(pool.spawned_threads(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_running_tasks() {
+        let pool = WorkerPool::new(2);
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+
+        for _ in 0..2 {
+            let done_tx = done_tx.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(50));
+                done_tx.send(()).unwrap();
+            });
+        }
+
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+        assert_eq!(done_rx.try_iter().count(), 2);
+
+        // Nothing runs once the pool is shut down
+        let late_tx = done_tx.clone();
+        pool.execute(move || late_tx.send(()).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(done_rx.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_after_timeout() {
+        let pool = WorkerPool::new(1);
+        let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+
+        pool.execute(move || {
+            let _ = release_rx.recv_timeout(Duration::from_secs(5));
+        });
+
+        assert_eq!(pool.shutdown(Duration::from_millis(50)), 1);
+        drop(release_tx);
+    }
+}
@@ -1,6 +1,10 @@
+use std::ffi::OsString;
+use std::fs;
 use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::process::{Child, ChildStdout, Command, Stdio};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
 use std::time::Duration;
 
 use agent_lsp::config::CURRENT_BACKEND;
@@ -17,15 +21,61 @@ fn set_nonblocking(fd: RawFd, nonblocking: bool) {
     }
 }
 
+/// Stand-in for every backend CLI: runs for a while, then fails without output.
+///
+/// With `FAKE_AGENT_OUTPUT` set it instead succeeds at once, writing that text to the output
+/// file named in the prompt; with `FAKE_AGENT_FLOOD` set it prints output forever. With
+/// `FAKE_AGENT_ARGS_LOG` set, its arguments are first written to that file, one per line.
+const FAKE_BACKEND_CLI: &str = r#"#!/bin/sh
+if [ -n "$FAKE_AGENT_ARGS_LOG" ]; then
+    printf '%s\n' "$@" > "$FAKE_AGENT_ARGS_LOG"
+fi
+if [ -n "$FAKE_AGENT_FLOOD" ]; then
+    exec yes "$FAKE_AGENT_FLOOD"
+fi
+if [ -n "$FAKE_AGENT_OUTPUT" ]; then
+    out=$(printf '%s\n' "$@" | sed -n 's/.*to the file: \([^ ]*\) .*/\1/p' | head -n 1)
+    mkdir -p "$(dirname "$out")"
+    printf '%s' "$FAKE_AGENT_OUTPUT" > "$out"
+    exit 0
+fi
+sleep 1
+exit 1
+"#;
+
+/// Put fake backend CLIs in `dir`, so jobs start without a real agent installed.
+fn install_fake_backend_clis(dir: &Path) {
+    for binary in ["amp", "opencode", "claude"] {
+        let path = dir.join(binary);
+        fs::write(&path, FAKE_BACKEND_CLI).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
 struct LspClient {
     child: Child,
     request_id: i32,
     stdout_fd: RawFd,
+    _backend_dir: tempfile::TempDir,
 }
 
 impl LspClient {
     fn spawn() -> Self {
+        Self::spawn_with_env(&[])
+    }
+
+    fn spawn_with_env(envs: &[(&str, &std::ffi::OsStr)]) -> Self {
+        let backend_dir = tempfile::TempDir::new().unwrap();
+        install_fake_backend_clis(backend_dir.path());
+        let mut path = OsString::from(backend_dir.path());
+        if let Some(system_path) = std::env::var_os("PATH") {
+            path.push(":");
+            path.push(system_path);
+        }
+
         let child = Command::new(env!("CARGO_BIN_EXE_agent-lsp"))
+            .env("PATH", path)
+            .envs(envs.iter().copied())
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -38,10 +88,16 @@ impl LspClient {
             child,
             request_id: 0,
             stdout_fd,
+            _backend_dir: backend_dir,
         }
     }
 
     fn send_message(&mut self, content: &Value) {
+        let stdin = self.child.stdin.as_mut().expect("Failed to get stdin");
+        Self::write_message(stdin, content);
+    }
+
+    fn write_message(stdin: &mut ChildStdin, content: &Value) {
         let content_str = serde_json::to_string(content).unwrap();
         let message = format!(
             "Content-Length: {}\r\n\r\n{}",
@@ -49,7 +105,6 @@ impl LspClient {
             content_str
         );
 
-        let stdin = self.child.stdin.as_mut().expect("Failed to get stdin");
         stdin.write_all(message.as_bytes()).unwrap();
         stdin.flush().unwrap();
     }
@@ -187,6 +242,14 @@ impl LspClient {
             set_nonblocking(self.stdout_fd, false);
 
             let msg = Self::read_message_body_from_reader(&mut reader, &header);
+            // Like an editor, apply every edit the server sends and say so
+            if msg["method"] == "workspace/applyEdit" {
+                let stdin = self.child.stdin.as_mut().expect("Failed to get stdin");
+                Self::write_message(
+                    stdin,
+                    &json!({ "jsonrpc": "2.0", "id": msg["id"], "result": { "applied": true } }),
+                );
+            }
             messages.push(msg);
 
             set_nonblocking(self.stdout_fd, true);
@@ -206,13 +269,7 @@ impl LspClient {
     }
 
     fn initialize(&mut self) -> Value {
-        let init_params = json!({
-            "processId": std::process::id(),
-            "rootUri": null,
-            "capabilities": {}
-        });
-        let response = self.send_request("initialize", init_params);
-        self.send_notification("initialized", json!({}));
+        let response = self.initialize_with_options(json!(null));
 
         // After initialization, server sends agent/backendInfo notification
         // We need to consume it to avoid it interfering with subsequent requests
@@ -222,6 +279,35 @@ impl LspClient {
         response
     }
 
+    /// Complete the initialize handshake with the given `initializationOptions`, leaving
+    /// any notifications the server sends afterwards unread.
+    fn initialize_with_options(&mut self, initialization_options: Value) -> Value {
+        // Like most editors, accept versioned `documentChanges` edits
+        let capabilities = json!({
+            "workspace": { "workspaceEdit": { "documentChanges": true } },
+            "textDocument": { "publishDiagnostics": { "versionSupport": true } }
+        });
+        self.initialize_with(capabilities, initialization_options)
+    }
+
+    /// Complete the initialize handshake advertising the given client `capabilities`,
+    /// leaving any notifications the server sends afterwards unread.
+    fn initialize_with_capabilities(&mut self, capabilities: Value) -> Value {
+        self.initialize_with(capabilities, json!(null))
+    }
+
+    fn initialize_with(&mut self, capabilities: Value, initialization_options: Value) -> Value {
+        let init_params = json!({
+            "processId": std::process::id(),
+            "rootUri": null,
+            "capabilities": capabilities,
+            "initializationOptions": initialization_options
+        });
+        let response = self.send_request("initialize", init_params);
+        self.send_notification("initialized", json!({}));
+        response
+    }
+
     fn shutdown(&mut self) {
         self.send_request("shutdown", json!(null));
         self.send_notification("exit", json!(null));
@@ -276,9 +362,10 @@ fn test_initialization() {
         capabilities.get("textDocumentSync").is_some(),
         "Expected textDocumentSync capability"
     );
+    // Completion is not advertised unless a completion mode asks for it
     assert!(
-        capabilities.get("completionProvider").is_some(),
-        "Expected completionProvider capability"
+        capabilities.get("completionProvider").is_none(),
+        "Expected no completionProvider capability"
     );
     assert!(
         capabilities.get("codeActionProvider").is_some(),
@@ -306,13 +393,71 @@ fn test_initialization() {
 }
 
 #[test]
-fn test_did_open_and_code_action() {
+fn test_excluded_file_gets_no_code_actions() {
     let mut client = LspClient::spawn();
-    client.initialize();
+    // Without a workspace root, globs match absolute paths
+    client.initialize_with_options(json!({ "exclude_globs": ["/tmp/agent_excluded/**"] }));
+    client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info notification");
+
+    let excluded_uri = "file:///tmp/agent_excluded/generated.rs";
+    let allowed_uri = "file:///tmp/agent_allowed.rs";
+    for uri in [excluded_uri, allowed_uri] {
+        client.send_notification(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": "rust",
+                    "version": 1,
+                    "text": "fn hello() {\n    todo!()\n}\n"
+                }
+            }),
+        );
+    }
 
-    let test_uri = "file:///tmp/test.rs";
-    let test_content = "fn hello() {\n    // TODO\n}\n";
+    let code_actions = |client: &mut LspClient, uri: &str| {
+        client.send_request(
+            "textDocument/codeAction",
+            json!({
+                "textDocument": { "uri": uri },
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 }
+                },
+                "context": { "diagnostics": [] }
+            }),
+        )
+    };
+    assert_eq!(code_actions(&mut client, excluded_uri)["result"], json!([]));
+    assert_eq!(
+        code_actions(&mut client, allowed_uri)["result"]
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    let response = client.send_request(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [excluded_uri, 0, 0, 1, "rust"]
+        }),
+    );
+    let message = response["error"]["message"].as_str().unwrap();
+    assert!(message.contains("excluded"), "{}", message);
+
+    client.shutdown();
+}
+
+#[test]
+fn test_work_done_progress_cancel_stops_job() {
+    let mut client = LspClient::spawn();
+    client.initialize_with_capabilities(json!({ "window": { "workDoneProgress": true } }));
 
+    let test_uri = "file:///tmp/test_progress_cancel.rs";
     client.send_notification(
         "textDocument/didOpen",
         json!({
@@ -320,64 +465,167 @@ fn test_did_open_and_code_action() {
                 "uri": test_uri,
                 "languageId": "rust",
                 "version": 1,
-                "text": test_content
+                "text": "fn hello() {\n    todo!()\n}\n"
             }
         }),
     );
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
 
-    std::thread::sleep(Duration::from_millis(50));
+    // The fake backend CLI runs for a second, so the job is still in progress here
+    let messages = client.collect_messages(Duration::from_millis(300));
+    let create = messages
+        .iter()
+        .find(|msg| msg["method"] == "window/workDoneProgress/create")
+        .expect("Expected the server to create a progress token");
+    let token = create["params"]["token"].clone();
+    let begin = messages
+        .iter()
+        .find(|msg| msg["method"] == "$/progress" && msg["params"]["token"] == token)
+        .expect("Expected $/progress begin");
+    assert_eq!(begin["params"]["value"]["kind"], "begin");
+    assert_eq!(begin["params"]["value"]["cancellable"], true);
 
-    let response = client.send_request(
-        "textDocument/codeAction",
+    client.send_message(&json!({ "jsonrpc": "2.0", "id": create["id"], "result": null }));
+    client.send_notification("window/workDoneProgress/cancel", json!({ "token": token }));
+
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["job_id"], token);
+    assert_eq!(completed["params"]["status"], "cancelled");
+    assert!(!messages
+        .iter()
+        .any(|msg| msg["method"] == "workspace/applyEdit"));
+    assert!(messages.iter().any(|msg| msg["method"] == "$/progress"
+        && msg["params"]["token"] == token
+        && msg["params"]["value"]["kind"] == "end"));
+
+    client.shutdown();
+}
+
+#[test]
+fn test_shutdown_with_running_job_exits_cleanly() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    let test_uri = "file:///tmp/test_shutdown_running_job.rs";
+    client.send_notification(
+        "textDocument/didOpen",
         json!({
             "textDocument": {
-                "uri": test_uri
-            },
-            "range": {
-                "start": { "line": 0, "character": 0 },
-                "end": { "line": 0, "character": 0 }
-            },
-            "context": {
-                "diagnostics": []
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
             }
         }),
     );
-
-    assert!(
-        response.get("result").is_some(),
-        "Expected result in response"
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
     );
-    let result = &response["result"];
-    assert!(result.is_array(), "Expected array of code actions");
+    // The fake backend CLI runs for a second, so the job is still in progress here
+    client.collect_messages(Duration::from_millis(200));
 
-    let actions = result.as_array().unwrap();
-    assert!(!actions.is_empty(), "Expected at least one code action");
+    let shutdown_id = client.send_request_async("shutdown", json!(null));
+    client.send_notification("exit", json!(null));
+    drop(client.child.stdin.take());
 
-    let action = &actions[0];
-    let expected_title = format!("Implement function with {}", CURRENT_BACKEND.display_name());
-    assert_eq!(action["title"].as_str().unwrap(), expected_title);
-    assert_eq!(
-        action["command"]["command"].as_str().unwrap(),
-        "agent.implFunction"
+    // The server only exits once the cancelled job has told the client
+    let messages = client.collect_messages(Duration::from_secs(3));
+    assert!(messages.iter().any(|msg| msg["id"] == shutdown_id));
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "cancelled");
+
+    let start = std::time::Instant::now();
+    let status = loop {
+        if let Some(status) = client.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "Server did not exit"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success(), "{:?}", status);
+    let stderr = client.drain_stderr();
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(!stderr.contains("disconnected"), "{}", stderr);
+}
+
+#[test]
+fn test_missing_backend_cli_fails_command_fast() {
+    // An empty PATH: no backend CLI can be found
+    let empty_dir = tempfile::TempDir::new().unwrap();
+    let mut client = LspClient::spawn_with_env(&[("PATH", empty_dir.path().as_os_str())]);
+    client.initialize();
+
+    let test_uri = "file:///tmp/test_missing_backend.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    let id = client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
     );
 
-    let args = action["command"]["arguments"].as_array().unwrap();
-    assert_eq!(args[0].as_str().unwrap(), test_uri);
-    assert_eq!(args[1].as_u64().unwrap(), 0);
-    assert_eq!(args[2].as_u64().unwrap(), 0);
-    assert_eq!(args[3].as_i64().unwrap(), 1);
-    assert_eq!(args[4].as_str().unwrap(), "rust");
+    let messages = client.collect_messages(Duration::from_millis(500));
+    let response = messages
+        .iter()
+        .find(|msg| msg["id"] == id)
+        .expect("Expected a response to the command");
+    let message = response["error"]["message"].as_str().unwrap();
+    assert!(message.contains("not found on PATH"), "{}", message);
+
+    let failed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobFailed")
+        .expect("Expected agent/jobFailed notification");
+    assert_eq!(failed["params"]["code"], "BackendNotFound");
+    assert_eq!(failed["params"]["uri"], test_uri);
+    assert!(!messages
+        .iter()
+        .any(|msg| msg["method"] == "agent/jobCompleted"));
 
     client.shutdown();
 }
 
 #[test]
-fn test_did_change() {
-    let mut client = LspClient::spawn();
+fn test_applied_edit_touches_only_changed_lines() {
+    let implementation =
+        "fn hello() {\n    let a = 1;\n    let first = a + 1;\n    let b = 2;\n    \
+                          let c = 3;\n    first + b + c\n}";
+    let mut client =
+        LspClient::spawn_with_env(&[("FAKE_AGENT_OUTPUT", std::ffi::OsStr::new(implementation))]);
     client.initialize();
 
-    let test_uri = "file:///tmp/test_change.rs";
-
+    let test_uri = "file:///tmp/test_minimal_edits.rs";
     client.send_notification(
         "textDocument/didOpen",
         json!({
@@ -385,61 +633,87 @@ fn test_did_change() {
                 "uri": test_uri,
                 "languageId": "rust",
                 "version": 1,
-                "text": "fn foo() {}\n"
+                "text": "fn hello() {\n    let a = 1;\n    todo!();\n    let b = 2;\n    let c = 3;\n    todo!()\n}\n\nfn other() {}\n"
             }
         }),
     );
+    // The body already has code besides the placeholders, so overwriting it needs force
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 3, 1, "rust", null, null, true]
+        }),
+    );
 
-    std::thread::sleep(Duration::from_millis(50));
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let apply_edit = messages
+        .iter()
+        .find(|msg| msg["method"] == "workspace/applyEdit")
+        .expect("Expected workspace/applyEdit request");
+    let edits = apply_edit["params"]["edit"]["documentChanges"][0]["edits"]
+        .as_array()
+        .unwrap();
+    assert_eq!(edits.len(), 2, "{:?}", edits);
+    assert_eq!(edits[0]["range"]["start"]["line"], 2);
+    assert_eq!(edits[0]["range"]["end"]["line"], 3);
+    assert_eq!(edits[0]["newText"], "    let first = a + 1;\n");
+    assert_eq!(edits[1]["range"]["start"]["line"], 5);
+    assert_eq!(edits[1]["range"]["end"]["line"], 6);
+    assert_eq!(edits[1]["newText"], "    first + b + c\n");
 
+    client.shutdown();
+}
+
+#[test]
+fn test_legacy_client_gets_changes_map() {
+    let implementation = "fn hello() {\n    42\n}";
+    let mut client =
+        LspClient::spawn_with_env(&[("FAKE_AGENT_OUTPUT", std::ffi::OsStr::new(implementation))]);
+    // No `workspace.workspaceEdit.documentChanges` support declared
+    client.initialize_with_capabilities(json!({}));
+
+    let test_uri = "file:///tmp/test_legacy_changes.rs";
     client.send_notification(
-        "textDocument/didChange",
+        "textDocument/didOpen",
         json!({
             "textDocument": {
                 "uri": test_uri,
-                "version": 2
-            },
-            "contentChanges": [
-                {
-                    "range": {
-                        "start": { "line": 0, "character": 3 },
-                        "end": { "line": 0, "character": 6 }
-                    },
-                    "text": "bar"
-                }
-            ]
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
         }),
     );
-
-    std::thread::sleep(Duration::from_millis(50));
-
-    let response = client.send_request(
-        "textDocument/codeAction",
+    client.send_request_async(
+        "workspace/executeCommand",
         json!({
-            "textDocument": { "uri": test_uri },
-            "range": {
-                "start": { "line": 0, "character": 0 },
-                "end": { "line": 0, "character": 0 }
-            },
-            "context": { "diagnostics": [] }
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
         }),
     );
 
-    assert!(response.get("result").is_some());
-    let actions = response["result"].as_array().unwrap();
-    assert!(!actions.is_empty());
-
-    assert_eq!(actions[0]["command"]["arguments"][3].as_i64().unwrap(), 2);
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let apply_edit = messages
+        .iter()
+        .find(|msg| msg["method"] == "workspace/applyEdit")
+        .expect("Expected workspace/applyEdit request");
+    let edit = &apply_edit["params"]["edit"];
+    assert!(edit.get("documentChanges").is_none(), "{}", edit);
+    let edits = edit["changes"][test_uri].as_array().unwrap();
+    assert_eq!(edits.len(), 1);
+    assert_eq!(edits[0]["newText"], "    42\n");
 
     client.shutdown();
 }
 
 #[test]
-fn test_completion_returns_null() {
-    let mut client = LspClient::spawn();
+fn test_impl_selection_replaces_only_the_selection() {
+    let mut client =
+        LspClient::spawn_with_env(&[("FAKE_AGENT_OUTPUT", std::ffi::OsStr::new("a * 2"))]);
     client.initialize();
 
-    let test_uri = "file:///tmp/test_completion.rs";
+    let test_uri = "file:///tmp/test_impl_selection.rs";
     client.send_notification(
         "textDocument/didOpen",
         json!({
@@ -447,30 +721,1016 @@ fn test_completion_returns_null() {
                 "uri": test_uri,
                 "languageId": "rust",
                 "version": 1,
-                "text": "fn test() {\n    let x = something.\n}\n"
+                "text": "fn hello() {\n    let a = 1;\n    let b = todo!();\n    a + b\n}\n"
             }
         }),
     );
+    // Select the `todo!()` in the middle of the body
+    let range = json!({
+        "start": { "line": 2, "character": 12 },
+        "end": { "line": 2, "character": 19 }
+    });
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implSelection",
+            "arguments": [test_uri, range]
+        }),
+    );
 
-    std::thread::sleep(Duration::from_millis(50));
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let apply_edit = messages
+        .iter()
+        .find(|msg| msg["method"] == "workspace/applyEdit")
+        .expect("Expected workspace/applyEdit request");
+    let edits = apply_edit["params"]["edit"]["documentChanges"][0]["edits"]
+        .as_array()
+        .unwrap();
+    assert_eq!(edits.len(), 1, "{:?}", edits);
+    assert_eq!(edits[0]["range"], range);
+    assert_eq!(edits[0]["newText"], "a * 2");
+
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "success");
+
+    client.shutdown();
+}
+
+#[cfg(feature = "test-hooks")]
+#[test]
+fn test_set_document_hook_reproduces_tricky_document() {
+    let implementation = "fn grüß() -> &'static str {\n    \"héllo\"\n}";
+    let mut client =
+        LspClient::spawn_with_env(&[("FAKE_AGENT_OUTPUT", std::ffi::OsStr::new(implementation))]);
+    let response = client.initialize();
+
+    // The hook works without being advertised
+    let commands = &response["result"]["capabilities"]["executeCommandProvider"]["commands"];
+    assert!(
+        !commands.to_string().contains("__setDocument"),
+        "{}",
+        commands
+    );
 
+    // CRLF line endings, non-ASCII text and no trailing newline, never opened
+    let test_uri = "file:///tmp/test_set_document_hook.rs";
+    let text = "// Größe: ½\r\nfn grüß() -> &'static str {\r\n    todo!()\r\n}";
     let response = client.send_request(
-        "textDocument/completion",
+        "workspace/executeCommand",
         json!({
-            "textDocument": { "uri": test_uri },
-            "position": { "line": 1, "character": 22 }
+            "command": "agent.__setDocument",
+            "arguments": [test_uri, text, 1, "rust"]
         }),
     );
+    assert!(response.get("error").is_none(), "{}", response);
 
-    assert!(response.get("result").is_some());
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 1, 0, 1, "rust"]
+        }),
+    );
+
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let apply_edit = messages
+        .iter()
+        .find(|msg| msg["method"] == "workspace/applyEdit")
+        .expect("Expected workspace/applyEdit request");
+    let edits = apply_edit["params"]["edit"]["documentChanges"][0]["edits"]
+        .as_array()
+        .unwrap();
+    assert_eq!(edits.len(), 1, "{:?}", edits);
+    assert_eq!(edits[0]["range"]["start"]["line"], 2);
+    let new_text = edits[0]["newText"].as_str().unwrap();
+    assert!(new_text.starts_with("    \"héllo\"\r\n"), "{:?}", new_text);
+    // The backend's LF output follows the document's CRLF line endings
     assert!(
-        response["result"].is_null(),
-        "Expected null completion result"
+        !new_text.replace("\r\n", "").contains('\n'),
+        "{:?}",
+        new_text
     );
 
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "success");
+
     client.shutdown();
 }
 
+#[test]
+fn test_impl_function_into_target_file() {
+    let output = "int add(int a, int b) {\n    return a + b;\n}";
+    let mut client =
+        LspClient::spawn_with_env(&[("FAKE_AGENT_OUTPUT", std::ffi::OsStr::new(output))]);
+    client.initialize();
+
+    // The prototype is declared in the header and implemented in the source file
+    let header_uri = "file:///tmp/test_target_add.h";
+    let source_uri = "file:///tmp/test_target_add.c";
+    for (uri, text) in [
+        (header_uri, "int add(int a, int b);\n"),
+        (source_uri, "#include \"add.h\"\n"),
+    ] {
+        client.send_notification(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": uri, "languageId": "c", "version": 1, "text": text }
+            }),
+        );
+    }
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [
+                header_uri, 0, 0, 1, "c", null, null, false, "int add(int a, int b);", source_uri
+            ]
+        }),
+    );
+
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let apply_edit = messages
+        .iter()
+        .find(|msg| msg["method"] == "workspace/applyEdit")
+        .expect("Expected workspace/applyEdit request");
+    let change = &apply_edit["params"]["edit"]["documentChanges"][0];
+    assert_eq!(change["textDocument"]["uri"], source_uri);
+    let new_text: String = change["edits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|edit| edit["newText"].as_str().unwrap())
+        .collect();
+    assert_eq!(new_text, format!("\n{}\n", output));
+
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "success");
+    assert_eq!(completed["params"]["uri"], header_uri);
+
+    // A target that is not open is rejected up front
+    let id = client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [
+                header_uri, 0, 0, 1, "c", null, null, false, null, "file:///tmp/not_open.c"
+            ]
+        }),
+    );
+    let messages = client.collect_messages(Duration::from_millis(500));
+    let response = messages
+        .iter()
+        .find(|msg| msg["id"] == id)
+        .expect("Expected a response");
+    assert_eq!(response["error"]["message"], "Target document not found");
+
+    client.shutdown();
+}
+
+#[test]
+fn test_backend_new_file_is_created_before_its_content() {
+    let output = json!({
+        "implementation": "fn hello() {\n    42\n}",
+        "files": [{ "path": "tests/hello_test.rs", "content": "#[test]\nfn hello_test() {}\n" }]
+    })
+    .to_string();
+    let mut client =
+        LspClient::spawn_with_env(&[("FAKE_AGENT_OUTPUT", std::ffi::OsStr::new(&output))]);
+    client.initialize_with_capabilities(json!({
+        "workspace": {
+            "workspaceEdit": { "documentChanges": true, "resourceOperations": ["create"] }
+        }
+    }));
+
+    let test_uri = "file:///tmp/test_new_file.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
+
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let create_edit = messages
+        .iter()
+        .filter(|msg| msg["method"] == "workspace/applyEdit")
+        .map(|msg| &msg["params"]["edit"]["documentChanges"])
+        .find(|changes| changes[0]["kind"] == "create")
+        .expect("Expected an edit creating the new file");
+    let new_uri = "file:///tmp/tests/hello_test.rs";
+    assert_eq!(create_edit[0]["uri"], new_uri);
+    assert_eq!(create_edit[0]["options"]["overwrite"], false);
+    assert_eq!(create_edit[0]["options"]["ignoreIfExists"], true);
+    assert_eq!(create_edit[1]["textDocument"]["uri"], new_uri);
+    assert_eq!(
+        create_edit[1]["edits"][0]["newText"],
+        "#[test]\nfn hello_test() {}\n"
+    );
+
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "success");
+
+    client.shutdown();
+}
+
+#[test]
+fn test_cli_backend_ignores_model_params() {
+    let args_dir = tempfile::TempDir::new().unwrap();
+    let args_log = args_dir.path().join("args");
+    let mut client = LspClient::spawn_with_env(&[
+        (
+            "FAKE_AGENT_OUTPUT",
+            std::ffi::OsStr::new("fn hello() {\n    42\n}"),
+        ),
+        ("FAKE_AGENT_ARGS_LOG", args_log.as_os_str()),
+    ]);
+    client.initialize_with_options(json!({ "model_params": { "temperature": 0.1, "top_p": 0.5 } }));
+
+    let test_uri = "file:///tmp/test_model_params.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
+
+    // The CLI takes no sampling parameters, so the job runs as if none were set
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "success");
+    let args = fs::read_to_string(&args_log).unwrap();
+    assert!(!args.contains("temperature"), "{}", args);
+    assert!(
+        !args.contains("top_p") && !args.contains("top-p"),
+        "{}",
+        args
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_runaway_backend_output_fails_job() {
+    let flood = std::ffi::OsStr::new("endless output");
+    let mut client = LspClient::spawn_with_env(&[("FAKE_AGENT_FLOOD", flood)]);
+    client.initialize_with_options(json!({ "max_output_bytes": 4096 }));
+
+    let test_uri = "file:///tmp/test_runaway_output.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
+
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    assert_eq!(completed["params"]["status"], "failed");
+    let error = completed["params"]["error"].as_str().unwrap();
+    assert!(error.contains("OutputTooLarge"), "{}", error);
+    assert!(error.contains("4096 bytes"), "{}", error);
+    assert!(!messages
+        .iter()
+        .any(|msg| msg["method"] == "workspace/applyEdit"));
+
+    client.shutdown();
+}
+
+#[test]
+fn test_custom_command_namespace() {
+    let mut client = LspClient::spawn();
+    let response = client.initialize_with_options(json!({ "command_namespace": "amp2" }));
+
+    let commands = &response["result"]["capabilities"]["executeCommandProvider"]["commands"];
+    assert_eq!(
+        commands,
+        &json!([
+            "amp2.implFunction",
+            "amp2.implAllFunctions",
+            "amp2.implSelection",
+            "amp2.setOutputShape",
+            "amp2.supportedLanguages",
+            "amp2.commandSchema",
+            "amp2.status"
+        ])
+    );
+
+    let backend_info = client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info notification");
+    assert_eq!(backend_info["method"], "amp2/backendInfo");
+
+    let test_uri = "file:///tmp/test_namespace.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+
+    let code_actions = client.send_request(
+        "textDocument/codeAction",
+        json!({
+            "textDocument": { "uri": test_uri },
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 0 }
+            },
+            "context": { "diagnostics": [] }
+        }),
+    );
+    assert_eq!(
+        code_actions["result"][0]["command"]["command"],
+        "amp2.implFunction"
+    );
+
+    // The default name belongs to another server now
+    let response = client.send_request(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
+    assert!(
+        response.get("error").is_some(),
+        "Expected unknown command error"
+    );
+
+    let response = client.send_request(
+        "workspace/executeCommand",
+        json!({
+            "command": "amp2.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
+    assert!(
+        response.get("result").is_some(),
+        "Expected command to be accepted"
+    );
+
+    // Closing the document cancels the job, whether or not the backend CLI exists
+    client.send_notification(
+        "textDocument/didClose",
+        json!({ "textDocument": { "uri": test_uri } }),
+    );
+
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let methods: Vec<&str> = messages
+        .iter()
+        .filter_map(|msg| msg.get("method").and_then(|m| m.as_str()))
+        .collect();
+    assert!(
+        methods.contains(&"amp2/jobCompleted"),
+        "Expected amp2/jobCompleted, got {:?}",
+        methods
+    );
+    assert!(
+        methods
+            .iter()
+            .all(|method| !method.starts_with("agent/") && !method.starts_with("agent.")),
+        "Expected only namespaced methods, got {:?}",
+        methods
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_backend_info_describes_configured_backend() {
+    let mut client = LspClient::spawn();
+    client.initialize_with_options(json!(null));
+    let backend_info = client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info notification");
+    assert_eq!(backend_info["method"], "agent/backendInfo");
+    assert_eq!(
+        backend_info["params"],
+        json!({
+            "backend": CURRENT_BACKEND.display_name(),
+            "model": CURRENT_BACKEND.model(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "supports_streaming": CURRENT_BACKEND.supports_streaming(),
+            "supports_cancel": CURRENT_BACKEND.supports_cancel(),
+        })
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_initialized_sends_backend_info_once() {
+    let mut client = LspClient::spawn();
+    let init_params = json!({
+        "processId": std::process::id(),
+        "rootUri": null,
+        "capabilities": {}
+    });
+    client.send_request("initialize", init_params);
+    // Nothing is announced before the client finishes the handshake
+    assert!(client
+        .try_read_message(Duration::from_millis(300))
+        .is_none());
+
+    client.send_notification("initialized", json!({}));
+    let backend_info_count = |messages: &[Value]| {
+        messages
+            .iter()
+            .filter(|msg| msg["method"] == "agent/backendInfo")
+            .count()
+    };
+    let messages = client.collect_messages(Duration::from_millis(500));
+    assert_eq!(backend_info_count(&messages), 1);
+
+    // A repeated `initialized` without a new `initialize` announces nothing
+    client.send_notification("initialized", json!({}));
+    let messages = client.collect_messages(Duration::from_millis(500));
+    assert_eq!(backend_info_count(&messages), 0);
+
+    client.shutdown();
+}
+
+#[test]
+fn test_backend_info_can_be_disabled() {
+    // Sent by default
+    let mut client = LspClient::spawn();
+    client.initialize_with_options(json!(null));
+    let backend_info = client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info notification");
+    assert_eq!(backend_info["method"], "agent/backendInfo");
+    client.shutdown();
+
+    // Turned off by the client, nothing is sent after `initialized`
+    let mut client = LspClient::spawn();
+    client.initialize_with_options(json!({ "backend_info": false }));
+    assert!(client
+        .try_read_message(Duration::from_millis(500))
+        .is_none());
+    client.shutdown();
+}
+
+#[test]
+fn test_reinitialize_re_announces_backend() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    // A restarted client session runs the handshake again against the same server
+    let response = client.initialize_with_options(json!(null));
+    let commands = &response["result"]["capabilities"]["executeCommandProvider"]["commands"];
+    assert!(commands
+        .as_array()
+        .unwrap()
+        .contains(&json!("agent.implFunction")));
+
+    let backend_info = client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info after re-initialization");
+    assert_eq!(backend_info["method"], "agent/backendInfo");
+    // No jobs are running, so nothing else is announced
+    assert!(client
+        .try_read_message(Duration::from_millis(200))
+        .is_none());
+
+    client.shutdown();
+}
+
+#[test]
+fn test_job_status_unknown_job() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    let response = client.send_request("agent/jobStatus", json!({ "job_id": "no-such-job" }));
+    assert_eq!(response["result"], json!({ "found": false }));
+
+    client.shutdown();
+}
+
+#[test]
+fn test_job_status_finished_job_in_history() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    let test_uri = "file:///tmp/test_job_status.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    // The job may finish before the command's response arrives, so read everything at once
+    client.send_request_async(
+        "workspace/executeCommand",
+        json!({
+            "command": "agent.implFunction",
+            "arguments": [test_uri, 0, 0, 1, "rust"]
+        }),
+    );
+
+    // Closing the document finishes the job even if the backend CLI is installed
+    client.send_notification(
+        "textDocument/didClose",
+        json!({ "textDocument": { "uri": test_uri } }),
+    );
+    let messages = client.collect_messages(Duration::from_secs(2));
+    let completed = messages
+        .iter()
+        .find(|msg| msg["method"] == "agent/jobCompleted")
+        .expect("Expected agent/jobCompleted notification");
+    let job_id = completed["params"]["job_id"].as_str().unwrap();
+
+    let response = client.send_request("agent/jobStatus", json!({ "job_id": job_id }));
+    let status = &response["result"];
+    assert_eq!(status["found"], true);
+    assert_eq!(status["job_id"], job_id);
+    assert_eq!(status["uri"], test_uri);
+    assert_eq!(status["function_signature"], "fn hello() {");
+    assert_eq!(status["backend"], CURRENT_BACKEND.display_name());
+    assert!(
+        status["state"] == "failed" || status["state"] == "cancelled",
+        "Expected a terminal state, got {}",
+        status["state"]
+    );
+    assert!(
+        status["error"].is_string(),
+        "Expected an error for {}",
+        status
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_job_stats_counts_finished_jobs() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    let response = client.send_request("agent/jobStats", json!(null));
+    assert_eq!(response["result"]["stats"]["total"], 0);
+
+    let test_uri = "file:///tmp/test_job_stats.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn one() {\n    todo!()\n}\n\nfn two() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    let request_ids: Vec<i32> = [0, 4]
+        .into_iter()
+        .map(|line| {
+            client.send_request_async(
+                "workspace/executeCommand",
+                json!({
+                    "command": "agent.implFunction",
+                    "arguments": [test_uri, line, 0, 1, "rust"]
+                }),
+            )
+        })
+        .collect();
+
+    // Closing the document finishes both jobs even if the backend CLI is installed
+    client.send_notification(
+        "textDocument/didClose",
+        json!({ "textDocument": { "uri": test_uri } }),
+    );
+    let messages = client.collect_messages(Duration::from_secs(2));
+    for id in request_ids {
+        assert!(
+            messages
+                .iter()
+                .any(|msg| msg["id"] == id && msg.get("result").is_some()),
+            "Expected command {} to be accepted",
+            id
+        );
+    }
+    let completed = messages
+        .iter()
+        .filter(|msg| msg["method"] == "agent/jobCompleted")
+        .count();
+    assert_eq!(completed, 2);
+
+    let response = client.send_request("agent/jobStats", json!(null));
+    let result = &response["result"];
+    let stats = &result["stats"];
+    assert_eq!(stats["total"], 2);
+    let by_outcome = stats["by_outcome"].as_object().unwrap();
+    let finished: u64 = by_outcome
+        .values()
+        .map(|count| count.as_u64().unwrap())
+        .sum();
+    assert_eq!(finished, 2);
+    assert_eq!(
+        stats["by_backend"][CURRENT_BACKEND.display_name()]["total"],
+        2
+    );
+    assert_eq!(result["recent"].as_array().unwrap().len(), 2);
+
+    client.shutdown();
+}
+
+#[test]
+fn test_recording_mode_writes_outgoing_transcript() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let transcript_path = dir.path().join("transcript.jsonl");
+    let mut client =
+        LspClient::spawn_with_env(&[("AGENT_LSP_RECORD", transcript_path.as_os_str())]);
+    client.initialize();
+
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": "file:///tmp/test_recording.rs",
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {}\n"
+            }
+        }),
+    );
+    let response = client.send_request(
+        "textDocument/codeAction",
+        json!({
+            "textDocument": { "uri": "file:///tmp/test_recording.rs" },
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 0 }
+            },
+            "context": { "diagnostics": [] }
+        }),
+    );
+    assert!(response.get("result").is_some());
+    client.shutdown();
+
+    let transcript: Vec<Value> = std::fs::read_to_string(&transcript_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let sequence: Vec<String> = transcript
+        .iter()
+        .map(|msg| match msg.get("method") {
+            Some(method) => method.as_str().unwrap().to_string(),
+            None => format!("response {}", msg["id"]),
+        })
+        .collect();
+
+    assert_eq!(
+        sequence,
+        vec![
+            "response 1".to_string(),
+            "agent/backendInfo".to_string(),
+            "response 2".to_string(),
+            "response 3".to_string(),
+        ]
+    );
+    assert_eq!(transcript[2]["result"], response["result"]);
+}
+
+#[test]
+fn test_did_open_and_code_action() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    let test_uri = "file:///tmp/test.rs";
+    let test_content = "fn hello() {\n    // TODO\n}\n";
+
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": test_content
+            }
+        }),
+    );
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    let response = client.send_request(
+        "textDocument/codeAction",
+        json!({
+            "textDocument": {
+                "uri": test_uri
+            },
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 0 }
+            },
+            "context": {
+                "diagnostics": []
+            }
+        }),
+    );
+
+    assert!(
+        response.get("result").is_some(),
+        "Expected result in response"
+    );
+    let result = &response["result"];
+    assert!(result.is_array(), "Expected array of code actions");
+
+    let actions = result.as_array().unwrap();
+    assert!(!actions.is_empty(), "Expected at least one code action");
+
+    let action = &actions[0];
+    let expected_title = format!("Implement function with {}", CURRENT_BACKEND.display_name());
+    assert_eq!(action["title"].as_str().unwrap(), expected_title);
+    assert_eq!(
+        action["command"]["command"].as_str().unwrap(),
+        "agent.implFunction"
+    );
+
+    let args = action["command"]["arguments"].as_array().unwrap();
+    assert_eq!(args[0].as_str().unwrap(), test_uri);
+    assert_eq!(args[1].as_u64().unwrap(), 0);
+    assert_eq!(args[2].as_u64().unwrap(), 0);
+    assert_eq!(args[3].as_i64().unwrap(), 1);
+    assert_eq!(args[4].as_str().unwrap(), "rust");
+    // Overwriting an existing implementation is never forced by the code action
+    assert_eq!(args[7], json!(false));
+    // The signature the user saw is sent along, so the server does not recompute it
+    assert_eq!(args[8], json!("fn hello() {"));
+
+    client.shutdown();
+}
+
+#[test]
+fn test_configured_code_action_kind() {
+    let mut client = LspClient::spawn();
+    let response =
+        client.initialize_with_options(json!({ "code_action_kind": "refactor.rewrite" }));
+    client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info notification");
+    assert_eq!(
+        response["result"]["capabilities"]["codeActionProvider"]["codeActionKinds"],
+        json!(["refactor.rewrite"])
+    );
+
+    let test_uri = "file:///tmp/test_action_kind.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn hello() {\n    todo!()\n}\n"
+            }
+        }),
+    );
+    let response = client.send_request(
+        "textDocument/codeAction",
+        json!({
+            "textDocument": { "uri": test_uri },
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 0 }
+            },
+            "context": { "diagnostics": [] }
+        }),
+    );
+    let actions = response["result"].as_array().unwrap();
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0]["kind"], json!("refactor.rewrite"));
+
+    client.shutdown();
+}
+
+#[test]
+fn test_config_file_and_env_precedence() {
+    let root = tempfile::TempDir::new().unwrap();
+    std::fs::write(
+        root.path().join(".agent.toml"),
+        "command_namespace = \"file\"\ncode_action_kind = \"source\"\n",
+    )
+    .unwrap();
+
+    // The environment beats both the file and the initialization options
+    let mut client =
+        LspClient::spawn_with_env(&[("AGENT_LSP_COMMAND_NAMESPACE", std::ffi::OsStr::new("env"))]);
+    let response = client.send_request(
+        "initialize",
+        json!({
+            "processId": std::process::id(),
+            "rootUri": format!("file://{}", root.path().display()),
+            "capabilities": {},
+            "initializationOptions": {
+                "command_namespace": "client",
+                "code_action_kind": "refactor.rewrite"
+            }
+        }),
+    );
+    client.send_notification("initialized", json!({}));
+    client
+        .try_read_message(Duration::from_secs(2))
+        .expect("Expected backend info notification");
+
+    let capabilities = &response["result"]["capabilities"];
+    assert!(capabilities["executeCommandProvider"]["commands"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("env.implFunction")));
+    assert_eq!(
+        capabilities["codeActionProvider"]["codeActionKinds"],
+        json!(["refactor.rewrite"])
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_did_change() {
+    let mut client = LspClient::spawn();
+    client.initialize();
+
+    let test_uri = "file:///tmp/test_change.rs";
+
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn foo() {}\n"
+            }
+        }),
+    );
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    client.send_notification(
+        "textDocument/didChange",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "version": 2
+            },
+            "contentChanges": [
+                {
+                    "range": {
+                        "start": { "line": 0, "character": 3 },
+                        "end": { "line": 0, "character": 6 }
+                    },
+                    "text": "bar"
+                }
+            ]
+        }),
+    );
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    let response = client.send_request(
+        "textDocument/codeAction",
+        json!({
+            "textDocument": { "uri": test_uri },
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 0 }
+            },
+            "context": { "diagnostics": [] }
+        }),
+    );
+
+    assert!(response.get("result").is_some());
+    let actions = response["result"].as_array().unwrap();
+    assert!(!actions.is_empty());
+
+    assert_eq!(actions[0]["command"]["arguments"][3].as_i64().unwrap(), 2);
+
+    client.shutdown();
+}
+
+#[test]
+fn test_completion_returns_null() {
+    let mut client = LspClient::spawn();
+    client.initialize_with_options(json!({ "completion_mode": "off" }));
+    std::thread::sleep(Duration::from_millis(50));
+    let _ = client.try_read_message(Duration::from_millis(100));
+
+    let test_uri = "file:///tmp/test_completion.rs";
+    client.send_notification(
+        "textDocument/didOpen",
+        json!({
+            "textDocument": {
+                "uri": test_uri,
+                "languageId": "rust",
+                "version": 1,
+                "text": "fn test() {\n    let x = something.\n}\n"
+            }
+        }),
+    );
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    let response = client.send_request(
+        "textDocument/completion",
+        json!({
+            "textDocument": { "uri": test_uri },
+            "position": { "line": 1, "character": 22 }
+        }),
+    );
+
+    assert!(response.get("result").is_some());
+    assert!(
+        response["result"].is_null(),
+        "Expected null completion result"
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_completion_provider_follows_completion_mode() {
+    for (mode, advertised) in [
+        (json!(null), false),
+        (json!("disabled"), false),
+        (json!("off"), true),
+        (json!("ai_inline"), true),
+    ] {
+        let mut client = LspClient::spawn();
+        let response = client.initialize_with_options(json!({ "completion_mode": mode }));
+        let provider = &response["result"]["capabilities"]["completionProvider"];
+        assert_eq!(!provider.is_null(), advertised, "{}", mode);
+        if advertised {
+            assert_eq!(provider["triggerCharacters"], json!(["."]), "{}", mode);
+        }
+        client.shutdown();
+    }
+}
+
 #[test]
 fn test_unknown_request_returns_error() {
     let mut client = LspClient::spawn();
@@ -922,7 +2182,7 @@ fn subtract(a: i32, b: i32) -> i32 {
         println!("  Unique job_ids: {}", job_ids.len());
 
         assert!(
-            job_ids.len() >= 1,
+            !job_ids.is_empty(),
             "Expected at least 1 unique job_id in progress notifications"
         );
     }
@@ -0,0 +1,34 @@
+local M = {}
+
+local function clamp(x, lo, hi)
+  if x < lo then
+    return lo
+  elseif x > hi then
+    return hi
+  end
+  return x
+end
+
+function M.sum(items)
+  local total = 0
+  for _, item in ipairs(items) do
+    total = total + item
+  end
+  return total
+end
+
+local on_exit = function(code)
+  -- TODO
+end
+
+M.map = function(items, f)
+  local out = {}
+  for i = 1, #items do
+    out[i] = f(items[i])
+  end
+  return out
+end
+
+function M.noop() end
+
+return M
@@ -0,0 +1,39 @@
+require "json"
+
+module Billing
+  class Invoice
+    attr_reader :items
+
+    def self.parse(text)
+      data = JSON.parse(text)
+      new(data["items"])
+    end
+
+    def total
+      sum = 0
+      items.each do |item|
+        next if item.nil?
+        if item[:discount]
+          sum += item[:price] - item[:discount]
+        else
+          sum += item[:price]
+        end
+      end
+      sum
+    end
+
+    def reset; end
+
+    def valid?(limit = 100)
+      return false if items.empty?
+      while limit > 0 do
+        limit -= 1
+      end
+      total <= limit # the end
+    end
+
+    def label
+      raise NotImplementedError
+    end
+  end
+end
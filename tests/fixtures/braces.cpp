@@ -0,0 +1,14 @@
+#include <string>
+
+// A function whose body mentions } everywhere
+std::string render(const std::string& name) {
+    const char* open = "{";
+    char close = '}';
+    auto raw = R"json({"k": "}"})json";
+    /* } */
+    return name + open + close + raw;
+}
+
+int after() {
+    return 0;
+}
@@ -0,0 +1,11 @@
+function render(name) {
+  const single = '}';
+  const text = 'not a char {';
+  const template = `${name} }
+    spans }} lines`;
+  return single + text + template;
+}
+
+function after() {
+  return 1;
+}
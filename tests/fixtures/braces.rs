@@ -0,0 +1,35 @@
+/// Renders `{` and `}` as-is; a doc comment closing a brace: }
+fn render(name: &str) -> String {
+    let open = '{';
+    let close = '}';
+    let quote = '\'';
+    // }}} a line comment full of braces {
+    format!("{{{}}} {}{}{}", name, open, close, quote)
+}
+
+fn raw_strings() -> &'static str {
+    let json = r#"{"key": "}"}"#;
+    let nested = r##"a "# } still raw"##;
+    let bytes = br"}";
+    let _ = (json, bytes);
+    nested
+}
+
+/* a block comment { with /* a nested } block */ and } more */
+fn lifetimes<'a>(items: &'a [&'a str]) -> Option<&'a str> {
+    let escaped = "a \" } quote";
+    let multi_line = "starts {
+        } and continues";
+    items.first().copied().filter(|s| s.len() > escaped.len() + multi_line.len())
+}
+
+fn unicode() -> char {
+    let s = "é}ü";
+    let c = '\u{7D}';
+    let _ = s;
+    c
+}
+
+fn after() {
+    todo!()
+}
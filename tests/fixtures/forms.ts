@@ -0,0 +1,47 @@
+import { Request, Response } from "express";
+
+export function add(a: number, b: number): number {
+  return a + b;
+}
+
+async function load(id: string) {
+  throw new Error("not implemented");
+}
+
+export default async function* stream<T>(items: T[]) {
+  yield* items;
+}
+
+export const handler = async (req: Request, res: Response) => {
+  res.send(`Hello ${req.params.name}! {unbalanced`);
+};
+
+const double = (x: number): number => {
+  return x * 2;
+};
+
+let greet = name => {
+  return `} ${name}`;
+};
+
+class Repo {
+  private items: string[] = [];
+
+  constructor(private readonly name: string) {
+    this.items = [];
+  }
+
+  async save(item: string): Promise<void> {
+    if (item) {
+      this.items.push(item);
+    }
+  }
+
+  static create<T>(name: string): Repo {
+    return new Repo(name);
+  }
+
+  onChange = (value: string) => {
+    this.save(value);
+  };
+}